@@ -0,0 +1,249 @@
+//! `#[derive(Table)]` for rsqlite3: maps a struct with named fields to a
+//! table, one column per field, by implementing `rsqlite3::model::FromRow`
+//! and `rsqlite3::model::TableModel`.
+//!
+//! The table is named after the struct in snake case unless the struct has
+//! `#[table(name = "...")]`. Fields take `#[column(...)]` with `name`,
+//! `type_name`, `primary_key` and `unique`; exactly one field is the
+//! primary key, which `find` looks rows up by. The column type follows from
+//! the field's type where it can, `Option<T>` as `T`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Fields, GenericArgument, LitStr, PathArguments, Type,
+};
+
+#[proc_macro_derive(Table, attributes(table, column))]
+pub fn derive_table(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// A field and the column it is stored in.
+struct Column {
+    field: syn::Ident,
+    name: String,
+    type_name: String,
+    primary_key: bool,
+    unique: bool,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "Table can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "Table can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut table_name = snake_case(&ident.to_string());
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("table"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                table_name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `name`"))
+            }
+        })?;
+    }
+
+    let mut columns = Vec::new();
+    for field in &fields.named {
+        let field_ident = field.ident.clone().expect("named field");
+        let mut column = Column {
+            name: field_ident.to_string().trim_start_matches("r#").to_string(),
+            type_name: sql_type(&field.ty).unwrap_or_default().to_string(),
+            field: field_ident,
+            primary_key: false,
+            unique: false,
+        };
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("column"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    column.name = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("type_name") {
+                    column.type_name = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("primary_key") {
+                    column.primary_key = true;
+                } else if meta.path.is_ident("unique") {
+                    column.unique = true;
+                } else {
+                    return Err(
+                        meta.error("expected `name`, `type_name`, `primary_key` or `unique`")
+                    );
+                }
+                Ok(())
+            })?;
+        }
+        if column.type_name.is_empty() {
+            return Err(syn::Error::new_spanned(
+                &field.ty,
+                "no column type for this field, give one with #[column(type_name = \"...\")]",
+            ));
+        }
+        columns.push(column);
+    }
+    if columns.is_empty() {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "a table needs at least one column",
+        ));
+    }
+    match columns.iter().filter(|column| column.primary_key).count() {
+        0 => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "a table needs a primary key, mark one field #[column(primary_key)]",
+            ))
+        }
+        1 => {}
+        _ => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "only one column can be the primary key",
+            ))
+        }
+    }
+
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let field_idents = columns
+        .iter()
+        .map(|column| &column.field)
+        .collect::<Vec<_>>();
+    let names = columns
+        .iter()
+        .map(|column| &column.name)
+        .collect::<Vec<_>>();
+    let infos = columns.iter().map(|column| {
+        let Column {
+            name,
+            type_name,
+            primary_key,
+            unique,
+            ..
+        } = column;
+        quote! {
+            ::rsqlite3::model::ColumnInfo {
+                name: #name,
+                type_name: #type_name,
+                primary_key: #primary_key,
+                unique: #unique,
+            }
+        }
+    });
+    Ok(quote! {
+        impl #impl_generics ::rsqlite3::model::FromRow for #ident #type_generics #where_clause {
+            fn from_row(
+                row: &::rsqlite3::rows::ResultRow,
+            ) -> ::rsqlite3::rows::Result<Self> {
+                ::std::result::Result::Ok(#ident {
+                    #(#field_idents: row.get(#names)?,)*
+                })
+            }
+        }
+
+        impl #impl_generics ::rsqlite3::model::TableModel for #ident #type_generics #where_clause {
+            const NAME: &'static str = #table_name;
+            const COLUMNS: &'static [::rsqlite3::model::ColumnInfo] = &[#(#infos),*];
+
+            fn values(&self) -> ::std::vec::Vec<::rsqlite3::value::Value> {
+                ::std::vec![
+                    #(::rsqlite3::value::Value::from(
+                        ::std::clone::Clone::clone(&self.#field_idents)
+                    )),*
+                ]
+            }
+        }
+    })
+}
+
+/// The column type of a field of type `ty`, `None` if it has no obvious
+/// one.
+fn sql_type(ty: &Type) -> Option<&'static str> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    let argument = match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => match arguments.args.first() {
+            Some(GenericArgument::Type(argument)) => Some(argument),
+            _ => None,
+        },
+        _ => None,
+    };
+    match (segment.ident.to_string().as_str(), argument) {
+        ("Option", Some(inner)) => sql_type(inner),
+        ("Vec", Some(inner)) if is_u8(inner) => Some("blob"),
+        ("i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32", None) => Some("integer"),
+        ("f32" | "f64", None) => Some("real"),
+        ("bool", None) => Some("boolean"),
+        ("String", None) => Some("text"),
+        _ => None,
+    }
+}
+
+fn is_u8(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path) if path.path.is_ident("u8"))
+}
+
+/// `OrderLine` as `order_line`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_types() {
+        let sql_type = |ty: &str| sql_type(&syn::parse_str::<Type>(ty).unwrap());
+        assert_eq!(sql_type("i64"), Some("integer"));
+        assert_eq!(sql_type("Option<f64>"), Some("real"));
+        assert_eq!(sql_type("std::string::String"), Some("text"));
+        assert_eq!(sql_type("Vec<u8>"), Some("blob"));
+        assert_eq!(sql_type("Option<Vec<u8>>"), Some("blob"));
+        assert_eq!(sql_type("Vec<i64>"), None);
+        assert_eq!(sql_type("bool"), Some("boolean"));
+        assert_eq!(sql_type("(i64, i64)"), None);
+        assert_eq!(snake_case("OrderLine"), "order_line");
+        assert_eq!(snake_case("Item"), "item");
+    }
+
+    #[test]
+    fn test_primary_key_required() {
+        let expand = |item: &str| expand(syn::parse_str(item).unwrap()).map(drop);
+        assert!(expand("struct Item { #[column(primary_key)] id: i64, name: String }").is_ok());
+        let error = expand("struct Item { id: i64, name: String }").unwrap_err();
+        assert!(error.to_string().starts_with("a table needs a primary key"));
+        let both = "struct Item { #[column(primary_key)] a: i64, #[column(primary_key)] b: i64 }";
+        assert!(expand(both).is_err());
+    }
+}
@@ -0,0 +1,554 @@
+use crate::expr::{to_numeric, BindError, Expr};
+use crate::sorter::{compare_keys, record_size, SortedRecords};
+use crate::table::Row;
+use crate::value::Value;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+
+// Rough per group bookkeeping cost, on top of the values it holds
+const GROUP_OVERHEAD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    GroupConcat,
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "avg" => Some(AggregateFunction::Avg),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            "group_concat" => Some(AggregateFunction::GroupConcat),
+            _ => None,
+        }
+    }
+
+    /// Whether the function can be called with `num_args` arguments, where
+    /// `count(*)` counts as none.
+    pub fn accepts(self, num_args: usize) -> bool {
+        match self {
+            AggregateFunction::Count => num_args <= 1,
+            AggregateFunction::GroupConcat => (1..=2).contains(&num_args),
+            _ => num_args == 1,
+        }
+    }
+}
+
+/// One aggregate call, with its arguments bound to the source row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateCall {
+    pub function: AggregateFunction,
+    pub args: Vec<Expr>,
+    pub distinct: bool,
+}
+
+/// The GROUP BY keys of a query and the aggregates it computes per group.
+///
+/// Rows are added as records of the keys, then the source row, then the
+/// arguments of every call in turn, all computed by the program. Each group
+/// produces a row laid out as its keys, then the last source row that fell
+/// into the group (for columns used outside an aggregate), then the result
+/// of every aggregate call.
+#[derive(Debug, Clone)]
+pub struct Aggregation {
+    pub keys: Vec<Expr>,
+    pub calls: Vec<AggregateCall>,
+    pub source_width: usize,
+}
+
+impl Aggregation {
+    /// `keys` must already be bound to rows `source_width` values wide.
+    pub fn new(keys: Vec<Expr>, source_width: usize) -> Result<Self, BindError> {
+        for key in &keys {
+            key.check_no_aggregate()?;
+        }
+        Ok(Aggregation {
+            keys,
+            calls: Vec::new(),
+            source_width,
+        })
+    }
+
+    /// Rewrites a bound expression to evaluate against group rows, collecting
+    /// the aggregate calls it makes.
+    pub fn rewrite(&mut self, expr: &Expr) -> Result<Expr, BindError> {
+        expr.transform(&mut |expr| {
+            if let Some(index) = self.keys.iter().position(|key| key == expr) {
+                return Ok(Some(Expr::ColumnIndex(index)));
+            }
+            match expr {
+                Expr::ColumnIndex(index) => Ok(Some(Expr::ColumnIndex(self.keys.len() + index))),
+                Expr::Function {
+                    name,
+                    args,
+                    distinct,
+                } if AggregateFunction::from_name(name).is_some() => {
+                    for arg in args {
+                        arg.check_no_aggregate()?;
+                    }
+                    let call = AggregateCall {
+                        function: AggregateFunction::from_name(name)
+                            .ok_or_else(|| BindError::NoSuchFunction(name.clone()))?,
+                        args: args.clone(),
+                        distinct: *distinct,
+                    };
+                    let index = match self.calls.iter().position(|c| *c == call) {
+                        Some(index) => index,
+                        None => {
+                            self.calls.push(call);
+                            self.calls.len() - 1
+                        }
+                    };
+                    Ok(Some(Expr::ColumnIndex(
+                        self.keys.len() + self.source_width + index,
+                    )))
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// The number of values in the records rows are added as.
+    pub fn record_width(&self) -> usize {
+        let num_args = self.calls.iter().map(|call| call.args.len()).sum::<usize>();
+        self.keys.len() + self.source_width + num_args
+    }
+}
+
+/// Encodes values so that ones that compare equal, such as 1 and 1.0, give
+/// the same bytes.
+pub fn group_key(values: &[Value]) -> Vec<u8> {
+    let values = values
+        .iter()
+        .map(|value| match value {
+            Value::Real(r) if r.fract() == 0.0 && *r >= i64::MIN as f64 && *r < i64::MAX as f64 => {
+                Value::Integer(*r as i64)
+            }
+            Value::Boolean(b) => Value::Integer(*b as i64),
+            value => value.clone(),
+        })
+        .collect();
+    Row { values }.to_payload()
+}
+
+/// Running state of one aggregate call within one group.
+struct Accumulator {
+    function: AggregateFunction,
+    seen: Option<HashSet<Vec<u8>>>,
+    count: i64,
+    /// `None` once a real was added or the sum overflowed
+    integer_sum: Option<i64>,
+    real_sum: f64,
+    extreme: Value,
+    text: Option<String>,
+}
+
+impl Accumulator {
+    fn new(call: &AggregateCall) -> Self {
+        Accumulator {
+            function: call.function,
+            seen: call.distinct.then(HashSet::new),
+            count: 0,
+            integer_sum: Some(0),
+            real_sum: 0.0,
+            extreme: Value::Null,
+            text: None,
+        }
+    }
+
+    /// Adds one row's arguments and returns roughly how many bytes the state
+    /// grew by.
+    fn step(&mut self, args: &[Value]) -> usize {
+        let mut grown = 0;
+        if let Some(value) = args.first() {
+            if value.is_null() {
+                return 0;
+            }
+            if let Some(seen) = &mut self.seen {
+                let key = group_key(std::slice::from_ref(value));
+                grown += key.len();
+                if !seen.insert(key) {
+                    return 0;
+                }
+            }
+        }
+        self.count += 1;
+        match self.function {
+            AggregateFunction::Count => {}
+            AggregateFunction::Sum | AggregateFunction::Avg => match to_numeric(&args[0]) {
+                Value::Integer(i) => {
+                    self.integer_sum = self.integer_sum.and_then(|sum| sum.checked_add(i));
+                    self.real_sum += i as f64;
+                }
+                Value::Real(r) => {
+                    self.integer_sum = None;
+                    self.real_sum += r;
+                }
+                _ => {}
+            },
+            AggregateFunction::Min | AggregateFunction::Max => {
+                let wanted = match self.function {
+                    AggregateFunction::Min => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                if self.extreme.is_null() || args[0].collate_cmp(&self.extreme) == wanted {
+                    self.extreme = args[0].clone();
+                }
+            }
+            AggregateFunction::GroupConcat => {
+                let text = args[0].to_string();
+                grown += text.len();
+                match &mut self.text {
+                    None => self.text = Some(text),
+                    Some(concat) => {
+                        match args.get(1) {
+                            Some(separator) if !separator.is_null() => {
+                                concat.push_str(&separator.to_string())
+                            }
+                            Some(_) => {}
+                            None => concat.push(','),
+                        }
+                        concat.push_str(&text);
+                    }
+                }
+            }
+        }
+        grown
+    }
+
+    fn finish(self) -> Value {
+        match self.function {
+            AggregateFunction::Count => Value::Integer(self.count),
+            _ if self.count == 0 => Value::Null,
+            AggregateFunction::Sum => match self.integer_sum {
+                Some(sum) => Value::Integer(sum),
+                None => Value::Real(self.real_sum),
+            },
+            AggregateFunction::Avg => Value::Real(self.real_sum / self.count as f64),
+            AggregateFunction::Min | AggregateFunction::Max => self.extreme,
+            AggregateFunction::GroupConcat => self.text.map_or(Value::Null, Value::Text),
+        }
+    }
+}
+
+struct Group {
+    keys: Vec<Value>,
+    last_row: Vec<Value>,
+    accumulators: Vec<Accumulator>,
+}
+
+impl Group {
+    fn new(aggregation: &Aggregation, keys: Vec<Value>) -> Self {
+        Group {
+            keys,
+            last_row: vec![Value::Null; aggregation.source_width],
+            accumulators: aggregation.calls.iter().map(Accumulator::new).collect(),
+        }
+    }
+
+    /// Adds a record past its keys: the source row and then the arguments.
+    fn step(&mut self, aggregation: &Aggregation, values: &[Value]) -> usize {
+        let (row, mut args) = values.split_at(aggregation.source_width);
+        self.last_row.clone_from_slice(row);
+        let mut grown = 0;
+        for (call, accumulator) in aggregation.calls.iter().zip(&mut self.accumulators) {
+            let (call_args, rest) = args.split_at(call.args.len());
+            grown += accumulator.step(call_args);
+            args = rest;
+        }
+        grown
+    }
+
+    fn finish(self) -> Vec<Value> {
+        let mut values = self.keys;
+        values.extend(self.last_row);
+        values.extend(self.accumulators.into_iter().map(Accumulator::finish));
+        values
+    }
+}
+
+/// Groups rows in a hash table, giving up once the groups would take more
+/// than the memory budget.
+pub struct HashAggregator {
+    aggregation: Arc<Aggregation>,
+    groups: HashMap<Vec<u8>, Group>,
+    memory_used: usize,
+    memory_budget: usize,
+}
+
+impl HashAggregator {
+    pub fn new(aggregation: Arc<Aggregation>, memory_budget: usize) -> Self {
+        HashAggregator {
+            aggregation,
+            groups: HashMap::new(),
+            memory_used: 0,
+            memory_budget,
+        }
+    }
+
+    /// Adds a record, false once the memory budget is exceeded.
+    pub fn push(&mut self, record: &[Value]) -> bool {
+        let (keys, values) = record.split_at(self.aggregation.keys.len());
+        let key = group_key(keys);
+        let memory_used = &mut self.memory_used;
+        let group = self.groups.entry(key).or_insert_with_key(|key| {
+            *memory_used += GROUP_OVERHEAD * (1 + self.aggregation.calls.len())
+                + key.len()
+                + record_size(record);
+            Group::new(&self.aggregation, keys.to_vec())
+        });
+        *memory_used += group.step(&self.aggregation, values);
+        self.memory_used <= self.memory_budget
+    }
+
+    /// The group rows in key order. Without GROUP BY there is always exactly
+    /// one group, even when there were no rows.
+    pub fn finish(self) -> Vec<Vec<Value>> {
+        let mut groups = self.groups.into_values().collect::<Vec<_>>();
+        if groups.is_empty() && self.aggregation.keys.is_empty() {
+            groups.push(Group::new(&self.aggregation, Vec::new()));
+        }
+        let ascending = vec![false; self.aggregation.keys.len()];
+        groups.sort_by(|a, b| compare_keys(&ascending, &a.keys, &b.keys));
+        groups.into_iter().map(Group::finish).collect()
+    }
+}
+
+/// Aggregates records that were sorted by group, yielding one group row at
+/// a time.
+pub struct SortedGroups {
+    aggregation: Arc<Aggregation>,
+    records: SortedRecords,
+    current: Option<Group>,
+    /// Whether any record was read, as without GROUP BY an empty input
+    /// still makes one group.
+    started: bool,
+}
+
+impl SortedGroups {
+    pub fn new(aggregation: Arc<Aggregation>, records: SortedRecords) -> Self {
+        SortedGroups {
+            aggregation,
+            records,
+            current: None,
+            started: false,
+        }
+    }
+}
+
+impl Iterator for SortedGroups {
+    type Item = io::Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let num_keys = self.aggregation.keys.len();
+        let ascending = vec![false; num_keys];
+        for record in self.records.by_ref() {
+            let mut keys = match record {
+                Ok(record) => record.values,
+                Err(e) => return Some(Err(e)),
+            };
+            self.started = true;
+            let row = keys.split_off(num_keys);
+            let finished = self
+                .current
+                .take_if(|group| compare_keys(&ascending, &group.keys, &keys) != Ordering::Equal);
+            self.current
+                .get_or_insert_with(|| Group::new(&self.aggregation, keys))
+                .step(&self.aggregation, &row);
+            if let Some(group) = finished {
+                return Some(Ok(group.finish()));
+            }
+        }
+        if let Some(group) = self.current.take() {
+            return Some(Ok(group.finish()));
+        }
+        if !self.started && num_keys == 0 {
+            self.started = true;
+            return Some(Ok(Group::new(&self.aggregation, Vec::new()).finish()));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::{ColumnRef, Scope};
+    use crate::sorter::Sorter;
+
+    fn function(name: &str, args: Vec<Expr>, distinct: bool) -> Expr {
+        Expr::Function {
+            name: name.to_string(),
+            args,
+            distinct,
+        }
+    }
+
+    fn column(name: &str) -> Expr {
+        Expr::Column(ColumnRef {
+            table: None,
+            name: name.to_string(),
+        })
+    }
+
+    fn rows() -> Vec<Vec<Value>> {
+        [
+            (Value::Text("a".to_string()), Value::Integer(1)),
+            (Value::Text("b".to_string()), Value::Real(2.5)),
+            (Value::Text("a".to_string()), Value::Null),
+            (Value::Text("a".to_string()), Value::Integer(1)),
+            (Value::Null, Value::Integer(7)),
+        ]
+        .into_iter()
+        .map(|(k, v)| vec![k, v])
+        .collect()
+    }
+
+    /// The record a program would add for the source row.
+    fn record(aggregation: &Aggregation, row: &[Value]) -> Vec<Value> {
+        let args = aggregation.calls.iter().flat_map(|call| &call.args);
+        let mut values = aggregation
+            .keys
+            .iter()
+            .map(|key| key.evaluate(row))
+            .collect::<Vec<_>>();
+        values.extend_from_slice(row);
+        values.extend(args.map(|arg| arg.evaluate(row)));
+        values
+    }
+
+    /// Group rows of `select k, <calls> from t group by k`, trimmed to the
+    /// keys and aggregate results.
+    fn aggregate(calls: Vec<Expr>, hash: bool) -> Vec<Vec<Value>> {
+        let scope = Scope::new("t", ["k".to_string(), "v".to_string()]);
+        let key = column("k").bind(&scope).unwrap();
+        let mut aggregation = Aggregation::new(vec![key], 2).unwrap();
+        for call in &calls {
+            aggregation.rewrite(&call.bind(&scope).unwrap()).unwrap();
+        }
+        let mut groups = if hash {
+            let records = rows()
+                .iter()
+                .map(|row| record(&aggregation, row))
+                .collect::<Vec<_>>();
+            let mut aggregator = HashAggregator::new(Arc::new(aggregation), usize::MAX);
+            for record in records {
+                assert!(aggregator.push(&record));
+            }
+            aggregator.finish()
+        } else {
+            let mut sorter = Sorter::new(vec![false], 0);
+            for row in rows() {
+                let values = record(&aggregation, &row);
+                sorter.push(Row { values }).unwrap();
+            }
+            let records = sorter.finish().unwrap();
+            SortedGroups::new(Arc::new(aggregation), records)
+                .collect::<io::Result<_>>()
+                .unwrap()
+        };
+        for group in &mut groups {
+            group.drain(1..3);
+        }
+        groups
+    }
+
+    #[test]
+    fn test_aggregate_functions() {
+        let calls = vec![
+            function("count", vec![], false),
+            function("count", vec![column("v")], false),
+            function("sum", vec![column("v")], false),
+            function("avg", vec![column("v")], false),
+            function("max", vec![column("v")], false),
+            function("group_concat", vec![column("v")], true),
+        ];
+        let expected = vec![
+            vec![
+                Value::Null,
+                Value::Integer(1),
+                Value::Integer(1),
+                Value::Integer(7),
+                Value::Real(7.0),
+                Value::Integer(7),
+                Value::Text("7".to_string()),
+            ],
+            vec![
+                Value::Text("a".to_string()),
+                Value::Integer(3),
+                Value::Integer(2),
+                Value::Integer(2),
+                Value::Real(1.0),
+                Value::Integer(1),
+                Value::Text("1".to_string()),
+            ],
+            vec![
+                Value::Text("b".to_string()),
+                Value::Integer(1),
+                Value::Integer(1),
+                Value::Real(2.5),
+                Value::Real(2.5),
+                Value::Real(2.5),
+                Value::Text("2.5".to_string()),
+            ],
+        ];
+        // Both strategies give the same groups in the same order
+        assert_eq!(aggregate(calls.clone(), true), expected);
+        assert_eq!(aggregate(calls, false), expected);
+    }
+
+    #[test]
+    fn test_aggregate_without_rows() {
+        let aggregation = Aggregation {
+            keys: Vec::new(),
+            calls: vec![
+                AggregateCall {
+                    function: AggregateFunction::Count,
+                    args: Vec::new(),
+                    distinct: false,
+                },
+                AggregateCall {
+                    function: AggregateFunction::Sum,
+                    args: vec![Expr::ColumnIndex(0)],
+                    distinct: false,
+                },
+            ],
+            source_width: 1,
+        };
+        let groups = HashAggregator::new(Arc::new(aggregation), usize::MAX).finish();
+        assert_eq!(groups, [[Value::Null, Value::Integer(0), Value::Null]]);
+    }
+
+    #[test]
+    fn test_sum_overflow_becomes_real() {
+        let call = AggregateCall {
+            function: AggregateFunction::Sum,
+            args: vec![Expr::ColumnIndex(0)],
+            distinct: false,
+        };
+        let mut accumulator = Accumulator::new(&call);
+        accumulator.step(&[Value::Integer(i64::MAX)]);
+        accumulator.step(&[Value::Integer(1)]);
+        assert_eq!(accumulator.finish(), Value::Real(i64::MAX as f64 + 1.0));
+    }
+
+    #[test]
+    fn test_rewrite_rejects_nested_aggregates() {
+        let mut aggregation = Aggregation::new(Vec::new(), 1).unwrap();
+        let nested = function("max", vec![function("count", vec![], false)], false);
+        assert_eq!(
+            aggregation.rewrite(&nested),
+            Err(BindError::MisuseOfAggregate("count".to_string()))
+        );
+    }
+}
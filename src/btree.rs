@@ -0,0 +1,798 @@
+use crate::pager::{read_u32, write_u32, Pager};
+use crate::table::{Row, PAGE_SIZE};
+use crate::value::Value;
+use std::cmp::Ordering;
+
+// Node header
+const NODE_TYPE_OFFSET: usize = 0;
+const NUM_CELLS_OFFSET: usize = 4;
+/// Leaves: the previous leaf. Interior nodes: the child after the last cell.
+const LINK_OFFSET: usize = 8;
+/// Leaves: the next leaf.
+const NEXT_LEAF_OFFSET: usize = 12;
+const CELLS_OFFSET: usize = 16;
+
+const LEAF_NODE: u8 = 1;
+const INTERIOR_NODE: u8 = 2;
+
+// Cells are [child (interior only)][key size][value size][overflow page][local payload]
+const CHILD_SIZE: usize = 4;
+const CELL_HEADER_SIZE: usize = 12;
+/// Payload bytes kept in the node, small enough that any four cells fit a page.
+const MAX_LOCAL_PAYLOAD: usize = (PAGE_SIZE - CELLS_OFFSET) / 4 - CHILD_SIZE - CELL_HEADER_SIZE;
+
+/// A B+tree of `(key, value)` entries ordered by key, stored one node per
+/// page. Keys are lists of values compared column by column with
+/// `Value::collate_cmp`; values are opaque bytes. Interior nodes only route
+/// the search, every entry lives in a leaf and the leaves are linked in key
+/// order.
+///
+/// The root stays on the same page for the life of the tree, so the page
+/// number can be kept in the schema table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BTree {
+    pub root_page: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Cell {
+    key: Vec<Value>,
+    /// Bytes of the encoded key.
+    key_size: usize,
+    value: Vec<u8>,
+    /// Overflow chain holding the tail of the payload, 0 if none is written.
+    overflow_page: u32,
+    /// Interior cells only: the child holding the keys before this one.
+    child: u32,
+}
+
+impl Cell {
+    fn new(key: Vec<Value>, value: Vec<u8>) -> Self {
+        let mut cell = Cell {
+            key,
+            key_size: 0,
+            value,
+            overflow_page: 0,
+            child: 0,
+        };
+        cell.key_size = cell.key_payload().len();
+        cell
+    }
+
+    fn key_payload(&self) -> Vec<u8> {
+        Row {
+            values: self.key.clone(),
+        }
+        .to_payload()
+    }
+
+    /// Bytes the cell takes in its node.
+    fn size(&self, leaf: bool) -> usize {
+        let payload_len = self.key_size + self.value.len();
+        let child = if leaf { 0 } else { CHILD_SIZE };
+        child + CELL_HEADER_SIZE + payload_len.min(MAX_LOCAL_PAYLOAD)
+    }
+}
+
+struct Node {
+    leaf: bool,
+    cells: Vec<Cell>,
+    /// See `LINK_OFFSET`.
+    link: u32,
+    next_leaf: u32,
+}
+
+impl Node {
+    fn size(&self) -> usize {
+        CELLS_OFFSET
+            + self
+                .cells
+                .iter()
+                .map(|cell| cell.size(self.leaf))
+                .sum::<usize>()
+    }
+
+    /// The child to descend into for cell position `index`.
+    fn child(&self, index: usize) -> u32 {
+        match self.cells.get(index) {
+            Some(cell) => cell.child,
+            None => self.link,
+        }
+    }
+
+    fn set_child(&mut self, index: usize, page_num: u32) {
+        match self.cells.get_mut(index) {
+            Some(cell) => cell.child = page_num,
+            None => self.link = page_num,
+        }
+    }
+}
+
+/// Compares keys column by column over the columns both have, so a shorter
+/// key compares equal to every key it is a prefix of.
+pub fn compare_prefix(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| a.collate_cmp(b))
+        .find(|&ordering| ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+fn read_node(pager: &mut Pager, page_num: u32) -> Node {
+    let page = pager.fetch_page(page_num);
+    let leaf = match page[NODE_TYPE_OFFSET] {
+        LEAF_NODE => true,
+        INTERIOR_NODE => false,
+        kind => panic!("Corrupt B-tree page {}: node type {}", page_num, kind),
+    };
+    let num_cells = read_u32(page, NUM_CELLS_OFFSET) as usize;
+    let mut offset = CELLS_OFFSET;
+    let mut cells = Vec::with_capacity(num_cells);
+    // Payloads that go on in an overflow chain are read once the node's
+    // page is no longer borrowed
+    let mut spilled = Vec::new();
+    for _ in 0..num_cells {
+        let child = if leaf {
+            0
+        } else {
+            offset += CHILD_SIZE;
+            read_u32(page, offset - CHILD_SIZE)
+        };
+        let key_size = read_u32(page, offset) as usize;
+        let value_size = read_u32(page, offset + 4) as usize;
+        let overflow_page = read_u32(page, offset + 8);
+        offset += CELL_HEADER_SIZE;
+        let payload_len = key_size + value_size;
+        let local_len = payload_len.min(MAX_LOCAL_PAYLOAD);
+        let payload = &page[offset..offset + local_len];
+        offset += local_len;
+        let mut cell = Cell {
+            key: Vec::new(),
+            key_size,
+            value: Vec::new(),
+            overflow_page,
+            child,
+        };
+        if payload_len > MAX_LOCAL_PAYLOAD {
+            spilled.push((cells.len(), payload.to_vec(), payload_len));
+        } else {
+            cell.key = decode_key(page_num, &payload[..key_size]);
+            cell.value = payload[key_size..].to_vec();
+        }
+        cells.push(cell);
+    }
+    let mut node = Node {
+        leaf,
+        cells,
+        link: read_u32(page, LINK_OFFSET),
+        next_leaf: read_u32(page, NEXT_LEAF_OFFSET),
+    };
+    for (index, mut payload, payload_len) in spilled {
+        let cell = &mut node.cells[index];
+        payload.extend(pager.read_overflow(cell.overflow_page, payload_len - MAX_LOCAL_PAYLOAD));
+        cell.key = decode_key(page_num, &payload[..cell.key_size]);
+        cell.value = payload[cell.key_size..].to_vec();
+    }
+    node
+}
+
+fn decode_key(page_num: u32, payload: &[u8]) -> Vec<Value> {
+    match Row::from_payload(payload) {
+        Ok(row) => row.values,
+        Err(e) => panic!("Corrupt B-tree page {}: {}", page_num, e),
+    }
+}
+
+/// Writes the node out, moving payload tails that have no overflow chain
+/// yet to new overflow pages.
+fn write_node(pager: &mut Pager, page_num: u32, node: &mut Node) {
+    let mut data = vec![0; PAGE_SIZE];
+    data[NODE_TYPE_OFFSET] = if node.leaf { LEAF_NODE } else { INTERIOR_NODE };
+    write_u32(&mut data, NUM_CELLS_OFFSET, node.cells.len() as u32);
+    write_u32(&mut data, LINK_OFFSET, node.link);
+    write_u32(&mut data, NEXT_LEAF_OFFSET, node.next_leaf);
+    let mut offset = CELLS_OFFSET;
+    for cell in &mut node.cells {
+        let key_payload = cell.key_payload();
+        let mut payload = key_payload.clone();
+        payload.extend_from_slice(&cell.value);
+        if payload.len() > MAX_LOCAL_PAYLOAD && cell.overflow_page == 0 {
+            cell.overflow_page = pager.write_overflow(&payload[MAX_LOCAL_PAYLOAD..]);
+        }
+        if !node.leaf {
+            write_u32(&mut data, offset, cell.child);
+            offset += CHILD_SIZE;
+        }
+        write_u32(&mut data, offset, key_payload.len() as u32);
+        write_u32(&mut data, offset + 4, cell.value.len() as u32);
+        write_u32(&mut data, offset + 8, cell.overflow_page);
+        offset += CELL_HEADER_SIZE;
+        let local_len = payload.len().min(MAX_LOCAL_PAYLOAD);
+        data[offset..offset + local_len].copy_from_slice(&payload[..local_len]);
+        offset += local_len;
+    }
+    *pager.fetch_page_mut(page_num) = data;
+}
+
+/// Where the cells of a too-large node are split so that both halves fit.
+fn split_point(node: &Node) -> usize {
+    let total = node.size() - CELLS_OFFSET;
+    let mut used = 0;
+    for (index, cell) in node.cells.iter().enumerate() {
+        used += cell.size(node.leaf);
+        if used >= total / 2 {
+            return index.clamp(1, node.cells.len() - 2);
+        }
+    }
+    node.cells.len() / 2
+}
+
+impl BTree {
+    /// Allocates the root page of a new, empty tree.
+    pub fn create(pager: &mut Pager) -> Self {
+        let root_page = pager.allocate_page();
+        let mut root = Node {
+            leaf: true,
+            cells: Vec::new(),
+            link: 0,
+            next_leaf: 0,
+        };
+        write_node(pager, root_page, &mut root);
+        BTree { root_page }
+    }
+
+    /// Adds an entry. Entries with equal keys are kept in insertion order.
+    pub fn insert(&self, pager: &mut Pager, key: Vec<Value>, value: Vec<u8>) {
+        let Some((separator, right)) =
+            self.insert_into(pager, self.root_page, Cell::new(key, value))
+        else {
+            return;
+        };
+
+        // The root was split: move its left half to a new page and turn the
+        // root into an interior node over both halves
+        let mut left = read_node(pager, self.root_page);
+        let left_page = pager.allocate_page();
+        if left.leaf {
+            write_u32(pager.fetch_page_mut(right), LINK_OFFSET, left_page);
+        }
+        write_node(pager, left_page, &mut left);
+        let mut root = Node {
+            leaf: false,
+            cells: vec![Cell {
+                child: left_page,
+                ..separator
+            }],
+            link: right,
+            next_leaf: 0,
+        };
+        write_node(pager, self.root_page, &mut root);
+    }
+
+    /// Inserts into the subtree at `page_num`. When the node has to split,
+    /// its first half stays in place and the separator and the page of the
+    /// second half are returned for the parent to link in.
+    fn insert_into(&self, pager: &mut Pager, page_num: u32, cell: Cell) -> Option<(Cell, u32)> {
+        let mut node = read_node(pager, page_num);
+        let index = node
+            .cells
+            .partition_point(|c| compare_prefix(&c.key, &cell.key) != Ordering::Greater);
+        if node.leaf {
+            node.cells.insert(index, cell);
+        } else {
+            let (separator, right) = self.insert_into(pager, node.child(index), cell)?;
+            let left = node.child(index);
+            node.set_child(index, right);
+            node.cells.insert(
+                index,
+                Cell {
+                    child: left,
+                    ..separator
+                },
+            );
+        }
+
+        if node.size() <= PAGE_SIZE {
+            write_node(pager, page_num, &mut node);
+            return None;
+        }
+
+        let mid = split_point(&node);
+        let right_page = pager.allocate_page();
+        let mut right_cells = node.cells.split_off(mid);
+        let (separator, mut right) = if node.leaf {
+            // Leaves keep every entry, the separator is a copy of the first key
+            let separator = Cell::new(right_cells[0].key.clone(), Vec::new());
+            if node.next_leaf != 0 {
+                write_u32(
+                    pager.fetch_page_mut(node.next_leaf),
+                    LINK_OFFSET,
+                    right_page,
+                );
+            }
+            let right = Node {
+                leaf: true,
+                cells: right_cells,
+                link: page_num,
+                next_leaf: node.next_leaf,
+            };
+            node.next_leaf = right_page;
+            (separator, right)
+        } else {
+            // The middle key moves up, its child becomes the left half's last
+            let separator = right_cells.remove(0);
+            let right = Node {
+                leaf: false,
+                cells: right_cells,
+                link: node.link,
+                next_leaf: 0,
+            };
+            node.link = separator.child;
+            (separator, right)
+        };
+        write_node(pager, page_num, &mut node);
+        write_node(pager, right_page, &mut right);
+        Some((separator, right_page))
+    }
+
+    /// Removes the entry with exactly this key and value, returning whether
+    /// it was found. Nodes are not merged when they become sparse, but a
+    /// leaf left empty is taken out of the tree along with its separator.
+    pub fn delete(&self, pager: &mut Pager, key: &[Value], value: &[u8]) -> bool {
+        let mut cursor = self.cursor();
+        let mut found = cursor.seek_ge(pager, key);
+        while found && compare_prefix(cursor.key(), key) == Ordering::Equal {
+            if cursor.key().len() == key.len() && cursor.value() == value {
+                let mut node = read_node(pager, cursor.page_num);
+                let cell = node.cells.remove(cursor.index);
+                pager.free_overflow(cell.overflow_page);
+                if !node.cells.is_empty() || !self.remove_leaf(pager, cursor.page_num, &node, key) {
+                    write_node(pager, cursor.page_num, &mut node);
+                }
+                return true;
+            }
+            found = cursor.next(pager);
+        }
+        false
+    }
+
+    /// Unlinks the empty leaf from its parent and its neighbours and frees
+    /// it, along with the overflow chain of the separator that led to it.
+    /// `key` is one the leaf held. Returns false, leaving the leaf in place,
+    /// if it is the root or the only child of its parent.
+    fn remove_leaf(&self, pager: &mut Pager, page_num: u32, leaf: &Node, key: &[Value]) -> bool {
+        if page_num == self.root_page {
+            return false;
+        }
+        let Some((parent_page, mut parent, index)) =
+            self.find_parent(pager, self.root_page, page_num, key)
+        else {
+            return false;
+        };
+        if parent.cells.is_empty() {
+            return false;
+        }
+        let separator = if index < parent.cells.len() {
+            parent.cells.remove(index)
+        } else {
+            // The leaf was the last child, the one before it takes its place
+            let separator = parent.cells.pop().expect("the parent has cells");
+            parent.link = separator.child;
+            separator
+        };
+        pager.free_overflow(separator.overflow_page);
+        if leaf.link != 0 {
+            write_u32(
+                pager.fetch_page_mut(leaf.link),
+                NEXT_LEAF_OFFSET,
+                leaf.next_leaf,
+            );
+        }
+        if leaf.next_leaf != 0 {
+            write_u32(pager.fetch_page_mut(leaf.next_leaf), LINK_OFFSET, leaf.link);
+        }
+        write_node(pager, parent_page, &mut parent);
+        pager.free_page(page_num);
+        true
+    }
+
+    /// Finds the interior node under `page_num` that has `child` as a
+    /// child, along with the child's position in it. Equal keys can span
+    /// several children, so every child that may hold `key` is searched.
+    fn find_parent(
+        &self,
+        pager: &mut Pager,
+        page_num: u32,
+        child: u32,
+        key: &[Value],
+    ) -> Option<(u32, Node, usize)> {
+        let node = read_node(pager, page_num);
+        if node.leaf {
+            return None;
+        }
+        let first = node
+            .cells
+            .partition_point(|c| compare_prefix(&c.key, key) == Ordering::Less);
+        let last = node
+            .cells
+            .partition_point(|c| compare_prefix(&c.key, key) != Ordering::Greater);
+        if let Some(index) = (first..=last).find(|&index| node.child(index) == child) {
+            return Some((page_num, node, index));
+        }
+        (first..=last).find_map(|index| self.find_parent(pager, node.child(index), child, key))
+    }
+
+    /// Finds the leaf and position of the first entry whose key is not less
+    /// than `key`, or with `after` greater than it, compared over the
+    /// columns of `key`.
+    fn seek_leaf(&self, pager: &mut Pager, key: &[Value], after: bool) -> (u32, Node, usize) {
+        let past = |c: &Cell| match compare_prefix(&c.key, key) {
+            Ordering::Less => true,
+            Ordering::Equal => after,
+            Ordering::Greater => false,
+        };
+        let mut page_num = self.root_page;
+        loop {
+            let node = read_node(pager, page_num);
+            let index = node.cells.partition_point(past);
+            if node.leaf {
+                return (page_num, node, index);
+            }
+            page_num = node.child(index);
+        }
+    }
+
+    /// A cursor on the tree, not on any entry until it is moved to one.
+    pub fn cursor(&self) -> BTreeCursor {
+        BTreeCursor {
+            tree: *self,
+            page_num: 0,
+            cells: Vec::new(),
+            index: 0,
+            prev_leaf: 0,
+            next_leaf: 0,
+            pages_freed: 0,
+        }
+    }
+}
+
+/// A position among the entries of a tree, which moves in key order either
+/// way, one leaf at a time. The leaf under the cursor is read when it gets
+/// there, so changes to the tree made since are not seen until the cursor
+/// is moved by a seek.
+pub struct BTreeCursor {
+    tree: BTree,
+    /// The leaf under the cursor.
+    page_num: u32,
+    cells: Vec<Cell>,
+    /// Past the last cell when the cursor is on no entry.
+    index: usize,
+    prev_leaf: u32,
+    next_leaf: u32,
+    /// The pager's count of freed pages when the leaf was read. If pages
+    /// were freed since, the neighbouring leaves are found again by key.
+    pages_freed: u64,
+}
+
+impl BTreeCursor {
+    fn load(&mut self, pager: &Pager, page_num: u32, node: Node, index: usize) {
+        self.page_num = page_num;
+        self.cells = node.cells;
+        self.index = index;
+        self.prev_leaf = node.link;
+        self.next_leaf = node.next_leaf;
+        self.pages_freed = pager.pages_freed;
+    }
+
+    /// Whether the leaves next to the cursor's may have been freed since it
+    /// read its own, so that their page numbers can not be followed.
+    fn stale(&self, pager: &Pager) -> bool {
+        self.pages_freed != pager.pages_freed
+    }
+
+    fn invalidate(&mut self) -> bool {
+        self.index = self.cells.len();
+        false
+    }
+
+    /// Skips over empty leaves to the first entry from the cursor on.
+    fn skip_forward(&mut self, pager: &mut Pager) -> bool {
+        while self.index >= self.cells.len() {
+            if self.stale(pager) {
+                // Start again after the last key the leaf held
+                let Some(last) = self.cells.last().map(|cell| cell.key.clone()) else {
+                    return self.invalidate();
+                };
+                let (page_num, node, index) = self.tree.seek_leaf(pager, &last, true);
+                self.load(pager, page_num, node, index);
+                continue;
+            }
+            if self.next_leaf == 0 {
+                return self.invalidate();
+            }
+            let page_num = self.next_leaf;
+            let node = read_node(pager, page_num);
+            self.load(pager, page_num, node, 0);
+        }
+        true
+    }
+
+    /// Moves to the first entry, returning false if the tree is empty.
+    pub fn first(&mut self, pager: &mut Pager) -> bool {
+        self.seek_ge(pager, &[])
+    }
+
+    /// Moves to the last entry, returning false if the tree is empty.
+    pub fn last(&mut self, pager: &mut Pager) -> bool {
+        self.seek_le(pager, &[])
+    }
+
+    /// Moves to the first entry whose key starts with `key`, returning
+    /// whether there is one.
+    pub fn seek(&mut self, pager: &mut Pager, key: &[Value]) -> bool {
+        self.seek_ge(pager, key) && compare_prefix(self.key(), key) == Ordering::Equal
+    }
+
+    /// Moves to the first entry whose key is not less than `key`, compared
+    /// over the columns of `key`. Returns false if there is none.
+    pub fn seek_ge(&mut self, pager: &mut Pager, key: &[Value]) -> bool {
+        let (page_num, node, index) = self.tree.seek_leaf(pager, key, false);
+        self.load(pager, page_num, node, index);
+        self.skip_forward(pager)
+    }
+
+    /// Moves to the last entry whose key is not greater than `key`,
+    /// compared over the columns of `key`. Returns false if there is none.
+    pub fn seek_le(&mut self, pager: &mut Pager, key: &[Value]) -> bool {
+        let (page_num, node, index) = self.tree.seek_leaf(pager, key, true);
+        self.load(pager, page_num, node, index);
+        self.prev(pager)
+    }
+
+    /// Moves to the next entry, returning false past the last one.
+    pub fn next(&mut self, pager: &mut Pager) -> bool {
+        if !self.is_valid() {
+            return false;
+        }
+        self.index += 1;
+        self.skip_forward(pager)
+    }
+
+    /// Moves to the entry before the one under the cursor, or before the
+    /// position a seek left it at. Returns false before the first one.
+    pub fn prev(&mut self, pager: &mut Pager) -> bool {
+        while self.index == 0 {
+            if self.stale(pager) {
+                // Start again before the first key the leaf held
+                let first = self.cells.first().map(|cell| cell.key.clone());
+                let (page_num, node, index) = match first {
+                    Some(first) => self.tree.seek_leaf(pager, &first, false),
+                    None => self.tree.seek_leaf(pager, &[], true),
+                };
+                self.load(pager, page_num, node, index);
+                continue;
+            }
+            if self.prev_leaf == 0 {
+                return self.invalidate();
+            }
+            let page_num = self.prev_leaf;
+            let node = read_node(pager, page_num);
+            let index = node.cells.len();
+            self.load(pager, page_num, node, index);
+        }
+        self.index -= 1;
+        true
+    }
+
+    /// Whether the cursor is on an entry.
+    pub fn is_valid(&self) -> bool {
+        self.index < self.cells.len()
+    }
+
+    /// The key of the entry under the cursor.
+    pub fn key(&self) -> &[Value] {
+        &self.cells[self.index].key
+    }
+
+    /// The value of the entry under the cursor.
+    pub fn value(&self) -> &[u8] {
+        &self.cells[self.index].value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    fn key(i: i64) -> Vec<Value> {
+        vec![
+            Value::Text(format!("user{:05}", (i * 7919) % 10007)),
+            Value::Integer(i),
+        ]
+    }
+
+    fn entries(tree: &BTree, pager: &mut Pager, from: &[Value]) -> Vec<(Vec<Value>, Vec<u8>)> {
+        let mut cursor = tree.cursor();
+        let mut found = cursor.seek_ge(pager, from);
+        let mut entries = Vec::new();
+        while found {
+            entries.push((cursor.key().to_vec(), cursor.value().to_vec()));
+            found = cursor.next(pager);
+        }
+        entries
+    }
+
+    #[test]
+    fn test_insert_splits_and_keeps_key_order() {
+        let path = temp_db_path("btree_insert");
+        let mut pager = Pager::pager_open(&path);
+        let tree = BTree::create(&mut pager);
+        for i in 0..2000 {
+            tree.insert(&mut pager, key(i), (i as u32).to_le_bytes().to_vec());
+        }
+        assert!(pager.num_pages > 20, "expected the tree to span many pages");
+
+        let found = entries(&tree, &mut pager, &[]);
+        let mut expected = (0..2000).map(key).collect::<Vec<_>>();
+        expected.sort_by(|a, b| compare_prefix(a, b));
+        assert_eq!(
+            found.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+            expected
+        );
+
+        // A prefix seek lands on the first key with that first column
+        let target = key(1234);
+        let found = entries(&tree, &mut pager, &target[..1]);
+        assert_eq!(found[0].0, target);
+        assert_eq!(found[0].1, 1234u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_delete_and_large_keys() {
+        let path = temp_db_path("btree_delete");
+        let mut pager = Pager::pager_open(&path);
+        let tree = BTree::create(&mut pager);
+        let big = |i: i64| {
+            vec![
+                Value::Text(format!("{}{}", i % 10, "x".repeat(3000))),
+                Value::Integer(i),
+            ]
+        };
+        for i in 0..100 {
+            tree.insert(&mut pager, big(i), vec![i as u8]);
+        }
+        for i in (0..100).filter(|i| i % 3 != 0) {
+            assert!(tree.delete(&mut pager, &big(i), &[i as u8]));
+        }
+        assert!(!tree.delete(&mut pager, &big(1), &[1]), "already deleted");
+        assert!(
+            !tree.delete(&mut pager, &big(0), &[1]),
+            "value does not match"
+        );
+
+        let found = entries(&tree, &mut pager, &[Value::Text("3".to_string())]);
+        let firsts = found.iter().map(|(_, v)| v[0]).collect::<Vec<_>>();
+        assert_eq!(&firsts[..4], [3, 33, 63, 93]);
+        assert_eq!(entries(&tree, &mut pager, &[]).len(), 34);
+
+        // Emptied leaves go back to the freelist with the overflow chains of
+        // their separators, so inserting the keys again takes no new pages
+        for i in (0..100).filter(|i| i % 3 == 0) {
+            assert!(tree.delete(&mut pager, &big(i), &[i as u8]));
+        }
+        assert!(entries(&tree, &mut pager, &[]).is_empty());
+        let num_pages = pager.num_pages;
+        assert!(pager.freelist_count > num_pages * 3 / 4);
+        for i in 0..100 {
+            tree.insert(&mut pager, big(i), vec![i as u8]);
+        }
+        assert_eq!(pager.num_pages, num_pages);
+        assert_eq!(entries(&tree, &mut pager, &[]).len(), 100);
+    }
+
+    #[test]
+    fn test_cursor_survives_freed_leaves() {
+        let path = temp_db_path("btree_freed_leaves");
+        let mut pager = Pager::pager_open(&path);
+        let tree = BTree::create(&mut pager);
+        let int = |i: i64| vec![Value::Integer(i)];
+        for i in 0..2000 {
+            tree.insert(&mut pager, int(i), vec![0; 100]);
+        }
+
+        // The leaves after the cursor's are freed while it is on an entry
+        let mut cursor = tree.cursor();
+        assert!(cursor.seek(&mut pager, &int(100)));
+        for i in 101..1500 {
+            assert!(tree.delete(&mut pager, &int(i), &[0; 100]));
+        }
+        let mut keys = Vec::new();
+        while cursor.next(&mut pager) {
+            keys.push(cursor.key()[0].clone());
+        }
+        assert!(keys
+            .windows(2)
+            .all(|pair| compare_prefix(&pair[..1], &pair[1..]) == Ordering::Less));
+        assert!(keys.ends_with(&(1500..2000).map(Value::Integer).collect::<Vec<_>>()));
+
+        // And the ones before it
+        assert!(cursor.seek(&mut pager, &int(1500)));
+        for i in 1..1500 {
+            tree.delete(&mut pager, &int(i), &[0; 100]);
+        }
+        assert!(cursor.prev(&mut pager));
+        assert_eq!(cursor.key(), int(0));
+        assert!(cursor.next(&mut pager));
+        assert_eq!(cursor.key(), int(1500));
+    }
+
+    #[test]
+    fn test_cursor_moves_both_ways() {
+        let path = temp_db_path("btree_cursor");
+        let mut pager = Pager::pager_open(&path);
+        let tree = BTree::create(&mut pager);
+        let int = |i: i64| vec![Value::Integer(i)];
+        let mut cursor = tree.cursor();
+        assert!(!cursor.first(&mut pager));
+        assert!(!cursor.last(&mut pager));
+        assert!(!cursor.next(&mut pager));
+
+        for i in 0..2000 {
+            tree.insert(&mut pager, int(i), vec![0; 100]);
+        }
+        // Leaves left empty are stepped over
+        for i in 500..900 {
+            assert!(tree.delete(&mut pager, &int(i), &[0; 100]));
+        }
+        for value in 1..3 {
+            tree.insert(&mut pager, int(1500), vec![value; 100]);
+        }
+
+        let mut backward = Vec::new();
+        let mut found = cursor.last(&mut pager);
+        while found {
+            backward.push(cursor.key()[0].clone());
+            found = cursor.prev(&mut pager);
+        }
+        let mut forward = entries(&tree, &mut pager, &[]);
+        forward.reverse();
+        assert_eq!(
+            backward,
+            forward
+                .into_iter()
+                .map(|(k, _)| k[0].clone())
+                .collect::<Vec<_>>()
+        );
+        assert!(!cursor.is_valid());
+
+        assert!(!cursor.seek(&mut pager, &int(700)));
+        assert!(cursor.seek_ge(&mut pager, &int(700)));
+        assert_eq!(cursor.key(), int(900));
+        assert!(cursor.prev(&mut pager));
+        assert_eq!(cursor.key(), int(499));
+        assert!(cursor.seek_le(&mut pager, &int(700)));
+        assert_eq!(cursor.key(), int(499));
+        assert!(cursor.next(&mut pager));
+        assert_eq!(cursor.key(), int(900));
+        assert!(!cursor.seek_le(&mut pager, &int(-1)));
+        assert!(!cursor.seek_ge(&mut pager, &int(2000)));
+
+        // Equal keys: the first of them going up, the last going down
+        assert!(cursor.seek(&mut pager, &int(1500)));
+        assert_eq!(cursor.value()[0], 0);
+        assert!(cursor.seek_le(&mut pager, &int(1500)));
+        assert_eq!(cursor.value()[0], 2);
+
+        // A range scan going down
+        let mut range = Vec::new();
+        let mut found = cursor.seek_le(&mut pager, &int(1450));
+        while found && compare_prefix(cursor.key(), &int(1400)) != Ordering::Less {
+            range.push(cursor.key()[0].clone());
+            found = cursor.prev(&mut pager);
+        }
+        assert_eq!(range.len(), 51);
+        assert_eq!(range[0], Value::Integer(1450));
+        assert_eq!(range[50], Value::Integer(1400));
+    }
+}
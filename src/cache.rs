@@ -0,0 +1,165 @@
+//! A cache of committed pages that the connections of one `Database`
+//! share, so a page one thread read from the file is there for the others.
+//! It is split into shards, each behind a latch of its own, so threads
+//! reading different pages do not wait for each other.
+//!
+//! A page is cached along with the change counter of the commit it was read
+//! at. A connection only takes pages cached at the commit it reads, so one
+//! still reading an older snapshot never sees a newer version.
+//!
+//! A full shard makes room by the clock algorithm: a hand goes round the
+//! pages, passing over those read since it last came by and evicting the
+//! first one that was not.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{PoisonError, RwLock};
+
+const SHARDS: usize = 16;
+
+/// How many pages a cache holds unless told otherwise.
+pub const DEFAULT_CACHE_PAGES: usize = 2000;
+
+struct CachedPage {
+    change_counter: u32,
+    data: Vec<u8>,
+    /// Set when the page is read, cleared as the clock hand passes it.
+    referenced: AtomicBool,
+}
+
+#[derive(Default)]
+struct Shard {
+    pages: HashMap<u32, CachedPage>,
+    /// The cached page numbers in the order the hand goes round them.
+    clock: Vec<u32>,
+    hand: usize,
+}
+
+impl Shard {
+    /// Evicts the first page the hand comes to that was not read since it
+    /// last went by, returning the slot in `clock` it leaves free.
+    fn evict(&mut self) -> usize {
+        loop {
+            let slot = self.hand;
+            self.hand = (self.hand + 1) % self.clock.len();
+            let page_num = self.clock[slot];
+            if !self.pages[&page_num]
+                .referenced
+                .swap(false, Ordering::Relaxed)
+            {
+                self.pages.remove(&page_num);
+                return slot;
+            }
+        }
+    }
+}
+
+pub struct PageCache {
+    shards: Vec<RwLock<Shard>>,
+    shard_capacity: usize,
+}
+
+impl PageCache {
+    /// A cache of up to about `capacity` pages.
+    pub fn new(capacity: usize) -> Self {
+        PageCache {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            shard_capacity: capacity.div_ceil(SHARDS).max(1),
+        }
+    }
+
+    fn shard(&self, page_num: u32) -> &RwLock<Shard> {
+        &self.shards[page_num as usize % SHARDS]
+    }
+
+    /// Copies the page as of the commit with `change_counter` into `page`,
+    /// returning false if it is not cached.
+    pub fn get(&self, page_num: u32, change_counter: u32, page: &mut [u8]) -> bool {
+        // A thread that panicked holding the latch left whole pages behind
+        let shard = self
+            .shard(page_num)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        match shard.pages.get(&page_num) {
+            Some(cached) if cached.change_counter == change_counter => {
+                page.copy_from_slice(&cached.data);
+                cached.referenced.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Caches the page as read at the commit with `change_counter`, in
+    /// place of any other version of it.
+    pub fn insert(&self, page_num: u32, change_counter: u32, page: &[u8]) {
+        let mut shard = self
+            .shard(page_num)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let cached = CachedPage {
+            change_counter,
+            data: page.to_vec(),
+            referenced: AtomicBool::new(false),
+        };
+        if let Some(version) = shard.pages.get_mut(&page_num) {
+            *version = cached;
+            return;
+        }
+        if shard.clock.len() < self.shard_capacity {
+            shard.clock.push(page_num);
+        } else {
+            let slot = shard.evict();
+            shard.clock[slot] = page_num;
+        }
+        shard.pages.insert(page_num, cached);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_and_capacity() {
+        let cache = PageCache::new(SHARDS);
+        let mut page = [0; 4];
+        assert!(!cache.get(1, 7, &mut page));
+        cache.insert(1, 7, &[1; 4]);
+        assert!(cache.get(1, 7, &mut page));
+        assert_eq!(page, [1; 4]);
+
+        // Only the version of the commit asked for
+        assert!(!cache.get(1, 8, &mut page));
+        cache.insert(1, 8, &[2; 4]);
+        assert!(!cache.get(1, 7, &mut page));
+        assert!(cache.get(1, 8, &mut page));
+        assert_eq!(page, [2; 4]);
+
+        // A full shard makes room
+        cache.insert(1 + SHARDS as u32, 8, &[3; 4]);
+        assert!(!cache.get(1, 8, &mut page));
+        assert!(cache.get(1 + SHARDS as u32, 8, &mut page));
+    }
+
+    #[test]
+    fn test_clock_keeps_pages_in_use() {
+        let cache = PageCache::new(3 * SHARDS);
+        let page_num = |i: u32| i * SHARDS as u32;
+        let mut page = [0; 4];
+        for i in 0..3 {
+            cache.insert(page_num(i), 1, &[i as u8; 4]);
+        }
+        // The page read since it was cached outlives those that were not,
+        // however early it came
+        assert!(cache.get(page_num(0), 1, &mut page));
+        cache.insert(page_num(3), 1, &[3; 4]);
+        cache.insert(page_num(4), 1, &[4; 4]);
+        assert!(cache.get(page_num(0), 1, &mut page));
+        assert_eq!(page, [0; 4]);
+        assert!(!cache.get(page_num(1), 1, &mut page));
+        assert!(!cache.get(page_num(2), 1, &mut page));
+        assert!(cache.get(page_num(3), 1, &mut page));
+        assert!(cache.get(page_num(4), 1, &mut page));
+    }
+}
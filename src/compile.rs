@@ -0,0 +1,2764 @@
+//! The compiler from parsed statements to the programs the VM runs. Tables
+//! and indexes are looked up and access paths planned here, so a program
+//! only fails on the data it meets.
+
+use crate::aggregate::Aggregation;
+use crate::error::Error;
+use crate::expr::{BinaryOp, ColumnRef, Expr, Scope};
+use crate::pager::JournalMode;
+use crate::parser::{
+    self, Delete, Insert, JoinKind, PrepareSyntaxError, ResultColumn, Select, StatementType,
+    TableRef, Update,
+};
+use crate::planner::{plan_joins, AccessPath, JoinStrategy, JoinTable, Level, TableInfo};
+use crate::schema::TableSchema;
+use crate::stats;
+use crate::table::{self, UniqueViolation};
+use crate::value::Value;
+use crate::vm::{Insn, Program, ProgramBuilder, SavepointOp, Vm, VmError};
+use std::io;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ExecuteResult {
+    Success,
+    RowNotFound,
+    TableNotFound(String),
+    TableExists(String),
+    IndexExists(String),
+    ColumnNotFound(String),
+    AmbiguousColumn(String),
+    NoSuchFunction(String),
+    WrongNumberOfArguments(String),
+    MisuseOfAggregate(String),
+    ColumnCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    UniqueViolation(UniqueViolation),
+    OrderByOutOfRange(i64),
+    TransactionActive,
+    NoTransaction,
+    NoSuchSavepoint(String),
+    /// Another connection holds a lock the statement needs.
+    Busy,
+    IoError(io::Error),
+    SyntaxError(PrepareSyntaxError),
+}
+
+impl From<Error> for ExecuteResult {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Syntax(error) => ExecuteResult::SyntaxError(error),
+            Error::RowNotFound => ExecuteResult::RowNotFound,
+            Error::TableNotFound(name) => ExecuteResult::TableNotFound(name),
+            Error::TableExists(name) => ExecuteResult::TableExists(name),
+            Error::IndexExists(name) => ExecuteResult::IndexExists(name),
+            Error::ColumnNotFound(name) => ExecuteResult::ColumnNotFound(name),
+            Error::AmbiguousColumn(name) => ExecuteResult::AmbiguousColumn(name),
+            Error::NoSuchFunction(name) => ExecuteResult::NoSuchFunction(name),
+            Error::WrongNumberOfArguments(name) => ExecuteResult::WrongNumberOfArguments(name),
+            Error::MisuseOfAggregate(name) => ExecuteResult::MisuseOfAggregate(name),
+            Error::ColumnCountMismatch { expected, actual } => {
+                ExecuteResult::ColumnCountMismatch { expected, actual }
+            }
+            Error::UniqueViolation(violation) => ExecuteResult::UniqueViolation(violation),
+            Error::OrderByOutOfRange(position) => ExecuteResult::OrderByOutOfRange(position),
+            Error::TransactionActive => ExecuteResult::TransactionActive,
+            Error::NoTransaction => ExecuteResult::NoTransaction,
+            Error::NoSuchSavepoint(name) => ExecuteResult::NoSuchSavepoint(name),
+            Error::Busy => ExecuteResult::Busy,
+            Error::Io(error) => ExecuteResult::IoError(error),
+            // Errors of the library API the compiler and the VM do not raise
+            error => ExecuteResult::IoError(io::Error::other(error)),
+        }
+    }
+}
+
+impl From<io::Error> for ExecuteResult {
+    fn from(error: io::Error) -> Self {
+        Error::from(error).into()
+    }
+}
+
+impl From<VmError> for ExecuteResult {
+    fn from(error: VmError) -> Self {
+        Error::from(error).into()
+    }
+}
+
+/// Runs a statement, in a transaction of its own when none is active. `run`
+/// runs the compiled program.
+pub(crate) fn execute(
+    statement: StatementType,
+    sql: &str,
+    table: &mut table::Table,
+    run: impl FnOnce(Arc<Program>, &mut table::Table) -> ExecuteResult,
+) -> ExecuteResult {
+    // Outside of a transaction every statement runs in one of its own
+    let autocommit = !table.in_transaction()
+        && !matches!(
+            statement,
+            StatementType::Begin
+                | StatementType::Commit
+                | StatementType::Rollback
+                | StatementType::Savepoint(_)
+                | StatementType::Release(_)
+                | StatementType::RollbackTo(_)
+        );
+    if autocommit {
+        let write = !matches!(
+            statement,
+            StatementType::Select(_) | StatementType::Pragma(_)
+        );
+        if let Err(error) = table.begin(write) {
+            return error.into();
+        }
+    }
+    let result = match create_legacy_table(&statement, table)
+        .and_then(|()| compile_statement(statement, sql, table))
+    {
+        Ok(program) => run(Arc::new(program), table),
+        Err(error) => error.into(),
+    };
+    if autocommit {
+        match result {
+            ExecuteResult::Success => {
+                if let Err(error) = table.commit() {
+                    // A busy commit keeps the transaction
+                    if table.in_transaction() {
+                        table.rollback();
+                    }
+                    return error.into();
+                }
+            }
+            _ => table.rollback(),
+        }
+    }
+    result
+}
+
+/// Creates the legacy users table the first time a statement uses it, by
+/// running its CREATE TABLE as a statement of the transaction.
+fn create_legacy_table(statement: &StatementType, table: &mut table::Table) -> Result<(), Error> {
+    let name = match statement {
+        StatementType::Insert(insert) => &insert.table,
+        StatementType::Select(select) => &select.from.name,
+        StatementType::Update(update) => &update.table,
+        StatementType::Delete(delete) => &delete.table,
+        StatementType::LegacyUpdate(update) => &update.table,
+        StatementType::LegacyDelete(delete) => &delete.table,
+        _ => return Ok(()),
+    };
+    if name != parser::LEGACY_TABLE || table.catalog.find_table(name).is_some() {
+        return Ok(());
+    }
+    let create = parser::parse_statement(parser::LEGACY_TABLE_SQL)?;
+    let program = compile_statement(create, parser::LEGACY_TABLE_SQL, table)?;
+    let mut vm = Vm::new(Arc::new(program));
+    while vm.step(table)?.is_some() {}
+    Ok(())
+}
+
+/// Compiles a statement into a program. Tables and indexes are looked up
+/// here, so a program only fails on the data it meets.
+pub(crate) fn compile_statement(
+    statement: StatementType,
+    sql: &str,
+    table: &mut table::Table,
+) -> Result<Program, Error> {
+    let single = |insn: Insn| {
+        let mut builder = ProgramBuilder::new(0);
+        builder.emit(insn);
+        builder.emit(Insn::Halt);
+        builder.finish(Vec::new())
+    };
+    match statement {
+        StatementType::CreateTable(create) => {
+            if table.catalog.find_table(&create.name).is_some() {
+                return Err(Error::TableExists(create.name));
+            }
+            let sql = sql.to_string();
+            Ok(single(Insn::CreateTable { create, sql }))
+        }
+        StatementType::CreateIndex(create) => {
+            if table.catalog.find_index(&create.name).is_some() {
+                return Err(Error::IndexExists(create.name));
+            }
+            find_table(&create.table, table)?;
+            let sql = sql.to_string();
+            Ok(single(Insn::CreateIndex { create, sql }))
+        }
+        StatementType::Insert(insert) => compile_insert(insert, table),
+        StatementType::Select(select) => Ok(prepare_query(select, table)?.compile()),
+        StatementType::Update(update) => Ok(prepare_update(update, table)?.compile()),
+        StatementType::Delete(delete) => Ok(prepare_delete(delete, table)?.compile()),
+        StatementType::LegacyUpdate(update) => {
+            Ok(
+                prepare_legacy_change(&update.table, update.key, Some(update.values), table)?
+                    .compile(),
+            )
+        }
+        StatementType::LegacyDelete(delete) => {
+            Ok(prepare_legacy_change(&delete.table, delete.key, None, table)?.compile())
+        }
+        StatementType::Analyze(analyze) => {
+            let names = match analyze.table {
+                Some(name) => vec![find_table(&name, table)?.name],
+                None => table
+                    .catalog
+                    .tables
+                    .iter()
+                    .map(|schema| schema.name.clone())
+                    .filter(|name| !name.eq_ignore_ascii_case(stats::STAT_TABLE))
+                    .collect(),
+            };
+            let mut builder = ProgramBuilder::new(0);
+            for name in names {
+                builder.emit(Insn::Analyze { table: name });
+            }
+            builder.emit(Insn::Halt);
+            Ok(builder.finish(Vec::new()))
+        }
+        StatementType::Pragma(pragma) => {
+            let mut builder = ProgramBuilder::new(0);
+            // Like SQLite, an unknown pragma does nothing, and a value that
+            // is no journal mode just reports the current one
+            if !pragma.name.eq_ignore_ascii_case("journal_mode") {
+                builder.emit(Insn::Halt);
+                return Ok(builder.finish(Vec::new()));
+            }
+            let dest = builder.alloc_registers(1);
+            builder.emit(Insn::JournalMode {
+                mode: pragma.value.as_deref().and_then(JournalMode::parse),
+                dest,
+            });
+            builder.emit(Insn::ResultRow {
+                registers: dest..dest + 1,
+            });
+            builder.emit(Insn::Halt);
+            Ok(builder.finish(vec!["journal_mode".to_string()]))
+        }
+        StatementType::Begin => Ok(single(Insn::AutoCommit {
+            enable: false,
+            rollback: false,
+        })),
+        StatementType::Commit => Ok(single(Insn::AutoCommit {
+            enable: true,
+            rollback: false,
+        })),
+        StatementType::Rollback => Ok(single(Insn::AutoCommit {
+            enable: true,
+            rollback: true,
+        })),
+        StatementType::Savepoint(name) => Ok(single(Insn::Savepoint {
+            op: SavepointOp::Begin,
+            name,
+        })),
+        StatementType::Release(name) => Ok(single(Insn::Savepoint {
+            op: SavepointOp::Release,
+            name,
+        })),
+        StatementType::RollbackTo(name) => Ok(single(Insn::Savepoint {
+            op: SavepointOp::Rollback,
+            name,
+        })),
+        StatementType::Explain(explain) => compile_explain(explain, sql, table),
+    }
+}
+
+/// A program returning the lines of EXPLAIN QUERY PLAN as rows, or the
+/// instructions of the statement's program for EXPLAIN. The statement
+/// itself is not run.
+fn compile_explain(
+    explain: parser::Explain,
+    sql: &str,
+    table: &mut table::Table,
+) -> Result<Program, Error> {
+    let mut builder = ProgramBuilder::new(0);
+    let columns = if explain.query_plan {
+        let dest = builder.alloc_registers(1);
+        for line in query_plan(*explain.statement, table)? {
+            builder.emit(Insn::Value {
+                value: Value::Text(line),
+                dest,
+            });
+            builder.emit(Insn::ResultRow {
+                registers: dest..dest + 1,
+            });
+        }
+        vec!["detail".to_string()]
+    } else {
+        let dest = builder.alloc_registers(3);
+        let program = compile_statement(*explain.statement, sql, table)?;
+        for (addr, insn) in program.insns.iter().enumerate() {
+            let (opcode, detail) = insn.describe();
+            builder.emit(Insn::Integer {
+                value: addr as i64,
+                dest,
+            });
+            builder.emit(Insn::Value {
+                value: Value::Text(opcode.to_string()),
+                dest: dest + 1,
+            });
+            builder.emit(Insn::Value {
+                value: Value::Text(detail),
+                dest: dest + 2,
+            });
+            builder.emit(Insn::ResultRow {
+                registers: dest..dest + 3,
+            });
+        }
+        ["addr", "opcode", "detail"].map(String::from).to_vec()
+    };
+    builder.emit(Insn::Halt);
+    Ok(builder.finish(columns))
+}
+
+/// Looks up a table in the catalog.
+fn find_table(name: &str, table: &table::Table) -> Result<TableSchema, Error> {
+    if let Some(schema) = table.catalog.find_table(name) {
+        return Ok(schema.clone());
+    }
+    Err(Error::TableNotFound(name.to_string()))
+}
+
+/// The positions in the table of the columns `values` are given for, all of
+/// them in order when there is no column list.
+fn value_columns(
+    schema: &TableSchema,
+    columns: &Option<Vec<String>>,
+    values: usize,
+) -> Result<Vec<usize>, Error> {
+    let indexes = match columns {
+        Some(columns) => columns
+            .iter()
+            .map(|name| {
+                schema
+                    .column_index(name)
+                    .ok_or_else(|| Error::ColumnNotFound(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => (0..schema.columns.len()).collect(),
+    };
+    if indexes.len() != values {
+        return Err(Error::ColumnCountMismatch {
+            expected: indexes.len(),
+            actual: values,
+        });
+    }
+    Ok(indexes)
+}
+
+fn compile_insert(insert: Insert, table: &mut table::Table) -> Result<Program, Error> {
+    let schema = find_table(&insert.table, table)?;
+    let width = schema.columns.len();
+    let rows = insert
+        .rows
+        .into_iter()
+        .map(|values| {
+            let mut row = vec![Expr::Literal(Value::Null); width];
+            for (index, value) in value_columns(&schema, &insert.columns, values.len())?
+                .into_iter()
+                .zip(values)
+            {
+                row[index] = value;
+            }
+            Ok(row)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    // Rows are checked one at a time so that they are also checked against
+    // each other. If one fails, the VM takes the rows inserted before it out.
+    let mut builder = ProgramBuilder::new(width);
+    let cursor = builder.alloc_cursor();
+    builder.emit(Insn::OpenWrite {
+        cursor,
+        root_page: schema.root_page,
+    });
+    for row in rows {
+        for (dest, value) in row.into_iter().enumerate() {
+            let affinity = schema.columns[dest].affinity;
+            builder.emit(match value {
+                Expr::Literal(value) => Insn::Value {
+                    value: affinity.apply(value),
+                    dest,
+                },
+                Expr::Parameter(parameter) => Insn::Variable {
+                    parameter,
+                    affinity,
+                    dest,
+                },
+                value => unreachable!("insert value {} is not a literal", value),
+            });
+        }
+        builder.emit(Insn::CheckUnique {
+            cursor,
+            record: 0..width,
+            replaces_current: false,
+        });
+        builder.emit(Insn::Insert {
+            cursor,
+            record: 0..width,
+        });
+    }
+    builder.emit(Insn::Halt);
+    Ok(builder.finish(Vec::new()))
+}
+
+/// Compiles the legacy UPDATE and DELETE, which change the first row whose
+/// primary key is `key`. `row` is the new row for an UPDATE.
+/// An UPDATE or DELETE bound to its table: the rows its WHERE clause picks,
+/// read the way the planner chose, and for an UPDATE the new value of each
+/// assigned column.
+struct RowChange {
+    query: Query,
+    /// By column position, `None` for a DELETE.
+    assignments: Option<Vec<(usize, Expr)>>,
+    /// Whether changing no row is a row-not-found error, as it is for the
+    /// legacy `update` and `delete` commands.
+    required: bool,
+}
+
+fn prepare_row_change(
+    name: &str,
+    filter: Option<Expr>,
+    assignments: Option<Vec<(String, Expr)>>,
+    table: &mut table::Table,
+) -> Result<RowChange, Error> {
+    let schema = find_table(name, table)?;
+    let scope = schema.scope_as(&schema.name);
+    let bind = |expr: Expr| {
+        expr.check_no_aggregate()?;
+        expr.bind(&scope)
+    };
+    let filter = filter.map(bind).transpose()?;
+    let assignments = match assignments {
+        Some(assignments) => Some(
+            assignments
+                .into_iter()
+                .map(|(column, expr)| match schema.column_index(&column) {
+                    Some(index) => Ok((index, bind(expr)?)),
+                    None => Err(Error::ColumnNotFound(column)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => None,
+    };
+    let sources = vec![FromTable {
+        table: TableRef {
+            name: schema.name.clone(),
+            alias: None,
+        },
+        schema,
+        offset: 0,
+        kind: JoinKind::Inner,
+        on: None,
+    }];
+    let levels = plan_sources(
+        &sources,
+        filter.as_ref(),
+        &[],
+        assignments.iter().flatten().map(|(_, expr)| expr),
+        table,
+    );
+    let query = Query {
+        sources,
+        levels,
+        columns: Vec::new(),
+        aggregation: None,
+        having: None,
+        exprs: Vec::new(),
+        sort_keys: Vec::new(),
+        descending: Vec::new(),
+        limit: None,
+        offset: 0,
+    };
+    Ok(RowChange {
+        query,
+        assignments,
+        required: false,
+    })
+}
+
+fn prepare_update(update: Update, table: &mut table::Table) -> Result<RowChange, Error> {
+    prepare_row_change(
+        &update.table,
+        update.filter,
+        Some(update.assignments),
+        table,
+    )
+}
+
+fn prepare_delete(delete: Delete, table: &mut table::Table) -> Result<RowChange, Error> {
+    prepare_row_change(&delete.table, delete.filter, None, table)
+}
+
+/// The legacy `update` and `delete` commands: a change of the row with
+/// primary key `key`, setting every column to `values` for an update.
+fn prepare_legacy_change(
+    name: &str,
+    key: Value,
+    values: Option<Vec<Value>>,
+    table: &mut table::Table,
+) -> Result<RowChange, Error> {
+    let schema = find_table(name, table)?;
+    let column = |index: usize| ColumnRef {
+        table: None,
+        name: schema.columns[index].name.clone(),
+    };
+    let filter = Expr::Binary {
+        op: BinaryOp::Equal,
+        left: Box::new(Expr::Column(column(schema.primary_key_index()))),
+        right: Box::new(Expr::Literal(key)),
+    };
+    let assignments = match values {
+        Some(values) => {
+            let indexes = value_columns(&schema, &None, values.len())?;
+            let assignments = indexes.into_iter().zip(values);
+            let assignments =
+                assignments.map(|(index, value)| (column(index).name, Expr::Literal(value)));
+            Some(assignments.collect())
+        }
+        None => None,
+    };
+    let change = prepare_row_change(name, Some(filter), assignments, table)?;
+    Ok(RowChange {
+        required: true,
+        ..change
+    })
+}
+
+impl RowChange {
+    /// Collects the rowids of the rows to change first, then changes them
+    /// one at a time, so that the scan never reads a tree it changed.
+    fn compile(&self) -> Program {
+        let mut compiler = QueryCompiler::new(&self.query, true);
+        let cursor = compiler.cursors[0].table;
+        let sorter = compiler.builder.alloc_cursor();
+        let rowid = compiler.builder.alloc_registers(1);
+        compiler.builder.emit(Insn::SorterOpen {
+            cursor: sorter,
+            descending: vec![false],
+        });
+        compiler.scan(&|compiler| {
+            compiler.builder.emit(Insn::Rowid {
+                cursor,
+                dest: rowid,
+            });
+            compiler.builder.emit(Insn::SorterInsert {
+                cursor: sorter,
+                record: rowid..rowid + 1,
+            });
+        });
+
+        let (top, next, end) = (
+            compiler.builder.label(),
+            compiler.builder.label(),
+            compiler.builder.label(),
+        );
+        let empty = match self.required {
+            true => compiler.builder.label(),
+            false => end,
+        };
+        compiler.builder.emit(Insn::SorterSort {
+            cursor: sorter,
+            if_empty: empty,
+        });
+        compiler.builder.resolve(top);
+        compiler.builder.emit(Insn::Column {
+            cursor: sorter,
+            column: 0,
+            dest: rowid,
+        });
+        compiler.builder.emit(Insn::SeekRowid {
+            cursor,
+            rowid,
+            if_none: next,
+        });
+        match &self.assignments {
+            // Every assignment sees the old row, which stays in the first
+            // registers while the new one is built after it
+            Some(assignments) => {
+                let schema = &self.query.sources[0].schema;
+                let width = schema.columns.len();
+                compiler.load_columns(cursor, 0, width);
+                let record = compiler.builder.alloc_registers(width);
+                compiler.builder.emit(Insn::Copy {
+                    src: 0..width,
+                    dest: record,
+                });
+                for (column, expr) in assignments {
+                    let reg = record + column;
+                    compiler.builder.expr(expr, 0, reg);
+                    compiler.builder.emit(Insn::Affinity {
+                        reg,
+                        affinity: schema.columns[*column].affinity,
+                    });
+                }
+                compiler.builder.emit(Insn::CheckUnique {
+                    cursor,
+                    record: record..record + width,
+                    replaces_current: true,
+                });
+                compiler.builder.emit(Insn::Update {
+                    cursor,
+                    record: record..record + width,
+                });
+            }
+            None => compiler.builder.emit(Insn::Delete { cursor }),
+        }
+        compiler.close_loop(Loop {
+            cursor: sorter,
+            top,
+            next,
+            end,
+        });
+        if self.required {
+            compiler.builder.emit(Insn::Goto {
+                target: compiler.halt,
+            });
+            compiler.builder.resolve(empty);
+            compiler.builder.emit(Insn::HaltNotFound);
+        }
+        compiler.finish()
+    }
+}
+
+/// Expands `*` into the columns of every table and binds every other entry
+/// of the select list, naming it by its alias, its column name or else its
+/// SQL text.
+fn bind_result_columns(
+    columns: Vec<ResultColumn>,
+    sources: &[FromTable],
+    scope: &Scope,
+) -> Result<Vec<(String, Expr)>, Error> {
+    let expand = |source: &FromTable| {
+        source
+            .schema
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                (
+                    column.name.clone(),
+                    Expr::ColumnIndex(source.offset + index),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut bound = Vec::new();
+    for column in columns {
+        match column {
+            ResultColumn::All => bound.extend(sources.iter().flat_map(expand)),
+            ResultColumn::TableAll(name) => {
+                let source = sources
+                    .iter()
+                    .find(|source| source.table.scope_name().eq_ignore_ascii_case(&name))
+                    .ok_or(Error::TableNotFound(name))?;
+                bound.extend(expand(source));
+            }
+            ResultColumn::Expr { expr, alias } => {
+                let name = match (alias, &expr) {
+                    (Some(alias), _) => alias,
+                    (None, Expr::Column(column)) => column.name.clone(),
+                    (None, _) => expr.to_string(),
+                };
+                bound.push((name, expr.bind(scope)?));
+            }
+        }
+    }
+    Ok(bound)
+}
+
+/// One table of the FROM clause. Its columns start at `offset` in the
+/// joined rows.
+struct FromTable {
+    table: TableRef,
+    schema: TableSchema,
+    offset: usize,
+    kind: JoinKind,
+    on: Option<Expr>,
+}
+
+impl FromTable {
+    fn width(&self) -> usize {
+        self.schema.columns.len()
+    }
+}
+
+/// Where an ORDER BY term takes its sort key from.
+enum SortKey {
+    /// A result column, named by its alias or its position.
+    Output(usize),
+    /// An expression over the table's columns.
+    Source(Expr),
+}
+
+/// A select bound to its tables, ready to run.
+struct Query {
+    sources: Vec<FromTable>,
+    /// The loops over the tables, outermost first.
+    levels: Vec<Level>,
+    columns: Vec<String>,
+    /// Set for aggregate queries, whose select list, HAVING and ORDER BY
+    /// are evaluated against group rows rather than table rows.
+    aggregation: Option<Aggregation>,
+    having: Option<Expr>,
+    exprs: Vec<Expr>,
+    sort_keys: Vec<SortKey>,
+    descending: Vec<bool>,
+    limit: Option<u64>,
+    offset: u64,
+}
+
+/// Orders the tables and chooses how to read each from the predicates that
+/// restrict it: the WHERE clause and the ON clauses. `exprs` and `others`
+/// are every other expression of the query, which decide whether an index
+/// covers it.
+fn plan_sources<'a>(
+    sources: &[FromTable],
+    filter: Option<&'a Expr>,
+    exprs: &'a [Expr],
+    others: impl Iterator<Item = &'a Expr>,
+    table: &table::Table,
+) -> Vec<Level> {
+    let mut used = exprs
+        .iter()
+        .chain(filter)
+        .chain(others)
+        .flat_map(Expr::column_indexes)
+        .collect::<Vec<_>>();
+    for source in sources {
+        used.extend(source.on.iter().flat_map(Expr::column_indexes));
+    }
+
+    let mut predicates = filter
+        .iter()
+        .flat_map(|filter| filter.conjuncts())
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut tables = Vec::new();
+    for source in sources {
+        let (start, end) = (source.offset, source.offset + source.width());
+        let used_columns = used
+            .iter()
+            .filter(|&&index| (start..end).contains(&index))
+            .map(|index| index - start)
+            .collect::<Vec<_>>();
+        let on = source.on.iter().flat_map(Expr::conjuncts).cloned();
+        let left_join_on = match source.kind {
+            JoinKind::Left => Some(on.collect()),
+            JoinKind::Inner => {
+                predicates.extend(on);
+                None
+            }
+        };
+        let info = TableInfo {
+            schema: &source.schema,
+            offset: source.offset,
+            indexes: table.catalog.table_indexes(source.schema.root_page),
+            stats: table.catalog.table_stats(&source.schema.name),
+            used_columns,
+        };
+        tables.push(JoinTable { info, left_join_on });
+    }
+    plan_joins(&tables, &predicates)
+}
+
+fn prepare_query(select: Select, table: &mut table::Table) -> Result<Query, Error> {
+    let mut sources = Vec::new();
+    let mut scope = Scope::default();
+    let joins = select
+        .joins
+        .into_iter()
+        .map(|join| (join.kind, join.table, join.on));
+    for (kind, table_ref, on) in [(JoinKind::Inner, select.from, None)]
+        .into_iter()
+        .chain(joins)
+    {
+        let schema = find_table(&table_ref.name, table)?;
+        let offset = scope.columns.len();
+        scope.extend(schema.scope_as(table_ref.scope_name()));
+        // ON sees this table and the ones to its left
+        let on = match on {
+            Some(on) => {
+                on.check_no_aggregate()?;
+                Some(on.bind(&scope)?)
+            }
+            None => None,
+        };
+        let source = FromTable {
+            table: table_ref,
+            schema,
+            offset,
+            kind,
+            on,
+        };
+        sources.push(source);
+    }
+    let filter = match select.filter {
+        Some(filter) => {
+            filter.check_no_aggregate()?;
+            Some(filter.bind(&scope)?)
+        }
+        None => None,
+    };
+    let (columns, mut exprs): (Vec<_>, Vec<_>) =
+        bind_result_columns(select.columns, &sources, &scope)?
+            .into_iter()
+            .unzip();
+    let mut having = match select.having {
+        Some(having) => Some(having.bind(&scope)?),
+        None => None,
+    };
+    let mut sort_keys = Vec::new();
+    let mut descending = Vec::new();
+    for term in select.order_by {
+        let output = match &term.expr {
+            Expr::Literal(Value::Integer(position)) => match usize::try_from(*position) {
+                Ok(position) if (1..=columns.len()).contains(&position) => Some(position - 1),
+                _ => return Err(Error::OrderByOutOfRange(*position)),
+            },
+            Expr::Column(ColumnRef { table: None, name }) => columns
+                .iter()
+                .position(|column| column.eq_ignore_ascii_case(name)),
+            _ => None,
+        };
+        sort_keys.push(match output {
+            Some(index) => SortKey::Output(index),
+            None => SortKey::Source(term.expr.bind(&scope)?),
+        });
+        descending.push(term.descending);
+    }
+
+    let group_by = select
+        .group_by
+        .iter()
+        .map(|key| key.bind(&scope))
+        .collect::<Result<Vec<_>, _>>()?;
+    let levels = plan_sources(
+        &sources,
+        filter.as_ref(),
+        &exprs,
+        having
+            .iter()
+            .chain(&group_by)
+            .chain(sort_keys.iter().filter_map(|key| match key {
+                SortKey::Source(expr) => Some(expr),
+                SortKey::Output(_) => None,
+            })),
+        table,
+    );
+
+    let is_aggregate = !group_by.is_empty()
+        || having.is_some()
+        || exprs.iter().any(|expr| expr.find_aggregate().is_some())
+        || sort_keys
+            .iter()
+            .any(|key| matches!(key, SortKey::Source(expr) if expr.find_aggregate().is_some()));
+    let aggregation = if is_aggregate {
+        let mut aggregation = Aggregation::new(group_by, scope.columns.len())?;
+        for expr in exprs.iter_mut().chain(having.as_mut()) {
+            *expr = aggregation.rewrite(expr)?;
+        }
+        for key in &mut sort_keys {
+            if let SortKey::Source(expr) = key {
+                *expr = aggregation.rewrite(expr)?;
+            }
+        }
+        Some(aggregation)
+    } else {
+        None
+    };
+    Ok(Query {
+        sources,
+        levels,
+        columns,
+        aggregation,
+        having,
+        exprs,
+        sort_keys,
+        descending,
+        limit: select.limit,
+        offset: select.offset,
+    })
+}
+
+/// The cursors the table of a level is read through.
+struct SourceCursors {
+    table: usize,
+    index: Option<usize>,
+    hash: Option<usize>,
+}
+
+/// A loop over the rows of one table, whose body follows `open_loop`.
+struct Loop {
+    cursor: usize,
+    top: usize,
+    next: usize,
+    end: usize,
+}
+
+/// Compiles a query into a program. The joined row is kept in the first
+/// registers, laid out the way the query's expressions are bound, so that
+/// they can be evaluated against the registers directly.
+struct QueryCompiler<'a> {
+    query: &'a Query,
+    builder: ProgramBuilder,
+    /// By level.
+    cursors: Vec<SourceCursors>,
+    /// Whether the rows found are changed, so tables are opened for writing
+    /// and always read through the table itself.
+    writes: bool,
+    halt: usize,
+    /// Registers of the ORDER BY keys, directly followed by the result row.
+    record: usize,
+    result: usize,
+    sorter: Option<usize>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl<'a> QueryCompiler<'a> {
+    fn new(query: &'a Query, writes: bool) -> Self {
+        let width = query.sources.iter().map(FromTable::width).sum();
+        let mut builder = ProgramBuilder::new(width);
+        let cursors = query
+            .levels
+            .iter()
+            .map(|level| SourceCursors {
+                table: builder.alloc_cursor(),
+                index: matches!(level.access, AccessPath::Index(_)).then(|| builder.alloc_cursor()),
+                hash: matches!(level.strategy, JoinStrategy::Hash { .. })
+                    .then(|| builder.alloc_cursor()),
+            })
+            .collect();
+        let halt = builder.label();
+        let record = builder.alloc_registers(query.sort_keys.len() + query.exprs.len());
+        QueryCompiler {
+            query,
+            builder,
+            cursors,
+            writes,
+            halt,
+            record,
+            result: record + query.sort_keys.len(),
+            sorter: None,
+            offset: None,
+            limit: None,
+        }
+    }
+
+    fn finish(mut self) -> Program {
+        self.builder.resolve(self.halt);
+        self.builder.emit(Insn::Halt);
+        self.builder.finish(self.query.columns.clone())
+    }
+
+    /// Evaluates the expression against the registers from `base` into a
+    /// new register.
+    fn eval(&mut self, expr: &Expr, base: usize) -> usize {
+        let dest = self.builder.alloc_registers(1);
+        self.builder.expr(expr, base, dest);
+        dest
+    }
+
+    fn compile_select(mut self) -> Program {
+        let query = self.query;
+        if query.limit == Some(0) {
+            return self.finish();
+        }
+        let counter = |builder: &mut ProgramBuilder, value: u64| {
+            let reg = builder.alloc_registers(1);
+            let value = i64::try_from(value).unwrap_or(i64::MAX);
+            builder.emit(Insn::Integer { value, dest: reg });
+            reg
+        };
+        if query.offset > 0 {
+            self.offset = Some(counter(&mut self.builder, query.offset));
+        }
+        if let Some(limit) = query.limit {
+            self.limit = Some(counter(&mut self.builder, limit));
+        }
+        if !query.sort_keys.is_empty() {
+            let sorter = self.builder.alloc_cursor();
+            self.builder.emit(Insn::SorterOpen {
+                cursor: sorter,
+                descending: query.descending.clone(),
+            });
+            self.sorter = Some(sorter);
+        }
+
+        match &query.aggregation {
+            None => self.scan(&|compiler| compiler.output(0)),
+            Some(aggregation) => self.aggregate(aggregation),
+        }
+
+        if let Some(sorter) = self.sorter {
+            let (top, end) = (self.builder.label(), self.builder.label());
+            self.builder.emit(Insn::SorterSort {
+                cursor: sorter,
+                if_empty: end,
+            });
+            self.builder.resolve(top);
+            let num_keys = query.sort_keys.len();
+            for i in 0..query.exprs.len() {
+                self.builder.emit(Insn::Column {
+                    cursor: sorter,
+                    column: num_keys + i,
+                    dest: self.result + i,
+                });
+            }
+            self.result_row();
+            self.builder.emit(Insn::Next {
+                cursor: sorter,
+                if_more: top,
+            });
+            self.builder.resolve(end);
+        }
+        self.finish()
+    }
+
+    /// Emits `body` for every joined row that satisfies the WHERE clause,
+    /// after building the hash tables of hash joins.
+    fn scan(&mut self, body: &dyn Fn(&mut Self)) {
+        let query = self.query;
+        for (level, cursors) in query.levels.iter().zip(&self.cursors) {
+            let root_page = query.sources[level.source].schema.root_page;
+            let cursor = cursors.table;
+            self.builder.emit(match self.writes {
+                true => Insn::OpenWrite { cursor, root_page },
+                false => Insn::OpenRead { cursor, root_page },
+            });
+            if let (Some(cursor), AccessPath::Index(scan)) = (cursors.index, &level.access) {
+                self.builder.emit(Insn::OpenIndex {
+                    cursor,
+                    root_page: scan.index.root_page,
+                });
+            }
+        }
+        for (position, level) in query.levels.iter().enumerate() {
+            let JoinStrategy::Hash { build, .. } = &level.strategy else {
+                continue;
+            };
+            let source = &query.sources[level.source];
+            // Rows with a NULL key are left out since they can never match
+            let cursor = self.cursors[position].hash.unwrap();
+            self.builder.emit(Insn::OpenHash { cursor });
+            let lp = self.open_loop(position);
+            let key = self.eval(build, source.offset);
+            self.builder.emit(Insn::IsNull {
+                reg: key,
+                target: lp.next,
+            });
+            self.builder.emit(Insn::HashInsert {
+                cursor,
+                key,
+                record: source.offset..source.offset + source.width(),
+            });
+            self.close_loop(lp);
+        }
+        self.scan_from(0, body);
+    }
+
+    /// Emits the loops of the levels from `position` on, with `body` in
+    /// the innermost one. Each level checks its WHERE conditions once its
+    /// row, or the NULL row of an unmatched LEFT JOIN, is loaded.
+    fn scan_from(&mut self, position: usize, body: &dyn Fn(&mut Self)) {
+        let query = self.query;
+        let Some(level) = query.levels.get(position) else {
+            body(self);
+            return;
+        };
+        let source = &query.sources[level.source];
+
+        let left_join = source.kind == JoinKind::Left;
+        let matched = self.builder.alloc_registers(1);
+        if left_join {
+            self.builder.emit(Insn::Integer {
+                value: 0,
+                dest: matched,
+            });
+        }
+        let lp = match &level.strategy {
+            JoinStrategy::NestedLoop => self.open_loop(position),
+            JoinStrategy::Hash { probe, .. } => {
+                let cursor = self.cursors[position].hash.unwrap();
+                let (top, next, end) = (
+                    self.builder.label(),
+                    self.builder.label(),
+                    self.builder.label(),
+                );
+                let key = self.eval(probe, 0);
+                self.builder.emit(Insn::IsNull {
+                    reg: key,
+                    target: end,
+                });
+                self.builder.emit(Insn::HashSeek {
+                    cursor,
+                    key,
+                    if_none: end,
+                });
+                self.builder.resolve(top);
+                self.load_columns(cursor, source.offset, source.width());
+                Loop {
+                    cursor,
+                    top,
+                    next,
+                    end,
+                }
+            }
+        };
+        for on in &level.on {
+            let matches = self.eval(on, 0);
+            self.builder.emit(Insn::IfNot {
+                reg: matches,
+                target: lp.next,
+            });
+        }
+        let body_start = self.builder.label();
+        self.builder.resolve(body_start);
+        if left_join {
+            self.builder.emit(Insn::Integer {
+                value: 1,
+                dest: matched,
+            });
+        }
+        for condition in &level.conditions {
+            let matches = self.eval(condition, 0);
+            self.builder.emit(Insn::IfNot {
+                reg: matches,
+                target: lp.next,
+            });
+        }
+        self.scan_from(position + 1, body);
+        self.close_loop(lp);
+
+        // Without a match, the left rows go on with NULLs for this table
+        if left_join {
+            let done = self.builder.label();
+            self.builder.emit(Insn::If {
+                reg: matched,
+                target: done,
+            });
+            self.builder.emit(Insn::Null {
+                dest: source.offset..source.offset + source.width(),
+            });
+            self.builder.emit(Insn::Goto { target: body_start });
+            self.builder.resolve(done);
+        }
+    }
+
+    fn load_columns(&mut self, cursor: usize, offset: usize, width: usize) {
+        for column in 0..width {
+            self.builder.emit(Insn::Column {
+                cursor,
+                column,
+                dest: offset + column,
+            });
+        }
+    }
+
+    /// Starts a loop over the rows the level's access path reads and loads
+    /// each into the table's registers.
+    fn open_loop(&mut self, position: usize) -> Loop {
+        let query = self.query;
+        let level = &query.levels[position];
+        let source = &query.sources[level.source];
+        let cursors = &self.cursors[position];
+        let (table_cursor, index_cursor) = (cursors.table, cursors.index);
+        let (top, next, end) = (
+            self.builder.label(),
+            self.builder.label(),
+            self.builder.label(),
+        );
+        let (offset, width) = (source.offset, source.width());
+        let scan = match &level.access {
+            AccessPath::FullScan => {
+                self.builder.emit(Insn::Rewind {
+                    cursor: table_cursor,
+                    if_empty: end,
+                });
+                self.builder.resolve(top);
+                self.load_columns(table_cursor, offset, width);
+                return Loop {
+                    cursor: table_cursor,
+                    top,
+                    next,
+                    end,
+                };
+            }
+            AccessPath::Index(scan) => scan,
+        };
+        let cursor = index_cursor.unwrap();
+
+        // The seek key is the equality values followed by the lower bound,
+        // the stop key the same values followed by the upper bound. NULL
+        // compares to nothing, so then no entry can match.
+        let num_equal = scan.equal.len();
+        let start_len = num_equal + usize::from(scan.lower.is_some());
+        let start = self.builder.alloc_registers(start_len);
+        let values = scan.equal.iter().chain(scan.lower.iter().map(|b| &b.value));
+        for (i, value) in values.enumerate() {
+            self.builder.expr(value, 0, start + i);
+        }
+        let stop = self.builder.alloc_registers(num_equal + 1);
+        if let Some(upper) = &scan.upper {
+            self.builder.emit(Insn::Copy {
+                src: start..start + num_equal,
+                dest: stop,
+            });
+            self.builder.expr(&upper.value, 0, stop + num_equal);
+        }
+        let upper_len = usize::from(scan.upper.is_some());
+        for reg in (start..start + start_len).chain(stop + num_equal..stop + num_equal + upper_len)
+        {
+            self.builder.emit(Insn::IsNull { reg, target: end });
+        }
+        self.builder.emit(Insn::SeekGe {
+            cursor,
+            key: start..start + start_len,
+            if_none: end,
+        });
+
+        self.builder.resolve(top);
+        match &scan.upper {
+            Some(upper) if upper.inclusive => self.builder.emit(Insn::IdxGt {
+                cursor,
+                key: stop..stop + num_equal + 1,
+                target: end,
+            }),
+            Some(_) => self.builder.emit(Insn::IdxGe {
+                cursor,
+                key: stop..stop + num_equal + 1,
+                target: end,
+            }),
+            None if num_equal > 0 => self.builder.emit(Insn::IdxGt {
+                cursor,
+                key: start..start + num_equal,
+                target: end,
+            }),
+            None => {}
+        }
+        if scan.lower.as_ref().is_some_and(|lower| !lower.inclusive) {
+            self.builder.emit(Insn::IdxLe {
+                cursor,
+                key: start..start + start_len,
+                target: next,
+            });
+        }
+        if scan.covering && !self.writes {
+            // The index key holds the indexed columns and then the primary key
+            let index = &scan.index;
+            self.builder.emit(Insn::Null {
+                dest: offset..offset + width,
+            });
+            let columns = index.columns.iter().chain([&index.primary_key]);
+            for (position, &column) in columns.enumerate() {
+                self.builder.emit(Insn::Column {
+                    cursor,
+                    column: position,
+                    dest: offset + column,
+                });
+            }
+        } else {
+            self.builder.emit(Insn::SeekRow {
+                cursor: table_cursor,
+                index_cursor: cursor,
+            });
+            self.load_columns(table_cursor, offset, width);
+        }
+        Loop {
+            cursor,
+            top,
+            next,
+            end,
+        }
+    }
+
+    fn close_loop(&mut self, lp: Loop) {
+        self.builder.resolve(lp.next);
+        self.builder.emit(Insn::Next {
+            cursor: lp.cursor,
+            if_more: lp.top,
+        });
+        self.builder.resolve(lp.end);
+    }
+
+    /// Groups the rows in a hash table, then runs the rest of the query on
+    /// every group. If the groups outgrow the memory budget the tables are
+    /// scanned again and the rows sorted by group instead.
+    fn aggregate(&mut self, aggregation: &Aggregation) {
+        let query = self.query;
+        let width = aggregation.source_width;
+        let num_keys = aggregation.keys.len();
+        let index = self.builder.add_aggregation(aggregation.clone());
+        let cursor = self.builder.alloc_cursor();
+        let (full, groups) = (self.builder.label(), self.builder.label());
+        let record_width = aggregation.record_width();
+        let record = self.builder.alloc_registers(record_width);
+        let record = record..record + record_width;
+        // Both passes add the keys, the row and the arguments of the calls
+        let build_record = |compiler: &mut Self| {
+            for (i, key) in aggregation.keys.iter().enumerate() {
+                compiler.builder.expr(key, 0, record.start + i);
+            }
+            compiler.builder.emit(Insn::Copy {
+                src: 0..width,
+                dest: record.start + num_keys,
+            });
+            let args = aggregation.calls.iter().flat_map(|call| &call.args);
+            for (i, arg) in args.enumerate() {
+                compiler
+                    .builder
+                    .expr(arg, 0, record.start + num_keys + width + i);
+            }
+        };
+        self.builder.emit(Insn::AggOpen {
+            cursor,
+            aggregation: index,
+        });
+        self.scan(&|compiler| {
+            build_record(compiler);
+            compiler.builder.emit(Insn::AggStep {
+                cursor,
+                record: record.clone(),
+                if_full: full,
+            })
+        });
+        self.builder.emit(Insn::Goto { target: groups });
+
+        self.builder.resolve(full);
+        self.builder.emit(Insn::Close { cursor });
+        let sorter = self.builder.alloc_cursor();
+        self.builder.emit(Insn::SorterOpen {
+            cursor: sorter,
+            descending: vec![false; num_keys],
+        });
+        self.scan(&|compiler| {
+            build_record(compiler);
+            compiler.builder.emit(Insn::SorterInsert {
+                cursor: sorter,
+                record: record.clone(),
+            });
+        });
+        self.builder.emit(Insn::AggSorted {
+            cursor,
+            aggregation: index,
+            sorter,
+        });
+
+        self.builder.resolve(groups);
+        let group_width = num_keys + width + aggregation.calls.len();
+        let group = self.builder.alloc_registers(group_width);
+        let (top, next, end) = (
+            self.builder.label(),
+            self.builder.label(),
+            self.builder.label(),
+        );
+        self.builder.emit(Insn::Rewind {
+            cursor,
+            if_empty: end,
+        });
+        self.builder.resolve(top);
+        self.load_columns(cursor, group, group_width);
+        if let Some(having) = &query.having {
+            let matches = self.eval(having, group);
+            self.builder.emit(Insn::IfNot {
+                reg: matches,
+                target: next,
+            });
+        }
+        self.output(group);
+        self.close_loop(Loop {
+            cursor,
+            top,
+            next,
+            end,
+        });
+    }
+
+    /// Evaluates the select list against the registers from `base`, then
+    /// either hands the row to the sorter or returns it.
+    fn output(&mut self, base: usize) {
+        let query = self.query;
+        for (i, expr) in query.exprs.iter().enumerate() {
+            self.builder.expr(expr, base, self.result + i);
+        }
+        let Some(sorter) = self.sorter else {
+            self.result_row();
+            return;
+        };
+        for (i, key) in query.sort_keys.iter().enumerate() {
+            let dest = self.record + i;
+            match key {
+                SortKey::Output(index) => self.builder.emit(Insn::Copy {
+                    src: self.result + index..self.result + index + 1,
+                    dest,
+                }),
+                SortKey::Source(expr) => self.builder.expr(expr, base, dest),
+            }
+        }
+        self.builder.emit(Insn::SorterInsert {
+            cursor: sorter,
+            record: self.record..self.result + query.exprs.len(),
+        });
+    }
+
+    /// Returns the result row unless OFFSET skips it, and halts once the
+    /// LIMIT is reached.
+    fn result_row(&mut self) {
+        let skip = self.builder.label();
+        if let Some(offset) = self.offset {
+            self.builder.emit(Insn::IfPos {
+                reg: offset,
+                target: skip,
+            });
+        }
+        self.builder.emit(Insn::ResultRow {
+            registers: self.result..self.result + self.query.exprs.len(),
+        });
+        if let Some(limit) = self.limit {
+            self.builder.emit(Insn::DecrJumpZero {
+                reg: limit,
+                target: self.halt,
+            });
+        }
+        self.builder.resolve(skip);
+    }
+}
+
+/// Renders a bound expression as SQL, naming column `i` by `names[i]`.
+fn sql_text(expr: &Expr, names: &[String]) -> String {
+    expr.transform(&mut |expr| match expr {
+        Expr::ColumnIndex(index) => Ok::<_, ()>(Some(Expr::Column(ColumnRef {
+            table: None,
+            name: names[*index].clone(),
+        }))),
+        _ => Ok(None),
+    })
+    .unwrap()
+    .to_string()
+}
+
+impl Query {
+    fn compile(&self) -> Program {
+        QueryCompiler::new(self, false).compile_select()
+    }
+
+    /// One line per table and per temporary structure, the way EXPLAIN
+    /// QUERY PLAN shows them.
+    fn query_plan(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for level in &self.levels {
+            let source = &self.sources[level.source];
+            let name = source.table.scope_name();
+            let mut line = match &level.strategy {
+                JoinStrategy::NestedLoop => level.access.describe(&source.schema, name),
+                JoinStrategy::Hash { build, .. } => {
+                    let names = source.schema.column_names();
+                    format!(
+                        "SEARCH {} USING HASH TABLE ({}=?)",
+                        name,
+                        sql_text(build, &names)
+                    )
+                }
+            };
+            if source.kind == JoinKind::Left {
+                line.push_str(" LEFT-JOIN");
+            }
+            lines.push(line);
+        }
+        if matches!(&self.aggregation, Some(aggregation) if !aggregation.keys.is_empty()) {
+            lines.push("USE HASH TABLE FOR GROUP BY".to_string());
+        }
+        if !self.sort_keys.is_empty() {
+            lines.push("USE TEMP B-TREE FOR ORDER BY".to_string());
+        }
+        lines
+    }
+}
+
+/// The EXPLAIN QUERY PLAN lines of a statement.
+pub(crate) fn query_plan(
+    statement: StatementType,
+    table: &mut table::Table,
+) -> Result<Vec<String>, Error> {
+    let change = match statement {
+        StatementType::Select(select) => return Ok(prepare_query(select, table)?.query_plan()),
+        StatementType::Update(update) => prepare_update(update, table)?,
+        StatementType::Delete(delete) => prepare_delete(delete, table)?,
+        StatementType::LegacyUpdate(update) => {
+            prepare_legacy_change(&update.table, update.key, Some(update.values), table)?
+        }
+        StatementType::LegacyDelete(delete) => {
+            prepare_legacy_change(&delete.table, delete.key, None, table)?
+        }
+        _ => return Ok(Vec::new()),
+    };
+    Ok(change.query.query_plan())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::Row;
+    use std::time::Duration;
+
+    fn run_statement(sql: &str, table: &mut table::Table) -> ExecuteResult {
+        let Ok(statement) = parser::parse_statement(sql) else {
+            panic!("could not parse: {}", sql);
+        };
+        execute(statement, sql, table, |program, table| {
+            let mut vm = Vm::new(program);
+            loop {
+                match vm.step(table) {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => return ExecuteResult::Success,
+                    Err(error) => return error.into(),
+                }
+            }
+        })
+    }
+
+    fn read_rows(name: &str, table: &mut table::Table) -> Vec<Row> {
+        let schema = table.catalog.find_table(name).unwrap().clone();
+        let mut cursor = schema.btree().cursor();
+        let mut found = cursor.first(&mut table.pager);
+        std::iter::from_fn(|| {
+            let row = found.then(|| Row::from_payload(cursor.value()).unwrap());
+            found = found && cursor.next(&mut table.pager);
+            row
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_execute_update_and_delete() {
+        let path = table::tests::temp_db_path("repl_update_delete");
+        let mut table = table::Table::db_open(&path);
+        // Compiling has no side effects, only running creates the table
+        let sql = "insert 1 alice alice@test.com";
+        assert!(matches!(
+            compile_statement(parser::parse_statement(sql).unwrap(), sql, &mut table),
+            Err(Error::TableNotFound(_))
+        ));
+        assert!(table.catalog.find_table(parser::LEGACY_TABLE).is_none());
+        for input in ["insert 1 alice alice@test.com", "insert 2 bob bob@test.com"] {
+            assert!(matches!(
+                run_statement(input, &mut table),
+                ExecuteResult::Success
+            ));
+        }
+
+        let big_email = "b".repeat(2 * table::PAGE_SIZE);
+        assert!(matches!(
+            run_statement(&format!("update 2 bob {}", big_email), &mut table),
+            ExecuteResult::Success
+        ));
+        let rows = read_rows(parser::LEGACY_TABLE, &mut table);
+        assert_eq!(rows[1].values[2], Value::Text(big_email));
+
+        assert!(matches!(
+            run_statement("delete 1", &mut table),
+            ExecuteResult::Success
+        ));
+        assert_eq!(read_rows(parser::LEGACY_TABLE, &mut table).len(), 1);
+
+        assert!(matches!(
+            run_statement("delete 1", &mut table),
+            ExecuteResult::RowNotFound
+        ));
+    }
+
+    #[test]
+    fn test_execute_sql_update_and_delete() {
+        let mut table = join_tables("repl_sql_update_delete");
+        run_statement("create index users_team on users (team)", &mut table);
+        let names = |clause: &str, table: &mut table::Table| {
+            let sql = format!("select name from users where {} order by id", clause);
+            values(query(&sql, table)).concat()
+        };
+
+        // Changing the indexed column while scanning the index must not
+        // visit a row twice, and the index has to follow
+        let sql = "update users set team = team + 5, name = name || '!' where team = 10";
+        let Ok(StatementType::Update(update)) = parser::parse_statement(sql) else {
+            panic!("not an update");
+        };
+        assert_eq!(
+            prepare_update(update, &mut table)
+                .unwrap()
+                .query
+                .query_plan(),
+            ["SEARCH users USING INDEX users_team (team=?)"]
+        );
+        assert!(matches!(
+            run_statement(sql, &mut table),
+            ExecuteResult::Success
+        ));
+        assert_eq!(names("team = 15", &mut table), ["alice!", "carol!"]);
+        assert!(names("team = 10", &mut table).is_empty());
+
+        // Every assignment sees the old row, and values take the column's
+        // affinity
+        run_statement(
+            "update teams set id = id + 1, title = title || id where id = 10",
+            &mut table,
+        );
+        assert_eq!(
+            values(query(
+                "select id, title from teams where id = 11",
+                &mut table
+            )),
+            [["11", "red10"]]
+        );
+        run_statement("update users set team = '25' where id = 2", &mut table);
+        assert_eq!(names("team = 25", &mut table), ["bob"]);
+        assert_eq!(
+            read_rows("users", &mut table)[1].values[2],
+            Value::Integer(25)
+        );
+
+        assert!(matches!(
+            run_statement("delete from users where team is null", &mut table),
+            ExecuteResult::Success
+        ));
+        assert_eq!(names("id > 0", &mut table), ["alice!", "bob", "carol!"]);
+        run_statement("delete from users where name = 'bob'", &mut table);
+        assert!(names("team = 25", &mut table).is_empty());
+        run_statement("delete from users", &mut table);
+        assert!(names("team = 15", &mut table).is_empty());
+        assert!(read_rows("users", &mut table).is_empty());
+
+        assert!(matches!(
+            run_statement("update users set nope = 1", &mut table),
+            ExecuteResult::ColumnNotFound(_)
+        ));
+        assert!(matches!(
+            run_statement("delete from nope where id = 1", &mut table),
+            ExecuteResult::TableNotFound(_)
+        ));
+    }
+
+    #[test]
+    fn test_execute_insert_nulls_and_affinity() {
+        let path = table::tests::temp_db_path("repl_nulls");
+        let mut table = table::Table::db_open(&path);
+        assert!(matches!(
+            run_statement(
+                "create table notes (id integer primary key, title text, score real, data blob)",
+                &mut table
+            ),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement("create table notes (id integer primary key)", &mut table),
+            ExecuteResult::TableExists(_)
+        ));
+        assert!(matches!(
+            run_statement(
+                "insert into notes values ('1', 2, '2.5', x'ff'), (2, NULL, NULL, '3')",
+                &mut table
+            ),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement(
+                "insert into notes (id, title) values (3, 'three')",
+                &mut table
+            ),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement("insert into notes (id, nope) values (4, 1)", &mut table),
+            ExecuteResult::ColumnNotFound(_)
+        ));
+        assert!(matches!(
+            run_statement("insert into notes values (4)", &mut table),
+            ExecuteResult::ColumnCountMismatch {
+                expected: 4,
+                actual: 1
+            }
+        ));
+        assert!(matches!(
+            run_statement("select * from missing", &mut table),
+            ExecuteResult::TableNotFound(_)
+        ));
+
+        table.db_close();
+        let mut table = table::Table::db_open(&path);
+        let rows = read_rows("notes", &mut table);
+        assert_eq!(
+            rows.iter()
+                .map(|row| row.values.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![
+                    Value::Integer(1),
+                    Value::Text("2".to_string()),
+                    Value::Real(2.5),
+                    Value::Blob(vec![0xff])
+                ],
+                vec![
+                    Value::Integer(2),
+                    Value::Null,
+                    Value::Null,
+                    Value::Text("3".to_string())
+                ],
+                vec![
+                    Value::Integer(3),
+                    Value::Text("three".to_string()),
+                    Value::Null,
+                    Value::Null
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_insert_numeric_and_boolean_columns() {
+        let path = table::tests::temp_db_path("repl_numeric_types");
+        let mut table = table::Table::db_open(&path);
+        run_statement(
+            "create table payments (ts bigint, amount double, paid boolean)",
+            &mut table,
+        );
+        assert!(matches!(
+            run_statement(
+                "insert into payments values (1717171717000000, -12.75, true), (-5, 3, 0), (0, '1e2', 'TRUE')",
+                &mut table
+            ),
+            ExecuteResult::Success
+        ));
+
+        table.db_close();
+        let mut table = table::Table::db_open(&path);
+        let rows = read_rows("payments", &mut table);
+        assert_eq!(
+            rows.iter()
+                .map(|row| row.values.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![
+                    Value::Integer(1717171717000000),
+                    Value::Real(-12.75),
+                    Value::Boolean(true)
+                ],
+                vec![Value::Integer(-5), Value::Real(3.0), Value::Boolean(false)],
+                vec![Value::Integer(0), Value::Real(100.0), Value::Boolean(true)],
+            ]
+        );
+    }
+
+    struct ResultSet {
+        columns: Vec<String>,
+        rows: Vec<Row>,
+    }
+
+    fn try_query(sql: &str, table: &mut table::Table) -> Result<ResultSet, ExecuteResult> {
+        let Ok(StatementType::Select(select)) = parser::parse_statement(sql) else {
+            panic!("expected select");
+        };
+        let program = Arc::new(prepare_query(select, table)?.compile());
+        let mut vm = Vm::new(Arc::clone(&program));
+        let mut rows = Vec::new();
+        while let Some(row) = vm.step(table)? {
+            rows.push(row);
+        }
+        Ok(ResultSet {
+            columns: program.columns.clone(),
+            rows,
+        })
+    }
+
+    fn query(sql: &str, table: &mut table::Table) -> ResultSet {
+        let Ok(result) = try_query(sql, table) else {
+            panic!("select failed");
+        };
+        result
+    }
+
+    fn select_where(clause: &str, table: &mut table::Table) -> Vec<Value> {
+        let sql = format!("select id from people where {}", clause);
+        query(&sql, table)
+            .rows
+            .into_iter()
+            .map(|row| row.values[0].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_execute_select_where() {
+        let path = table::tests::temp_db_path("repl_where");
+        let mut table = table::Table::db_open(&path);
+        run_statement(
+            "create table people (id integer primary key, name text, age integer)",
+            &mut table,
+        );
+        run_statement(
+            "insert into people values (1, 'alice', 30), (2, 'bob', NULL), (3, 'carol', 25), (4, 'dave', 41)",
+            &mut table,
+        );
+
+        let ids = |values: &[i64]| {
+            values
+                .iter()
+                .map(|&i| Value::Integer(i))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(select_where("age > 26", &mut table), ids(&[1, 4]));
+        assert_eq!(select_where("age is null", &mut table), ids(&[2]));
+        // NULL age is unknown for both the test and its negation
+        assert_eq!(select_where("not age > 26", &mut table), ids(&[3]));
+        assert_eq!(
+            select_where("name like '_a%' and age between 20 and 40", &mut table),
+            ids(&[3])
+        );
+        assert_eq!(
+            select_where("id in (2, 4) or age * 2 = 50", &mut table),
+            ids(&[2, 3, 4])
+        );
+        assert_eq!(
+            select_where("people.name || '!' = 'bob!'", &mut table),
+            ids(&[2])
+        );
+
+        assert!(matches!(
+            try_query("select * from people where height > 1", &mut table),
+            Err(ExecuteResult::ColumnNotFound(name)) if name == "height"
+        ));
+    }
+
+    #[test]
+    fn test_execute_select_projection() {
+        let path = table::tests::temp_db_path("repl_projection");
+        let mut table = table::Table::db_open(&path);
+        run_statement(
+            "create table people (id integer primary key, name text, age integer)",
+            &mut table,
+        );
+        run_statement(
+            "insert into people values (1, 'alice', 30), (2, 'bob', NULL)",
+            &mut table,
+        );
+
+        let result = query(
+            "select name, age + 1 as next, id * 10 ten, 'x' || name from people",
+            &mut table,
+        );
+        assert_eq!(result.columns, ["name", "next", "ten", "'x' || name"]);
+        assert_eq!(
+            result.rows[0].values,
+            [
+                Value::Text("alice".to_string()),
+                Value::Integer(31),
+                Value::Integer(10),
+                Value::Text("xalice".to_string())
+            ]
+        );
+        assert_eq!(result.rows[1].values[1], Value::Null);
+
+        let result = query("select people.*, id from people where id = 2", &mut table);
+        assert_eq!(result.columns, ["id", "name", "age", "id"]);
+        assert_eq!(result.rows.len(), 1);
+
+        assert!(matches!(
+            try_query("select other.* from people", &mut table),
+            Err(ExecuteResult::TableNotFound(name)) if name == "other"
+        ));
+    }
+
+    #[test]
+    fn test_execute_select_order_by() {
+        let path = table::tests::temp_db_path("repl_order_by");
+        let mut table = table::Table::db_open(&path);
+        run_statement(
+            "create table people (id integer primary key, name text, age integer)",
+            &mut table,
+        );
+        run_statement(
+            "insert into people values (1, 'alice', 30), (2, 'bob', NULL), (3, 'carol', 25), (4, 'dave', 30)",
+            &mut table,
+        );
+        let ids = |sql: &str, table: &mut table::Table| {
+            query(sql, table)
+                .rows
+                .into_iter()
+                .map(|row| row.values[0].clone())
+                .collect::<Vec<_>>()
+        };
+        let expected = |values: &[i64]| {
+            values
+                .iter()
+                .map(|&i| Value::Integer(i))
+                .collect::<Vec<_>>()
+        };
+
+        // NULL sorts first, ties keep table order
+        assert_eq!(
+            ids("select id from people order by age", &mut table),
+            expected(&[2, 3, 1, 4])
+        );
+        assert_eq!(
+            ids(
+                "select id from people order by age desc, name desc",
+                &mut table
+            ),
+            expected(&[4, 1, 3, 2])
+        );
+        // Aliases and positions refer to result columns
+        assert_eq!(
+            ids(
+                "select id, -id as neg from people where age > 0 order by neg",
+                &mut table
+            ),
+            expected(&[4, 3, 1])
+        );
+        assert_eq!(
+            ids("select id, name from people order by 2 desc", &mut table),
+            expected(&[4, 3, 2, 1])
+        );
+        assert!(matches!(
+            try_query("select id from people order by 2", &mut table),
+            Err(ExecuteResult::OrderByOutOfRange(2))
+        ));
+    }
+
+    #[test]
+    fn test_execute_select_limit_offset() {
+        let path = table::tests::temp_db_path("repl_limit");
+        let mut table = table::Table::db_open(&path);
+        run_statement("create table t (id integer, label text)", &mut table);
+        for i in 0..200 {
+            let sql = format!("insert into t values ({}, '{}')", i, "x".repeat(200));
+            run_statement(&sql, &mut table);
+        }
+        let ids = |sql: &str, table: &mut table::Table| {
+            query(sql, table)
+                .rows
+                .into_iter()
+                .map(|row| row.values[0].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ids("select id from t limit 3 offset 10", &mut table),
+            [10, 11, 12].map(Value::Integer)
+        );
+        assert_eq!(
+            ids("select id from t order by id desc limit 2", &mut table),
+            [199, 198].map(Value::Integer)
+        );
+        assert_eq!(ids("select id from t limit 0", &mut table), []);
+        assert_eq!(
+            ids("select id from t limit 5 offset 198", &mut table).len(),
+            2
+        );
+
+        // A fresh pager only reads the pages the limited scan touched
+        table.db_close();
+        let mut table = table::Table::db_open(&path);
+        let data_pages = table.pager.num_pages as usize;
+        assert_eq!(
+            ids("select id from t limit 1", &mut table),
+            [Value::Integer(0)]
+        );
+        let loaded = table
+            .pager
+            .pages
+            .iter()
+            .filter(|page| page.is_some())
+            .count();
+        assert!(
+            loaded < data_pages / 2,
+            "read {} of {} pages",
+            loaded,
+            data_pages
+        );
+    }
+
+    #[test]
+    fn test_execute_select_aggregates() {
+        let path = table::tests::temp_db_path("repl_aggregates");
+        let mut table = table::Table::db_open(&path);
+        run_statement(
+            "create table sales (id integer primary key, region text, amount integer)",
+            &mut table,
+        );
+        assert_eq!(
+            query("select count(*), sum(amount) from sales", &mut table).rows[0].values,
+            [Value::Integer(0), Value::Null]
+        );
+        run_statement(
+            "insert into sales values (1, 'north', 10), (2, 'south', 5), (3, 'north', 30), (4, 'east', NULL), (5, 'south', 7)",
+            &mut table,
+        );
+
+        let result = query(
+            "select region, count(*) as n, sum(amount), max(amount) - min(amount) from sales group by region having count(amount) > 0 order by sum(amount) desc",
+            &mut table,
+        );
+        assert_eq!(
+            result.columns,
+            ["region", "n", "sum(amount)", "max(amount) - min(amount)"]
+        );
+        let rows = result
+            .rows
+            .into_iter()
+            .map(|row| row.values)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                [
+                    Value::Text("north".to_string()),
+                    Value::Integer(2),
+                    Value::Integer(40),
+                    Value::Integer(20)
+                ],
+                [
+                    Value::Text("south".to_string()),
+                    Value::Integer(2),
+                    Value::Integer(12),
+                    Value::Integer(2)
+                ]
+            ]
+        );
+
+        let result = query(
+            "select avg(amount), group_concat(region, '/') from sales where id < 4",
+            &mut table,
+        );
+        assert_eq!(
+            result.rows[0].values,
+            [
+                Value::Real(15.0),
+                Value::Text("north/south/north".to_string())
+            ]
+        );
+
+        assert!(matches!(
+            try_query("select * from sales where count(*) > 1", &mut table),
+            Err(ExecuteResult::MisuseOfAggregate(name)) if name == "count"
+        ));
+        assert!(matches!(
+            try_query("select total(amount) from sales", &mut table),
+            Err(ExecuteResult::NoSuchFunction(name)) if name == "total"
+        ));
+        assert!(matches!(
+            try_query("select sum(amount, id) from sales", &mut table),
+            Err(ExecuteResult::WrongNumberOfArguments(name)) if name == "sum"
+        ));
+    }
+
+    #[test]
+    fn test_execute_select_group_by_falls_back_to_sorting() {
+        let path = table::tests::temp_db_path("repl_group_by_sort");
+        let mut table = table::Table::db_open(&path);
+        run_statement("create table t (id integer, bucket integer)", &mut table);
+        for i in 0..1000 {
+            let sql = format!("insert into t values ({}, {})", i, (i * 7919) % 300);
+            run_statement(&sql, &mut table);
+        }
+        let sql = "select bucket, count(*), sum(id) from t group by bucket";
+        let hashed = query(sql, &mut table).rows;
+        table.sort_memory_budget = 2048;
+        let sorted = query(sql, &mut table).rows;
+        assert_eq!(hashed.len(), 300);
+        assert_eq!(hashed, sorted);
+        assert_eq!(
+            hashed[0].values[..2],
+            [Value::Integer(0), Value::Integer(4)]
+        );
+    }
+
+    fn prepare(sql: &str, table: &mut table::Table) -> Query {
+        let Ok(StatementType::Select(select)) = parser::parse_statement(sql) else {
+            panic!("expected select");
+        };
+        let Ok(query) = prepare_query(select, table) else {
+            panic!("prepare failed");
+        };
+        query
+    }
+
+    fn join_tables(name: &str) -> table::Table {
+        let mut table = table::Table::db_open(&table::tests::temp_db_path(name));
+        run_statement(
+            "create table users (id integer primary key, name text, team integer)",
+            &mut table,
+        );
+        run_statement(
+            "create table teams (id integer primary key, title text)",
+            &mut table,
+        );
+        run_statement(
+            "insert into users values (1, 'alice', 10), (2, 'bob', 20), (3, 'carol', 10), (4, 'dave', NULL)",
+            &mut table,
+        );
+        run_statement(
+            "insert into teams values (10, 'red'), (20, 'blue'), (30, 'green')",
+            &mut table,
+        );
+        table
+    }
+
+    fn values(result: ResultSet) -> Vec<Vec<String>> {
+        result
+            .rows
+            .into_iter()
+            .map(|row| row.values.iter().map(Value::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_execute_select_join() {
+        let mut table = join_tables("repl_join");
+
+        // teams.id is looked up in the primary key's index. Unanalyzed tables
+        // count as equally large, so users goes first whatever the FROM order.
+        let sql = "select u.name, t.title from users u join teams t on u.team = t.id";
+        let plan = ["SCAN u", "SEARCH t USING PRIMARY KEY (id=?)"];
+        assert_eq!(prepare(sql, &mut table).query_plan(), plan);
+        assert_eq!(
+            values(query(sql, &mut table)),
+            [["alice", "red"], ["bob", "blue"], ["carol", "red"]]
+        );
+        let sql = "select t.title, u.name from teams t join users u on u.team = t.id";
+        assert_eq!(prepare(sql, &mut table).query_plan(), plan);
+        assert_eq!(
+            values(query(sql, &mut table)),
+            [["red", "alice"], ["blue", "bob"], ["red", "carol"]]
+        );
+
+        // WHERE equalities join the same way
+        let sql = "select name, title from users, teams where team = teams.id and title <> 'blue'";
+        assert_eq!(
+            prepare(sql, &mut table).query_plan(),
+            ["SCAN users", "SEARCH teams USING PRIMARY KEY (id=?)"]
+        );
+        assert_eq!(
+            values(query(sql, &mut table)),
+            [["alice", "red"], ["carol", "red"]]
+        );
+
+        // A LEFT JOIN keeps its place, and users.team is not indexed so it
+        // gets hashed
+        let sql = "select teams.*, users.name from teams left join users on users.team = teams.id and users.id > 1 order by teams.id";
+        assert_eq!(
+            prepare(sql, &mut table).query_plan()[..2],
+            [
+                "SCAN teams",
+                "SEARCH users USING HASH TABLE (team=?) LEFT-JOIN"
+            ]
+        );
+        let result = query(sql, &mut table);
+        assert_eq!(result.columns, ["id", "title", "name"]);
+        assert_eq!(
+            values(result),
+            [
+                ["10", "red", "carol"],
+                ["20", "blue", "bob"],
+                ["30", "green", "NULL"]
+            ]
+        );
+
+        assert!(matches!(
+            try_query("select id from users join teams on team = teams.id", &mut table),
+            Err(ExecuteResult::AmbiguousColumn(name)) if name == "id"
+        ));
+    }
+
+    #[test]
+    fn test_execute_select_self_join_with_aggregate() {
+        let mut table = join_tables("repl_self_join");
+        // Pairs of users in the same team
+        let result = query(
+            "select a.name, count(b.id) from users a left join users as b on a.team = b.team and a.id <> b.id group by a.name",
+            &mut table,
+        );
+        assert_eq!(
+            values(result),
+            [["alice", "1"], ["bob", "0"], ["carol", "1"], ["dave", "0"]]
+        );
+    }
+
+    #[test]
+    fn test_execute_select_index_join() {
+        let path = table::tests::temp_db_path("repl_index_join");
+        let mut table = join_tables("repl_index_join");
+        assert!(matches!(
+            run_statement("create index users_team on users (team)", &mut table),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement("create index users_team on users (name)", &mut table),
+            ExecuteResult::IndexExists(name) if name == "users_team"
+        ));
+        assert!(matches!(
+            run_statement("create index users_bad on users (nope)", &mut table),
+            ExecuteResult::ColumnNotFound(name) if name == "nope"
+        ));
+        assert!(matches!(
+            run_statement("create index teams_bad on nope (id)", &mut table),
+            ExecuteResult::TableNotFound(_)
+        ));
+
+        let sql =
+            "select t.title, u.name from teams t join users u on t.id = u.team where t.title <> 'purple'";
+        assert_eq!(
+            prepare(sql, &mut table).query_plan(),
+            ["SCAN t", "SEARCH u USING INDEX users_team (team=?)"]
+        );
+        assert_eq!(
+            values(query(sql, &mut table)),
+            [["red", "alice"], ["red", "carol"], ["blue", "bob"]]
+        );
+
+        // The index follows changes to the table and survives a reopen
+        run_statement("delete 1", &mut table);
+        run_statement("insert into users values (5, 'erin', 30)", &mut table);
+        run_statement("update 2 bob 30", &mut table);
+        table.db_close();
+        let mut table = table::Table::db_open(&path);
+        assert_eq!(
+            values(query(&format!("{} order by t.id", sql), &mut table)),
+            [["red", "carol"], ["green", "bob"], ["green", "erin"]]
+        );
+    }
+
+    #[test]
+    fn test_execute_unique_constraints() {
+        let mut table = table::Table::db_open(&table::tests::temp_db_path("repl_unique"));
+        let violation = |result: ExecuteResult| match result {
+            ExecuteResult::UniqueViolation(violation) => {
+                let values = violation.values.iter().map(Value::to_string);
+                (violation.index, values.collect::<Vec<_>>())
+            }
+            _ => panic!("expected a unique constraint violation"),
+        };
+
+        // The legacy users table has a unique email besides its primary key
+        run_statement("insert 1 alice a@test.com", &mut table);
+        run_statement("insert 2 bob b@test.com", &mut table);
+        assert_eq!(
+            violation(run_statement("insert 1 carol c@test.com", &mut table)),
+            (
+                "sqlite_autoindex_users_1".to_string(),
+                vec!["1".to_string()]
+            )
+        );
+        assert_eq!(
+            violation(run_statement("update 2 bob a@test.com", &mut table)),
+            (
+                "sqlite_autoindex_users_2".to_string(),
+                vec!["a@test.com".to_string()]
+            )
+        );
+        assert!(matches!(
+            run_statement("update 2 bobby b@test.com", &mut table),
+            ExecuteResult::Success
+        ));
+
+        run_statement(
+            "create table members (id integer primary key, org integer, name text, unique (org, name))",
+            &mut table,
+        );
+        assert!(matches!(
+            run_statement(
+                "insert into members values (1, 1, 'ann'), (2, 2, 'ann'), (3, NULL, 'ann'), (4, NULL, 'ann')",
+                &mut table
+            ),
+            ExecuteResult::Success
+        ));
+        // A statement that fails part way inserts none of its rows
+        assert_eq!(
+            violation(run_statement(
+                "insert into members values (5, 3, 'ann'), (6, 3, 'ann')",
+                &mut table
+            )),
+            (
+                "sqlite_autoindex_members_2".to_string(),
+                vec!["3".to_string(), "ann".to_string()]
+            )
+        );
+        assert_eq!(query("select id from members", &mut table).rows.len(), 4);
+
+        // An UPDATE is held to the same constraints, and one that fails
+        // part way changes none of its rows
+        assert_eq!(
+            violation(run_statement(
+                "update members set id = 1 where id = 2",
+                &mut table
+            )),
+            (
+                "sqlite_autoindex_members_1".to_string(),
+                vec!["1".to_string()]
+            )
+        );
+        assert_eq!(
+            violation(run_statement(
+                "update members set org = 9 where id >= 3",
+                &mut table
+            )),
+            (
+                "sqlite_autoindex_members_2".to_string(),
+                vec!["9".to_string(), "ann".to_string()]
+            )
+        );
+        assert_eq!(
+            values(query("select id, org from members order by id", &mut table)),
+            [["1", "1"], ["2", "2"], ["3", "NULL"], ["4", "NULL"]]
+        );
+        assert!(query("select id from members where org = 9", &mut table)
+            .rows
+            .is_empty());
+        // A row may keep its own values
+        assert!(matches!(
+            run_statement("update members set name = name, id = id", &mut table),
+            ExecuteResult::Success
+        ));
+
+        assert_eq!(
+            violation(run_statement(
+                "create unique index members_name on members (name)",
+                &mut table
+            )),
+            ("members_name".to_string(), vec!["ann".to_string()])
+        );
+        assert!(table.catalog.find_index("members_name").is_none());
+        assert!(matches!(
+            run_statement(
+                "create unique index members_org on members (org, id)",
+                &mut table
+            ),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement("create table bad (a, unique (b))", &mut table),
+            ExecuteResult::ColumnNotFound(name) if name == "b"
+        ));
+        assert!(table.catalog.find_table("bad").is_none());
+    }
+
+    #[test]
+    fn test_execute_select_uses_access_path() {
+        let path = table::tests::temp_db_path("repl_access_path");
+        let mut table = table::Table::db_open(&path);
+        run_statement(
+            "create table t (id integer primary key, tag text, size integer, body text)",
+            &mut table,
+        );
+        run_statement("create index t_tag_size on t (tag, size)", &mut table);
+        for i in 0..300 {
+            let sql = format!(
+                "insert into t values ({}, 'tag{}', {}, '{}')",
+                (i * 7) % 300,
+                i % 3,
+                i,
+                "x".repeat(200)
+            );
+            run_statement(&sql, &mut table);
+        }
+        let access =
+            |sql: &str, table: &mut table::Table| prepare(sql, table).levels[0].access.clone();
+        let ids = |sql: &str, table: &mut table::Table| {
+            let rows = query(sql, table).rows;
+            rows.into_iter()
+                .map(|row| row.values[0].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let sql = "select id, size from t where id = 21";
+        assert!(
+            matches!(access(sql, &mut table), AccessPath::Index(scan) if scan.is_point_lookup())
+        );
+        assert_eq!(ids(sql, &mut table), ["21"]);
+
+        // Index scans return rows in index order
+        let sql = "select id from t where id between 10 and 14 and size > 100";
+        assert!(matches!(access(sql, &mut table), AccessPath::Index(scan) if scan.primary_key));
+        assert_eq!(ids(sql, &mut table), ["10", "11", "12", "13"]);
+        assert_eq!(
+            ids("select id from t where id > 297", &mut table),
+            ["298", "299"]
+        );
+        assert_eq!(
+            ids("select id from t where 3 > id", &mut table),
+            ["0", "1", "2"]
+        );
+
+        let sql = "select size from t where tag = 'tag1' and size >= 290";
+        let AccessPath::Index(scan) = access(sql, &mut table) else {
+            panic!("expected an index scan");
+        };
+        assert_eq!(
+            (scan.index.name.as_str(), scan.covering),
+            ("t_tag_size", true)
+        );
+        assert_eq!(ids(sql, &mut table), ["292", "295", "298"]);
+        let sql = "select body from t where tag = 'tag1' and size >= 290";
+        assert!(matches!(access(sql, &mut table), AccessPath::Index(scan) if !scan.covering));
+        assert_eq!(query(sql, &mut table).rows.len(), 3);
+
+        assert_eq!(
+            access("select id from t where size = 3", &mut table),
+            AccessPath::FullScan
+        );
+        assert_eq!(
+            ids("select id from t where id = NULL", &mut table),
+            Vec::<String>::new()
+        );
+        // The text is compared as a number, as the column's affinity says
+        let sql = "select id from t where id = '21'";
+        assert!(matches!(access(sql, &mut table), AccessPath::Index(scan) if scan.primary_key));
+        assert_eq!(ids(sql, &mut table), ["21"]);
+        assert_eq!(
+            ids("select id from t where id between '20' and 22", &mut table),
+            ["20", "21", "22"]
+        );
+        assert_eq!(ids("select id from t where size = '3'", &mut table), ["21"]);
+
+        // A fresh pager only reads the index pages leading to the row
+        table.db_close();
+        let mut table = table::Table::db_open(&path);
+        assert_eq!(ids("select id from t where id = 150", &mut table), ["150"]);
+        let loaded = table
+            .pager
+            .pages
+            .iter()
+            .filter(|page| page.is_some())
+            .count();
+        assert!(loaded < 10, "read {} pages", loaded);
+    }
+
+    #[test]
+    fn test_execute_analyze() {
+        let mut table = table::Table::db_open(&table::tests::temp_db_path("repl_analyze"));
+        run_statement(
+            "create table big (id integer primary key, kind integer, body text)",
+            &mut table,
+        );
+        run_statement("create index big_kind on big (kind)", &mut table);
+        run_statement("create table small (id integer primary key)", &mut table);
+        for i in 0..200 {
+            let sql = format!("insert into big values ({}, {}, 'x')", i, i % 2);
+            run_statement(&sql, &mut table);
+        }
+        run_statement("insert into small values (1), (2), (3)", &mut table);
+        let plan = |sql: &str, table: &mut table::Table| prepare(sql, table).query_plan();
+
+        let filter = "select body from big where kind = 1";
+        let join = "select b.kind from big b join small s on b.id = s.id";
+        assert_eq!(
+            plan(filter, &mut table),
+            ["SEARCH big USING INDEX big_kind (kind=?)"]
+        );
+        assert_eq!(
+            plan(join, &mut table),
+            ["SCAN b", "SEARCH s USING PRIMARY KEY (id=?)"]
+        );
+
+        assert!(matches!(
+            run_statement("analyze nope", &mut table),
+            ExecuteResult::TableNotFound(_)
+        ));
+        assert!(matches!(
+            run_statement("analyze", &mut table),
+            ExecuteResult::Success
+        ));
+        assert_eq!(
+            values(query(
+                "select idx, col, nrow, ndistinct from sqlite_stat where tbl = 'big' and idx is null order by col",
+                &mut table
+            )),
+            [
+                ["NULL", "NULL", "200", "NULL"],
+                ["NULL", "body", "200", "1"],
+                ["NULL", "id", "200", "200"],
+                ["NULL", "kind", "200", "2"],
+            ]
+        );
+
+        // Half of big has each kind, so reading it through the index costs
+        // more than a scan, and the smaller table goes first
+        assert_eq!(plan(filter, &mut table), ["SCAN big"]);
+        assert_eq!(
+            plan(join, &mut table),
+            ["SCAN s", "SEARCH b USING PRIMARY KEY (id=?)"]
+        );
+        assert_eq!(values(query(join, &mut table)), [["1"], ["0"], ["1"]]);
+    }
+
+    #[test]
+    fn test_execute_transactions() {
+        let path = table::tests::temp_db_path("repl_transactions");
+        let mut table = table::Table::db_open(&path);
+        let ids = |table: &mut table::Table| {
+            let rows = query("select id from t order by id", table).rows;
+            rows.into_iter()
+                .map(|row| row.values[0].to_string())
+                .collect::<Vec<_>>()
+        };
+        run_statement("create table t (id integer primary key)", &mut table);
+        run_statement("insert into t values (1)", &mut table);
+
+        assert!(matches!(
+            run_statement("begin", &mut table),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement("begin", &mut table),
+            ExecuteResult::TransactionActive
+        ));
+        run_statement("insert into t values (2), (3)", &mut table);
+        run_statement("create table u (a)", &mut table);
+        assert_eq!(ids(&mut table), ["1", "2", "3"]);
+        assert!(matches!(
+            run_statement("rollback", &mut table),
+            ExecuteResult::Success
+        ));
+        assert_eq!(ids(&mut table), ["1"]);
+        assert!(table.catalog.find_table("u").is_none());
+        assert!(matches!(
+            run_statement("commit", &mut table),
+            ExecuteResult::NoTransaction
+        ));
+
+        // A failed statement only undoes itself
+        run_statement("begin transaction", &mut table);
+        run_statement("insert into t values (4)", &mut table);
+        assert!(matches!(
+            run_statement("insert into t values (5), (1)", &mut table),
+            ExecuteResult::UniqueViolation(_)
+        ));
+        // The first row is rewritten before the second one collides
+        assert!(matches!(
+            run_statement("update t set id = 5", &mut table),
+            ExecuteResult::UniqueViolation(_)
+        ));
+        assert!(table.in_transaction());
+        assert_eq!(ids(&mut table), ["1", "4"]);
+        run_statement("end", &mut table);
+        assert_eq!(ids(&mut table), ["1", "4"]);
+
+        // Committed statements are in the file without closing it
+        run_statement("insert into t values (6)", &mut table);
+        let mut other = table::Table::db_open(&path);
+        assert_eq!(ids(&mut other), ["1", "4", "6"]);
+
+        // A savepoint undoes part of a transaction
+        run_statement("begin", &mut table);
+        run_statement("insert into t values (7)", &mut table);
+        run_statement("savepoint batch", &mut table);
+        run_statement("insert into t values (8)", &mut table);
+        assert!(matches!(
+            run_statement("rollback to batch", &mut table),
+            ExecuteResult::Success
+        ));
+        assert!(matches!(
+            run_statement("release unknown", &mut table),
+            ExecuteResult::NoSuchSavepoint(name) if name == "unknown"
+        ));
+        run_statement("release batch", &mut table);
+        run_statement("commit", &mut table);
+        assert_eq!(ids(&mut table), ["1", "4", "6", "7"]);
+
+        // Outside a transaction the outermost savepoint commits on release
+        run_statement("savepoint outer", &mut table);
+        run_statement("insert into t values (9)", &mut table);
+        run_statement("release outer", &mut table);
+        assert!(!table.in_transaction());
+        let mut other = table::Table::db_open(&path);
+        assert_eq!(ids(&mut other), ["1", "4", "6", "7", "9"]);
+    }
+
+    // Elsewhere locks of one process never conflict
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_execute_busy() {
+        let path = table::tests::temp_db_path("repl_busy");
+        let mut a = table::Table::db_open(&path);
+        let mut b = table::Table::db_open(&path);
+        let count = |table: &mut table::Table| {
+            table.begin(false).unwrap();
+            let rows = query("select id from t", table).rows.len();
+            table.commit().unwrap();
+            rows
+        };
+        run_statement("create table t (id integer primary key)", &mut a);
+        assert_eq!(count(&mut b), 0);
+
+        run_statement("begin", &mut a);
+        run_statement("insert into t values (1)", &mut a);
+        assert!(matches!(
+            run_statement("insert into t values (2)", &mut b),
+            ExecuteResult::Busy
+        ));
+        assert_eq!(count(&mut b), 0);
+        run_statement("commit", &mut a);
+        assert_eq!(count(&mut b), 1);
+
+        // With a busy timeout the statement waits for the other writer
+        b.set_busy_timeout(Duration::from_secs(10));
+        run_statement("begin", &mut a);
+        run_statement("insert into t values (3)", &mut a);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                run_statement("commit", &mut a);
+            });
+            assert!(matches!(
+                run_statement("insert into t values (4)", &mut b),
+                ExecuteResult::Success
+            ));
+        });
+        assert_eq!(count(&mut a), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_execute_wal_mode() {
+        let path = table::tests::temp_db_path("repl_wal");
+        let mut a = table::Table::db_open(&path);
+        let mut b = table::Table::db_open(&path);
+        let count = |table: &mut table::Table| query("select id from t", table).rows.len();
+        run_statement("create table t (id integer primary key)", &mut a);
+        assert!(matches!(
+            run_statement("pragma journal_mode = wal", &mut a),
+            ExecuteResult::Success
+        ));
+        assert_eq!(a.journal_mode(), JournalMode::Wal);
+        assert!(matches!(
+            run_statement("pragma journal_mode = bogus", &mut a),
+            ExecuteResult::Success
+        ));
+        assert_eq!(a.journal_mode(), JournalMode::Wal);
+        run_statement("pragma cache_size = 10", &mut a);
+
+        // A reader no longer keeps the writer from committing, and goes on
+        // seeing the rows that were there when it started
+        run_statement("begin", &mut b);
+        assert_eq!(count(&mut b), 0);
+        assert!(matches!(
+            run_statement("insert into t values (1)", &mut a),
+            ExecuteResult::Success
+        ));
+        assert_eq!(count(&mut b), 0);
+        assert!(matches!(
+            run_statement("insert into t values (2)", &mut b),
+            ExecuteResult::Busy
+        ));
+        run_statement("rollback", &mut b);
+        run_statement("begin", &mut b);
+        assert_eq!(count(&mut b), 1);
+        run_statement("rollback", &mut b);
+
+        // The last connection to close checkpoints and removes the log
+        let wal_path = format!("{}-wal", path);
+        a.db_close();
+        drop(a);
+        assert!(std::path::Path::new(&wal_path).exists());
+        b.db_close();
+        drop(b);
+        assert!(!std::path::Path::new(&wal_path).exists());
+        let mut table = table::Table::db_open(&path);
+        assert_eq!(table.journal_mode(), JournalMode::Wal);
+        run_statement("begin", &mut table);
+        assert_eq!(count(&mut table), 1);
+        run_statement("rollback", &mut table);
+        run_statement("insert into t values (2)", &mut table);
+        assert!(std::path::Path::new(&wal_path).exists());
+        table.db_close();
+        drop(table);
+        assert!(!std::path::Path::new(&wal_path).exists());
+        let mut table = table::Table::db_open(&path);
+        assert_eq!(count(&mut table), 2);
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut table = join_tables("repl_explain");
+        run_statement("create index users_team on users (team)", &mut table);
+        let plan = |sql: &str, table: &mut table::Table| {
+            let Ok(StatementType::Explain(explain)) = parser::parse_statement(sql) else {
+                panic!("expected explain: {}", sql);
+            };
+            let Ok(plan) = query_plan(*explain.statement, table) else {
+                panic!("explain failed: {}", sql);
+            };
+            plan
+        };
+        assert_eq!(
+            plan(
+                "explain query plan select name from users where id = 2",
+                &mut table
+            ),
+            ["SEARCH users USING PRIMARY KEY (id=?)"]
+        );
+        assert_eq!(
+            plan(
+                "explain query plan select t.title, u.name from teams t left join users u on u.team = t.id order by u.name",
+                &mut table
+            ),
+            [
+                "SCAN t",
+                "SEARCH u USING INDEX users_team (team=?) LEFT-JOIN",
+                "USE TEMP B-TREE FOR ORDER BY",
+            ]
+        );
+        assert_eq!(
+            plan(
+                "explain query plan select team, count(*) from users group by team",
+                &mut table
+            ),
+            ["SCAN users", "USE HASH TABLE FOR GROUP BY"]
+        );
+        assert_eq!(
+            plan("explain query plan delete 1", &mut table),
+            ["SEARCH users USING PRIMARY KEY (id=?)"]
+        );
+
+        let opcodes = |sql: &str, table: &mut table::Table| {
+            let Ok(program) = compile_statement(parser::parse_statement(sql).unwrap(), sql, table)
+            else {
+                panic!("compile failed: {}", sql);
+            };
+            let opcodes = program.insns.iter().map(|insn| insn.describe().0);
+            opcodes.collect::<Vec<_>>().join(" ")
+        };
+        assert_eq!(
+            opcodes("select name from users where id = 2", &mut table),
+            "OpenRead OpenIndex Value IsNull SeekGe IdxGt SeekRow Column Column Column \
+             Copy Value Eq IfNot Copy ResultRow Next Halt"
+        );
+        assert_eq!(
+            opcodes("select title from teams order by title limit 2", &mut table),
+            "Integer SorterOpen OpenRead Rewind Column Column Copy Copy SorterInsert Next \
+             SorterSort Column ResultRow DecrJumpZero Next Halt"
+        );
+        assert_eq!(
+            opcodes("insert into teams values (40, 'gray')", &mut table),
+            "OpenWrite Value Value CheckUnique Insert Halt"
+        );
+        assert!(opcodes("delete 1", &mut table).ends_with("Delete Next Goto HaltNotFound Halt"));
+    }
+
+    #[test]
+    fn test_execute_select_order_by_spills_to_disk() {
+        let path = table::tests::temp_db_path("repl_order_by_spill");
+        let mut table = table::Table::db_open(&path);
+        table.sort_memory_budget = 4096;
+        run_statement("create table t (id integer, label text)", &mut table);
+        for i in 0..2000 {
+            let sql = format!("insert into t values ({}, 'row {}')", (i * 7919) % 2000, i);
+            run_statement(&sql, &mut table);
+        }
+        let rows = query("select id from t order by id desc", &mut table).rows;
+        let ids = rows
+            .iter()
+            .map(|row| row.values[0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (0..2000).rev().map(Value::Integer).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,271 @@
+//! A handle on a database that threads share. `read` runs on one of a pool
+//! of connections, so any number of threads read at once, while `write`
+//! goes through the one writer connection. A thread can also open a
+//! connection of its own with `connect`, and the file locks keep writers to
+//! one at a time. The connections share one cache of committed pages, so a
+//! page read by one thread is not read from the file again by the next.
+//!
+//! In WAL mode readers and the writer do not wait for each other: each
+//! reader goes on seeing the commits that were there when its transaction
+//! started.
+
+use crate::cache::{PageCache, DEFAULT_CACHE_PAGES};
+use crate::error::Result;
+use crate::table::Table;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// How long the connections of `read` and `write` wait for a lock another
+/// connection holds.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Database {
+    path: String,
+    cache: Arc<PageCache>,
+    /// Connections `read` is done with, for the next reads to take.
+    readers: Mutex<Vec<Table>>,
+    /// The connection of `write`, opened by the first one.
+    writer: Mutex<Option<Table>>,
+}
+
+impl Database {
+    pub fn open(path: &str) -> Self {
+        Database::with_cache_pages(path, DEFAULT_CACHE_PAGES)
+    }
+
+    /// Opens the database with a shared cache of up to about `pages` pages.
+    pub fn with_cache_pages(path: &str, pages: usize) -> Self {
+        Database {
+            path: path.to_string(),
+            cache: Arc::new(PageCache::new(pages)),
+            readers: Mutex::default(),
+            writer: Mutex::default(),
+        }
+    }
+
+    /// Opens a connection for the calling thread.
+    pub fn connect(&self) -> Result<Table> {
+        Ok(Table::open(&self.path, Some(Arc::clone(&self.cache)))?)
+    }
+
+    fn open_connection(&self) -> Result<Table> {
+        let mut table = self.connect()?;
+        table.set_busy_timeout(BUSY_TIMEOUT);
+        Ok(table)
+    }
+
+    /// Runs `read` in a read transaction of its own, on a connection no
+    /// other thread is using, so reads on other threads go on at the same
+    /// time. Whatever it writes is rolled back.
+    pub fn read<T>(&self, read: impl FnOnce(&mut Table) -> Result<T>) -> Result<T> {
+        let pooled = self
+            .readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let mut table = match pooled {
+            Some(table) => table,
+            None => self.open_connection()?,
+        };
+        let result = table.begin(false).map_err(Into::into).and_then(|()| {
+            let result = read(&mut table);
+            if table.in_transaction() {
+                table.rollback();
+            }
+            result
+        });
+        self.readers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(table);
+        result
+    }
+
+    /// Runs `write` in a transaction on the writer connection, one thread
+    /// at a time. The transaction commits if it returns `Ok` and is rolled
+    /// back if not.
+    pub fn write<T>(&self, write: impl FnOnce(&mut Table) -> Result<T>) -> Result<T> {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        if writer.is_none() {
+            *writer = Some(self.open_connection()?);
+        }
+        let table = writer.as_mut().expect("the writer was just opened");
+        table.begin(true)?;
+        match write(table) {
+            Ok(value) if !table.in_transaction() => Ok(value),
+            Ok(value) => {
+                if let Err(error) = table.commit() {
+                    if table.in_transaction() {
+                        table.rollback();
+                    }
+                    return Err(error.into());
+                }
+                Ok(value)
+            }
+            Err(error) => {
+                if table.in_transaction() {
+                    table.rollback();
+                }
+                Err(error)
+            }
+        }
+    }
+}
+
+impl Drop for Database {
+    /// Closes the connections of `read` and `write` one by one, so that the
+    /// last of them to close checkpoints the log.
+    fn drop(&mut self) {
+        let readers = self
+            .readers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let writer = self
+            .writer
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for mut table in readers.drain(..).chain(writer.take()) {
+            table.db_close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::pager::JournalMode;
+    use crate::parser::{self, StatementType};
+    use crate::statement::PreparedStatement;
+    use crate::table::tests::temp_db_path;
+    use crate::table::{Row, PAGE_SIZE};
+    use crate::value::Value;
+    use std::sync::Barrier;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_connect_reports_unreadable_file() {
+        let path = temp_db_path("database_not_a_database");
+        std::fs::write(&path, vec![b'x'; PAGE_SIZE]).unwrap();
+        let db = Database::open(&path);
+        assert!(matches!(db.connect(), Err(Error::Io(_))));
+        assert!(db.read(|_| Ok(())).is_err());
+    }
+
+    // Elsewhere locks of one process never conflict
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_threads_read_while_one_writes() {
+        assert_send_sync::<Database>();
+        let path = temp_db_path("database_threads");
+        let db = Database::open(&path);
+        let mut writer = db.connect().unwrap();
+        writer.set_busy_timeout(Duration::from_secs(10));
+        let sql = "create table t (id integer primary key)";
+        let Ok(StatementType::CreateTable(create)) = parser::parse_statement(sql) else {
+            panic!("expected create table");
+        };
+        writer.begin(true).unwrap();
+        let root_page = writer.create_table(&create, sql).unwrap().root_page;
+        writer.set_journal_mode(JournalMode::Wal).unwrap();
+        writer.commit().unwrap();
+        let row = |id: usize| Row {
+            values: vec![Value::Integer(id as i64)],
+        };
+        let insert = |writer: &mut Table, id: usize| {
+            writer.begin(true).unwrap();
+            writer.insert_row(root_page, &row(id));
+            writer.commit().unwrap();
+        };
+
+        // Every reader sees a whole commit and keeps seeing it until its
+        // transaction ends
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut reader = db.connect().unwrap();
+                    reader.set_busy_timeout(Duration::from_secs(10));
+                    let mut seen = 0;
+                    while seen < 50 {
+                        reader.begin(false).unwrap();
+                        let num_rows = reader.num_rows(root_page);
+                        assert!(num_rows >= seen);
+                        for id in 0..num_rows {
+                            let read = reader.read_row(root_page, id as i64 + 1);
+                            assert_eq!(read.unwrap(), Some(row(id)));
+                        }
+                        assert_eq!(reader.num_rows(root_page), num_rows);
+                        reader.rollback();
+                        seen = num_rows;
+                    }
+                });
+            }
+            for id in 0..50 {
+                insert(&mut writer, id);
+            }
+        });
+        writer.db_close();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shared_handle_reads_overlap() {
+        let path = temp_db_path("database_shared");
+        let db = Arc::new(Database::open(&path));
+        let execute =
+            |sql: &str, table: &mut Table| PreparedStatement::prepare(sql, table)?.execute(table);
+        db.write(|table| {
+            execute("create table t (id integer primary key)", table)?;
+            execute("insert into t values (1), (2)", table)?;
+            table.set_journal_mode(JournalMode::Wal)?;
+            Ok(())
+        })
+        .unwrap();
+        let count = |table: &mut Table| {
+            let mut statement = PreparedStatement::prepare("select count(*) from t", table)?;
+            let row = statement
+                .query(table)?
+                .next()
+                .expect("count returns a row")?;
+            row.get::<i64>(0)
+        };
+
+        // Every reader is inside `read` at once, or the barriers never let
+        // them through, and the commit made meanwhile stays out of their
+        // snapshots
+        const READERS: usize = 4;
+        let inside = Barrier::new(READERS + 1);
+        let written = Barrier::new(READERS + 1);
+        std::thread::scope(|scope| {
+            let readers = (0..READERS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        db.read(|table| {
+                            let before = count(table)?;
+                            inside.wait();
+                            written.wait();
+                            Ok((before, count(table)?))
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            inside.wait();
+            db.write(|table| execute("insert into t values (3)", table))
+                .unwrap();
+            written.wait();
+            for reader in readers {
+                assert_eq!(reader.join().unwrap().unwrap(), (2, 2));
+            }
+        });
+        assert_eq!(db.read(count).unwrap(), 3);
+
+        // A failed write is rolled back
+        assert!(db
+            .write(|table| execute("insert into t values (4), (1)", table))
+            .is_err());
+        assert_eq!(db.read(count).unwrap(), 3);
+        drop(db);
+        assert!(!std::path::Path::new(&format!("{}-wal", path)).exists());
+    }
+}
@@ -0,0 +1,189 @@
+//! The error type of the library API, which the compiler reports its errors
+//! as too.
+
+use crate::expr::BindError;
+use crate::parser::PrepareSyntaxError;
+use crate::table::UniqueViolation;
+use crate::value::Value;
+use crate::vm::VmError;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The SQL does not parse.
+    Syntax(PrepareSyntaxError),
+    RowNotFound,
+    TableNotFound(String),
+    TableExists(String),
+    IndexExists(String),
+    ColumnNotFound(String),
+    AmbiguousColumn(String),
+    NoSuchFunction(String),
+    WrongNumberOfArguments(String),
+    MisuseOfAggregate(String),
+    ColumnCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    UniqueViolation(UniqueViolation),
+    OrderByOutOfRange(i64),
+    TransactionActive,
+    NoTransaction,
+    NoSuchSavepoint(String),
+    /// Another connection holds a lock the statement needs.
+    Busy,
+    Io(io::Error),
+    /// A result column was asked for past the last one.
+    ColumnIndexOutOfRange(usize),
+    /// A result column was asked for as a type its value does not convert
+    /// to.
+    InvalidColumnType {
+        column: String,
+        value: Value,
+    },
+    /// A parameter was bound by a number the statement does not have.
+    ParameterIndexOutOfRange(usize),
+    /// A parameter was bound by a name the statement does not have.
+    ParameterNotFound(String),
+    /// A struct could not be turned into a row, or a row into a struct.
+    Serialization(String),
+    /// A table does not have the columns the struct mapped to it expects.
+    SchemaMismatch(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(error) => write!(f, "syntax error: {:?}", error),
+            Error::RowNotFound => write!(f, "row not found"),
+            Error::TableNotFound(name) => write!(f, "no such table: {}", name),
+            Error::TableExists(name) => write!(f, "table {} already exists", name),
+            Error::IndexExists(name) => write!(f, "index {} already exists", name),
+            Error::ColumnNotFound(name) => write!(f, "no such column: {}", name),
+            Error::AmbiguousColumn(name) => write!(f, "ambiguous column name: {}", name),
+            Error::NoSuchFunction(name) => write!(f, "no such function: {}", name),
+            Error::WrongNumberOfArguments(name) => {
+                write!(f, "wrong number of arguments to {}()", name)
+            }
+            Error::MisuseOfAggregate(name) => {
+                write!(f, "misuse of aggregate function {}()", name)
+            }
+            Error::ColumnCountMismatch { expected, actual } => {
+                write!(f, "expected {} values, got {}", expected, actual)
+            }
+            Error::UniqueViolation(violation) => {
+                let values = violation.values.iter().map(Value::to_string);
+                let values = values.collect::<Vec<_>>().join(", ");
+                write!(
+                    f,
+                    "UNIQUE constraint failed: {} ({})",
+                    violation.index, values
+                )
+            }
+            Error::OrderByOutOfRange(position) => {
+                write!(f, "ORDER BY term out of range: {}", position)
+            }
+            Error::TransactionActive => {
+                write!(f, "cannot start a transaction within a transaction")
+            }
+            Error::NoTransaction => write!(f, "no transaction is active"),
+            Error::NoSuchSavepoint(name) => write!(f, "no such savepoint: {}", name),
+            Error::Busy => write!(f, "database is locked"),
+            Error::Io(error) => write!(f, "{}", error),
+            Error::ColumnIndexOutOfRange(index) => {
+                write!(f, "column index out of range: {}", index)
+            }
+            Error::InvalidColumnType { column, value } => {
+                write!(f, "invalid type for column {}: {}", column, value)
+            }
+            Error::ParameterIndexOutOfRange(index) => {
+                write!(f, "parameter index out of range: {}", index)
+            }
+            Error::ParameterNotFound(name) => write!(f, "no such parameter: {}", name),
+            Error::Serialization(message) => write!(f, "{}", message),
+            Error::SchemaMismatch(message) => write!(f, "schema mismatch: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::WouldBlock => Error::Busy,
+            _ => Error::Io(error),
+        }
+    }
+}
+
+impl From<VmError> for Error {
+    fn from(error: VmError) -> Self {
+        match error {
+            VmError::Io(error) => error.into(),
+            VmError::UniqueViolation(violation) => Error::UniqueViolation(violation),
+            VmError::RowNotFound => Error::RowNotFound,
+            VmError::ColumnNotFound(name) => Error::ColumnNotFound(name),
+            VmError::TransactionActive => Error::TransactionActive,
+            VmError::NoTransaction => Error::NoTransaction,
+            VmError::NoSuchSavepoint(name) => Error::NoSuchSavepoint(name),
+        }
+    }
+}
+
+impl From<BindError> for Error {
+    fn from(error: BindError) -> Self {
+        match error {
+            BindError::ColumnNotFound(name) => Error::ColumnNotFound(name),
+            BindError::AmbiguousColumn(name) => Error::AmbiguousColumn(name),
+            BindError::NoSuchFunction(name) => Error::NoSuchFunction(name),
+            BindError::WrongNumberOfArguments(name) => Error::WrongNumberOfArguments(name),
+            BindError::MisuseOfAggregate(name) => Error::MisuseOfAggregate(name),
+        }
+    }
+}
+
+impl From<PrepareSyntaxError> for Error {
+    fn from(error: PrepareSyntaxError) -> Self {
+        Error::Syntax(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from() {
+        let error = Error::from(BindError::ColumnNotFound("age".to_string()));
+        assert!(matches!(&error, Error::ColumnNotFound(name) if name == "age"));
+        assert_eq!(error.to_string(), "no such column: age");
+        let error = Error::from(VmError::NoSuchSavepoint("sp".to_string()));
+        assert_eq!(error.to_string(), "no such savepoint: sp");
+
+        let busy = io::Error::new(io::ErrorKind::WouldBlock, "locked");
+        assert!(matches!(Error::from(busy), Error::Busy));
+        let io = Error::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert_eq!(io.to_string(), "missing");
+        assert!(std::error::Error::source(&io).is_some());
+
+        let violation = Error::UniqueViolation(UniqueViolation {
+            index: "users_email".to_string(),
+            values: vec![Value::Text("a@b".to_string())],
+        });
+        assert_eq!(
+            violation.to_string(),
+            "UNIQUE constraint failed: users_email (a@b)"
+        );
+    }
+}
@@ -0,0 +1,1223 @@
+use crate::aggregate::AggregateFunction;
+use crate::value::{and3, not3, or3, Affinity, Value};
+use std::cmp::Ordering;
+use std::convert::Infallible;
+use std::fmt;
+
+/// A possibly table-qualified column name as written in the query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub name: String,
+}
+
+impl fmt::Display for ColumnRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.table {
+            Some(table) => write!(f, "{}.{}", table, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Plus,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Value),
+    /// A column by name, replaced by `ColumnIndex` when the expression is bound.
+    Column(ColumnRef),
+    ColumnIndex(usize),
+    /// A placeholder for the value bound to parameter `n`, numbered from 1.
+    Parameter(usize),
+    /// The value of `expr` converted by the affinity of a column it is
+    /// compared with, added when the comparison is bound.
+    Affinity {
+        expr: Box<Expr>,
+        affinity: Affinity,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `left IS [NOT] right`, equality where NULL equals NULL.
+    Is {
+        left: Box<Expr>,
+        right: Box<Expr>,
+        negated: bool,
+    },
+    Like {
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        negated: bool,
+    },
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    Between {
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+        negated: bool,
+    },
+    /// `CASE [operand] WHEN .. THEN .. [ELSE default] END`. Without an
+    /// operand each WHEN is a condition, with one it is compared to it.
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: Vec<(Expr, Expr)>,
+        default: Option<Box<Expr>>,
+    },
+    /// A function call. `count(*)` has no arguments.
+    Function {
+        name: String,
+        args: Vec<Expr>,
+        distinct: bool,
+    },
+}
+
+/// A function of its arguments alone, computed one row at a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScalarFunction {
+    Abs,
+    Coalesce,
+    IfNull,
+    Length,
+    Lower,
+    NullIf,
+    TypeOf,
+    Upper,
+}
+
+impl ScalarFunction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "abs" => Some(ScalarFunction::Abs),
+            "coalesce" => Some(ScalarFunction::Coalesce),
+            "ifnull" => Some(ScalarFunction::IfNull),
+            "length" => Some(ScalarFunction::Length),
+            "lower" => Some(ScalarFunction::Lower),
+            "nullif" => Some(ScalarFunction::NullIf),
+            "typeof" => Some(ScalarFunction::TypeOf),
+            "upper" => Some(ScalarFunction::Upper),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScalarFunction::Abs => "abs",
+            ScalarFunction::Coalesce => "coalesce",
+            ScalarFunction::IfNull => "ifnull",
+            ScalarFunction::Length => "length",
+            ScalarFunction::Lower => "lower",
+            ScalarFunction::NullIf => "nullif",
+            ScalarFunction::TypeOf => "typeof",
+            ScalarFunction::Upper => "upper",
+        }
+    }
+
+    pub fn accepts(self, num_args: usize) -> bool {
+        match self {
+            ScalarFunction::Coalesce => num_args >= 2,
+            ScalarFunction::IfNull | ScalarFunction::NullIf => num_args == 2,
+            _ => num_args == 1,
+        }
+    }
+
+    pub fn call(self, args: &[Value]) -> Value {
+        let text = |value: &Value, convert: fn(&str) -> String| match value {
+            Value::Null => Value::Null,
+            value => Value::Text(convert(&value.to_string())),
+        };
+        match self {
+            ScalarFunction::Abs => match to_numeric(&args[0]) {
+                Value::Integer(i) => i
+                    .checked_abs()
+                    .map_or(Value::Real((i as f64).abs()), Value::Integer),
+                Value::Real(r) => Value::Real(r.abs()),
+                value => value,
+            },
+            ScalarFunction::Coalesce | ScalarFunction::IfNull => args
+                .iter()
+                .find(|value| !value.is_null())
+                .cloned()
+                .unwrap_or(Value::Null),
+            ScalarFunction::Length => match &args[0] {
+                Value::Null => Value::Null,
+                Value::Blob(bytes) => Value::Integer(bytes.len() as i64),
+                value => Value::Integer(value.to_string().chars().count() as i64),
+            },
+            ScalarFunction::Lower => text(&args[0], str::to_ascii_lowercase),
+            ScalarFunction::Upper => text(&args[0], str::to_ascii_uppercase),
+            ScalarFunction::NullIf => match args[0].sql_eq(&args[1]) {
+                Some(true) => Value::Null,
+                _ => args[0].clone(),
+            },
+            ScalarFunction::TypeOf => Value::Text(
+                match &args[0] {
+                    Value::Null => "null",
+                    Value::Integer(_) | Value::Boolean(_) => "integer",
+                    Value::Real(_) => "real",
+                    Value::Text(_) => "text",
+                    Value::Blob(_) => "blob",
+                }
+                .to_string(),
+            ),
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Less
+                | BinaryOp::LessEqual
+                | BinaryOp::Greater
+                | BinaryOp::GreaterEqual
+        )
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+            BinaryOp::Concat => "||",
+            BinaryOp::Equal => "=",
+            BinaryOp::NotEqual => "<>",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Equal | BinaryOp::NotEqual => 4,
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 5,
+            BinaryOp::Add | BinaryOp::Subtract => 6,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Remainder => 7,
+            BinaryOp::Concat => 8,
+        }
+    }
+}
+
+impl Expr {
+    /// How tightly the expression binds, matching the parser's levels.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary { op, .. } => op.precedence(),
+            Expr::Unary {
+                op: UnaryOp::Not, ..
+            } => 3,
+            Expr::Is { .. } | Expr::Like { .. } | Expr::InList { .. } | Expr::Between { .. } => 4,
+            Expr::Unary { .. } => 9,
+            Expr::Affinity { expr, .. } => expr.precedence(),
+            Expr::Literal(_)
+            | Expr::Column(_)
+            | Expr::ColumnIndex(_)
+            | Expr::Parameter(_)
+            | Expr::Case { .. }
+            | Expr::Function { .. } => 10,
+        }
+    }
+
+    /// Writes `expr`, in parentheses when it binds looser than `min`.
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, min: u8) -> fmt::Result {
+        if self.precedence() < min {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+/// Formats the expression as SQL, which is also the name of a result column
+/// that has no alias.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let not = |negated: &bool| if *negated { "NOT " } else { "" };
+        match self {
+            Expr::Literal(Value::Text(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Expr::Literal(value) => write!(f, "{}", value),
+            Expr::Column(column) => write!(f, "{}", column),
+            Expr::ColumnIndex(index) => write!(f, "#{}", index),
+            Expr::Parameter(n) => write!(f, "?{}", n),
+            Expr::Affinity { expr, .. } => write!(f, "{}", expr),
+            Expr::Unary { op, expr } => {
+                let op = match op {
+                    UnaryOp::Negate => "-",
+                    UnaryOp::Plus => "+",
+                    UnaryOp::Not => "NOT ",
+                };
+                write!(f, "{}", op)?;
+                expr.fmt_operand(f, self.precedence())
+            }
+            Expr::Binary { op, left, right } => {
+                // Operators are left associative, so an equal right side needs parentheses
+                left.fmt_operand(f, op.precedence())?;
+                write!(f, " {} ", op.symbol())?;
+                right.fmt_operand(f, op.precedence() + 1)
+            }
+            Expr::Is {
+                left,
+                right,
+                negated,
+            } => {
+                left.fmt_operand(f, 4)?;
+                write!(f, " IS {}", not(negated))?;
+                right.fmt_operand(f, 5)
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => {
+                expr.fmt_operand(f, 4)?;
+                write!(f, " {}LIKE ", not(negated))?;
+                pattern.fmt_operand(f, 5)
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                expr.fmt_operand(f, 4)?;
+                write!(f, " {}IN (", not(negated))?;
+                for (i, item) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                expr.fmt_operand(f, 4)?;
+                write!(f, " {}BETWEEN ", not(negated))?;
+                low.fmt_operand(f, 5)?;
+                write!(f, " AND ")?;
+                high.fmt_operand(f, 5)
+            }
+            Expr::Case {
+                operand,
+                branches,
+                default,
+            } => {
+                write!(f, "CASE")?;
+                if let Some(operand) = operand {
+                    write!(f, " {}", operand)?;
+                }
+                for (when, then) in branches {
+                    write!(f, " WHEN {} THEN {}", when, then)?;
+                }
+                if let Some(default) = default {
+                    write!(f, " ELSE {}", default)?;
+                }
+                write!(f, " END")
+            }
+            Expr::Function {
+                name,
+                args,
+                distinct,
+            } => {
+                write!(f, "{}(", name)?;
+                if *distinct {
+                    write!(f, "DISTINCT ")?;
+                }
+                if args.is_empty() {
+                    write!(f, "*")?;
+                }
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// The columns an expression can refer to, in the order their values appear
+/// in the rows it is evaluated against.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub columns: Vec<(String, String)>,
+    /// The affinity of each column, none (`Blob`) for those it does not
+    /// list.
+    pub affinities: Vec<Affinity>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BindError {
+    ColumnNotFound(String),
+    AmbiguousColumn(String),
+    NoSuchFunction(String),
+    WrongNumberOfArguments(String),
+    /// An aggregate used where there are no groups, such as in WHERE.
+    MisuseOfAggregate(String),
+}
+
+impl Scope {
+    pub fn new(table: &str, columns: impl IntoIterator<Item = String>) -> Self {
+        Scope {
+            columns: columns
+                .into_iter()
+                .map(|column| (table.to_string(), column))
+                .collect(),
+            affinities: Vec::new(),
+        }
+    }
+
+    /// Adds the columns of `other` after this scope's.
+    pub fn extend(&mut self, other: Scope) {
+        let len = self.columns.len();
+        self.affinities.resize(len, Affinity::Blob);
+        self.columns.extend(other.columns);
+        self.affinities.extend(other.affinities);
+    }
+
+    fn affinity(&self, index: usize) -> Affinity {
+        self.affinities
+            .get(index)
+            .copied()
+            .unwrap_or(Affinity::Blob)
+    }
+
+    pub fn resolve(&self, column: &ColumnRef) -> Result<usize, BindError> {
+        let mut matches = self
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, (table, name))| {
+                name.eq_ignore_ascii_case(&column.name)
+                    && column
+                        .table
+                        .as_ref()
+                        .is_none_or(|t| t.eq_ignore_ascii_case(table))
+            });
+        match (matches.next(), matches.next()) {
+            (Some((index, _)), None) => Ok(index),
+            (Some(_), Some(_)) => Err(BindError::AmbiguousColumn(column.to_string())),
+            (None, _) => Err(BindError::ColumnNotFound(column.to_string())),
+        }
+    }
+}
+
+impl Expr {
+    /// Replaces every column name with its position in `scope` and checks
+    /// that every function exists and has a valid number of arguments.
+    /// What a column is compared with is converted by the column's affinity.
+    pub fn bind(&self, scope: &Scope) -> Result<Expr, BindError> {
+        let bound = self.transform(&mut |expr| match expr {
+            Expr::Column(column) => Ok(Some(Expr::ColumnIndex(scope.resolve(column)?))),
+            Expr::Function { name, args, .. } => {
+                let accepts = match (
+                    AggregateFunction::from_name(name),
+                    ScalarFunction::from_name(name),
+                ) {
+                    (Some(function), _) => function.accepts(args.len()),
+                    (None, Some(function)) => function.accepts(args.len()),
+                    (None, None) => return Err(BindError::NoSuchFunction(name.clone())),
+                };
+                if !accepts {
+                    return Err(BindError::WrongNumberOfArguments(name.clone()));
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        })?;
+        Ok(bound.with_comparison_affinity(scope))
+    }
+
+    /// Applies affinity to the operands of comparisons the way SQLite does:
+    /// a numeric column converts a text or untyped operand to a number, a
+    /// text column converts an untyped one to text. So `id = '21'` finds
+    /// the integer 21, by index as well as row by row.
+    fn with_comparison_affinity(&self, scope: &Scope) -> Expr {
+        let compared = |left: Expr, right: Expr| {
+            let affinity = |expr: &Expr| match expr {
+                Expr::ColumnIndex(index) => scope.affinity(*index),
+                _ => Affinity::Blob,
+            };
+            match (affinity(&left), affinity(&right)) {
+                (l, r) if l.is_numeric() && !r.is_numeric() => (left, right.converted(l)),
+                (l, r) if r.is_numeric() && !l.is_numeric() => (left.converted(r), right),
+                (Affinity::Text, Affinity::Blob) => (left, right.converted(Affinity::Text)),
+                (Affinity::Blob, Affinity::Text) => (left.converted(Affinity::Text), right),
+                _ => (left, right),
+            }
+        };
+        let Ok(expr) = self.transform(&mut |expr| {
+            let convert = |expr: &Expr| expr.with_comparison_affinity(scope);
+            Ok::<_, Infallible>(match expr {
+                Expr::Binary { op, left, right } if op.is_comparison() => {
+                    let (left, right) = compared(convert(left), convert(right));
+                    Some(Expr::Binary {
+                        op: *op,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    })
+                }
+                Expr::Is {
+                    left,
+                    right,
+                    negated,
+                } => {
+                    let (left, right) = compared(convert(left), convert(right));
+                    Some(Expr::Is {
+                        left: Box::new(left),
+                        right: Box::new(right),
+                        negated: *negated,
+                    })
+                }
+                // The value being tested keeps its own affinity
+                Expr::Between {
+                    expr,
+                    low,
+                    high,
+                    negated,
+                } => {
+                    let expr = convert(expr);
+                    let (_, low) = compared(expr.clone(), convert(low));
+                    let (_, high) = compared(expr.clone(), convert(high));
+                    Some(Expr::Between {
+                        expr: Box::new(expr),
+                        low: Box::new(low),
+                        high: Box::new(high),
+                        negated: *negated,
+                    })
+                }
+                Expr::InList {
+                    expr,
+                    list,
+                    negated,
+                } => {
+                    let expr = convert(expr);
+                    let list = list
+                        .iter()
+                        .map(|item| compared(expr.clone(), convert(item)).1)
+                        .collect();
+                    Some(Expr::InList {
+                        expr: Box::new(expr),
+                        list,
+                        negated: *negated,
+                    })
+                }
+                _ => None,
+            })
+        });
+        expr
+    }
+
+    /// The expression's value converted by `affinity`, folded into the
+    /// literal when it is one.
+    fn converted(self, affinity: Affinity) -> Expr {
+        match self {
+            Expr::Literal(value) => Expr::Literal(affinity.apply(value)),
+            expr => Expr::Affinity {
+                expr: Box::new(expr),
+                affinity,
+            },
+        }
+    }
+
+    /// Rebuilds the expression bottom up, replacing every subexpression for
+    /// which `f` returns a replacement. Children of a replaced expression
+    /// are not visited.
+    pub fn transform<E>(
+        &self,
+        f: &mut impl FnMut(&Expr) -> Result<Option<Expr>, E>,
+    ) -> Result<Expr, E> {
+        if let Some(replacement) = f(self)? {
+            return Ok(replacement);
+        }
+        let mut child = |expr: &Expr| expr.transform(f).map(Box::new);
+        Ok(match self {
+            Expr::Literal(_) | Expr::Column(_) | Expr::ColumnIndex(_) | Expr::Parameter(_) => {
+                self.clone()
+            }
+            Expr::Affinity { expr, affinity } => Expr::Affinity {
+                expr: child(expr)?,
+                affinity: *affinity,
+            },
+            Expr::Unary { op, expr } => Expr::Unary {
+                op: *op,
+                expr: child(expr)?,
+            },
+            Expr::Binary { op, left, right } => Expr::Binary {
+                op: *op,
+                left: child(left)?,
+                right: child(right)?,
+            },
+            Expr::Is {
+                left,
+                right,
+                negated,
+            } => Expr::Is {
+                left: child(left)?,
+                right: child(right)?,
+                negated: *negated,
+            },
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => Expr::Like {
+                expr: child(expr)?,
+                pattern: child(pattern)?,
+                negated: *negated,
+            },
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => Expr::InList {
+                expr: child(expr)?,
+                list: list
+                    .iter()
+                    .map(|item| item.transform(f))
+                    .collect::<Result<_, _>>()?,
+                negated: *negated,
+            },
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => Expr::Between {
+                expr: child(expr)?,
+                low: child(low)?,
+                high: child(high)?,
+                negated: *negated,
+            },
+            Expr::Case {
+                operand,
+                branches,
+                default,
+            } => Expr::Case {
+                operand: operand.as_deref().map(&mut child).transpose()?,
+                branches: branches
+                    .iter()
+                    .map(|(when, then)| Ok((*child(when)?, *child(then)?)))
+                    .collect::<Result<_, _>>()?,
+                default: default.as_deref().map(&mut child).transpose()?,
+            },
+            Expr::Function {
+                name,
+                args,
+                distinct,
+            } => Expr::Function {
+                name: name.clone(),
+                args: args
+                    .iter()
+                    .map(|arg| arg.transform(f))
+                    .collect::<Result<_, _>>()?,
+                distinct: *distinct,
+            },
+        })
+    }
+
+    /// Positions of the columns a bound expression reads.
+    pub fn column_indexes(&self) -> Vec<usize> {
+        let mut indexes = Vec::new();
+        let _ = self.transform(&mut |expr| {
+            if let Expr::ColumnIndex(index) = expr {
+                indexes.push(*index);
+            }
+            Ok::<_, ()>(None)
+        });
+        indexes
+    }
+
+    /// Whether the expression reads a parameter, so that its value is not
+    /// known until the statement runs.
+    pub fn has_parameters(&self) -> bool {
+        let mut found = false;
+        let _ = self.transform(&mut |expr| {
+            found |= matches!(expr, Expr::Parameter(_));
+            Ok::<_, ()>(None)
+        });
+        found
+    }
+
+    /// The terms of a chain of ANDs.
+    pub fn conjuncts(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Binary {
+                op: BinaryOp::And,
+                left,
+                right,
+            } => {
+                let mut terms = left.conjuncts();
+                terms.extend(right.conjuncts());
+                terms
+            }
+            _ => vec![self],
+        }
+    }
+
+    /// The first aggregate call in the expression, if any.
+    pub fn find_aggregate(&self) -> Option<&Expr> {
+        match self {
+            Expr::Function { name, .. } if AggregateFunction::from_name(name).is_some() => {
+                Some(self)
+            }
+            Expr::Function { args, .. } => args.iter().find_map(Expr::find_aggregate),
+            Expr::Case {
+                operand,
+                branches,
+                default,
+            } => operand
+                .iter()
+                .chain(default)
+                .map(|expr| &**expr)
+                .chain(branches.iter().flat_map(|(when, then)| [when, then]))
+                .find_map(Expr::find_aggregate),
+            Expr::Literal(_) | Expr::Column(_) | Expr::ColumnIndex(_) | Expr::Parameter(_) => None,
+            Expr::Unary { expr, .. } | Expr::Affinity { expr, .. } => expr.find_aggregate(),
+            Expr::Binary { left, right, .. } | Expr::Is { left, right, .. } => {
+                left.find_aggregate().or_else(|| right.find_aggregate())
+            }
+            Expr::Like { expr, pattern, .. } => {
+                expr.find_aggregate().or_else(|| pattern.find_aggregate())
+            }
+            Expr::InList { expr, list, .. } => expr
+                .find_aggregate()
+                .or_else(|| list.iter().find_map(Expr::find_aggregate)),
+            Expr::Between {
+                expr, low, high, ..
+            } => expr
+                .find_aggregate()
+                .or_else(|| low.find_aggregate())
+                .or_else(|| high.find_aggregate()),
+        }
+    }
+
+    /// Fails with `MisuseOfAggregate` if the expression contains an aggregate.
+    pub fn check_no_aggregate(&self) -> Result<(), BindError> {
+        match self.find_aggregate() {
+            Some(Expr::Function { name, .. }) => Err(BindError::MisuseOfAggregate(name.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Evaluates a bound expression against the values of one row.
+    pub fn evaluate(&self, row: &[Value]) -> Value {
+        match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Column(column) => unreachable!("column {} was not bound", column),
+            Expr::Function { name, args, .. } => match ScalarFunction::from_name(name) {
+                Some(function) => {
+                    let args = args.iter().map(|arg| arg.evaluate(row)).collect::<Vec<_>>();
+                    function.call(&args)
+                }
+                None => unreachable!("aggregate {} was not computed", self),
+            },
+            Expr::ColumnIndex(index) => row[*index].clone(),
+            Expr::Parameter(_) => Value::Null,
+            Expr::Affinity { expr, affinity } => affinity.apply(expr.evaluate(row)),
+            Expr::Unary { op, expr } => {
+                let value = expr.evaluate(row);
+                match op {
+                    UnaryOp::Not => Value::from_truth(not3(value.truth())),
+                    UnaryOp::Plus => value,
+                    UnaryOp::Negate => negate(&value),
+                }
+            }
+            Expr::Binary { op, left, right } => {
+                let left = left.evaluate(row);
+                match op {
+                    // Skip the right side when the left already decides
+                    BinaryOp::And if left.truth() == Some(false) => Value::Integer(0),
+                    BinaryOp::Or if left.truth() == Some(true) => Value::Integer(1),
+                    _ => binary_op(*op, &left, &right.evaluate(row)),
+                }
+            }
+            Expr::Is {
+                left,
+                right,
+                negated,
+            } => {
+                let equal = is_equal(&left.evaluate(row), &right.evaluate(row));
+                Value::from_truth(Some(equal != *negated))
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => {
+                let (value, pattern) = (expr.evaluate(row), pattern.evaluate(row));
+                if value.is_null() || pattern.is_null() {
+                    return Value::Null;
+                }
+                let matched = like(&pattern.to_string(), &value.to_string());
+                Value::from_truth(Some(matched != *negated))
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let value = expr.evaluate(row);
+                let mut result = Some(false);
+                for item in list {
+                    result = or3(result, value.sql_eq(&item.evaluate(row)));
+                    if result == Some(true) {
+                        break;
+                    }
+                }
+                Value::from_truth(if *negated { not3(result) } else { result })
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let value = expr.evaluate(row);
+                let above = value
+                    .sql_cmp(&low.evaluate(row))
+                    .map(|o| o != Ordering::Less);
+                let below = value
+                    .sql_cmp(&high.evaluate(row))
+                    .map(|o| o != Ordering::Greater);
+                let result = and3(above, below);
+                Value::from_truth(if *negated { not3(result) } else { result })
+            }
+            Expr::Case {
+                operand,
+                branches,
+                default,
+            } => {
+                let operand = operand.as_ref().map(|operand| operand.evaluate(row));
+                for (when, then) in branches {
+                    let when = when.evaluate(row);
+                    let matched = match &operand {
+                        Some(operand) => operand.sql_eq(&when),
+                        None => when.truth(),
+                    };
+                    if matched == Some(true) {
+                        return then.evaluate(row);
+                    }
+                }
+                default
+                    .as_ref()
+                    .map_or(Value::Null, |default| default.evaluate(row))
+            }
+        }
+    }
+
+    /// True when the expression evaluates to true, NULL counts as false.
+    pub fn matches(&self, row: &[Value]) -> bool {
+        self.evaluate(row).truth() == Some(true)
+    }
+}
+
+/// Converts a value for use in arithmetic: text that looks like a number is
+/// parsed, other text and blobs count as 0.
+pub fn to_numeric(value: &Value) -> Value {
+    match value {
+        Value::Null | Value::Integer(_) | Value::Real(_) => value.clone(),
+        Value::Boolean(b) => Value::Integer(*b as i64),
+        Value::Text(s) => match Affinity::Numeric.apply(Value::Text(s.clone())) {
+            Value::Text(_) => Value::Integer(0),
+            numeric => numeric,
+        },
+        Value::Blob(_) => Value::Integer(0),
+    }
+}
+
+fn as_f64(value: &Value) -> f64 {
+    match value {
+        Value::Integer(i) => *i as f64,
+        Value::Real(r) => *r,
+        _ => 0.0,
+    }
+}
+
+/// `-value`, where integers that overflow become reals.
+pub fn negate(value: &Value) -> Value {
+    match to_numeric(value) {
+        Value::Integer(i) => match i.checked_neg() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(-(i as f64)),
+        },
+        Value::Real(r) => Value::Real(-r),
+        other => other,
+    }
+}
+
+/// `left IS right`, equality where NULL equals NULL.
+pub fn is_equal(left: &Value, right: &Value) -> bool {
+    match (left.is_null(), right.is_null()) {
+        (true, true) => true,
+        (false, false) => left.sql_eq(right) == Some(true),
+        _ => false,
+    }
+}
+
+pub fn binary_op(op: BinaryOp, left: &Value, right: &Value) -> Value {
+    match op {
+        BinaryOp::And => Value::from_truth(and3(left.truth(), right.truth())),
+        BinaryOp::Or => Value::from_truth(or3(left.truth(), right.truth())),
+        BinaryOp::Equal => Value::from_truth(left.sql_eq(right)),
+        BinaryOp::NotEqual => Value::from_truth(not3(left.sql_eq(right))),
+        BinaryOp::Less => compare(left, right, |o| o == Ordering::Less),
+        BinaryOp::LessEqual => compare(left, right, |o| o != Ordering::Greater),
+        BinaryOp::Greater => compare(left, right, |o| o == Ordering::Greater),
+        BinaryOp::GreaterEqual => compare(left, right, |o| o != Ordering::Less),
+        BinaryOp::Concat => {
+            if left.is_null() || right.is_null() {
+                Value::Null
+            } else {
+                Value::Text(format!("{}{}", left, right))
+            }
+        }
+        BinaryOp::Add
+        | BinaryOp::Subtract
+        | BinaryOp::Multiply
+        | BinaryOp::Divide
+        | BinaryOp::Remainder => arithmetic(op, &to_numeric(left), &to_numeric(right)),
+    }
+}
+
+/// The value bound to parameter `n`, NULL if there is none.
+pub fn parameter(parameters: &[Value], n: usize) -> Value {
+    n.checked_sub(1)
+        .and_then(|i| parameters.get(i))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+fn compare(left: &Value, right: &Value, test: impl Fn(Ordering) -> bool) -> Value {
+    Value::from_truth(left.sql_cmp(right).map(test))
+}
+
+/// Integer arithmetic falls back to reals on overflow. Division or remainder
+/// by zero is NULL.
+fn arithmetic(op: BinaryOp, left: &Value, right: &Value) -> Value {
+    match (left, right) {
+        (Value::Null, _) | (_, Value::Null) => Value::Null,
+        (Value::Integer(a), Value::Integer(b)) => {
+            let result = match op {
+                BinaryOp::Add => a.checked_add(*b),
+                BinaryOp::Subtract => a.checked_sub(*b),
+                BinaryOp::Multiply => a.checked_mul(*b),
+                BinaryOp::Divide if *b == 0 => return Value::Null,
+                BinaryOp::Divide => a.checked_div(*b),
+                BinaryOp::Remainder if *b == 0 => return Value::Null,
+                _ => a.checked_rem(*b),
+            };
+            match result {
+                Some(i) => Value::Integer(i),
+                None => arithmetic(op, &Value::Real(*a as f64), &Value::Real(*b as f64)),
+            }
+        }
+        _ => {
+            let (a, b) = (as_f64(left), as_f64(right));
+            match op {
+                BinaryOp::Add => Value::Real(a + b),
+                BinaryOp::Subtract => Value::Real(a - b),
+                BinaryOp::Multiply => Value::Real(a * b),
+                _ if b == 0.0 => Value::Null,
+                BinaryOp::Divide => Value::Real(a / b),
+                _ => Value::Real(a % b),
+            }
+        }
+    }
+}
+
+/// SQL LIKE: `%` matches any run of characters, `_` exactly one, and ASCII
+/// letters match regardless of case.
+pub fn like(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    // matched[j] is true when the pattern so far matches text[..j]
+    let mut matched = vec![false; text.len() + 1];
+    matched[0] = true;
+    for p in pattern {
+        let mut next = vec![false; text.len() + 1];
+        match p {
+            '%' => {
+                let mut any = false;
+                for j in 0..=text.len() {
+                    any |= matched[j];
+                    next[j] = any;
+                }
+            }
+            _ => {
+                for j in 0..text.len() {
+                    next[j + 1] = matched[j] && (p == '_' || p.eq_ignore_ascii_case(&text[j]));
+                }
+            }
+        }
+        matched = next;
+    }
+    matched[text.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str) -> Box<Expr> {
+        Box::new(Expr::Column(ColumnRef {
+            table: None,
+            name: name.to_string(),
+        }))
+    }
+
+    fn literal(value: Value) -> Box<Expr> {
+        Box::new(Expr::Literal(value))
+    }
+
+    #[test]
+    fn test_bind_resolves_columns() {
+        let scope = Scope::new("t", ["a".to_string(), "b".to_string()]);
+        let expr = Expr::Binary {
+            op: BinaryOp::Add,
+            left: column("B"),
+            right: literal(Value::Integer(1)),
+        };
+        let bound = expr.bind(&scope).unwrap();
+        assert_eq!(
+            bound.evaluate(&[Value::Integer(0), Value::Integer(41)]),
+            Value::Integer(42)
+        );
+        assert_eq!(
+            Expr::Column(ColumnRef {
+                table: Some("u".to_string()),
+                name: "a".to_string()
+            })
+            .bind(&scope),
+            Err(BindError::ColumnNotFound("u.a".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_bind_applies_comparison_affinity() {
+        let mut scope = Scope::new("t", ["id".to_string(), "name".to_string()]);
+        scope.affinities = vec![Affinity::Integer, Affinity::Text];
+        let compare = |left: Box<Expr>, right: Box<Expr>| {
+            let expr = Expr::Binary {
+                op: BinaryOp::Equal,
+                left,
+                right,
+            };
+            match expr.bind(&scope).unwrap() {
+                Expr::Binary { left, right, .. } => (*left, *right),
+                bound => panic!("unexpected {:?}", bound),
+            }
+        };
+
+        // Literals are converted when bound, anything else when evaluated
+        let text = || literal(Value::Text("21".to_string()));
+        assert_eq!(
+            compare(text(), column("id")),
+            (Expr::Literal(Value::Integer(21)), Expr::ColumnIndex(0))
+        );
+        assert_eq!(
+            compare(column("name"), literal(Value::Integer(21))).1,
+            Expr::Literal(Value::Text("21".to_string()))
+        );
+        let (_, parameter) = compare(column("id"), Box::new(Expr::Parameter(1)));
+        assert!(matches!(
+            parameter,
+            Expr::Affinity {
+                affinity: Affinity::Integer,
+                ..
+            }
+        ));
+        assert_eq!(parameter.to_string(), "?1");
+
+        // A text column compared with a numeric one is read as a number
+        let (name, _) = compare(column("name"), column("id"));
+        let row = [Value::Integer(21), Value::Text("21".to_string())];
+        assert_eq!(name.evaluate(&row), Value::Integer(21));
+        assert_eq!(compare(text(), text()).0, *text(), "no column, no affinity");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let eval = |op, a, b| binary_op(op, &a, &b);
+        assert_eq!(
+            eval(BinaryOp::Divide, Value::Integer(7), Value::Integer(2)),
+            Value::Integer(3)
+        );
+        assert_eq!(
+            eval(BinaryOp::Divide, Value::Integer(7), Value::Integer(0)),
+            Value::Null
+        );
+        assert_eq!(
+            eval(BinaryOp::Add, Value::Integer(i64::MAX), Value::Integer(1)),
+            Value::Real(i64::MAX as f64 + 1.0)
+        );
+        assert_eq!(
+            eval(
+                BinaryOp::Multiply,
+                Value::Text("2.5".to_string()),
+                Value::Integer(2)
+            ),
+            Value::Real(5.0)
+        );
+        assert_eq!(
+            eval(BinaryOp::Subtract, Value::Null, Value::Integer(2)),
+            Value::Null
+        );
+        assert_eq!(
+            eval(
+                BinaryOp::Concat,
+                Value::Text("a".to_string()),
+                Value::Integer(1)
+            ),
+            Value::Text("a1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_null_comparisons_use_three_valued_logic() {
+        let row = [Value::Null, Value::Integer(1)];
+        let scope = Scope::new("t", ["a".to_string(), "b".to_string()]);
+        let eval = |expr: Expr| expr.bind(&scope).unwrap().evaluate(&row);
+
+        let equal = Expr::Binary {
+            op: BinaryOp::Equal,
+            left: column("a"),
+            right: literal(Value::Null),
+        };
+        assert_eq!(eval(equal.clone()), Value::Null);
+        assert_eq!(
+            eval(Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(equal.clone())
+            }),
+            Value::Null
+        );
+        assert_eq!(
+            eval(Expr::Binary {
+                op: BinaryOp::Or,
+                left: Box::new(equal.clone()),
+                right: column("b")
+            }),
+            Value::Integer(1)
+        );
+        assert_eq!(
+            eval(Expr::Is {
+                left: column("a"),
+                right: literal(Value::Null),
+                negated: false
+            }),
+            Value::Integer(1)
+        );
+        assert_eq!(
+            eval(Expr::Is {
+                left: column("b"),
+                right: literal(Value::Null),
+                negated: true
+            }),
+            Value::Integer(1)
+        );
+        // 1 NOT IN (2, NULL) is unknown, 1 IN (1, NULL) is true
+        assert_eq!(
+            eval(Expr::InList {
+                expr: column("b"),
+                list: vec![Expr::Literal(Value::Integer(2)), Expr::Literal(Value::Null)],
+                negated: true
+            }),
+            Value::Null
+        );
+        assert_eq!(
+            eval(Expr::InList {
+                expr: column("b"),
+                list: vec![Expr::Literal(Value::Integer(1)), Expr::Literal(Value::Null)],
+                negated: false
+            }),
+            Value::Integer(1)
+        );
+        assert!(!equal.bind(&scope).unwrap().matches(&row));
+    }
+
+    #[test]
+    fn test_display_adds_needed_parentheses() {
+        let sum = Expr::Binary {
+            op: BinaryOp::Add,
+            left: column("a"),
+            right: literal(Value::Integer(1)),
+        };
+        let product = Expr::Binary {
+            op: BinaryOp::Multiply,
+            left: Box::new(sum.clone()),
+            right: literal(Value::Text("it's".to_string())),
+        };
+        assert_eq!(product.to_string(), "(a + 1) * 'it''s'");
+        let difference = Expr::Binary {
+            op: BinaryOp::Subtract,
+            left: column("b"),
+            right: Box::new(sum),
+        };
+        assert_eq!(difference.to_string(), "b - (a + 1)");
+        let negated = Expr::Unary {
+            op: UnaryOp::Negate,
+            expr: Box::new(difference),
+        };
+        assert_eq!(negated.to_string(), "-(b - (a + 1))");
+    }
+
+    #[test]
+    fn test_like() {
+        assert!(like("a%", "Alice"));
+        assert!(like("%@example.com", "bob@EXAMPLE.com"));
+        assert!(like("b_b", "bob"));
+        assert!(!like("b_b", "boob"));
+        assert!(like("%", ""));
+        assert!(!like("_", ""));
+    }
+}
@@ -1,4 +1,33 @@
-pub mod cursor;
+// Lets the code `#[derive(Table)]` writes name this crate from inside it too
+extern crate self as rsqlite3;
+
+pub mod aggregate;
+pub mod btree;
+pub mod cache;
+pub mod compile;
+pub mod database;
+pub mod error;
+pub mod expr;
+pub mod lock;
+pub mod model;
 pub mod pager;
+pub mod parser;
+pub mod planner;
 pub mod repl;
+pub mod rows;
+pub mod schema;
+#[cfg(feature = "serde")]
+pub mod serde;
+pub mod sorter;
+pub mod statement;
+pub mod stats;
 pub mod table;
+pub mod tokenizer;
+pub mod value;
+pub mod vm;
+pub mod wal;
+
+pub use error::Error;
+
+#[cfg(feature = "derive")]
+pub use rsqlite3_derive::Table;
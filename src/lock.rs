@@ -0,0 +1,197 @@
+//! Advisory locks that let several connections share a database file, in
+//! the states SQLite uses. Any number of connections can read under SHARED
+//! locks. A connection about to write takes RESERVED, which only one can
+//! hold while the others keep reading, then PENDING, which keeps new readers
+//! out, and EXCLUSIVE once the last reader is gone.
+//!
+//! Each state is a fcntl lock on bytes far past the data of any real
+//! database. On Linux they are open file description locks, so two
+//! connections of one process exclude each other as well. Besides them,
+//! every connection holds a read lock on one more byte for as long as it
+//! has the file open, so that a connection closing can tell whether it is
+//! the last one. In WAL mode readers also hold a read lock on the byte of
+//! the read mark they use, see `wal`.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+const PENDING_BYTE: i64 = 0x4000_0000;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+/// Readers lock the shared range for reading, a writer all of it for
+/// writing.
+const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+const SHARED_SIZE: i64 = 510;
+const OPEN_BYTE: i64 = SHARED_FIRST + SHARED_SIZE;
+const READ_MARK_FIRST: i64 = OPEN_BYTE + 1;
+/// The number of read marks, each locked through one byte.
+pub const READ_MARKS: usize = 8;
+
+#[cfg(target_os = "linux")]
+const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+#[cfg(not(target_os = "linux"))]
+const SET_LOCK: libc::c_int = libc::F_SETLK;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    Unlocked,
+    Shared,
+    Reserved,
+    Pending,
+    Exclusive,
+}
+
+/// The error for a lock another connection is in the way of.
+pub fn busy_error() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "database is locked")
+}
+
+/// Sets a lock of `kind` on `len` bytes from `start`, returning false if
+/// another connection holds a conflicting one.
+fn set(file: &File, kind: libc::c_int, start: i64, len: i64) -> io::Result<bool> {
+    // SAFETY: flock is plain data, for which all zeroes is valid
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = kind as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start as libc::off_t;
+    lock.l_len = len as libc::off_t;
+    // SAFETY: the descriptor stays open while `file` is borrowed
+    if unsafe { libc::fcntl(file.as_raw_fd(), SET_LOCK, &lock) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EAGAIN | libc::EACCES) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Takes the state right above the one held. Returns false, holding what
+/// it did before, if another connection is in the way.
+pub fn raise(file: &File, level: LockLevel) -> io::Result<bool> {
+    match level {
+        LockLevel::Unlocked => Ok(true),
+        LockLevel::Shared => {
+            // A pending writer keeps new readers out
+            if !set(file, libc::F_RDLCK, PENDING_BYTE, 1)? {
+                return Ok(false);
+            }
+            let locked = set(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE);
+            set(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+            locked
+        }
+        LockLevel::Reserved => set(file, libc::F_WRLCK, RESERVED_BYTE, 1),
+        LockLevel::Pending => set(file, libc::F_WRLCK, PENDING_BYTE, 1),
+        LockLevel::Exclusive => set(file, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE),
+    }
+}
+
+/// Gives up every state above `level`.
+pub fn lower(file: &File, level: LockLevel) -> io::Result<()> {
+    match level {
+        LockLevel::Unlocked => {
+            let len = SHARED_FIRST + SHARED_SIZE - PENDING_BYTE;
+            set(file, libc::F_UNLCK, PENDING_BYTE, len)?;
+        }
+        LockLevel::Shared => {
+            set(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+            set(file, libc::F_UNLCK, PENDING_BYTE, 2)?;
+        }
+        LockLevel::Reserved => {
+            set(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+            set(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+        }
+        LockLevel::Pending => {
+            set(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+        }
+        LockLevel::Exclusive => {}
+    }
+    Ok(())
+}
+
+/// Marks the file as open by this connection. Returns false if a
+/// connection closing is just then checking for others.
+pub fn hold_open(file: &File) -> io::Result<bool> {
+    set(file, libc::F_RDLCK, OPEN_BYTE, 1)
+}
+
+/// Locks read mark `slot`, for `write` to change it or else to read from
+/// the log up to it. Returns false if another connection holds a
+/// conflicting lock.
+pub fn lock_read_mark(file: &File, slot: usize, write: bool) -> io::Result<bool> {
+    let kind = if write { libc::F_WRLCK } else { libc::F_RDLCK };
+    set(file, kind, READ_MARK_FIRST + slot as i64, 1)
+}
+
+pub fn unlock_read_mark(file: &File, slot: usize) -> io::Result<()> {
+    set(file, libc::F_UNLCK, READ_MARK_FIRST + slot as i64, 1)?;
+    Ok(())
+}
+
+/// Whether any other connection has the file open.
+pub fn others_open(file: &File) -> io::Result<bool> {
+    if !set(file, libc::F_WRLCK, OPEN_BYTE, 1)? {
+        return Ok(true);
+    }
+    set(file, libc::F_RDLCK, OPEN_BYTE, 1)?;
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    // Elsewhere locks of one process never conflict
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lock_states() {
+        let path = temp_db_path("lock_states");
+        let open = || {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+        let (a, b) = (open(), open());
+        assert!(raise(&a, LockLevel::Shared).unwrap());
+        assert!(raise(&b, LockLevel::Shared).unwrap());
+
+        // Only one reader can reserve, and neither can write while the
+        // other reads
+        assert!(raise(&a, LockLevel::Reserved).unwrap());
+        assert!(!raise(&b, LockLevel::Reserved).unwrap());
+        assert!(raise(&a, LockLevel::Pending).unwrap());
+        assert!(!raise(&a, LockLevel::Exclusive).unwrap());
+
+        // A pending writer lets the last reader go but no new one in
+        lower(&b, LockLevel::Unlocked).unwrap();
+        assert!(!raise(&b, LockLevel::Shared).unwrap());
+        assert!(raise(&a, LockLevel::Exclusive).unwrap());
+
+        lower(&a, LockLevel::Shared).unwrap();
+        assert!(raise(&b, LockLevel::Shared).unwrap());
+        assert!(raise(&b, LockLevel::Reserved).unwrap());
+        lower(&b, LockLevel::Unlocked).unwrap();
+        assert!(raise(&a, LockLevel::Reserved).unwrap());
+
+        // Unlocked connections still count as open
+        assert!(hold_open(&a).unwrap());
+        assert!(hold_open(&b).unwrap());
+        assert!(others_open(&a).unwrap());
+
+        // Readers share a read mark, which keeps it from being changed
+        assert!(lock_read_mark(&a, 0, false).unwrap());
+        assert!(lock_read_mark(&b, 0, false).unwrap());
+        assert!(!lock_read_mark(&b, 0, true).unwrap());
+        assert!(lock_read_mark(&b, 1, true).unwrap());
+        unlock_read_mark(&a, 0).unwrap();
+        assert!(lock_read_mark(&b, 0, true).unwrap());
+
+        drop(b);
+        assert!(!others_open(&a).unwrap());
+    }
+}
@@ -0,0 +1,285 @@
+//! Structs mapped to tables, one field per column, as `#[derive(Table)]`
+//! declares them. The derive writes the column list and the conversions to
+//! and from rows; the SQL to create, fill and read the table is built from
+//! those here. `TableModel::open` creates the table, or checks that the one
+//! in the catalog still has the columns the struct expects.
+
+use crate::error::Error;
+use crate::rows::{Result, ResultRow};
+use crate::statement::PreparedStatement;
+use crate::table::Table;
+use crate::value::{Affinity, Value};
+
+/// A type read from a result row.
+pub trait FromRow: Sized {
+    /// Reads the value from a row with a column for each of its fields.
+    fn from_row(row: &ResultRow) -> Result<Self>;
+}
+
+/// A column of a mapped table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColumnInfo {
+    pub name: &'static str,
+    pub type_name: &'static str,
+    pub primary_key: bool,
+    pub unique: bool,
+}
+
+/// A struct stored as the rows of a table.
+pub trait TableModel: FromRow {
+    const NAME: &'static str;
+    const COLUMNS: &'static [ColumnInfo];
+
+    /// The values of the fields, in the order of `COLUMNS`.
+    fn values(&self) -> Vec<Value>;
+
+    fn create_table_sql() -> String {
+        let columns = Self::COLUMNS
+            .iter()
+            .map(|column| {
+                let mut def = format!("{} {}", quote(column.name), column.type_name);
+                if column.primary_key {
+                    def.push_str(" primary key");
+                }
+                if column.unique {
+                    def.push_str(" unique");
+                }
+                def
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "create table {} ({})",
+            quote(Self::NAME),
+            columns.join(", ")
+        )
+    }
+
+    /// Creates the table if the catalog does not have it yet, and otherwise
+    /// checks that it has a column of the same affinity for every field,
+    /// with the same primary key, and that the fields marked unique are.
+    fn open(table: &mut Table) -> Result<()> {
+        let Some(schema) = table.catalog.find_table(Self::NAME) else {
+            let sql = Self::create_table_sql();
+            return PreparedStatement::prepare(&sql, table)?.execute(table);
+        };
+        for column in Self::COLUMNS {
+            let mismatch = |problem: &str| {
+                Error::SchemaMismatch(format!(
+                    "column {} of {} {}",
+                    column.name,
+                    Self::NAME,
+                    problem
+                ))
+            };
+            let Some(index) = schema.column_index(column.name) else {
+                return Err(mismatch("does not exist"));
+            };
+            let existing = &schema.columns[index];
+            if existing.affinity != Affinity::from_type_name(column.type_name) {
+                return Err(mismatch(&format!(
+                    "is {}, not {}",
+                    existing.type_name, column.type_name
+                )));
+            }
+            if existing.primary_key != column.primary_key {
+                return Err(mismatch(match column.primary_key {
+                    true => "is not the primary key",
+                    false => "is the primary key",
+                }));
+            }
+            let unique = table
+                .catalog
+                .table_indexes(schema.root_page)
+                .iter()
+                .any(|constraint| constraint.unique && constraint.columns == [index]);
+            if column.unique && !unique {
+                return Err(mismatch("is not unique"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts the struct as a new row, through an insert the connection
+    /// keeps prepared.
+    fn insert(&self, table: &mut Table) -> Result<()> {
+        let columns = Self::COLUMNS
+            .iter()
+            .map(|column| quote(column.name))
+            .collect::<Vec<_>>();
+        let parameters = vec!["?"; columns.len()];
+        let sql = format!(
+            "insert into {} ({}) values ({})",
+            quote(Self::NAME),
+            columns.join(", "),
+            parameters.join(", ")
+        );
+        PreparedStatement::cached(&sql, table, |statement, table| {
+            for (n, value) in self.values().into_iter().enumerate() {
+                statement.bind(n + 1, value)?;
+            }
+            statement.execute(table)
+        })
+    }
+
+    /// The rows for which `filter` holds, with `parameters` bound to its
+    /// `?`s in order. An empty filter reads every row.
+    ///
+    /// `filter` is raw SQL, pasted into the query after `WHERE`, and may go
+    /// on with ORDER BY or LIMIT. It is not checked against the struct's
+    /// fields, so values must go in as parameters and never be formatted
+    /// into the text.
+    fn select(table: &mut Table, filter: &str, parameters: &[Value]) -> Result<Vec<Self>> {
+        let columns = Self::COLUMNS
+            .iter()
+            .map(|column| quote(column.name))
+            .collect::<Vec<_>>();
+        let mut sql = format!("select {} from {}", columns.join(", "), quote(Self::NAME));
+        if !filter.is_empty() {
+            sql = format!("{} where {}", sql, filter);
+        }
+        let mut statement = PreparedStatement::prepare(&sql, table)?;
+        for (n, value) in parameters.iter().enumerate() {
+            statement.bind(n + 1, value.clone())?;
+        }
+        let rows = statement.query(table)?;
+        rows.map(|row| Self::from_row(&row?)).collect()
+    }
+
+    fn all(table: &mut Table) -> Result<Vec<Self>> {
+        Self::select(table, "", &[])
+    }
+
+    /// The row whose primary key is `key`. Fails if no column is the
+    /// primary key, which the derive does not allow.
+    fn find(table: &mut Table, key: impl Into<Value>) -> Result<Option<Self>> {
+        let Some(primary_key) = Self::COLUMNS.iter().find(|column| column.primary_key) else {
+            let message = format!("{} has no primary key", Self::NAME);
+            return Err(Error::SchemaMismatch(message));
+        };
+        let filter = format!("{} = ?", quote(primary_key.name));
+        Ok(Self::select(table, &filter, &[key.into()])?.pop())
+    }
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    #[derive(Debug, PartialEq, crate::Table)]
+    #[table(name = "products")]
+    struct Product {
+        #[column(primary_key)]
+        id: i64,
+        #[column(unique)]
+        name: String,
+        price: Option<f64>,
+        in_stock: bool,
+        #[column(name = "qty")]
+        quantity: u32,
+        image: Vec<u8>,
+    }
+
+    #[derive(Debug, PartialEq, crate::Table)]
+    struct OrderLine {
+        #[column(primary_key, type_name = "text")]
+        id: i64,
+    }
+
+    #[derive(Debug, PartialEq, crate::Table)]
+    struct Tag {
+        #[column(primary_key)]
+        id: i64,
+        #[column(unique)]
+        label: String,
+    }
+
+    fn product(id: i64, price: Option<f64>) -> Product {
+        Product {
+            id,
+            name: format!("product{}", id),
+            price,
+            in_stock: id % 2 == 1,
+            quantity: id as u32 * 3,
+            image: vec![id as u8],
+        }
+    }
+
+    #[test]
+    fn test_derived_table() {
+        assert_eq!(
+            Product::create_table_sql(),
+            "create table \"products\" (\"id\" integer primary key, \"name\" text unique, \
+             \"price\" real, \"in_stock\" boolean, \"qty\" integer, \"image\" blob)"
+        );
+        assert_eq!(OrderLine::NAME, "order_line");
+        assert_eq!(OrderLine::COLUMNS[0].type_name, "text");
+
+        let path = temp_db_path("model_derived");
+        let mut table = Table::db_open(&path);
+        Product::open(&mut table).unwrap();
+        for id in 1..=3 {
+            product(id, Some(id as f64 / 2.0))
+                .insert(&mut table)
+                .unwrap();
+        }
+        product(4, None).insert(&mut table).unwrap();
+        // Every insert ran the one statement the connection keeps
+        assert_eq!(table.statements.len(), 1);
+        assert!(matches!(
+            product(1, None).insert(&mut table),
+            Err(Error::UniqueViolation(_))
+        ));
+
+        assert_eq!(
+            Product::all(&mut table).unwrap(),
+            (1..=4)
+                .map(|id| product(id, (id < 4).then_some(id as f64 / 2.0)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Product::select(
+                &mut table,
+                "price > ? order by id desc",
+                &[Value::Real(0.6)]
+            )
+            .unwrap(),
+            [product(3, Some(1.5)), product(2, Some(1.0))]
+        );
+        assert_eq!(
+            Product::find(&mut table, 4).unwrap(),
+            Some(product(4, None))
+        );
+        assert_eq!(Product::find(&mut table, 5).unwrap(), None);
+
+        // Opening again checks the table in the catalog
+        table.db_close();
+        let mut table = Table::db_open(&path);
+        Product::open(&mut table).unwrap();
+        assert_eq!(Product::all(&mut table).unwrap().len(), 4);
+        PreparedStatement::prepare(
+            "create table order_line (id integer primary key)",
+            &mut table,
+        )
+        .and_then(|mut statement| statement.execute(&mut table))
+        .unwrap();
+        assert!(matches!(
+            OrderLine::open(&mut table),
+            Err(Error::SchemaMismatch(message)) if message == "column id of order_line is integer, not text"
+        ));
+        PreparedStatement::prepare(
+            "create table tag (id integer primary key, label text)",
+            &mut table,
+        )
+        .and_then(|mut statement| statement.execute(&mut table))
+        .unwrap();
+        assert!(matches!(
+            Tag::open(&mut table),
+            Err(Error::SchemaMismatch(message)) if message == "column label of tag is not unique"
+        ));
+    }
+}
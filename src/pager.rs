@@ -1,122 +1,1119 @@
-use crate::table::{PAGE_SIZE, ROW_SIZE, TABLE_MAX_PAGES};
-use log::info;
+use crate::cache::PageCache;
+use crate::lock::{self, LockLevel};
+use crate::table::PAGE_SIZE;
+use crate::wal::{ReadMark, Wal};
+use log::{error, info};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fs::File, os::unix::fs::FileExt};
+
+// Database header, stored at the start of page 0
+const MAGIC: &[u8; 16] = b"rsqlite3 format\0";
+const MAGIC_OFFSET: usize = 0;
+const NUM_PAGES_OFFSET: usize = MAGIC_OFFSET + MAGIC.len();
+const FREELIST_HEAD_OFFSET: usize = NUM_PAGES_OFFSET + 4;
+const FREELIST_COUNT_OFFSET: usize = FREELIST_HEAD_OFFSET + 4;
+// Bumped by every write, so that other connections know to drop their cache
+const CHANGE_COUNTER_OFFSET: usize = FREELIST_COUNT_OFFSET + 4;
+// 1 if commits go to the write-ahead log
+const WAL_MODE_OFFSET: usize = CHANGE_COUNTER_OFFSET + 4;
+// The layout of the pages, 0 in files whose tables are not B-trees yet
+const FORMAT_VERSION_OFFSET: usize = WAL_MODE_OFFSET + 4;
+// Bumped by every schema change, so that prepared statements compile again
+const SCHEMA_COOKIE_OFFSET: usize = FORMAT_VERSION_OFFSET + 4;
+const HEADER_SIZE: usize = SCHEMA_COOKIE_OFFSET + 4;
+
+/// Tables are B-trees keyed by rowid.
+const FORMAT_VERSION: u32 = 1;
+
+/// A commit that leaves the log at least this many frames long tries to
+/// checkpoint it.
+const CHECKPOINT_FRAMES: u32 = 1000;
+
+/// How many pages a connection keeps in its own cache, not counting the
+/// ones it has changed.
+pub const DEFAULT_CONNECTION_PAGES: usize = 2000;
+
+/// The longest wait between two attempts at a busy lock.
+const MAX_BUSY_DELAY: Duration = Duration::from_millis(100);
+
+// Overflow pages start with the number of the next page in the chain (0 ends it)
+const OVERFLOW_NEXT_OFFSET: usize = 0;
+const OVERFLOW_DATA_OFFSET: usize = OVERFLOW_NEXT_OFFSET + 4;
+pub const OVERFLOW_PAGE_CAPACITY: usize = PAGE_SIZE - OVERFLOW_DATA_OFFSET;
+
+// A commit first saves the pages it overwrites in a journal next to the
+// file: a header of the magic, the file length to truncate back to and the
+// number of pages, then each page number followed by the page.
+const JOURNAL_MAGIC: &[u8; 16] = b"rsqlite3 journal";
+const JOURNAL_HEADER_SIZE: usize = JOURNAL_MAGIC.len() + 8 + 4;
+const JOURNAL_RECORD_SIZE: usize = 4 + PAGE_SIZE;
+
+/// The file as it was at some point: the header fields, and the pages
+/// changed since then as they were. Pages allocated since are not kept, as
+/// rolling back drops them.
+struct Snapshot {
+    pages: BTreeMap<u32, Vec<u8>>,
+    num_pages: u32,
+    freelist_head: u32,
+    freelist_count: u32,
+}
+
+impl Snapshot {
+    fn record(&mut self, page_num: u32, page: &[u8]) {
+        if page_num < self.num_pages {
+            self.pages.entry(page_num).or_insert_with(|| page.to_vec());
+        }
+    }
+}
+
+struct Transaction {
+    /// The file as the transaction found it.
+    start: Snapshot,
+    /// Open savepoints, outermost first.
+    savepoints: Vec<(String, Snapshot)>,
+    /// Whether the outermost savepoint started the transaction, so that
+    /// releasing it commits.
+    savepoint_began: bool,
+    /// The file as the running statement found it, so that a statement
+    /// that fails can be undone on its own.
+    statement: Option<Snapshot>,
+}
+
+/// How commits keep the file safe from a crash halfway through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalMode {
+    /// The pages a commit overwrites are saved in a rollback journal first,
+    /// and readers keep writers from committing.
+    Delete,
+    /// Commits are appended to a write-ahead log, and readers go on seeing
+    /// the commits that were there when they started.
+    Wal,
+}
+
+impl JournalMode {
+    pub fn parse(name: &str) -> Option<JournalMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "delete" => Some(JournalMode::Delete),
+            "wal" => Some(JournalMode::Wal),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Wal => "wal",
+        }
+    }
+}
+
 pub struct Pager {
     pub file_descriptor: File,
     pub file_length: u64,
-    pub num_pages: u64,
-    pub pages: Vec<Vec<u8>>,
+    pub num_pages: u32,
+    pub pages: Vec<Option<Vec<u8>>>,
+    pub dirty: BTreeSet<u32>,
+    /// The pages in the order they were read, oldest first, so that clean
+    /// ones can be dropped once there are more than `cache_pages`.
+    loaded: VecDeque<u32>,
+    pub cache_pages: usize,
+    pub freelist_head: u32,
+    pub freelist_count: u32,
+    /// How many pages the connection has freed, so that B-tree cursors can
+    /// tell that a page they were about to move to may be gone.
+    pub pages_freed: u64,
+    journal_path: String,
+    wal_path: String,
+    /// The write-ahead log, in WAL mode.
+    wal: Option<Wal>,
+    /// How many frames of the log this connection sees, those committed
+    /// when its transaction started.
+    read_mark: u32,
+    /// The read mark the transaction holds in WAL mode.
+    read_lock: Option<ReadMark>,
+    /// The mode the file is left in by the next commit.
+    journal_mode: JournalMode,
+    transaction: Option<Transaction>,
+    /// The change counter of the file the cache was read from, `None` if
+    /// the file could have changed since without it.
+    change_counter: Option<u32>,
+    lock: LockLevel,
+    /// How long to keep trying for a lock another connection holds.
+    pub busy_timeout: Duration,
+    /// Committed pages shared with the other connections of a `Database`.
+    shared_cache: Option<Arc<PageCache>>,
+}
+
+pub fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn write_u32(page: &mut [u8], offset: usize, value: u32) {
+    page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
 }
 
 impl Pager {
     pub fn pager_open(filename: &str) -> Self {
+        Pager::open(filename, None).unwrap_or_else(|e| panic!("Error opening {}: {}", filename, e))
+    }
+
+    /// Opens the file, sharing committed pages through `cache` with the
+    /// other connections that use it. `None` for a connection of its own.
+    /// Fails if the file is not a database this version can read.
+    pub fn open(filename: &str, shared_cache: Option<Arc<PageCache>>) -> io::Result<Self> {
         let file = File::options()
             .read(true)
             .write(true)
             .create(true)
-            .open(filename)
-            .unwrap();
-        let file_length = file.metadata().unwrap().len();
-        let num_pages = (file_length + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+            .truncate(false)
+            .open(filename)?;
+        let deadline = Instant::now() + MAX_BUSY_DELAY;
+        let mut delay = Duration::from_millis(1);
+        while !lock::hold_open(&file)? {
+            if !backoff(deadline, &mut delay) {
+                return Err(lock::busy_error());
+            }
+        }
+        let file_length = file.metadata()?.len();
 
-        // Initialize pager with empty pages
         let mut pager = Self {
             file_descriptor: file,
             file_length,
-            num_pages,
-            pages: vec![vec![0; PAGE_SIZE]; TABLE_MAX_PAGES],
+            num_pages: 0,
+            pages: Vec::new(),
+            dirty: BTreeSet::new(),
+            loaded: VecDeque::new(),
+            cache_pages: DEFAULT_CONNECTION_PAGES,
+            freelist_head: 0,
+            freelist_count: 0,
+            pages_freed: 0,
+            journal_path: format!("{}-journal", filename),
+            wal_path: format!("{}-wal", filename),
+            wal: None,
+            read_mark: 0,
+            read_lock: None,
+            journal_mode: JournalMode::Delete,
+            transaction: None,
+            change_counter: None,
+            lock: LockLevel::Unlocked,
+            busy_timeout: Duration::ZERO,
+            shared_cache,
+        };
+        // Without a lock the header may be read halfway through another
+        // connection's commit, so the first transaction reads it again
+        let result = match pager.lock_shared(Instant::now()) {
+            Ok(()) => {
+                let result = pager.refresh();
+                pager.unlock(LockLevel::Unlocked);
+                result
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let result = pager.refresh();
+                pager.release_read_mark();
+                pager.change_counter = None;
+                result
+            }
+            Err(e) => Err(e),
         };
+        result?;
+        Ok(pager)
+    }
 
-        // Load existing pages from file
-        for page_num in 0..num_pages {
-            let mut buffer = vec![0; PAGE_SIZE];
-            match pager
-                .file_descriptor
-                .read_exact_at(&mut buffer, page_num * PAGE_SIZE as u64)
-            {
-                Ok(_) => {
-                    pager.pages[page_num as usize] = buffer;
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    // If we hit EOF, that's okay - just use what we have
-                    pager.pages[page_num as usize] = buffer;
-                }
-                Err(e) => {
-                    panic!("Error reading page from file: {}", e);
+    pub fn journal_mode(&self) -> JournalMode {
+        self.journal_mode
+    }
+
+    /// Reads the header fields from page 0, setting up a new one if the
+    /// file is empty.
+    fn read_header(&mut self) -> io::Result<()> {
+        self.num_pages = self.file_length.div_ceil(PAGE_SIZE as u64) as u32;
+        if self.num_pages == 0 {
+            // Brand new file, page 0 only holds the header
+            self.num_pages = 1;
+            self.pages.push(Some(vec![0; PAGE_SIZE]));
+            self.dirty.insert(0);
+            self.freelist_head = 0;
+            self.freelist_count = 0;
+            self.change_counter = Some(0);
+            self.journal_mode = JournalMode::Delete;
+        } else {
+            let header = self.fetch_page(0);
+            if &header[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()] != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file is not a database",
+                ));
+            }
+            if read_u32(header, FORMAT_VERSION_OFFSET) != FORMAT_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "database uses an older table layout that this version can not read",
+                ));
+            }
+            let (num_pages, freelist_head, freelist_count, change_counter, wal_mode) = (
+                read_u32(header, NUM_PAGES_OFFSET),
+                read_u32(header, FREELIST_HEAD_OFFSET),
+                read_u32(header, FREELIST_COUNT_OFFSET),
+                read_u32(header, CHANGE_COUNTER_OFFSET),
+                read_u32(header, WAL_MODE_OFFSET),
+            );
+            self.num_pages = num_pages;
+            self.freelist_head = freelist_head;
+            self.freelist_count = freelist_count;
+            self.change_counter = Some(change_counter);
+            self.journal_mode = match wal_mode {
+                0 => JournalMode::Delete,
+                _ => JournalMode::Wal,
+            };
+        }
+        Ok(())
+    }
+
+    /// The change counter in the header in the file, and whether it is in
+    /// WAL mode. In WAL mode the newer header in the log has the counter.
+    fn file_header(&self) -> io::Result<(u32, bool)> {
+        if self.file_descriptor.metadata()?.len() < HEADER_SIZE as u64 {
+            return Ok((0, false));
+        }
+        let mut header = [0; HEADER_SIZE];
+        self.file_descriptor.read_exact_at(&mut header, 0)?;
+        Ok((
+            read_u32(&header, CHANGE_COUNTER_OFFSET),
+            read_u32(&header, WAL_MODE_OFFSET) != 0,
+        ))
+    }
+
+    /// Brings the cache up to date with what other connections committed,
+    /// under a SHARED lock. Returns whether any of it was dropped.
+    fn refresh(&mut self) -> io::Result<bool> {
+        let (change_counter, wal_mode) = self.file_header()?;
+        let replaced = match &self.wal {
+            Some(wal) => wal.replaced(&self.wal_path)?,
+            None => false,
+        };
+        if wal_mode != self.wal.is_some() || replaced {
+            // Another connection switched journal modes, or closed last
+            // and removed the log
+            self.wal = match wal_mode {
+                true => Some(Wal::open(&self.wal_path)?),
+                false => None,
+            };
+            self.change_counter = None;
+        }
+        let mut changed = Vec::new();
+        let mut stale = self.change_counter.is_none();
+        self.release_read_mark();
+        match &mut self.wal {
+            Some(wal) => {
+                // The log may have been read since, by `reserve`
+                let (restarted, read_lock) = wal.begin_read(&self.file_descriptor)?;
+                stale |= restarted;
+                changed = wal.changed_since(self.read_mark);
+                self.read_mark = wal.max_frame;
+                self.read_lock = Some(read_lock);
+            }
+            None => stale |= self.change_counter != Some(change_counter),
+        }
+        if stale {
+            self.pages.clear();
+            self.dirty.clear();
+            self.loaded.clear();
+        } else if !changed.is_empty() {
+            for page_num in &changed {
+                if let Some(page) = self.pages.get_mut(*page_num as usize) {
+                    *page = None;
                 }
+                self.dirty.remove(page_num);
             }
+        } else {
+            return Ok(false);
         }
+        info!("Database changed by another connection, dropping the cache");
+        self.file_length = self.file_descriptor.metadata()?.len();
+        // Page 0 must not be shared as part of the commit before
+        self.change_counter = None;
+        self.read_header()?;
+        Ok(true)
+    }
 
-        pager
+    /// Raises the lock to `level` a state at a time, retrying until
+    /// `deadline` while other connections are in the way. RESERVED is not
+    /// waited for, as whoever holds it is waiting for this connection's
+    /// readers to go.
+    fn lock(&mut self, level: LockLevel, deadline: Instant) -> io::Result<()> {
+        let mut delay = Duration::from_millis(1);
+        while self.lock < level {
+            let next = match self.lock {
+                LockLevel::Unlocked => LockLevel::Shared,
+                LockLevel::Shared => LockLevel::Reserved,
+                LockLevel::Reserved => LockLevel::Pending,
+                LockLevel::Pending | LockLevel::Exclusive => LockLevel::Exclusive,
+            };
+            if lock::raise(&self.file_descriptor, next)? {
+                self.lock = next;
+            } else if next == LockLevel::Reserved || !backoff(deadline, &mut delay) {
+                return Err(lock::busy_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the lock down to `level`.
+    fn unlock(&mut self, level: LockLevel) {
+        if level == LockLevel::Unlocked {
+            self.release_read_mark();
+        }
+        if self.lock <= level {
+            return;
+        }
+        if let Err(e) = lock::lower(&self.file_descriptor, level) {
+            error!("Error unlocking database file: {}", e);
+        }
+        self.lock = level;
     }
 
-    pub fn fetch_page(&mut self, page_num: u64) -> &Vec<u8> {
-        if page_num > self.num_pages {
+    /// Lets go of the read mark, so that checkpoints can go past it.
+    fn release_read_mark(&mut self) {
+        let Some(read_lock) = self.read_lock.take() else {
+            return;
+        };
+        if let Err(e) = lock::unlock_read_mark(&self.file_descriptor, read_lock.slot) {
+            error!("Error unlocking read mark: {}", e);
+        }
+    }
+
+    /// Takes a SHARED lock and rolls back the journal of a commit that did
+    /// not finish. Committing writers hold EXCLUSIVE, so a journal found
+    /// under SHARED is always left over.
+    fn lock_shared(&mut self, deadline: Instant) -> io::Result<()> {
+        self.lock(LockLevel::Shared, deadline)?;
+        if std::path::Path::new(&self.journal_path).exists() {
+            let result = self
+                .lock(LockLevel::Exclusive, deadline)
+                .and_then(|()| self.restore_journal());
+            if let Err(e) = result {
+                self.unlock(LockLevel::Unlocked);
+                return Err(e);
+            }
+            self.unlock(LockLevel::Shared);
+            self.change_counter = None;
+        }
+        Ok(())
+    }
+
+    pub fn fetch_page(&mut self, page_num: u32) -> &Vec<u8> {
+        self.load_page(page_num);
+        self.pages[page_num as usize].as_ref().unwrap()
+    }
+
+    pub fn fetch_page_mut(&mut self, page_num: u32) -> &mut Vec<u8> {
+        self.load_page(page_num);
+        self.dirty.insert(page_num);
+        let page = self.pages[page_num as usize].as_mut().unwrap();
+        if let Some(transaction) = &mut self.transaction {
+            transaction.start.record(page_num, page);
+            if let Some((_, savepoint)) = transaction.savepoints.last_mut() {
+                savepoint.record(page_num, page);
+            }
+            if let Some(statement) = &mut transaction.statement {
+                statement.record(page_num, page);
+            }
+        }
+        page
+    }
+
+    fn load_page(&mut self, page_num: u32) {
+        if page_num >= self.num_pages {
             panic!("Tried to fetch page number out of bounds. {}", page_num);
         }
+        if self.pages.len() <= page_num as usize {
+            self.pages.resize(page_num as usize + 1, None);
+        }
+        if self.pages[page_num as usize].is_some() {
+            return;
+        }
 
-        if self.pages[page_num as usize].iter().all(|&x| x == 0) {
-            // cache miss, read from file
-            info!("Cache miss for page {}", page_num);
-            let mut buffer = vec![0; PAGE_SIZE];
-            let mut num_pages = self.file_length / PAGE_SIZE as u64;
+        // cache miss, read from the shared cache, the log or the file
+        info!("Cache miss for page {}", page_num);
+        self.make_room();
+        self.loaded.push_back(page_num);
+        let mut buffer = vec![0; PAGE_SIZE];
+        let shared = self.shared_cache.as_ref().zip(self.change_counter);
+        if let Some((cache, change_counter)) = shared {
+            if cache.get(page_num, change_counter, &mut buffer) {
+                self.pages[page_num as usize] = Some(buffer);
+                return;
+            }
+        }
+        let offset = page_num as u64 * PAGE_SIZE as u64;
+        // A reader that started with every frame in the file reads only
+        // the file, as the log may start over under it
+        let read_log = self.read_lock.as_ref().is_none_or(|lock| lock.read_log);
+        let frame = self.wal.as_ref().filter(|_| read_log).and_then(|wal| {
+            let frame = wal.find(page_num, self.read_mark)?;
+            Some((wal, frame))
+        });
+        if let Some((wal, frame)) = frame {
+            if let Err(e) = wal.read_frame(frame, &mut buffer) {
+                panic!("Error reading page from the log: {}", e);
+            }
+        } else if offset < self.file_length {
+            if let Err(e) = self.file_descriptor.read_at(&mut buffer, offset) {
+                panic!("Error reading page from file: {}", e);
+            }
+        }
+        // Without a lock the page may be from halfway through a commit
+        if let Some((cache, change_counter)) = shared {
+            if self.lock >= LockLevel::Shared {
+                cache.insert(page_num, change_counter, &buffer);
+            }
+        }
+        self.pages[page_num as usize] = Some(buffer);
+    }
 
-            if self.file_length % PAGE_SIZE as u64 != 0 {
-                num_pages += 1;
+    /// Drops the oldest clean page once the cache is full. Changed pages stay
+    /// until they are written, and are tried again after the others.
+    fn make_room(&mut self) {
+        let mut attempts = self.loaded.len();
+        while self.loaded.len() >= self.cache_pages.max(1) && attempts > 0 {
+            let Some(page_num) = self.loaded.pop_front() else {
+                return;
+            };
+            attempts -= 1;
+            let Some(page) = self.pages.get_mut(page_num as usize) else {
+                continue;
+            };
+            if page.is_none() {
+                // Dropped since, by a refresh or a rollback
+                continue;
             }
+            if self.dirty.contains(&page_num) {
+                self.loaded.push_back(page_num);
+                continue;
+            }
+            *page = None;
+            return;
+        }
+    }
+
+    /// Hands out a zeroed page, reusing one from the freelist when possible.
+    pub fn allocate_page(&mut self) -> u32 {
+        let page_num = if self.freelist_head != 0 {
+            let page_num = self.freelist_head;
+            self.freelist_head = read_u32(self.fetch_page(page_num), 0);
+            self.freelist_count -= 1;
+            page_num
+        } else {
+            self.num_pages += 1;
+            self.num_pages - 1
+        };
+        info!("Allocated page {}", page_num);
+        self.fetch_page_mut(page_num).fill(0);
+        page_num
+    }
 
-            if page_num <= num_pages {
-                if let Err(e) = self
-                    .file_descriptor
-                    .read_at(&mut buffer, page_num * PAGE_SIZE as u64)
-                {
-                    panic!("Error reading page from file: {}", e);
+    pub fn free_page(&mut self, page_num: u32) {
+        info!("Freeing page {}", page_num);
+        let head = self.freelist_head;
+        let page = self.fetch_page_mut(page_num);
+        page.fill(0);
+        write_u32(page, 0, head);
+        self.freelist_head = page_num;
+        self.freelist_count += 1;
+        self.pages_freed += 1;
+    }
+
+    /// Writes `data` into a freshly allocated chain of overflow pages and
+    /// returns the first page of the chain.
+    pub fn write_overflow(&mut self, data: &[u8]) -> u32 {
+        let chunks = data.chunks(OVERFLOW_PAGE_CAPACITY).collect::<Vec<_>>();
+        let page_nums = chunks
+            .iter()
+            .map(|_| self.allocate_page())
+            .collect::<Vec<_>>();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = page_nums.get(i + 1).copied().unwrap_or(0);
+            let page = self.fetch_page_mut(page_nums[i]);
+            write_u32(page, OVERFLOW_NEXT_OFFSET, next);
+            page[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + chunk.len()].copy_from_slice(chunk);
+        }
+        page_nums.first().copied().unwrap_or(0)
+    }
+
+    /// Reads `len` bytes back from the overflow chain starting at `first_page`.
+    pub fn read_overflow(&mut self, first_page: u32, len: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(len);
+        let mut page_num = first_page;
+        while data.len() < len {
+            if page_num == 0 {
+                panic!("Overflow chain ended early, expected {} bytes", len);
+            }
+            let page = self.fetch_page(page_num);
+            let chunk_len = (len - data.len()).min(OVERFLOW_PAGE_CAPACITY);
+            data.extend_from_slice(&page[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + chunk_len]);
+            page_num = read_u32(page, OVERFLOW_NEXT_OFFSET);
+        }
+        data
+    }
+
+    pub fn free_overflow(&mut self, first_page: u32) {
+        let mut page_num = first_page;
+        while page_num != 0 {
+            let next = read_u32(self.fetch_page(page_num), OVERFLOW_NEXT_OFFSET);
+            self.free_page(page_num);
+            page_num = next;
+        }
+    }
+
+    /// The schema cookie in the header, as of the transaction.
+    pub fn schema_cookie(&mut self) -> u32 {
+        read_u32(self.fetch_page(0), SCHEMA_COOKIE_OFFSET)
+    }
+
+    /// Records a change to the schema in the header, undone along with it.
+    pub fn bump_schema_cookie(&mut self) {
+        let header = self.fetch_page_mut(0);
+        let cookie = read_u32(header, SCHEMA_COOKIE_OFFSET);
+        write_u32(header, SCHEMA_COOKIE_OFFSET, cookie.wrapping_add(1));
+    }
+
+    fn write_header(&mut self) {
+        let (num_pages, freelist_head, freelist_count) =
+            (self.num_pages, self.freelist_head, self.freelist_count);
+        let change_counter = self.change_counter.unwrap_or(0);
+        let wal_mode = self.journal_mode == JournalMode::Wal;
+        let header = self.fetch_page_mut(0);
+        header[MAGIC_OFFSET..MAGIC_OFFSET + MAGIC.len()].copy_from_slice(MAGIC);
+        write_u32(header, NUM_PAGES_OFFSET, num_pages);
+        write_u32(header, FREELIST_HEAD_OFFSET, freelist_head);
+        write_u32(header, FREELIST_COUNT_OFFSET, freelist_count);
+        write_u32(header, CHANGE_COUNTER_OFFSET, change_counter);
+        write_u32(header, WAL_MODE_OFFSET, u32::from(wal_mode));
+        write_u32(header, FORMAT_VERSION_OFFSET, FORMAT_VERSION);
+    }
+
+    pub fn flush(&mut self, page_num: u32) -> Result<(), std::io::Error> {
+        info!("Flushing page {}", page_num);
+        let Some(page) = self.pages.get(page_num as usize).and_then(|p| p.as_ref()) else {
+            return Ok(());
+        };
+        let offset = page_num as u64 * PAGE_SIZE as u64;
+        self.file_descriptor.write_all_at(page, offset)?;
+        self.file_length = self.file_length.max(offset + PAGE_SIZE as u64);
+        self.dirty.remove(&page_num);
+        info!("Flushed entire page {} to file", page_num);
+        Ok(())
+    }
+
+    /// Writes the page out if it is dirty and drops it from the cache.
+    pub fn evict(&mut self, page_num: u32) -> Result<(), std::io::Error> {
+        if self.dirty.contains(&page_num) {
+            self.flush(page_num)?;
+        }
+        if let Some(page) = self.pages.get_mut(page_num as usize) {
+            *page = None;
+        }
+        Ok(())
+    }
+
+    /// Writes the header and every dirty page back to the file.
+    pub fn flush_all(&mut self) -> Result<(), std::io::Error> {
+        self.write_header();
+        let dirty = std::mem::take(&mut self.dirty);
+        for page_num in dirty {
+            self.flush(page_num)?;
+        }
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Starts remembering the pages as they are, so that every change from
+    /// here on can be committed or rolled back together. Takes a SHARED
+    /// lock, and RESERVED too if the transaction will `write`, waiting up to
+    /// the busy timeout for other connections. Returns whether the cache
+    /// was dropped because another connection changed the file.
+    pub fn begin(&mut self, write: bool) -> io::Result<bool> {
+        assert!(self.transaction.is_none(), "transaction already started");
+        let deadline = Instant::now() + self.busy_timeout;
+        let mut delay = Duration::from_millis(1);
+        self.lock_shared(deadline)?;
+        if write {
+            while let Err(e) = self.lock(LockLevel::Reserved, deadline) {
+                // Nothing was read yet, so the reader can step aside for
+                // the writer in the way
+                self.unlock(LockLevel::Unlocked);
+                if e.kind() != io::ErrorKind::WouldBlock || !backoff(deadline, &mut delay) {
+                    return Err(e);
                 }
+                self.lock_shared(deadline)?;
             }
-            self.pages[page_num as usize] = buffer;
-        }
-        return &self.pages[page_num as usize];
-    }
-
-    pub fn flush(
-        &mut self,
-        page_num: u64,
-        num_remaining_rows: Option<usize>,
-    ) -> Result<(), std::io::Error> {
-        info!(
-            "Flushing page {} with {} remaining rows",
-            page_num,
-            num_remaining_rows.unwrap_or(0)
-        );
-        if let Some(num_remaining_rows) = num_remaining_rows {
-            let offset: usize = num_remaining_rows * ROW_SIZE;
-            let bytes_to_write = &self.pages[page_num as usize][..offset];
-            info!(
-                "Writing {:?} to offset {}",
-                bytes_to_write,
-                page_num * PAGE_SIZE as u64
+        }
+
+        let stale = match self.refresh() {
+            Ok(stale) => stale,
+            Err(e) => {
+                self.unlock(LockLevel::Unlocked);
+                return Err(e);
+            }
+        };
+        self.transaction = Some(Transaction {
+            start: self.snapshot(),
+            savepoints: Vec::new(),
+            savepoint_began: false,
+            statement: None,
+        });
+        Ok(stale)
+    }
+
+    /// Takes the RESERVED lock a transaction needs before it writes. Fails
+    /// at once if another connection has it, or in WAL mode if another
+    /// connection committed since the transaction started reading.
+    pub fn reserve(&mut self) -> io::Result<()> {
+        if self.transaction.is_none() || self.lock >= LockLevel::Reserved {
+            return Ok(());
+        }
+        self.lock(LockLevel::Reserved, Instant::now())?;
+        // Writing over a commit the transaction did not see would lose it
+        let read_mark = self.read_mark;
+        let result = match &mut self.wal {
+            Some(wal) => {
+                wal.refresh()
+                    .and_then(|restarted| match !restarted && wal.max_frame == read_mark {
+                        true => Ok(()),
+                        false => Err(lock::busy_error()),
+                    })
+            }
+            None => Ok(()),
+        };
+        if result.is_err() {
+            self.unlock(LockLevel::Shared);
+        }
+        result
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pages: BTreeMap::new(),
+            num_pages: self.num_pages,
+            freelist_head: self.freelist_head,
+            freelist_count: self.freelist_count,
+        }
+    }
+
+    /// Puts the file back the way the snapshot saw it.
+    fn restore(&mut self, snapshot: Snapshot) {
+        for (page_num, page) in snapshot.pages {
+            self.pages[page_num as usize] = Some(page);
+        }
+        self.num_pages = snapshot.num_pages;
+        self.freelist_head = snapshot.freelist_head;
+        self.freelist_count = snapshot.freelist_count;
+        self.pages.truncate(self.num_pages as usize);
+        let num_pages = self.num_pages;
+        self.dirty.retain(|&page_num| page_num < num_pages);
+    }
+
+    /// Opens a savepoint inside the transaction, starting one if there is
+    /// none. Returns whether that dropped the cache, as `begin` does.
+    pub fn savepoint(&mut self, name: &str) -> io::Result<bool> {
+        let mut stale = false;
+        if self.transaction.is_none() {
+            stale = self.begin(false)?;
+            self.transaction.as_mut().unwrap().savepoint_began = true;
+        }
+        let snapshot = self.snapshot();
+        let transaction = self.transaction.as_mut().unwrap();
+        transaction.savepoints.push((name.to_string(), snapshot));
+        Ok(stale)
+    }
+
+    /// The position of the innermost savepoint of that name.
+    fn find_savepoint(&self, name: &str) -> Option<usize> {
+        self.transaction
+            .as_ref()?
+            .savepoints
+            .iter()
+            .rposition(|(savepoint, _)| savepoint.eq_ignore_ascii_case(name))
+    }
+
+    /// Closes the savepoint and the ones opened after it, keeping their
+    /// changes. Releasing the savepoint that started the transaction
+    /// commits it. Returns false if there is no such savepoint.
+    pub fn release(&mut self, name: &str) -> io::Result<bool> {
+        let Some(position) = self.find_savepoint(name) else {
+            return Ok(false);
+        };
+        let transaction = self.transaction.as_mut().unwrap();
+        let released = transaction.savepoints.split_off(position);
+        match transaction.savepoints.last_mut() {
+            // The outer savepoint keeps the older images
+            Some((_, outer)) => {
+                for (_, snapshot) in released {
+                    for (page_num, page) in snapshot.pages {
+                        outer.record(page_num, &page);
+                    }
+                }
+            }
+            None if transaction.savepoint_began => self.commit()?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Undoes every change since the savepoint was opened and closes the
+    /// savepoints opened after it, leaving it and the transaction open.
+    /// Returns false if there is no such savepoint.
+    pub fn rollback_to(&mut self, name: &str) -> bool {
+        let Some(position) = self.find_savepoint(name) else {
+            return false;
+        };
+        let transaction = self.transaction.as_mut().unwrap();
+        let undone = transaction.savepoints.split_off(position);
+        let name = undone[0].0.clone();
+        // Newest first, so that older images of a page win
+        for (_, snapshot) in undone.into_iter().rev() {
+            self.restore(snapshot);
+        }
+        let snapshot = self.snapshot();
+        let transaction = self.transaction.as_mut().unwrap();
+        transaction.savepoints.push((name, snapshot));
+        true
+    }
+
+    /// Starts keeping what the running statement changes, until it ends.
+    /// Does nothing outside of a transaction or if one is kept already.
+    pub fn begin_statement(&mut self) {
+        let snapshot = self.snapshot();
+        if let Some(transaction) = &mut self.transaction {
+            transaction.statement.get_or_insert(snapshot);
+        }
+    }
+
+    /// Keeps the running statement's changes.
+    pub fn end_statement(&mut self) {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.statement = None;
+        }
+    }
+
+    /// Undoes the running statement's changes, leaving the rest of the
+    /// transaction as it was. Returns whether there was one to undo.
+    pub fn rollback_statement(&mut self) -> bool {
+        let statement = self
+            .transaction
+            .as_mut()
+            .and_then(|transaction| transaction.statement.take());
+        match statement {
+            Some(snapshot) => {
+                self.restore(snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes the transaction's changes to the file and syncs it. The pages
+    /// they overwrite are saved in the journal first, so a crash part way
+    /// leaves a file that is rolled back when next opened. If writing fails
+    /// the transaction is rolled back.
+    pub fn commit(&mut self) -> io::Result<()> {
+        let transaction = self.transaction.as_ref().expect("no transaction");
+        let header_changed = (self.num_pages, self.freelist_head, self.freelist_count)
+            != (
+                transaction.start.num_pages,
+                transaction.start.freelist_head,
+                transaction.start.freelist_count,
             );
-            if let Err(e) = self
-                .file_descriptor
-                .write_all_at(bytes_to_write, page_num * PAGE_SIZE as u64)
-            {
+        if transaction.start.pages.is_empty() && !header_changed && self.dirty.is_empty() {
+            self.transaction = None;
+            self.unlock(LockLevel::Unlocked);
+            return Ok(());
+        }
+        if self.wal.is_some() {
+            return self.commit_wal();
+        }
+        // Readers have to go before the file changes under them. If they
+        // stay, the transaction is kept to commit later.
+        let held = self.lock;
+        if let Err(e) = self.lock(LockLevel::Exclusive, Instant::now() + self.busy_timeout) {
+            self.unlock(held);
+            return Err(e);
+        }
+        // A log left from an earlier time in WAL mode must not be read as
+        // part of this one
+        let wal = match self.journal_mode {
+            JournalMode::Wal => Some(Wal::open(&self.wal_path).and_then(|mut wal| {
+                wal.restart()?;
+                Ok(wal)
+            })),
+            JournalMode::Delete => None,
+        }
+        .transpose();
+        self.change_counter = Some(self.change_counter.unwrap_or(0).wrapping_add(1));
+        let result = wal.and_then(|wal| {
+            self.write_journal()?;
+            self.flush_all()?;
+            self.file_descriptor.sync_data()?;
+            std::fs::remove_file(&self.journal_path)?;
+            Ok(wal)
+        });
+        match result {
+            Ok(wal) => {
+                if wal.is_some() {
+                    self.wal = wal;
+                    self.read_mark = 0;
+                }
+            }
+            Err(e) => {
+                let _ = self.restore_journal();
+                self.rollback();
+                self.change_counter = None;
                 return Err(e);
-            } else {
-                info!("Flushed {} bytes to file", bytes_to_write.len());
-                return Ok(());
             }
-        } else {
-            // Write the entire page
-            if let Err(e) = self
-                .file_descriptor
-                .write_at(&self.pages[page_num as usize], page_num * PAGE_SIZE as u64)
-            {
+        }
+        self.transaction = None;
+        self.unlock(LockLevel::Unlocked);
+        Ok(())
+    }
+
+    /// Appends the transaction's pages to the log, while other connections
+    /// go on reading the versions they started with.
+    fn commit_wal(&mut self) -> io::Result<()> {
+        // Pages written without `reserve` may be from before the last commit
+        self.reserve()?;
+        if let Err(e) = self.write_wal() {
+            self.rollback();
+            self.change_counter = None;
+            return Err(e);
+        }
+        self.transaction = None;
+        if self.journal_mode == JournalMode::Delete {
+            // Back to a rollback journal once the file has every page. The
+            // lock is already EXCLUSIVE, taken when the mode was set.
+            let result = self.checkpoint().and_then(|done| match done {
+                true => std::fs::remove_file(&self.wal_path),
+                false => Err(lock::busy_error()),
+            });
+            self.wal = None;
+            if let Err(e) = result {
+                self.change_counter = None;
+                self.unlock(LockLevel::Unlocked);
                 return Err(e);
             }
-            info!("Flushed entire page {} to file", page_num);
+        } else if self.read_mark >= CHECKPOINT_FRAMES {
+            self.try_checkpoint();
+        }
+        self.unlock(LockLevel::Unlocked);
+        Ok(())
+    }
+
+    /// Appends the dirty pages to the log as one commit.
+    fn write_wal(&mut self) -> io::Result<()> {
+        self.change_counter = Some(self.change_counter.unwrap_or(0).wrapping_add(1));
+        self.write_header();
+        // Every page is read by now, and the log may start over
+        self.release_read_mark();
+        let wal = self.wal.as_mut().expect("not in WAL mode");
+        let pages = self
+            .dirty
+            .iter()
+            .map(|&page_num| {
+                let page = self.pages[page_num as usize].as_deref();
+                (page_num, page.expect("dirty page is cached"))
+            })
+            .collect::<Vec<_>>();
+        wal.append(&self.file_descriptor, &pages, self.num_pages)?;
+        self.read_mark = wal.max_frame;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Copies as much of the log into the file as the readers let it.
+    /// Returns whether the file has all of it. Needs a RESERVED lock, so
+    /// that no commit comes in meanwhile.
+    fn checkpoint(&mut self) -> io::Result<bool> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(true);
+        };
+        if wal.refresh()? {
+            self.change_counter = None;
+        }
+        let done = wal.checkpoint(&self.file_descriptor)?;
+        self.file_length = self.file_descriptor.metadata()?.len();
+        Ok(done)
+    }
+
+    /// Checkpoints the log unless another connection is writing, without
+    /// waiting for it.
+    fn try_checkpoint(&mut self) {
+        if self.lock(LockLevel::Reserved, Instant::now()).is_err() {
+            return;
+        }
+        if let Err(e) = self.checkpoint() {
+            error!("Error checkpointing the log: {}", e);
+            self.change_counter = None;
+        }
+    }
+
+    /// Puts back the pages and header the transaction started with.
+    pub fn rollback(&mut self) {
+        let transaction = self.transaction.take().expect("no transaction");
+        self.restore(transaction.start);
+        self.journal_mode = match self.wal {
+            Some(_) => JournalMode::Wal,
+            None => JournalMode::Delete,
+        };
+        self.unlock(LockLevel::Unlocked);
+    }
+
+    /// Switches the file to the journal mode when the transaction commits.
+    /// That needs an EXCLUSIVE lock, so other connections must not be
+    /// reading.
+    pub fn set_journal_mode(&mut self, mode: JournalMode) -> io::Result<()> {
+        assert!(self.transaction.is_some(), "no transaction");
+        if mode == self.journal_mode {
+            return Ok(());
         }
+        let held = self.lock;
+        if let Err(e) = self.lock(LockLevel::Exclusive, Instant::now() + self.busy_timeout) {
+            self.unlock(held);
+            return Err(e);
+        }
+        self.journal_mode = mode;
+        // The header records the mode
+        self.fetch_page_mut(0);
         Ok(())
     }
+
+    /// Writes the pages changed outside of a transaction to the file, under
+    /// an EXCLUSIVE lock.
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.wal.is_some() {
+            return self.close_wal();
+        }
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let result = self
+            .lock(LockLevel::Exclusive, Instant::now() + self.busy_timeout)
+            .and_then(|()| {
+                self.change_counter = Some(self.change_counter.unwrap_or(0).wrapping_add(1));
+                self.flush_all()?;
+                self.file_descriptor.sync_data()
+            });
+        self.unlock(LockLevel::Unlocked);
+        result
+    }
+
+    /// Appends the dirty pages to the log and checkpoints it if no one else
+    /// is reading. The last connection to close removes the log as well.
+    fn close_wal(&mut self) -> io::Result<()> {
+        let result = self.lock(LockLevel::Shared, Instant::now() + self.busy_timeout);
+        let result = result.and_then(|()| {
+            if !self.dirty.is_empty() {
+                self.lock(LockLevel::Reserved, Instant::now())?;
+                self.write_wal()?;
+            } else {
+                // Checkpoint what others committed since, not a stale log
+                self.refresh()?;
+            }
+            Ok(())
+        });
+        let result = result.and_then(|()| {
+            self.release_read_mark();
+            self.try_checkpoint();
+            let checkpointed = self
+                .wal
+                .as_ref()
+                .is_some_and(|wal| wal.backfill == wal.max_frame);
+            if checkpointed
+                && self.lock(LockLevel::Exclusive, Instant::now()).is_ok()
+                && !lock::others_open(&self.file_descriptor)?
+            {
+                self.wal = None;
+                self.change_counter = None;
+                std::fs::remove_file(&self.wal_path)?;
+            }
+            Ok(())
+        });
+        self.unlock(LockLevel::Unlocked);
+        result
+    }
+
+    /// Saves the pages in the file the transaction changed, as they were,
+    /// and syncs the journal.
+    fn write_journal(&mut self) -> io::Result<()> {
+        // The header is part of the commit, so page 0 is saved too
+        self.write_header();
+        let transaction = self.transaction.as_ref().expect("no transaction");
+        let file_pages = self.file_length.div_ceil(PAGE_SIZE as u64);
+        let saved = transaction
+            .start
+            .pages
+            .iter()
+            .filter(|(&page_num, _)| u64::from(page_num) < file_pages)
+            .collect::<Vec<_>>();
+        let mut journal =
+            Vec::with_capacity(JOURNAL_HEADER_SIZE + saved.len() * JOURNAL_RECORD_SIZE);
+        journal.extend_from_slice(JOURNAL_MAGIC);
+        journal.extend_from_slice(&self.file_length.to_le_bytes());
+        journal.extend_from_slice(&(saved.len() as u32).to_le_bytes());
+        for (page_num, page) in saved {
+            journal.extend_from_slice(&page_num.to_le_bytes());
+            journal.extend_from_slice(page);
+        }
+        let file = File::create(&self.journal_path)?;
+        file.write_all_at(&journal, 0)?;
+        file.sync_data()
+    }
+
+    /// Copies the pages in a complete journal back into the file and
+    /// removes the journal. A journal cut short was still being written,
+    /// before anything in the file changed, and is just removed.
+    fn restore_journal(&mut self) -> io::Result<()> {
+        let journal = match std::fs::read(&self.journal_path) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let complete = journal.len() >= JOURNAL_HEADER_SIZE
+            && &journal[..JOURNAL_MAGIC.len()] == JOURNAL_MAGIC
+            && journal.len()
+                == JOURNAL_HEADER_SIZE
+                    + read_u32(&journal, JOURNAL_HEADER_SIZE - 4) as usize * JOURNAL_RECORD_SIZE;
+        if complete {
+            info!("Rolling back journal {}", self.journal_path);
+            let offset = JOURNAL_MAGIC.len();
+            let file_length = u64::from_le_bytes(journal[offset..offset + 8].try_into().unwrap());
+            for record in journal[JOURNAL_HEADER_SIZE..].chunks(JOURNAL_RECORD_SIZE) {
+                let page_num = read_u32(record, 0);
+                let offset = page_num as u64 * PAGE_SIZE as u64;
+                self.file_descriptor.write_all_at(&record[4..], offset)?;
+            }
+            self.file_descriptor.set_len(file_length)?;
+            self.file_descriptor.sync_data()?;
+            self.file_length = file_length;
+        }
+        std::fs::remove_file(&self.journal_path)
+    }
+}
+
+/// Sleeps before the next attempt at a lock, for longer each time. Returns
+/// false instead once `deadline` has passed.
+fn backoff(deadline: Instant, delay: &mut Duration) -> bool {
+    let now = Instant::now();
+    if now >= deadline {
+        return false;
+    }
+    std::thread::sleep((*delay).min(deadline - now));
+    *delay = (*delay * 2).min(MAX_BUSY_DELAY);
+    true
 }
 
 impl Default for Pager {
@@ -124,3 +1121,275 @@ impl Default for Pager {
         Self::pager_open("test.db")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    #[test]
+    fn test_rollback_restores_pages() {
+        let mut pager = Pager::pager_open(&temp_db_path("pager_rollback"));
+        let page_num = pager.allocate_page();
+        pager.fetch_page_mut(page_num)[0] = 1;
+        pager.begin(false).unwrap();
+        pager.fetch_page_mut(page_num)[0] = 2;
+        pager.free_page(page_num);
+        pager.allocate_page();
+        pager.allocate_page();
+        pager.rollback();
+        assert_eq!(pager.fetch_page(page_num)[0], 1);
+        assert_eq!((pager.num_pages, pager.freelist_count), (page_num + 1, 0));
+        assert!(!pager.in_transaction());
+    }
+
+    #[test]
+    fn test_cache_keeps_changed_pages_past_its_size() {
+        let path = temp_db_path("pager_cache_size");
+        let mut pager = Pager::pager_open(&path);
+        pager.cache_pages = 4;
+        let cached = |pager: &Pager| pager.pages.iter().flatten().count();
+        pager.begin(true).unwrap();
+        let page_nums = (0..10u8)
+            .map(|i| {
+                let page_num = pager.allocate_page();
+                pager.fetch_page_mut(page_num)[0] = i + 1;
+                page_num
+            })
+            .collect::<Vec<_>>();
+        assert!(cached(&pager) > 10);
+        pager.commit().unwrap();
+
+        let mut reader = Pager::pager_open(&path);
+        reader.cache_pages = 4;
+        reader.begin(false).unwrap();
+        for _ in 0..2 {
+            for (i, page_num) in page_nums.iter().enumerate() {
+                assert_eq!(reader.fetch_page(*page_num)[0], i as u8 + 1);
+                assert!(cached(&reader) <= 4);
+            }
+        }
+        reader.rollback();
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut pager = Pager::pager_open(&temp_db_path("pager_savepoints"));
+        let page_num = pager.allocate_page();
+        pager.savepoint("a").unwrap();
+        assert!(pager.in_transaction());
+        pager.fetch_page_mut(page_num)[0] = 1;
+        pager.savepoint("b").unwrap();
+        pager.fetch_page_mut(page_num)[0] = 2;
+        pager.allocate_page();
+        pager.savepoint("c").unwrap();
+        pager.fetch_page_mut(page_num)[0] = 3;
+
+        // Rolling back to b also drops c but keeps b open
+        assert!(pager.rollback_to("B"));
+        assert_eq!(pager.fetch_page(page_num)[0], 1);
+        assert_eq!(pager.num_pages, page_num + 1);
+        assert!(!pager.rollback_to("c"));
+        pager.fetch_page_mut(page_num)[0] = 4;
+        assert!(pager.rollback_to("b"));
+        assert_eq!(pager.fetch_page(page_num)[0], 1);
+
+        // Released changes are undone with the savepoint around them
+        pager.fetch_page_mut(page_num)[0] = 5;
+        assert!(pager.release("b").unwrap());
+        assert!(pager.in_transaction());
+        assert!(pager.rollback_to("a"));
+        assert_eq!(pager.fetch_page(page_num)[0], 0);
+
+        // Releasing the savepoint that began the transaction commits it
+        pager.fetch_page_mut(page_num)[0] = 6;
+        assert!(pager.release("a").unwrap());
+        assert!(!pager.in_transaction());
+        assert!(!pager.release("a").unwrap());
+    }
+
+    // Elsewhere locks of one process never conflict
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_connections_take_turns() {
+        let path = temp_db_path("pager_locks");
+        let mut a = Pager::pager_open(&path);
+        let mut b = Pager::pager_open(&path);
+        let page_num = a.allocate_page();
+        a.close().unwrap();
+
+        // One writer at a time, while others keep reading
+        assert!(b.begin(false).unwrap());
+        a.begin(true).unwrap();
+        b.reserve().unwrap_err();
+        a.fetch_page_mut(page_num)[0] = 1;
+        let busy = a.commit().unwrap_err();
+        assert_eq!(busy.kind(), io::ErrorKind::WouldBlock);
+        assert!(a.in_transaction());
+        assert_eq!(b.fetch_page(page_num)[0], 0);
+        b.rollback();
+        a.commit().unwrap();
+
+        // The next transaction sees the change
+        assert!(b.begin(true).unwrap());
+        assert_eq!(b.fetch_page(page_num)[0], 1);
+        assert_eq!(a.begin(true).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        b.commit().unwrap();
+        assert!(!a.begin(false).unwrap());
+        a.rollback();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wal_readers_keep_their_snapshot() {
+        let path = temp_db_path("pager_wal");
+        let wal_path = format!("{}-wal", path);
+        let mut a = Pager::pager_open(&path);
+        let mut b = Pager::pager_open(&path);
+        let page_num = a.allocate_page();
+        a.close().unwrap();
+        a.begin(false).unwrap();
+        a.set_journal_mode(JournalMode::Wal).unwrap();
+        a.commit().unwrap();
+        assert_eq!(a.journal_mode(), JournalMode::Wal);
+
+        // The writer commits while the reader goes on seeing what was there
+        // when it started
+        assert!(b.begin(false).unwrap());
+        assert_eq!(b.journal_mode(), JournalMode::Wal);
+        a.begin(true).unwrap();
+        a.fetch_page_mut(page_num)[0] = 1;
+        a.commit().unwrap();
+        assert_eq!(b.fetch_page(page_num)[0], 0);
+        assert_eq!(b.reserve().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        b.fetch_page_mut(page_num)[0] = 2;
+        assert_eq!(b.commit().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        b.rollback();
+        assert!(b.begin(false).unwrap());
+        assert_eq!(b.fetch_page(page_num)[0], 1);
+
+        // The reader's snapshot has every commit, so a checkpoint copies
+        // them all, but the log does not start over while it reads from it
+        a.close().unwrap();
+        let wal = a.wal.as_ref().unwrap();
+        assert_eq!(wal.backfill, wal.max_frame);
+        let mut page = vec![0; PAGE_SIZE];
+        let offset = page_num as u64 * PAGE_SIZE as u64;
+        a.file_descriptor.read_exact_at(&mut page, offset).unwrap();
+        assert_eq!(page[0], 1);
+        let max_frame = wal.max_frame;
+        a.begin(true).unwrap();
+        a.fetch_page_mut(page_num)[1] = 1;
+        a.commit().unwrap();
+        assert!(a.wal.as_ref().unwrap().max_frame > max_frame);
+        assert_eq!(b.fetch_page(page_num)[1], 0);
+        b.rollback();
+        a.close().unwrap();
+
+        // A log removed by a connection that closed last is opened again
+        std::fs::remove_file(&wal_path).unwrap();
+        a.begin(true).unwrap();
+        a.fetch_page_mut(page_num)[0] = 2;
+        a.commit().unwrap();
+        assert!(std::path::Path::new(&wal_path).exists());
+        b.begin(false).unwrap();
+        assert_eq!(b.fetch_page(page_num)[0], 2);
+        b.rollback();
+
+        // Back to a rollback journal
+        a.begin(false).unwrap();
+        a.set_journal_mode(JournalMode::Delete).unwrap();
+        a.commit().unwrap();
+        assert!(!std::path::Path::new(&wal_path).exists());
+        b.begin(false).unwrap();
+        assert_eq!(b.journal_mode(), JournalMode::Delete);
+        assert_eq!(b.fetch_page(page_num)[0], 2);
+        b.rollback();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_wal_checkpoints_past_busy_reader() {
+        let path = temp_db_path("pager_wal_busy_reader");
+        let mut a = Pager::pager_open(&path);
+        let mut b = Pager::pager_open(&path);
+        let page_num = a.allocate_page();
+        a.close().unwrap();
+        a.begin(false).unwrap();
+        a.set_journal_mode(JournalMode::Wal).unwrap();
+        a.commit().unwrap();
+        let file_page = |a: &Pager| {
+            let mut page = vec![0; PAGE_SIZE];
+            let offset = page_num as u64 * PAGE_SIZE as u64;
+            a.file_descriptor.read_exact_at(&mut page, offset).unwrap();
+            page[0]
+        };
+
+        // The reader is always in a transaction, but each one starts after
+        // the last commit, so the checkpoints get up to the one before
+        b.begin(false).unwrap();
+        for i in 1..=5 {
+            a.begin(true).unwrap();
+            a.fetch_page_mut(page_num)[0] = i;
+            a.commit().unwrap();
+            a.close().unwrap();
+            assert_eq!(file_page(&a), i - 1);
+            b.rollback();
+            b.begin(false).unwrap();
+            assert_eq!(b.fetch_page(page_num)[0], i);
+        }
+        assert!(a.wal.as_ref().unwrap().max_frame > 0);
+
+        // Once the file has every commit the reader reads only the file,
+        // and the next commit starts the log over under it
+        b.rollback();
+        a.close().unwrap();
+        assert_eq!(file_page(&a), 5);
+        b.begin(false).unwrap();
+        a.begin(true).unwrap();
+        a.fetch_page_mut(page_num)[0] = 6;
+        a.commit().unwrap();
+        assert_eq!(a.wal.as_ref().unwrap().max_frame, 2);
+        b.pages.clear();
+        assert_eq!(b.fetch_page(page_num)[0], 5);
+
+        // Nor does the file change while it reads it
+        a.close().unwrap();
+        assert_eq!(file_page(&a), 5);
+        b.rollback();
+        a.close().unwrap();
+        assert_eq!(file_page(&a), 6);
+    }
+
+    #[test]
+    fn test_unfinished_commit_is_rolled_back_on_open() {
+        let path = temp_db_path("pager_journal");
+        let journal_path = format!("{}-journal", path);
+        let mut pager = Pager::pager_open(&path);
+        pager.begin(false).unwrap();
+        let page_num = pager.allocate_page();
+        pager.fetch_page_mut(page_num)[0] = 1;
+        pager.commit().unwrap();
+        assert!(!std::path::Path::new(&journal_path).exists());
+
+        // The file is written but the journal not yet removed
+        pager.begin(false).unwrap();
+        pager.fetch_page_mut(page_num)[0] = 2;
+        pager.allocate_page();
+        pager.write_journal().unwrap();
+        pager.flush_all().unwrap();
+        drop(pager);
+
+        let mut pager = Pager::pager_open(&path);
+        assert!(!std::path::Path::new(&journal_path).exists());
+        assert_eq!(pager.num_pages, page_num + 1);
+        assert_eq!(pager.file_length, (page_num as u64 + 1) * PAGE_SIZE as u64);
+        assert_eq!(pager.fetch_page(page_num)[0], 1);
+
+        // A journal cut short is dropped without touching the file
+        std::fs::write(&journal_path, &JOURNAL_MAGIC[..4]).unwrap();
+        let mut pager = Pager::pager_open(&path);
+        assert!(!std::path::Path::new(&journal_path).exists());
+        assert_eq!(pager.fetch_page(page_num)[0], 1);
+    }
+}
@@ -0,0 +1,1767 @@
+use crate::expr::{BinaryOp, ColumnRef, Expr, UnaryOp};
+use crate::tokenizer::{tokenize, Token};
+use crate::value::Value;
+
+#[derive(Debug, PartialEq)]
+pub enum PrepareSyntaxError {
+    UnrecognizedStatement,
+    InvalidId,
+    InvalidNumberOfArguments,
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    InvalidBlob,
+    InvalidNumber(String),
+    /// A `?NNN` numbered 0 or past `MAX_PARAMETERS`.
+    InvalidParameter(String),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+}
+
+/// Keywords that can not be used as bare column names in expressions.
+const RESERVED: [&str; 37] = [
+    "and", "or", "not", "is", "in", "like", "between", "null", "true", "false", "select", "from",
+    "where", "values", "into", "set", "as", "order", "by", "asc", "desc", "limit", "offset",
+    "group", "having", "distinct", "join", "inner", "left", "outer", "cross", "on", "case", "when",
+    "then", "else", "end",
+];
+
+/// The highest number a parameter can have.
+pub const MAX_PARAMETERS: usize = 32766;
+
+/// Table used by the tutorial style `insert 1 user email` / `select` commands.
+pub const LEGACY_TABLE: &str = "users";
+pub const LEGACY_TABLE_SQL: &str =
+    "create table users (id integer primary key, username text, email text unique)";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub type_name: String,
+    pub primary_key: bool,
+    pub unique: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    /// Column lists of the `unique (column, ...)` table constraints.
+    pub unique: Vec<Vec<String>>,
+}
+
+/// `create [unique] index name on table (column, ...)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    pub unique: bool,
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Insert {
+    pub table: String,
+    pub columns: Option<Vec<String>>,
+    /// Literals or parameters.
+    pub rows: Vec<Vec<Expr>>,
+}
+
+/// One entry of a SELECT list.
+#[derive(Debug, PartialEq)]
+pub enum ResultColumn {
+    /// `*`, every column of every table in FROM.
+    All,
+    /// `t.*`
+    TableAll(String),
+    Expr {
+        expr: Expr,
+        alias: Option<String>,
+    },
+}
+
+/// One `expr [ASC|DESC]` of an ORDER BY clause.
+#[derive(Debug, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+/// A table named in FROM, optionally under an alias.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+impl TableRef {
+    /// The name the table's columns are qualified with.
+    pub fn scope_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    /// Keeps left rows without a match, with NULLs for the right table.
+    Left,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub on: Option<Expr>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Select {
+    pub columns: Vec<ResultColumn>,
+    pub from: TableRef,
+    pub joins: Vec<Join>,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+    /// At most this many rows, all of them when `None`.
+    pub limit: Option<u64>,
+    /// Rows skipped before the first one returned.
+    pub offset: u64,
+}
+
+/// `update table set column = expr, ... [where expr]`
+#[derive(Debug, PartialEq)]
+pub struct Update {
+    pub table: String,
+    pub assignments: Vec<(String, Expr)>,
+    pub filter: Option<Expr>,
+}
+
+/// `delete from table [where expr]`
+#[derive(Debug, PartialEq)]
+pub struct Delete {
+    pub table: String,
+    pub filter: Option<Expr>,
+}
+
+/// Replaces the row whose primary key is `key`.
+#[derive(Debug, PartialEq)]
+pub struct LegacyUpdate {
+    pub table: String,
+    pub key: Value,
+    pub values: Vec<Value>,
+}
+
+/// Removes the row whose primary key is `key`.
+#[derive(Debug, PartialEq)]
+pub struct LegacyDelete {
+    pub table: String,
+    pub key: Value,
+}
+
+/// `analyze [table]`, which gathers the statistics of one table or of all.
+#[derive(Debug, PartialEq)]
+pub struct Analyze {
+    pub table: Option<String>,
+}
+
+/// `pragma name [= value]`, which reads or sets a setting of the database.
+#[derive(Debug, PartialEq)]
+pub struct Pragma {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// `explain [query plan] <statement>`
+#[derive(Debug, PartialEq)]
+pub struct Explain {
+    /// Show the access path of each table instead of the whole program.
+    pub query_plan: bool,
+    pub statement: Box<StatementType>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StatementType {
+    CreateTable(CreateTable),
+    CreateIndex(CreateIndex),
+    Insert(Insert),
+    Select(Select),
+    Update(Update),
+    Delete(Delete),
+    /// `update <id> <username> <email>`
+    LegacyUpdate(LegacyUpdate),
+    /// `delete <id>`
+    LegacyDelete(LegacyDelete),
+    Explain(Explain),
+    Analyze(Analyze),
+    Pragma(Pragma),
+    /// `begin [transaction]`
+    Begin,
+    /// `commit [transaction]` or `end [transaction]`
+    Commit,
+    /// `rollback [transaction]`
+    Rollback,
+    /// `savepoint <name>`
+    Savepoint(String),
+    /// `release [savepoint] <name>`
+    Release(String),
+    /// `rollback [transaction] to [savepoint] <name>`
+    RollbackTo(String),
+}
+
+/// Returns what follows `keyword` at the start of the input, if it starts
+/// with it.
+fn strip_keyword<'a>(input: &'a str, keyword: &str) -> Option<&'a str> {
+    let input = input.trim_start();
+    let end = input.find(char::is_whitespace).unwrap_or(input.len());
+    input[..end]
+        .eq_ignore_ascii_case(keyword)
+        .then_some(&input[end..])
+}
+
+pub fn parse_statement(input: &str) -> Result<StatementType, PrepareSyntaxError> {
+    parse_with_parameters(input).map(|(statement, _)| statement)
+}
+
+/// Parses a statement along with the names of its parameters, by number
+/// from 1. A `?` has no name and `?NNN` is named as written.
+pub fn parse_with_parameters(
+    input: &str,
+) -> Result<(StatementType, Vec<Option<String>>), PrepareSyntaxError> {
+    if let Some(rest) = strip_keyword(input, "explain") {
+        let (query_plan, rest) = match strip_keyword(rest, "query") {
+            Some(rest) => (
+                true,
+                strip_keyword(rest, "plan")
+                    .ok_or_else(|| PrepareSyntaxError::UnexpectedToken("query".to_string()))?,
+            ),
+            None => (false, rest),
+        };
+        return match parse_with_parameters(rest)? {
+            (StatementType::Explain(_), _) => {
+                Err(PrepareSyntaxError::UnexpectedToken("explain".to_string()))
+            }
+            (statement, parameters) => Ok((
+                StatementType::Explain(Explain {
+                    query_plan,
+                    statement: Box::new(statement),
+                }),
+                parameters,
+            )),
+        };
+    }
+    let parts = input.split_whitespace().collect::<Vec<&str>>();
+    let keyword = |i: usize| {
+        parts
+            .get(i)
+            .map(|p| p.trim_end_matches(';').to_ascii_lowercase())
+    };
+    let statement = match keyword(0).as_deref() {
+        Some("insert") if keyword(1).as_deref() != Some("into") => parse_legacy(&parts),
+        Some("update") if keyword(2).as_deref() != Some("set") => parse_legacy(&parts),
+        Some("delete") if keyword(1).as_deref() != Some("from") => parse_legacy(&parts),
+        Some("select") if parts.len() == 1 => Ok(StatementType::Select(Select {
+            columns: vec![ResultColumn::All],
+            from: TableRef {
+                name: LEGACY_TABLE.to_string(),
+                alias: None,
+            },
+            joins: Vec::new(),
+            filter: None,
+            group_by: Vec::new(),
+            having: None,
+            order_by: Vec::new(),
+            limit: None,
+            offset: 0,
+        })),
+        Some(
+            "create" | "insert" | "select" | "update" | "delete" | "analyze" | "begin" | "commit"
+            | "end" | "rollback" | "savepoint" | "release" | "pragma",
+        ) => {
+            let mut parser = Parser {
+                tokens: tokenize(input)?,
+                pos: 0,
+                parameters: Vec::new(),
+            };
+            let statement = parser.parse()?;
+            parser.consume_symbol(";");
+            return match parser.peek() {
+                Some(token) => Err(PrepareSyntaxError::UnexpectedToken(format!("{:?}", token))),
+                None => Ok((statement, parser.parameters)),
+            };
+        }
+        _ => Err(PrepareSyntaxError::UnrecognizedStatement),
+    };
+    statement.map(|statement| (statement, Vec::new()))
+}
+
+/// Parses the whitespace separated `insert <id> <username> <email>`,
+/// `update <id> <username> <email>` and `delete <id>` commands against the
+/// legacy users table. A bare `null` stands for a missing value.
+fn parse_legacy(parts: &[&str]) -> Result<StatementType, PrepareSyntaxError> {
+    let command = parts[0].to_ascii_lowercase();
+    let expected_parts = if command == "delete" { 2 } else { 4 };
+    if parts.len() != expected_parts {
+        return Err(PrepareSyntaxError::InvalidNumberOfArguments);
+    }
+
+    let id = match parts[1].parse::<i64>() {
+        Ok(id) => Value::Integer(id),
+        Err(_) => return Err(PrepareSyntaxError::InvalidId),
+    };
+    let field = |part: &str| {
+        if part.eq_ignore_ascii_case("null") {
+            Value::Null
+        } else {
+            // Values longer than a cell are moved to overflow pages, so there
+            // is no length limit on either field.
+            Value::Text(part.to_string())
+        }
+    };
+
+    let table = LEGACY_TABLE.to_string();
+    Ok(match command.as_str() {
+        "insert" => StatementType::Insert(Insert {
+            table,
+            columns: None,
+            rows: vec![vec![id, field(parts[2]), field(parts[3])]
+                .into_iter()
+                .map(Expr::Literal)
+                .collect()],
+        }),
+        "update" => StatementType::LegacyUpdate(LegacyUpdate {
+            table,
+            values: vec![id.clone(), field(parts[2]), field(parts[3])],
+            key: id,
+        }),
+        _ => StatementType::LegacyDelete(LegacyDelete { table, key: id }),
+    })
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// The names of the parameters seen so far, by number from 1.
+    parameters: Vec<Option<String>>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, PrepareSyntaxError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or(PrepareSyntaxError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn unexpected(&self) -> PrepareSyntaxError {
+        match self.peek() {
+            Some(token) => PrepareSyntaxError::UnexpectedToken(format!("{:?}", token)),
+            None => PrepareSyntaxError::UnexpectedEnd,
+        }
+    }
+
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_keyword(keyword)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), PrepareSyntaxError> {
+        if self.consume_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn consume_symbol(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), PrepareSyntaxError> {
+        if self.consume_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, PrepareSyntaxError> {
+        match self.peek() {
+            Some(Token::Identifier(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    /// Parses a comma separated list of `item`s.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, PrepareSyntaxError>,
+    ) -> Result<Vec<T>, PrepareSyntaxError> {
+        let mut items = vec![item(self)?];
+        while self.consume_symbol(",") {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn parse(&mut self) -> Result<StatementType, PrepareSyntaxError> {
+        if self.consume_keyword("create") {
+            if self.consume_keyword("unique") {
+                self.expect_keyword("index")?;
+                self.parse_create_index(true)
+            } else if self.consume_keyword("index") {
+                self.parse_create_index(false)
+            } else {
+                self.parse_create_table()
+            }
+        } else if self.consume_keyword("insert") {
+            self.parse_insert()
+        } else if self.consume_keyword("select") {
+            self.parse_select()
+        } else if self.consume_keyword("update") {
+            self.parse_update()
+        } else if self.consume_keyword("delete") {
+            self.expect_keyword("from")?;
+            let table = self.identifier()?;
+            let filter = self.parse_where()?;
+            Ok(StatementType::Delete(Delete { table, filter }))
+        } else if self.consume_keyword("analyze") {
+            let table = match self.peek() {
+                Some(Token::Identifier(_)) => Some(self.identifier()?),
+                _ => None,
+            };
+            Ok(StatementType::Analyze(Analyze { table }))
+        } else if self.consume_keyword("pragma") {
+            let name = self.identifier()?;
+            let value = match self.consume_symbol("=") {
+                true => match self.next()? {
+                    Token::Identifier(value) | Token::String(value) | Token::Number(value) => {
+                        Some(value)
+                    }
+                    token => {
+                        return Err(PrepareSyntaxError::UnexpectedToken(format!("{:?}", token)))
+                    }
+                },
+                false => None,
+            };
+            Ok(StatementType::Pragma(Pragma { name, value }))
+        } else if self.consume_keyword("begin") {
+            self.consume_keyword("transaction");
+            Ok(StatementType::Begin)
+        } else if self.consume_keyword("commit") || self.consume_keyword("end") {
+            self.consume_keyword("transaction");
+            Ok(StatementType::Commit)
+        } else if self.consume_keyword("rollback") {
+            self.consume_keyword("transaction");
+            if !self.consume_keyword("to") {
+                return Ok(StatementType::Rollback);
+            }
+            self.consume_keyword("savepoint");
+            Ok(StatementType::RollbackTo(self.identifier()?))
+        } else if self.consume_keyword("savepoint") {
+            Ok(StatementType::Savepoint(self.identifier()?))
+        } else if self.consume_keyword("release") {
+            self.consume_keyword("savepoint");
+            Ok(StatementType::Release(self.identifier()?))
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    fn parse_create_table(&mut self) -> Result<StatementType, PrepareSyntaxError> {
+        self.expect_keyword("table")?;
+        let name = self.identifier()?;
+        self.expect_symbol("(")?;
+        let mut columns = vec![self.parse_column_def()?];
+        let mut unique = Vec::new();
+        while self.consume_symbol(",") {
+            // Table constraints follow the column definitions
+            if self.consume_keyword("unique") {
+                self.expect_symbol("(")?;
+                unique.push(self.list(Self::identifier)?);
+                self.expect_symbol(")")?;
+            } else if unique.is_empty() {
+                columns.push(self.parse_column_def()?);
+            } else {
+                return Err(self.unexpected());
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(StatementType::CreateTable(CreateTable {
+            name,
+            columns,
+            unique,
+        }))
+    }
+
+    fn parse_create_index(&mut self, unique: bool) -> Result<StatementType, PrepareSyntaxError> {
+        let name = self.identifier()?;
+        self.expect_keyword("on")?;
+        let table = self.identifier()?;
+        self.expect_symbol("(")?;
+        let columns = self.list(Self::identifier)?;
+        self.expect_symbol(")")?;
+        Ok(StatementType::CreateIndex(CreateIndex {
+            unique,
+            name,
+            table,
+            columns,
+        }))
+    }
+
+    fn parse_column_def(&mut self) -> Result<ColumnDef, PrepareSyntaxError> {
+        let name = self.identifier()?;
+        let mut type_words = Vec::new();
+        while let Some(Token::Identifier(word)) = self.peek() {
+            if word.eq_ignore_ascii_case("primary") || word.eq_ignore_ascii_case("unique") {
+                break;
+            }
+            type_words.push(word.clone());
+            self.pos += 1;
+        }
+        let mut type_name = type_words.join(" ");
+        // Sizes such as VARCHAR(255) are kept in the type name but not enforced
+        if self.consume_symbol("(") {
+            let sizes = self.list(|parser| match parser.next()? {
+                Token::Number(n) => Ok(n),
+                _ => Err(PrepareSyntaxError::UnexpectedToken(
+                    "column size".to_string(),
+                )),
+            })?;
+            self.expect_symbol(")")?;
+            type_name = format!("{}({})", type_name, sizes.join(","));
+        }
+
+        let (mut primary_key, mut unique) = (false, false);
+        loop {
+            if self.consume_keyword("primary") {
+                self.expect_keyword("key")?;
+                primary_key = true;
+            } else if self.consume_keyword("unique") {
+                unique = true;
+            } else {
+                break;
+            }
+        }
+        Ok(ColumnDef {
+            name,
+            type_name,
+            primary_key,
+            unique,
+        })
+    }
+
+    fn parse_insert(&mut self) -> Result<StatementType, PrepareSyntaxError> {
+        self.expect_keyword("into")?;
+        let table = self.identifier()?;
+        let columns = if self.consume_symbol("(") {
+            let columns = self.list(Self::identifier)?;
+            self.expect_symbol(")")?;
+            Some(columns)
+        } else {
+            None
+        };
+        self.expect_keyword("values")?;
+        let rows = self.list(|parser| {
+            parser.expect_symbol("(")?;
+            let values = parser.list(|parser| match parser.peek() {
+                Some(Token::Parameter(_)) => parser.parse_parameter(),
+                _ => Ok(Expr::Literal(parser.parse_literal()?)),
+            })?;
+            parser.expect_symbol(")")?;
+            Ok(values)
+        })?;
+        Ok(StatementType::Insert(Insert {
+            table,
+            columns,
+            rows,
+        }))
+    }
+
+    fn parse_update(&mut self) -> Result<StatementType, PrepareSyntaxError> {
+        let table = self.identifier()?;
+        self.expect_keyword("set")?;
+        let assignments = self.list(|parser| {
+            let column = parser.identifier()?;
+            parser.expect_symbol("=")?;
+            Ok((column, parser.parse_expr()?))
+        })?;
+        let filter = self.parse_where()?;
+        Ok(StatementType::Update(Update {
+            table,
+            assignments,
+            filter,
+        }))
+    }
+
+    /// An optional `WHERE expr`.
+    fn parse_where(&mut self) -> Result<Option<Expr>, PrepareSyntaxError> {
+        match self.consume_keyword("where") {
+            true => Ok(Some(self.parse_expr()?)),
+            false => Ok(None),
+        }
+    }
+
+    fn parse_select(&mut self) -> Result<StatementType, PrepareSyntaxError> {
+        let columns = self.list(Self::parse_result_column)?;
+        self.expect_keyword("from")?;
+        let from = self.parse_table_ref()?;
+        let mut joins = Vec::new();
+        while let Some(kind) = self.parse_join_operator()? {
+            let table = self.parse_table_ref()?;
+            let on = if self.consume_keyword("on") {
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+            joins.push(Join { kind, table, on });
+        }
+        let filter = self.parse_where()?;
+        let group_by = if self.consume_keyword("group") {
+            self.expect_keyword("by")?;
+            self.list(Self::parse_expr)?
+        } else {
+            Vec::new()
+        };
+        let having = if self.consume_keyword("having") {
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+        let order_by = if self.consume_keyword("order") {
+            self.expect_keyword("by")?;
+            self.list(Self::parse_ordering_term)?
+        } else {
+            Vec::new()
+        };
+        let (mut limit, mut offset) = (None, 0);
+        if self.consume_keyword("limit") {
+            let count = self.parse_integer()?;
+            // `LIMIT offset, count` is the older spelling of LIMIT ... OFFSET
+            if self.consume_symbol(",") {
+                offset = count.max(0) as u64;
+                limit = u64::try_from(self.parse_integer()?).ok();
+            } else {
+                limit = u64::try_from(count).ok();
+                if self.consume_keyword("offset") {
+                    offset = self.parse_integer()?.max(0) as u64;
+                }
+            }
+        }
+        Ok(StatementType::Select(Select {
+            columns,
+            from,
+            joins,
+            filter,
+            group_by,
+            having,
+            order_by,
+            limit,
+            offset,
+        }))
+    }
+
+    /// An integer literal. A negative LIMIT means no limit, as in SQLite.
+    fn parse_integer(&mut self) -> Result<i64, PrepareSyntaxError> {
+        match self.parse_literal()? {
+            Value::Integer(i) => Ok(i),
+            value => Err(PrepareSyntaxError::InvalidNumber(value.to_string())),
+        }
+    }
+
+    fn parse_ordering_term(&mut self) -> Result<OrderingTerm, PrepareSyntaxError> {
+        let expr = self.parse_expr()?;
+        let descending = if self.consume_keyword("desc") {
+            true
+        } else {
+            self.consume_keyword("asc");
+            false
+        };
+        Ok(OrderingTerm { expr, descending })
+    }
+
+    fn parse_table_ref(&mut self) -> Result<TableRef, PrepareSyntaxError> {
+        let name = self.identifier()?;
+        let alias = self.parse_alias()?;
+        Ok(TableRef { name, alias })
+    }
+
+    /// Parses `[AS] alias`, where the AS may be left out as long as the alias
+    /// is not a keyword.
+    fn parse_alias(&mut self) -> Result<Option<String>, PrepareSyntaxError> {
+        let implicit_alias =
+            matches!(self.peek(), Some(token @ Token::Identifier(_)) if !is_reserved(token));
+        if !self.consume_keyword("as") && !implicit_alias {
+            return Ok(None);
+        }
+        if self.peek().is_some_and(is_reserved) {
+            return Err(self.unexpected());
+        }
+        Ok(Some(self.identifier()?))
+    }
+
+    /// Parses `,`, `[INNER | CROSS] JOIN` or `LEFT [OUTER] JOIN`.
+    fn parse_join_operator(&mut self) -> Result<Option<JoinKind>, PrepareSyntaxError> {
+        if self.consume_symbol(",") {
+            return Ok(Some(JoinKind::Inner));
+        }
+        let kind = if self.consume_keyword("left") {
+            self.consume_keyword("outer");
+            JoinKind::Left
+        } else if self.consume_keyword("inner")
+            || self.consume_keyword("cross")
+            || self.peek().is_some_and(|token| token.is_keyword("join"))
+        {
+            JoinKind::Inner
+        } else {
+            return Ok(None);
+        };
+        self.expect_keyword("join")?;
+        Ok(Some(kind))
+    }
+
+    fn parse_result_column(&mut self) -> Result<ResultColumn, PrepareSyntaxError> {
+        if self.consume_symbol("*") {
+            return Ok(ResultColumn::All);
+        }
+        if let (
+            Some(Token::Identifier(table)),
+            Some(Token::Symbol(".")),
+            Some(Token::Symbol("*")),
+        ) = (
+            self.tokens.get(self.pos),
+            self.tokens.get(self.pos + 1),
+            self.tokens.get(self.pos + 2),
+        ) {
+            let table = table.clone();
+            self.pos += 3;
+            return Ok(ResultColumn::TableAll(table));
+        }
+        let expr = self.parse_expr()?;
+        let alias = self.parse_alias()?;
+        Ok(ResultColumn::Expr { expr, alias })
+    }
+
+    /// Parses an expression. Operators bind from loosest to tightest as OR,
+    /// AND, NOT, equality-like tests, ordering comparisons, `+ -`, `* / %`,
+    /// `||` and finally unary signs.
+    pub fn parse_expr(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_and()?;
+        while self.consume_keyword("or") {
+            left = binary(BinaryOp::Or, left, self.parse_and()?);
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_not()?;
+        while self.consume_keyword("and") {
+            left = binary(BinaryOp::And, left, self.parse_not()?);
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        if self.consume_keyword("not") {
+            let expr = self.parse_not()?;
+            return Ok(Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(expr),
+            });
+        }
+        self.parse_equality()
+    }
+
+    fn parse_equality(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_comparison()?;
+        loop {
+            let op = if self.consume_symbol("=") || self.consume_symbol("==") {
+                BinaryOp::Equal
+            } else if self.consume_symbol("!=") || self.consume_symbol("<>") {
+                BinaryOp::NotEqual
+            } else if self.consume_keyword("is") {
+                let negated = self.consume_keyword("not");
+                left = Expr::Is {
+                    left: Box::new(left),
+                    right: Box::new(self.parse_comparison()?),
+                    negated,
+                };
+                continue;
+            } else {
+                let start = self.pos;
+                let negated = self.consume_keyword("not");
+                if self.consume_keyword("like") {
+                    left = Expr::Like {
+                        expr: Box::new(left),
+                        pattern: Box::new(self.parse_comparison()?),
+                        negated,
+                    };
+                } else if self.consume_keyword("in") {
+                    self.expect_symbol("(")?;
+                    let list = self.list(Self::parse_expr)?;
+                    self.expect_symbol(")")?;
+                    left = Expr::InList {
+                        expr: Box::new(left),
+                        list,
+                        negated,
+                    };
+                } else if self.consume_keyword("between") {
+                    let low = self.parse_comparison()?;
+                    self.expect_keyword("and")?;
+                    let high = self.parse_comparison()?;
+                    left = Expr::Between {
+                        expr: Box::new(left),
+                        low: Box::new(low),
+                        high: Box::new(high),
+                        negated,
+                    };
+                } else {
+                    self.pos = start;
+                    return Ok(left);
+                }
+                continue;
+            };
+            left = binary(op, left, self.parse_comparison()?);
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_additive()?;
+        loop {
+            let op = if self.consume_symbol("<=") {
+                BinaryOp::LessEqual
+            } else if self.consume_symbol(">=") {
+                BinaryOp::GreaterEqual
+            } else if self.consume_symbol("<") {
+                BinaryOp::Less
+            } else if self.consume_symbol(">") {
+                BinaryOp::Greater
+            } else {
+                return Ok(left);
+            };
+            left = binary(op, left, self.parse_additive()?);
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_multiplicative()?;
+        loop {
+            let op = if self.consume_symbol("+") {
+                BinaryOp::Add
+            } else if self.consume_symbol("-") {
+                BinaryOp::Subtract
+            } else {
+                return Ok(left);
+            };
+            left = binary(op, left, self.parse_multiplicative()?);
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_concat()?;
+        loop {
+            let op = if self.consume_symbol("*") {
+                BinaryOp::Multiply
+            } else if self.consume_symbol("/") {
+                BinaryOp::Divide
+            } else if self.consume_symbol("%") {
+                BinaryOp::Remainder
+            } else {
+                return Ok(left);
+            };
+            left = binary(op, left, self.parse_concat()?);
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let mut left = self.parse_unary()?;
+        while self.consume_symbol("||") {
+            left = binary(BinaryOp::Concat, left, self.parse_unary()?);
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        // Signed numbers are literals so that -9223372036854775808 fits
+        if matches!(self.peek(), Some(Token::Symbol("-" | "+")))
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::Number(_)))
+        {
+            return Ok(Expr::Literal(self.parse_literal()?));
+        }
+        let op = if self.consume_symbol("-") {
+            UnaryOp::Negate
+        } else if self.consume_symbol("+") {
+            UnaryOp::Plus
+        } else {
+            return self.parse_primary();
+        };
+        Ok(Expr::Unary {
+            op,
+            expr: Box::new(self.parse_unary()?),
+        })
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        if self.consume_symbol("(") {
+            let expr = self.parse_expr()?;
+            self.expect_symbol(")")?;
+            return Ok(expr);
+        }
+        if self.consume_keyword("case") {
+            return self.parse_case();
+        }
+        match self.peek() {
+            Some(token @ Token::Identifier(_))
+                if !["null", "true", "false"]
+                    .iter()
+                    .any(|k| token.is_keyword(k)) =>
+            {
+                if is_reserved(token) {
+                    return Err(self.unexpected());
+                }
+                let name = self.identifier()?;
+                if self.consume_symbol("(") {
+                    return self.parse_function_call(name);
+                }
+                if self.consume_symbol(".") {
+                    return Ok(Expr::Column(ColumnRef {
+                        table: Some(name),
+                        name: self.identifier()?,
+                    }));
+                }
+                Ok(Expr::Column(ColumnRef { table: None, name }))
+            }
+            Some(Token::Parameter(_)) => self.parse_parameter(),
+            _ => Ok(Expr::Literal(self.parse_literal()?)),
+        }
+    }
+
+    /// Parses `[operand] WHEN expr THEN expr ... [ELSE expr] END` after the
+    /// CASE.
+    fn parse_case(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let operand = match self.peek() {
+            Some(token) if token.is_keyword("when") => None,
+            _ => Some(Box::new(self.parse_expr()?)),
+        };
+        let mut branches = Vec::new();
+        while self.consume_keyword("when") {
+            let condition = self.parse_expr()?;
+            self.expect_keyword("then")?;
+            branches.push((condition, self.parse_expr()?));
+        }
+        if branches.is_empty() {
+            return Err(self.unexpected());
+        }
+        let default = match self.consume_keyword("else") {
+            true => Some(Box::new(self.parse_expr()?)),
+            false => None,
+        };
+        self.expect_keyword("end")?;
+        Ok(Expr::Case {
+            operand,
+            branches,
+            default,
+        })
+    }
+
+    /// Numbers a parameter as SQLite does: `?` takes the number after the
+    /// highest so far, `?NNN` takes NNN, and a `:name` seen before takes
+    /// the same number as the first time.
+    fn parse_parameter(&mut self) -> Result<Expr, PrepareSyntaxError> {
+        let Token::Parameter(text) = self.next()? else {
+            return Err(self.unexpected());
+        };
+        let n = match text.strip_prefix('?') {
+            Some("") => {
+                self.parameters.push(None);
+                return Ok(Expr::Parameter(self.parameters.len()));
+            }
+            Some(digits) => digits
+                .parse::<usize>()
+                .ok()
+                .filter(|n| (1..=MAX_PARAMETERS).contains(n))
+                .ok_or_else(|| PrepareSyntaxError::InvalidParameter(text.clone()))?,
+            None => match self
+                .parameters
+                .iter()
+                .position(|p| p.as_ref() == Some(&text))
+            {
+                Some(i) => return Ok(Expr::Parameter(i + 1)),
+                None => self.parameters.len() + 1,
+            },
+        };
+        if self.parameters.len() < n {
+            self.parameters.resize(n, None);
+        }
+        self.parameters[n - 1].get_or_insert(text);
+        Ok(Expr::Parameter(n))
+    }
+
+    /// Parses the arguments of a call after the opening parenthesis. Only
+    /// `count(*)` may be written with a star, which stands for no arguments.
+    fn parse_function_call(&mut self, name: String) -> Result<Expr, PrepareSyntaxError> {
+        let distinct = self.consume_keyword("distinct");
+        let args = if !distinct && name.eq_ignore_ascii_case("count") && self.consume_symbol("*") {
+            Vec::new()
+        } else {
+            self.list(Self::parse_expr)?
+        };
+        self.expect_symbol(")")?;
+        Ok(Expr::Function {
+            name,
+            args,
+            distinct,
+        })
+    }
+
+    fn parse_literal(&mut self) -> Result<Value, PrepareSyntaxError> {
+        match self.next()? {
+            Token::Identifier(word) if word.eq_ignore_ascii_case("null") => Ok(Value::Null),
+            Token::Identifier(word) if word.eq_ignore_ascii_case("true") => {
+                Ok(Value::Boolean(true))
+            }
+            Token::Identifier(word) if word.eq_ignore_ascii_case("false") => {
+                Ok(Value::Boolean(false))
+            }
+            Token::Symbol(sign @ ("-" | "+")) => match self.next()? {
+                Token::Number(n) => parse_number(&format!("{}{}", sign, n)),
+                token => Err(PrepareSyntaxError::UnexpectedToken(format!("{:?}", token))),
+            },
+            Token::Number(n) => parse_number(&n),
+            Token::String(s) => Ok(Value::Text(s)),
+            Token::Blob(b) => Ok(Value::Blob(b)),
+            token => Err(PrepareSyntaxError::UnexpectedToken(format!("{:?}", token))),
+        }
+    }
+}
+
+fn is_reserved(token: &Token) -> bool {
+    RESERVED.iter().any(|keyword| token.is_keyword(keyword))
+}
+
+fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+    Expr::Binary {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+/// Parses a possibly signed numeric literal. Integers that do not fit in 64
+/// bits become reals, and `0x` prefixed literals are read as hexadecimal.
+fn parse_number(text: &str) -> Result<Value, PrepareSyntaxError> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        return match u64::from_str_radix(hex, 16) {
+            // Hex literals are the bit pattern of the integer
+            Ok(bits) => Ok(Value::Integer(if negative {
+                (bits as i64).wrapping_neg()
+            } else {
+                bits as i64
+            })),
+            Err(_) => Err(PrepareSyntaxError::InvalidNumber(text.to_string())),
+        };
+    }
+
+    let signed = if negative {
+        format!("-{}", digits)
+    } else {
+        digits.to_string()
+    };
+    if let Ok(i) = signed.parse::<i64>() {
+        Ok(Value::Integer(i))
+    } else if let Ok(r) = signed.parse::<f64>() {
+        Ok(Value::Real(r))
+    } else {
+        Err(PrepareSyntaxError::InvalidNumber(text.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_create_table() {
+        let statement =
+            parse_statement("CREATE TABLE notes (id INTEGER PRIMARY KEY, body VARCHAR(255), data)")
+                .unwrap();
+        let StatementType::CreateTable(create) = statement else {
+            panic!("expected create table");
+        };
+        assert_eq!(create.name, "notes");
+        assert_eq!(
+            create.columns,
+            vec![
+                ColumnDef {
+                    name: "id".to_string(),
+                    type_name: "INTEGER".to_string(),
+                    primary_key: true,
+                    unique: false,
+                },
+                ColumnDef {
+                    name: "body".to_string(),
+                    type_name: "VARCHAR(255)".to_string(),
+                    primary_key: false,
+                    unique: false,
+                },
+                ColumnDef {
+                    name: "data".to_string(),
+                    type_name: String::new(),
+                    primary_key: false,
+                    unique: false,
+                },
+            ]
+        );
+        assert!(create.unique.is_empty());
+    }
+
+    #[test]
+    fn test_parse_unique_constraints() {
+        let statement = parse_statement(
+            "create table t (a integer unique primary key, b text unique, c, unique (b, c))",
+        )
+        .unwrap();
+        let StatementType::CreateTable(create) = statement else {
+            panic!("expected create table");
+        };
+        let flags = create
+            .columns
+            .iter()
+            .map(|c| (c.primary_key, c.unique))
+            .collect::<Vec<_>>();
+        assert_eq!(flags, [(true, true), (false, true), (false, false)]);
+        assert_eq!(create.unique, [["b", "c"]]);
+        assert!(parse_statement("create table t (a, unique (a), b)").is_err());
+        assert!(parse_statement("create table t (a, unique ())").is_err());
+    }
+
+    #[test]
+    fn test_parse_explain() {
+        let Ok(StatementType::Explain(explain)) =
+            parse_statement("EXPLAIN QUERY  PLAN select * from t where id = 1")
+        else {
+            panic!("expected explain");
+        };
+        assert!(explain.query_plan);
+        assert!(matches!(*explain.statement, StatementType::Select(_)));
+        assert!(matches!(
+            parse_statement("explain insert 1 a b"),
+            Ok(StatementType::Explain(Explain {
+                query_plan: false,
+                ..
+            }))
+        ));
+        assert!(parse_statement("explain query select 1").is_err());
+        assert!(parse_statement("explain explain select * from t").is_err());
+        assert_eq!(
+            parse_statement("explain"),
+            Err(PrepareSyntaxError::UnrecognizedStatement)
+        );
+    }
+
+    #[test]
+    fn test_parse_analyze() {
+        assert_eq!(
+            parse_statement("ANALYZE"),
+            Ok(StatementType::Analyze(Analyze { table: None }))
+        );
+        assert_eq!(
+            parse_statement("analyze users;"),
+            Ok(StatementType::Analyze(Analyze {
+                table: Some("users".to_string())
+            }))
+        );
+        assert!(parse_statement("analyze users teams").is_err());
+    }
+
+    #[test]
+    fn test_parse_transactions() {
+        assert_eq!(parse_statement("begin"), Ok(StatementType::Begin));
+        assert_eq!(
+            parse_statement("BEGIN TRANSACTION;"),
+            Ok(StatementType::Begin)
+        );
+        assert_eq!(parse_statement("commit"), Ok(StatementType::Commit));
+        assert_eq!(
+            parse_statement("end transaction"),
+            Ok(StatementType::Commit)
+        );
+        assert_eq!(parse_statement("rollback;"), Ok(StatementType::Rollback));
+        assert!(parse_statement("begin users").is_err());
+
+        let name = || "batch".to_string();
+        assert_eq!(
+            parse_statement("savepoint batch"),
+            Ok(StatementType::Savepoint(name()))
+        );
+        assert_eq!(
+            parse_statement("release batch"),
+            Ok(StatementType::Release(name()))
+        );
+        assert_eq!(
+            parse_statement("RELEASE SAVEPOINT batch;"),
+            Ok(StatementType::Release(name()))
+        );
+        assert_eq!(
+            parse_statement("rollback to batch"),
+            Ok(StatementType::RollbackTo(name()))
+        );
+        assert_eq!(
+            parse_statement("rollback transaction to savepoint batch"),
+            Ok(StatementType::RollbackTo(name()))
+        );
+        assert!(parse_statement("savepoint").is_err());
+        assert!(parse_statement("rollback to").is_err());
+    }
+
+    #[test]
+    fn test_parse_pragma() {
+        assert_eq!(
+            parse_statement("pragma journal_mode"),
+            Ok(StatementType::Pragma(Pragma {
+                name: "journal_mode".to_string(),
+                value: None,
+            }))
+        );
+        assert_eq!(
+            parse_statement("PRAGMA journal_mode = WAL;"),
+            Ok(StatementType::Pragma(Pragma {
+                name: "journal_mode".to_string(),
+                value: Some("WAL".to_string()),
+            }))
+        );
+        assert_eq!(
+            parse_statement("pragma journal_mode = 'delete'"),
+            Ok(StatementType::Pragma(Pragma {
+                name: "journal_mode".to_string(),
+                value: Some("delete".to_string()),
+            }))
+        );
+        assert!(parse_statement("pragma").is_err());
+        assert!(parse_statement("pragma journal_mode =").is_err());
+    }
+
+    #[test]
+    fn test_parse_create_index() {
+        assert_eq!(
+            parse_statement("create index users_name on users (username, email)"),
+            Ok(StatementType::CreateIndex(CreateIndex {
+                unique: false,
+                name: "users_name".to_string(),
+                table: "users".to_string(),
+                columns: vec!["username".to_string(), "email".to_string()],
+            }))
+        );
+        assert!(matches!(
+            parse_statement("create unique index users_email on users (email)"),
+            Ok(StatementType::CreateIndex(CreateIndex { unique: true, .. }))
+        ));
+        assert!(parse_statement("create unique users_email on users (email)").is_err());
+        assert!(parse_statement("create index on users (username)").is_err());
+        assert!(parse_statement("create index i on users ()").is_err());
+    }
+
+    #[test]
+    fn test_parse_insert_with_nulls() {
+        let statement =
+            parse_statement("insert into notes (id, body) values (1, NULL), (2, 'two');").unwrap();
+        assert_eq!(
+            statement,
+            StatementType::Insert(Insert {
+                table: "notes".to_string(),
+                columns: Some(vec!["id".to_string(), "body".to_string()]),
+                rows: vec![
+                    vec![Expr::Literal(Value::Integer(1)), Expr::Literal(Value::Null)],
+                    vec![
+                        Expr::Literal(Value::Integer(2)),
+                        Expr::Literal(Value::Text("two".to_string()))
+                    ],
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_legacy_insert_with_null() {
+        let statement = parse_statement("insert 1 null a@b.c").unwrap();
+        let StatementType::Insert(insert) = statement else {
+            panic!("expected insert");
+        };
+        assert_eq!(insert.table, LEGACY_TABLE);
+        assert_eq!(
+            insert.rows,
+            vec![vec![
+                Expr::Literal(Value::Integer(1)),
+                Expr::Literal(Value::Null),
+                Expr::Literal(Value::Text("a@b.c".to_string()))
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_parse_numeric_and_boolean_literals() {
+        let statement = parse_statement(
+            "insert into t values (-9223372036854775808, 9223372036854775808, -1.5e3, 0x10, +7, TRUE, false)",
+        )
+        .unwrap();
+        let StatementType::Insert(insert) = statement else {
+            panic!("expected insert");
+        };
+        assert_eq!(
+            insert.rows[0],
+            [
+                Value::Integer(i64::MIN),
+                Value::Real(9223372036854775808.0),
+                Value::Real(-1500.0),
+                Value::Integer(16),
+                Value::Integer(7),
+                Value::Boolean(true),
+                Value::Boolean(false),
+            ]
+            .map(Expr::Literal)
+        );
+        assert_eq!(
+            parse_statement("insert into t values (1.2.3)"),
+            Err(PrepareSyntaxError::InvalidNumber("1.2.3".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_parameters() {
+        let (statement, parameters) =
+            parse_with_parameters("insert into t values (?, :name, ?5, :name, ?)").unwrap();
+        let StatementType::Insert(insert) = statement else {
+            panic!("expected insert");
+        };
+        assert_eq!(insert.rows[0], [1, 2, 5, 2, 6].map(Expr::Parameter),);
+        assert_eq!(
+            parameters,
+            [None, Some(":name"), None, None, Some("?5"), None].map(|name| name.map(String::from))
+        );
+
+        let (statement, parameters) =
+            parse_with_parameters("select * from t where a = ?2 and b > ?").unwrap();
+        let StatementType::Select(select) = statement else {
+            panic!("expected select");
+        };
+        assert_eq!(select.filter.unwrap().to_string(), "a = ?2 AND b > ?3");
+        assert_eq!(parameters.len(), 3);
+        assert_eq!(
+            parse_with_parameters("select ?0"),
+            Err(PrepareSyntaxError::InvalidParameter("?0".to_string()))
+        );
+        assert_eq!(
+            parse_with_parameters("select ?32767"),
+            Err(PrepareSyntaxError::InvalidParameter("?32767".to_string()))
+        );
+    }
+
+    fn column(name: &str) -> Box<Expr> {
+        Box::new(Expr::Column(ColumnRef {
+            table: None,
+            name: name.to_string(),
+        }))
+    }
+
+    fn parse_where(clause: &str) -> Expr {
+        let statement = parse_statement(&format!("select * from t where {}", clause)).unwrap();
+        let StatementType::Select(select) = statement else {
+            panic!("expected select");
+        };
+        select.filter.unwrap()
+    }
+
+    #[test]
+    fn test_parse_where_precedence() {
+        // a = 1 OR (b = 2 AND (NOT c))
+        assert_eq!(
+            parse_where("a = 1 or b = 2 and not c"),
+            binary(
+                BinaryOp::Or,
+                binary(
+                    BinaryOp::Equal,
+                    *column("a"),
+                    Expr::Literal(Value::Integer(1))
+                ),
+                binary(
+                    BinaryOp::And,
+                    binary(
+                        BinaryOp::Equal,
+                        *column("b"),
+                        Expr::Literal(Value::Integer(2))
+                    ),
+                    Expr::Unary {
+                        op: UnaryOp::Not,
+                        expr: column("c")
+                    }
+                )
+            )
+        );
+        // a + b * -2 > t.c
+        assert_eq!(
+            parse_where("a + b * -2 > t.c"),
+            binary(
+                BinaryOp::Greater,
+                binary(
+                    BinaryOp::Add,
+                    *column("a"),
+                    binary(
+                        BinaryOp::Multiply,
+                        *column("b"),
+                        Expr::Literal(Value::Integer(-2))
+                    )
+                ),
+                Expr::Column(ColumnRef {
+                    table: Some("t".to_string()),
+                    name: "c".to_string()
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_result_columns() {
+        let statement = parse_statement("select *, t.*, a as x, b y, c from t").unwrap();
+        let StatementType::Select(select) = statement else {
+            panic!("expected select");
+        };
+        assert_eq!(
+            select.columns,
+            vec![
+                ResultColumn::All,
+                ResultColumn::TableAll("t".to_string()),
+                ResultColumn::Expr {
+                    expr: *column("a"),
+                    alias: Some("x".to_string())
+                },
+                ResultColumn::Expr {
+                    expr: *column("b"),
+                    alias: Some("y".to_string())
+                },
+                ResultColumn::Expr {
+                    expr: *column("c"),
+                    alias: None
+                },
+            ]
+        );
+        assert_eq!(
+            parse_statement("select a as from t"),
+            Err(PrepareSyntaxError::UnexpectedToken(
+                "Identifier(\"from\")".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_order_by() {
+        let statement =
+            parse_statement("select * from t where a order by a desc, b + 1 asc, c").unwrap();
+        let StatementType::Select(select) = statement else {
+            panic!("expected select");
+        };
+        assert_eq!(select.filter, Some(*column("a")));
+        assert_eq!(
+            select.order_by,
+            vec![
+                OrderingTerm {
+                    expr: *column("a"),
+                    descending: true
+                },
+                OrderingTerm {
+                    expr: binary(
+                        BinaryOp::Add,
+                        *column("b"),
+                        Expr::Literal(Value::Integer(1))
+                    ),
+                    descending: false
+                },
+                OrderingTerm {
+                    expr: *column("c"),
+                    descending: false
+                },
+            ]
+        );
+        assert_eq!(
+            parse_statement("select * from t order a"),
+            Err(PrepareSyntaxError::UnexpectedToken(
+                "Identifier(\"a\")".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_limit_offset() {
+        let limits = |sql: &str| match parse_statement(sql) {
+            Ok(StatementType::Select(select)) => (select.limit, select.offset),
+            other => panic!("expected select, got {:?}", other),
+        };
+        assert_eq!(limits("select * from t"), (None, 0));
+        assert_eq!(limits("select * from t limit 10"), (Some(10), 0));
+        assert_eq!(limits("select * from t limit 10 offset 5"), (Some(10), 5));
+        assert_eq!(limits("select * from t limit 5, 10"), (Some(10), 5));
+        assert_eq!(limits("select * from t limit -1 offset -3"), (None, 0));
+        assert_eq!(
+            parse_statement("select * from t limit 1.5"),
+            Err(PrepareSyntaxError::InvalidNumber("1.5".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_group_by() {
+        let statement = parse_statement(
+            "select k, count(*), sum(distinct v) from t group by k, v > 1 having count(*) > 1",
+        )
+        .unwrap();
+        let StatementType::Select(select) = statement else {
+            panic!("expected select");
+        };
+        let count = Expr::Function {
+            name: "count".to_string(),
+            args: vec![],
+            distinct: false,
+        };
+        assert_eq!(
+            select.columns[2],
+            ResultColumn::Expr {
+                expr: Expr::Function {
+                    name: "sum".to_string(),
+                    args: vec![*column("v")],
+                    distinct: true
+                },
+                alias: None
+            }
+        );
+        assert_eq!(
+            select.group_by,
+            vec![
+                *column("k"),
+                binary(
+                    BinaryOp::Greater,
+                    *column("v"),
+                    Expr::Literal(Value::Integer(1))
+                )
+            ]
+        );
+        assert_eq!(
+            select.having,
+            Some(binary(
+                BinaryOp::Greater,
+                count,
+                Expr::Literal(Value::Integer(1))
+            ))
+        );
+        assert_eq!(
+            parse_statement("select sum(*) from t"),
+            Err(PrepareSyntaxError::UnexpectedToken(
+                "Symbol(\"*\")".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_joins() {
+        let statement =
+            parse_statement("select * from a x join b on x.id = b.id left outer join c as y, d")
+                .unwrap();
+        let StatementType::Select(select) = statement else {
+            panic!("expected select");
+        };
+        assert_eq!(
+            select.from,
+            TableRef {
+                name: "a".to_string(),
+                alias: Some("x".to_string())
+            }
+        );
+        let joins = select
+            .joins
+            .iter()
+            .map(|join| (join.kind, join.table.scope_name(), join.on.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            joins,
+            [
+                (JoinKind::Inner, "b", true),
+                (JoinKind::Left, "y", false),
+                (JoinKind::Inner, "d", false)
+            ]
+        );
+        assert_eq!(
+            parse_statement("select * from a left b"),
+            Err(PrepareSyntaxError::UnexpectedToken(
+                "Identifier(\"b\")".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_where_predicates() {
+        assert_eq!(
+            parse_where("a is not null"),
+            Expr::Is {
+                left: column("a"),
+                right: Box::new(Expr::Literal(Value::Null)),
+                negated: true
+            }
+        );
+        assert_eq!(
+            parse_where("a not between 1 and 2 and b"),
+            binary(
+                BinaryOp::And,
+                Expr::Between {
+                    expr: column("a"),
+                    low: Box::new(Expr::Literal(Value::Integer(1))),
+                    high: Box::new(Expr::Literal(Value::Integer(2))),
+                    negated: true
+                },
+                *column("b")
+            )
+        );
+        assert_eq!(
+            parse_where("a not in (1, 'x')"),
+            Expr::InList {
+                expr: column("a"),
+                list: vec![
+                    Expr::Literal(Value::Integer(1)),
+                    Expr::Literal(Value::Text("x".to_string()))
+                ],
+                negated: true
+            }
+        );
+        assert_eq!(
+            parse_where("a like '%x'"),
+            Expr::Like {
+                expr: column("a"),
+                pattern: Box::new(Expr::Literal(Value::Text("%x".to_string()))),
+                negated: false
+            }
+        );
+        assert_eq!(
+            parse_statement("select * from t where a ="),
+            Err(PrepareSyntaxError::UnexpectedEnd)
+        );
+        assert_eq!(
+            parse_statement("select * from t where and"),
+            Err(PrepareSyntaxError::UnexpectedToken(
+                "Identifier(\"and\")".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_update_and_delete() {
+        assert_eq!(
+            parse_statement("update users set name = 'x', team = team + 1 where id = 2"),
+            Ok(StatementType::Update(Update {
+                table: "users".to_string(),
+                assignments: vec![
+                    (
+                        "name".to_string(),
+                        Expr::Literal(Value::Text("x".to_string()))
+                    ),
+                    (
+                        "team".to_string(),
+                        binary(
+                            BinaryOp::Add,
+                            *column("team"),
+                            Expr::Literal(Value::Integer(1))
+                        )
+                    ),
+                ],
+                filter: Some(parse_where("id = 2")),
+            }))
+        );
+        assert_eq!(
+            parse_statement("DELETE FROM users;"),
+            Ok(StatementType::Delete(Delete {
+                table: "users".to_string(),
+                filter: None,
+            }))
+        );
+        // Without SET or FROM they are the legacy commands
+        assert_eq!(
+            parse_statement("delete 3"),
+            Ok(StatementType::LegacyDelete(LegacyDelete {
+                table: LEGACY_TABLE.to_string(),
+                key: Value::Integer(3),
+            }))
+        );
+        assert!(parse_statement("update users set where id = 1").is_err());
+        assert!(parse_statement("delete from").is_err());
+    }
+
+    #[test]
+    fn test_parse_case() {
+        let literal = |value: i64| Expr::Literal(Value::Integer(value));
+        assert_eq!(
+            parse_where("case team when 1 then 2 when 3 then 4 else 5 end"),
+            Expr::Case {
+                operand: Some(column("team")),
+                branches: vec![(literal(1), literal(2)), (literal(3), literal(4))],
+                default: Some(Box::new(literal(5))),
+            }
+        );
+        assert_eq!(
+            parse_where("case when team is null then 1 end"),
+            Expr::Case {
+                operand: None,
+                branches: vec![(
+                    Expr::Is {
+                        left: column("team"),
+                        right: Box::new(Expr::Literal(Value::Null)),
+                        negated: false,
+                    },
+                    literal(1)
+                )],
+                default: None,
+            }
+        );
+        assert!(parse_statement("select case end from t").is_err());
+        assert!(parse_statement("select case when 1 then 2 from t").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_statement("select * from"),
+            Err(PrepareSyntaxError::UnexpectedEnd)
+        );
+        assert_eq!(
+            parse_statement("select * from t alias garbage"),
+            Err(PrepareSyntaxError::UnexpectedToken(
+                "Identifier(\"garbage\")".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_statement("drop table t"),
+            Err(PrepareSyntaxError::UnrecognizedStatement)
+        );
+    }
+}
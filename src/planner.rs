@@ -0,0 +1,826 @@
+use crate::expr::{BinaryOp, Expr};
+use crate::schema::{IndexSchema, TableSchema};
+use crate::stats::TableStats;
+
+/// How the rows of one table of a query are read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessPath {
+    /// Every row, in row order.
+    FullScan,
+    /// The rows an index finds for the WHERE or ON predicates.
+    Index(Box<IndexScan>),
+}
+
+/// A search of an index for the entries whose leading columns equal
+/// `equal`, with the column after them between `lower` and `upper`.
+///
+/// The values are expressions over the tables to the left, so an index can
+/// be searched once per row of the tables before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexScan {
+    pub index: IndexSchema,
+    /// Whether `index` is the one backing the table's primary key.
+    pub primary_key: bool,
+    pub equal: Vec<Expr>,
+    pub lower: Option<Bound>,
+    pub upper: Option<Bound>,
+    /// The index holds every column the query uses, so rows are built from
+    /// its keys without reading the table.
+    pub covering: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bound {
+    pub value: Expr,
+    pub inclusive: bool,
+}
+
+impl IndexScan {
+    /// At most one row can match: every column of a unique index is fixed.
+    pub fn is_point_lookup(&self) -> bool {
+        self.index.unique && self.equal.len() == self.index.columns.len()
+    }
+}
+
+/// A predicate `column op value` on one column of the table.
+struct Constraint {
+    column: usize,
+    op: BinaryOp,
+    value: Expr,
+}
+
+// Without statistics a table is taken to hold a million rows, of which ten
+// share a value of an indexed column, as SQLite assumes.
+const DEFAULT_ROWS: f64 = 1_000_000.0;
+const DEFAULT_EQUAL_ROWS: f64 = 10.0;
+/// The fraction of rows taken to pass each bound of a range.
+const RANGE_SELECTIVITY: f64 = 0.25;
+/// The fraction of rows taken to pass a predicate that is not a constraint.
+const OTHER_SELECTIVITY: f64 = 0.25;
+/// Tables up to this many are joined in the best of every order, larger
+/// joins are ordered greedily.
+const MAX_EXHAUSTIVE_TABLES: usize = 6;
+
+/// What the planner knows of one table of a query.
+pub struct TableInfo<'a> {
+    pub schema: &'a TableSchema,
+    /// Position of the table's first column in the joined rows.
+    pub offset: usize,
+    pub indexes: Vec<IndexSchema>,
+    /// The statistics of the last ANALYZE of the table, if any.
+    pub stats: Option<&'a TableStats>,
+    /// The columns of the table the query uses.
+    pub used_columns: Vec<usize>,
+}
+
+impl TableInfo<'_> {
+    fn width(&self) -> usize {
+        self.schema.columns.len()
+    }
+
+    fn contains(&self, column: usize) -> bool {
+        (self.offset..self.offset + self.width()).contains(&column)
+    }
+
+    fn rows(&self) -> f64 {
+        self.stats.map_or(DEFAULT_ROWS, |stats| stats.rows as f64)
+    }
+
+    /// The constraints on the table's columns in a predicate. The other
+    /// side of the comparison may only use the `available` columns.
+    fn constraints(&self, predicate: &Expr, available: &[bool]) -> Vec<Constraint> {
+        let column = |expr: &Expr| match expr {
+            Expr::ColumnIndex(index) if self.contains(*index) => Some(index - self.offset),
+            _ => None,
+        };
+        let is_value = |expr: &Expr| {
+            expr.column_indexes()
+                .iter()
+                .all(|&index| available.get(index) == Some(&true))
+        };
+        match predicate {
+            Expr::Binary { op, left, right } => {
+                // `5 > id` is the same as `id < 5`
+                let flipped = match op {
+                    BinaryOp::Equal => BinaryOp::Equal,
+                    BinaryOp::Less => BinaryOp::Greater,
+                    BinaryOp::LessEqual => BinaryOp::GreaterEqual,
+                    BinaryOp::Greater => BinaryOp::Less,
+                    BinaryOp::GreaterEqual => BinaryOp::LessEqual,
+                    _ => return Vec::new(),
+                };
+                if let (Some(column), true) = (column(left), is_value(right)) {
+                    vec![Constraint {
+                        column,
+                        op: *op,
+                        value: (**right).clone(),
+                    }]
+                } else if let (Some(column), true) = (column(right), is_value(left)) {
+                    vec![Constraint {
+                        column,
+                        op: flipped,
+                        value: (**left).clone(),
+                    }]
+                } else {
+                    Vec::new()
+                }
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated: false,
+            } => match column(expr) {
+                Some(column) if is_value(low) && is_value(high) => vec![
+                    Constraint {
+                        column,
+                        op: BinaryOp::GreaterEqual,
+                        value: (**low).clone(),
+                    },
+                    Constraint {
+                        column,
+                        op: BinaryOp::LessEqual,
+                        value: (**high).clone(),
+                    },
+                ],
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// The fraction of rows that satisfy the constraints on `column`.
+    fn column_selectivity(&self, column: usize, constraints: &[Constraint]) -> f64 {
+        let find = |ops: &[BinaryOp]| {
+            constraints
+                .iter()
+                .find(|c| c.column == column && ops.contains(&c.op))
+        };
+        let stats = self
+            .stats
+            .filter(|stats| stats.rows > 0)
+            .and_then(|stats| Some((stats.rows, stats.columns.get(column)?)));
+        if find(&[BinaryOp::Equal]).is_some() {
+            if let Some((rows, stats)) = stats {
+                return stats.equal_selectivity(rows);
+            }
+            let unique = self
+                .indexes
+                .iter()
+                .any(|index| index.unique && index.columns == [column]);
+            let rows = if unique { 1.0 } else { DEFAULT_EQUAL_ROWS };
+            return (rows / self.rows()).min(1.0);
+        }
+        let lower = find(&[BinaryOp::Greater, BinaryOp::GreaterEqual]);
+        let upper = find(&[BinaryOp::Less, BinaryOp::LessEqual]);
+        // Bounds that do not depend on other tables or on parameters are
+        // looked up in the histogram
+        let constant = |bound: Option<&Constraint>| match bound {
+            Some(c) if c.value.column_indexes().is_empty() && !c.value.has_parameters() => {
+                Some(Some(c.value.evaluate(&[])))
+            }
+            Some(_) => None,
+            None => Some(None),
+        };
+        if let (Some((rows, stats)), Some(low), Some(high)) =
+            (stats, constant(lower), constant(upper))
+        {
+            if let Some(selectivity) = stats.range_selectivity(rows, low.as_ref(), high.as_ref()) {
+                return selectivity;
+            }
+        }
+        [lower, upper]
+            .iter()
+            .flatten()
+            .map(|_| RANGE_SELECTIVITY)
+            .product()
+    }
+
+    /// The fraction of rows for which every predicate holds, taking them
+    /// to be independent of each other.
+    fn selectivity(&self, predicates: &[&Expr], available: &[bool]) -> f64 {
+        let mut constraints = Vec::new();
+        let mut selectivity = 1.0;
+        for predicate in predicates {
+            let found = self.constraints(predicate, available);
+            if found.is_empty() {
+                selectivity *= OTHER_SELECTIVITY;
+            }
+            constraints.extend(found);
+        }
+        let mut columns = constraints.iter().map(|c| c.column).collect::<Vec<_>>();
+        columns.sort_unstable();
+        columns.dedup();
+        for column in columns {
+            selectivity *= self.column_selectivity(column, &constraints);
+        }
+        selectivity
+    }
+
+    /// The estimated cost of reading the rows the path finds, in rows read,
+    /// and how many it finds.
+    fn estimate(&self, path: &AccessPath, constraints: &[Constraint]) -> (f64, f64) {
+        let rows = self.rows();
+        let AccessPath::Index(scan) = path else {
+            return (rows, rows);
+        };
+        let matched = if scan.is_point_lookup() {
+            rows.min(1.0)
+        } else {
+            let num_bounded =
+                scan.equal.len() + usize::from(scan.lower.is_some() || scan.upper.is_some());
+            let selectivity: f64 = scan.index.columns[..num_bounded]
+                .iter()
+                .map(|&column| self.column_selectivity(column, constraints))
+                .product();
+            rows * selectivity
+        };
+        // Rows outside a covering index are looked up in the table as well
+        let per_row = if scan.covering { 1.0 } else { 2.0 };
+        ((rows + 1.0).log2() + matched * per_row, matched)
+    }
+}
+
+/// Builds the best search of `index` the constraints allow, `None` if they
+/// do not restrict its first column.
+fn index_scan(
+    schema: &TableSchema,
+    index: &IndexSchema,
+    constraints: &[Constraint],
+    used_columns: &[usize],
+) -> Option<IndexScan> {
+    let find = |column: usize, ops: &[BinaryOp]| {
+        constraints
+            .iter()
+            .find(|c| c.column == column && ops.contains(&c.op))
+    };
+    let mut equal = Vec::new();
+    for &column in &index.columns {
+        match find(column, &[BinaryOp::Equal]) {
+            Some(constraint) => equal.push(constraint.value.clone()),
+            None => break,
+        }
+    }
+    let (mut lower, mut upper) = (None, None);
+    if let Some(&column) = index.columns.get(equal.len()) {
+        lower = find(column, &[BinaryOp::Greater, BinaryOp::GreaterEqual]).map(|c| Bound {
+            value: c.value.clone(),
+            inclusive: c.op == BinaryOp::GreaterEqual,
+        });
+        upper = find(column, &[BinaryOp::Less, BinaryOp::LessEqual]).map(|c| Bound {
+            value: c.value.clone(),
+            inclusive: c.op == BinaryOp::LessEqual,
+        });
+    }
+    if equal.is_empty() && lower.is_none() && upper.is_none() {
+        return None;
+    }
+    let primary_key = schema.columns[index.primary_key].primary_key
+        && index.unique
+        && index.columns == [index.primary_key];
+    let covering = used_columns
+        .iter()
+        .all(|column| *column == index.primary_key || index.columns.contains(column));
+    Some(IndexScan {
+        index: index.clone(),
+        primary_key,
+        equal,
+        lower,
+        upper,
+        covering,
+    })
+}
+
+/// Chooses how to read a table given the predicates that must hold for its
+/// rows, whose values may use the `available` columns of the joined rows.
+/// Returns the path and the estimated cost of one search through it.
+///
+/// The cheapest path wins. Of equally cheap ones a lookup of a single row
+/// through a unique index comes first, the primary key's first; then the
+/// index with the most leading columns fixed by equalities; then one with a
+/// range on the next column; then a covering index; then a full scan.
+pub fn plan_access(
+    table: &TableInfo,
+    predicates: &[&Expr],
+    available: &[bool],
+) -> (AccessPath, f64) {
+    let constraints = predicates
+        .iter()
+        .flat_map(|predicate| table.constraints(predicate, available))
+        .collect::<Vec<_>>();
+    let rank = |scan: &IndexScan| {
+        (
+            scan.is_point_lookup(),
+            scan.is_point_lookup() && scan.primary_key,
+            scan.equal.len(),
+            scan.lower.is_some() || scan.upper.is_some(),
+            scan.covering,
+            scan.primary_key,
+        )
+    };
+    let mut scans = table
+        .indexes
+        .iter()
+        .filter_map(|index| index_scan(table.schema, index, &constraints, &table.used_columns))
+        .collect::<Vec<_>>();
+    // Stable, so the first of equally ranked indexes wins
+    scans.sort_by_key(|scan| std::cmp::Reverse(rank(scan)));
+    let full_scan = table.estimate(&AccessPath::FullScan, &constraints).0;
+    let mut best = (AccessPath::FullScan, full_scan);
+    for scan in scans.into_iter().rev() {
+        let path = AccessPath::Index(Box::new(scan));
+        let cost = table.estimate(&path, &constraints).0;
+        if cost <= best.1 {
+            best = (path, cost);
+        }
+    }
+    best
+}
+
+/// How a joined table finds the rows matching the rows before it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinStrategy {
+    /// Reads the table through its access path for every row before it.
+    NestedLoop,
+    /// Loads the rows the access path finds into a hash table keyed by
+    /// `build`, evaluated on the table's own rows, and looks up `probe`,
+    /// evaluated on the rows before it.
+    Hash { probe: Expr, build: Expr },
+}
+
+/// One table of a join to order.
+pub struct JoinTable<'a> {
+    pub info: TableInfo<'a>,
+    /// The conjuncts of the ON clause of a LEFT JOIN. The ON clause of an
+    /// inner join is part of the WHERE clause.
+    pub left_join_on: Option<Vec<Expr>>,
+}
+
+/// One loop of a join, in the order they nest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    /// Position of the table in the FROM clause.
+    pub source: usize,
+    pub access: AccessPath,
+    pub strategy: JoinStrategy,
+    /// The conjuncts of a LEFT JOIN's ON clause, which decide whether a row
+    /// of the table matches.
+    pub on: Vec<Expr>,
+    /// The WHERE conjuncts checked once the table's row is loaded, which
+    /// are the ones the tables of this and the outer loops are enough for.
+    pub conditions: Vec<Expr>,
+}
+
+/// A table placed next in a join order.
+struct Step {
+    level: Level,
+    cost: f64,
+    /// The estimated number of joined rows so far.
+    rows: f64,
+}
+
+struct JoinPlanner<'a, 'b> {
+    tables: &'b [JoinTable<'a>],
+    predicates: &'b [Expr],
+    /// The tables each predicate reads.
+    predicate_tables: Vec<Vec<usize>>,
+    width: usize,
+}
+
+impl JoinPlanner<'_, '_> {
+    /// Whether the table may be placed after the `placed` ones: a LEFT
+    /// JOIN needs every table before it in the FROM clause.
+    fn can_place(&self, placed: &[bool], table: usize) -> bool {
+        !placed[table]
+            && (self.tables[table].left_join_on.is_none() || placed[..table].iter().all(|&p| p))
+    }
+
+    /// Picks how to read the table after the `placed` ones, which yield
+    /// `outer_rows` rows.
+    fn step(&self, placed: &[bool], table: usize, outer_rows: f64) -> Step {
+        let join_table = &self.tables[table];
+        let info = &join_table.info;
+        let mut available = vec![false; self.width];
+        for (other, _) in placed.iter().enumerate().filter(|(_, &p)| p) {
+            let other = &self.tables[other].info;
+            available[other.offset..other.offset + other.width()].fill(true);
+        }
+        let first = !placed.contains(&true);
+        let conditions = self
+            .predicates
+            .iter()
+            .zip(&self.predicate_tables)
+            .filter(|(_, tables)| {
+                tables.iter().all(|&t| t == table || placed[t])
+                    && (first || tables.contains(&table))
+            })
+            .map(|(predicate, _)| predicate.clone())
+            .collect::<Vec<_>>();
+        let on = join_table.left_join_on.clone().unwrap_or_default();
+        let restricting = match join_table.left_join_on {
+            Some(_) => on.iter().collect::<Vec<_>>(),
+            None => conditions.iter().collect(),
+        };
+
+        let (mut access, search_cost) = plan_access(info, &restricting, &available);
+        let mut cost = outer_rows * search_cost;
+        let matched = info.rows() * info.selectivity(&restricting, &available);
+        let mut strategy = JoinStrategy::NestedLoop;
+        let hash_key = match access.depends_on(&available) {
+            true => None,
+            false => hash_key(info, &restricting, &available),
+        };
+        if let Some((probe, build)) = hash_key {
+            // The hash table holds the rows the predicates on this table
+            // alone find, and costs about as much to build as an index
+            let none = vec![false; self.width];
+            let own = restricting
+                .iter()
+                .copied()
+                .filter(|predicate| predicate.column_indexes().iter().all(|&c| info.contains(c)))
+                .collect::<Vec<_>>();
+            let (build_access, build_cost) = plan_access(info, &own, &none);
+            let build_rows = info.rows() * info.selectivity(&own, &none);
+            let hash_cost =
+                build_cost + build_rows * (build_rows + 1.0).log2() + outer_rows * (1.0 + matched);
+            if hash_cost < cost {
+                access = build_access;
+                cost = hash_cost;
+                strategy = JoinStrategy::Hash { probe, build };
+            }
+        }
+        let rows = match join_table.left_join_on {
+            Some(_) => {
+                let conditions = conditions.iter().collect::<Vec<_>>();
+                outer_rows * matched.max(1.0) * info.selectivity(&conditions, &available)
+            }
+            None => outer_rows * matched,
+        };
+        Step {
+            level: Level {
+                source: table,
+                access,
+                strategy,
+                on,
+                conditions,
+            },
+            cost,
+            rows,
+        }
+    }
+
+    /// Tries every order that starts with `order`, keeping the cheapest
+    /// complete one in `best`. Orders that cost more than it already are
+    /// given up early.
+    fn search(
+        &self,
+        order: &mut Vec<usize>,
+        placed: &mut [bool],
+        cost: f64,
+        rows: f64,
+        best: &mut Option<(f64, Vec<usize>)>,
+    ) {
+        if best.as_ref().is_some_and(|(best, _)| cost >= *best) {
+            return;
+        }
+        if order.len() == self.tables.len() {
+            *best = Some((cost, order.clone()));
+            return;
+        }
+        for table in 0..self.tables.len() {
+            if !self.can_place(placed, table) {
+                continue;
+            }
+            let step = self.step(placed, table, rows);
+            placed[table] = true;
+            order.push(table);
+            self.search(order, placed, cost + step.cost, step.rows, best);
+            order.pop();
+            placed[table] = false;
+        }
+    }
+
+    /// Places the table that is cheapest to add next until all are placed.
+    fn greedy(&self) -> Vec<usize> {
+        let mut placed = vec![false; self.tables.len()];
+        let mut order = Vec::new();
+        let mut rows = 1.0;
+        while order.len() < self.tables.len() {
+            let (table, step) = (0..self.tables.len())
+                .filter(|&table| self.can_place(&placed, table))
+                .map(|table| (table, self.step(&placed, table, rows)))
+                .reduce(|best, next| {
+                    if next.1.cost < best.1.cost {
+                        next
+                    } else {
+                        best
+                    }
+                })
+                .expect("the first table not placed can always be placed");
+            placed[table] = true;
+            order.push(table);
+            rows = step.rows;
+        }
+        order
+    }
+}
+
+/// The equality between an expression over the available columns and one
+/// over the table alone that a hash join can look rows up by, with the
+/// table's side rewritten to be evaluated on its own rows.
+fn hash_key(info: &TableInfo, predicates: &[&Expr], available: &[bool]) -> Option<(Expr, Expr)> {
+    let outer = |expr: &Expr| {
+        expr.column_indexes()
+            .iter()
+            .all(|&index| available.get(index) == Some(&true))
+    };
+    let own = |expr: &Expr| {
+        let indexes = expr.column_indexes();
+        !indexes.is_empty() && indexes.iter().all(|&index| info.contains(index))
+    };
+    predicates.iter().find_map(|predicate| {
+        let Expr::Binary {
+            op: BinaryOp::Equal,
+            left,
+            right,
+        } = predicate
+        else {
+            return None;
+        };
+        let (probe, build) = if outer(left) && own(right) {
+            (left, right)
+        } else if outer(right) && own(left) {
+            (right, left)
+        } else {
+            return None;
+        };
+        let build = build
+            .transform(&mut |expr| match expr {
+                Expr::ColumnIndex(index) => {
+                    Ok::<_, ()>(Some(Expr::ColumnIndex(index - info.offset)))
+                }
+                _ => Ok(None),
+            })
+            .unwrap();
+        Some(((**probe).clone(), build))
+    })
+}
+
+/// Orders the tables of a join by estimated cost and picks how to read
+/// each. `predicates` are the conjuncts of the WHERE clause and of the ON
+/// clauses of inner joins; each is checked in the outermost loop whose
+/// tables it needs, and helps the table of that loop find its rows. Of
+/// orders that cost the same the one closest to the FROM clause wins.
+pub fn plan_joins(tables: &[JoinTable], predicates: &[Expr]) -> Vec<Level> {
+    let predicate_tables = predicates
+        .iter()
+        .map(|predicate| {
+            let columns = predicate.column_indexes();
+            (0..tables.len())
+                .filter(|&table| columns.iter().any(|&c| tables[table].info.contains(c)))
+                .collect()
+        })
+        .collect();
+    let planner = JoinPlanner {
+        tables,
+        predicates,
+        predicate_tables,
+        width: tables.iter().map(|table| table.info.width()).sum(),
+    };
+    let order = if tables.len() <= MAX_EXHAUSTIVE_TABLES {
+        let mut best = None;
+        let mut placed = vec![false; tables.len()];
+        planner.search(&mut Vec::new(), &mut placed, 0.0, 1.0, &mut best);
+        best.expect("some order places every table").1
+    } else {
+        planner.greedy()
+    };
+
+    let mut placed = vec![false; tables.len()];
+    let mut rows = 1.0;
+    let mut levels = Vec::new();
+    for table in order {
+        let step = planner.step(&placed, table, rows);
+        placed[table] = true;
+        rows = step.rows;
+        levels.push(step.level);
+    }
+    levels
+}
+
+impl AccessPath {
+    /// Describes the path the way EXPLAIN QUERY PLAN shows it, such as
+    /// `SEARCH users USING INDEX users_email (email=?)`.
+    pub fn describe(&self, schema: &TableSchema, name: &str) -> String {
+        match self.using(schema) {
+            Some(using) => format!("SEARCH {} USING {}", name, using),
+            None => format!("SCAN {}", name),
+        }
+    }
+
+    /// The index an index search uses and the constraints it searches for,
+    /// `None` for a full scan.
+    pub fn using(&self, schema: &TableSchema) -> Option<String> {
+        let AccessPath::Index(scan) = self else {
+            return None;
+        };
+        let column = |index: usize| &schema.columns[scan.index.columns[index]].name;
+        let mut terms = (0..scan.equal.len())
+            .map(|i| format!("{}=?", column(i)))
+            .collect::<Vec<_>>();
+        let bounds = [(&scan.lower, ">"), (&scan.upper, "<")];
+        for (bound, op) in bounds {
+            if let Some(bound) = bound {
+                let equal = if bound.inclusive { "=" } else { "" };
+                terms.push(format!("{}{}{}?", column(scan.equal.len()), op, equal));
+            }
+        }
+        let index = if scan.primary_key {
+            "PRIMARY KEY".to_string()
+        } else if scan.covering {
+            format!("COVERING INDEX {}", scan.index.name)
+        } else {
+            format!("INDEX {}", scan.index.name)
+        };
+        Some(format!("{} ({})", index, terms.join(" AND ")))
+    }
+
+    /// Whether the path looks up values taken from the `available`
+    /// columns, the ones the outer loops load.
+    pub fn depends_on(&self, available: &[bool]) -> bool {
+        let AccessPath::Index(scan) = self else {
+            return false;
+        };
+        scan.equal
+            .iter()
+            .chain(scan.lower.iter().map(|bound| &bound.value))
+            .chain(scan.upper.iter().map(|bound| &bound.value))
+            .flat_map(Expr::column_indexes)
+            .any(|index| available.get(index) == Some(&true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, StatementType};
+    use crate::stats::ColumnStats;
+
+    fn schema_with_indexes() -> (TableSchema, Vec<IndexSchema>) {
+        let sql = "create table users (id integer primary key, name text, email text unique, age integer)";
+        let StatementType::CreateTable(create) = parser::parse_statement(sql).unwrap() else {
+            unreachable!()
+        };
+        let schema = TableSchema::new(&create, 2, sql);
+        let mut indexes = crate::schema::unique_constraints(&create)
+            .iter()
+            .map(|create| IndexSchema::new(create, &schema, 3, "").unwrap())
+            .collect::<Vec<_>>();
+        let sql = "create index users_name_age on users (name, age)";
+        let StatementType::CreateIndex(create) = parser::parse_statement(sql).unwrap() else {
+            unreachable!()
+        };
+        indexes.push(IndexSchema::new(&create, &schema, 4, sql).unwrap());
+        (schema, indexes)
+    }
+
+    fn plan(filter: &str, used_columns: &[usize]) -> AccessPath {
+        let (schema, indexes) = schema_with_indexes();
+        let StatementType::Select(select) =
+            parser::parse_statement(&format!("select * from users where {}", filter)).unwrap()
+        else {
+            unreachable!()
+        };
+        let filter = select.filter.unwrap().bind(&schema.scope()).unwrap();
+        let table = TableInfo {
+            schema: &schema,
+            offset: 0,
+            indexes,
+            stats: None,
+            used_columns: used_columns.to_vec(),
+        };
+        plan_access(&table, &filter.conjuncts(), &[false; 4]).0
+    }
+
+    fn index_name(path: &AccessPath) -> Option<&str> {
+        match path {
+            AccessPath::Index(scan) => Some(&scan.index.name),
+            AccessPath::FullScan => None,
+        }
+    }
+
+    #[test]
+    fn test_plan_access() {
+        let all = [0, 1, 2, 3];
+        let AccessPath::Index(scan) = plan("id = 5 and name = 'a'", &all) else {
+            panic!("expected an index scan");
+        };
+        assert!(scan.primary_key && scan.is_point_lookup() && !scan.covering);
+
+        let AccessPath::Index(scan) = plan("10 >= id and id > 2", &all) else {
+            panic!("expected an index scan");
+        };
+        assert!(scan.primary_key && !scan.is_point_lookup());
+        assert_eq!(scan.lower.map(|b| b.inclusive), Some(false));
+        assert_eq!(scan.upper.map(|b| b.inclusive), Some(true));
+
+        assert_eq!(
+            index_name(&plan("email = 'a' and name = 'b'", &all)),
+            Some("sqlite_autoindex_users_2")
+        );
+        let path = plan("name = 'a' and age between 20 and 30", &[0, 1, 3]);
+        assert_eq!(index_name(&path), Some("users_name_age"));
+        let AccessPath::Index(scan) = path else {
+            unreachable!()
+        };
+        assert!(scan.covering && scan.equal.len() == 1 && scan.lower.is_some());
+
+        let (schema, _) = schema_with_indexes();
+        let describe = |filter: &str, used_columns: &[usize]| {
+            plan(filter, used_columns).describe(&schema, "u")
+        };
+        assert_eq!(
+            describe("id = 1", &all),
+            "SEARCH u USING PRIMARY KEY (id=?)"
+        );
+        assert_eq!(
+            describe("name = 'a' and age > 1 and age <= 9", &[1]),
+            "SEARCH u USING COVERING INDEX users_name_age (name=? AND age>? AND age<=?)"
+        );
+        assert_eq!(
+            describe("email = 'a'", &all),
+            "SEARCH u USING INDEX sqlite_autoindex_users_2 (email=?)"
+        );
+        assert_eq!(describe("age = 3", &all), "SCAN u");
+
+        assert_eq!(plan("age = 3 or id = 1", &all), AccessPath::FullScan);
+        assert_eq!(plan("id + 1 = 3", &all), AccessPath::FullScan);
+        assert_eq!(plan("id = age", &all), AccessPath::FullScan);
+    }
+
+    fn join_table<'a>(
+        schema: &'a TableSchema,
+        indexes: &[IndexSchema],
+        offset: usize,
+        stats: &'a TableStats,
+        left_join_on: Option<Vec<Expr>>,
+    ) -> JoinTable<'a> {
+        let info = TableInfo {
+            schema,
+            offset,
+            indexes: indexes.to_vec(),
+            stats: Some(stats),
+            used_columns: vec![0, 1],
+        };
+        JoinTable { info, left_join_on }
+    }
+
+    #[test]
+    fn test_plan_joins() {
+        let (schema, indexes) = schema_with_indexes();
+        let stats = |rows: usize| TableStats {
+            rows,
+            columns: vec![
+                ColumnStats {
+                    non_null: rows,
+                    distinct: rows,
+                    histogram: Vec::new(),
+                };
+                4
+            ],
+            indexes: Default::default(),
+        };
+        let (big, small) = (stats(10_000), stats(10));
+        let table = |offset: usize, stats, left_join_on| {
+            join_table(&schema, &indexes, offset, stats, left_join_on)
+        };
+        // big.id = small.id
+        let join = Expr::Binary {
+            op: BinaryOp::Equal,
+            left: Box::new(Expr::ColumnIndex(0)),
+            right: Box::new(Expr::ColumnIndex(4)),
+        };
+        let order = |levels: &[Level]| levels.iter().map(|level| level.source).collect::<Vec<_>>();
+
+        // The small table is scanned and the big one searched by primary key
+        let levels = plan_joins(
+            &[table(0, &big, None), table(4, &small, None)],
+            std::slice::from_ref(&join),
+        );
+        assert_eq!(order(&levels), [1, 0]);
+        assert_eq!(levels[0].access, AccessPath::FullScan);
+        assert!(matches!(&levels[1].access, AccessPath::Index(scan) if scan.primary_key));
+        assert_eq!(levels[1].conditions, std::slice::from_ref(&join));
+
+        // The right side of a LEFT JOIN stays after the left side
+        let levels = plan_joins(
+            &[
+                table(0, &big, None),
+                table(4, &small, Some(vec![join.clone()])),
+            ],
+            &[],
+        );
+        assert_eq!(order(&levels), [0, 1]);
+        assert_eq!(levels[1].on, [join]);
+    }
+}
@@ -1,76 +1,86 @@
-use crate::cursor::{Cursor, CursorLocation};
-use crate::table::{self, Row};
+use crate::compile::{self, compile_statement, query_plan, ExecuteResult};
+use crate::parser::{self, Explain, PrepareSyntaxError, StatementType};
+use crate::table;
+use crate::value::Value;
+use crate::vm::{Program, Vm};
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 enum StatementResult {
     Success,
     UnrecognizedStatement,
     PrepareSyntaxError(PrepareSyntaxError),
 }
 
-#[derive(Debug)]
-enum PrepareSyntaxError {
-    InvalidId,
-    InvalidUsername,
-    InvalidEmail,
-    InvalidNumberOfArguments,
-}
-
 enum MetaCommandResult {
-    _Success,
+    Success,
     UnrecognizedCommand,
 }
 
 struct Statement {
-    statement_type: StatementType,
+    statement_type: Option<StatementType>,
     statement: String,
-    row_to_insert: Row,
 }
 
 impl Statement {
     fn new() -> Self {
         Statement {
-            statement_type: StatementType::Insert,
+            statement_type: None,
             statement: String::new(),
-            row_to_insert: Row::new(),
         }
     }
 }
 
-enum StatementType {
-    Insert,
-    Select,
-}
-
-enum ExecuteResult {
-    Success,
-    TableFull,
-}
-
 fn execute_statement(statement: Statement, table: &mut table::Table) -> ExecuteResult {
-    match statement.statement_type {
-        StatementType::Insert => execute_insert(statement, table),
-        StatementType::Select => execute_select(statement, table),
+    let statement_type = statement
+        .statement_type
+        .expect("statement was not prepared");
+    if let StatementType::Explain(explain) = statement_type {
+        return execute_explain(explain, &statement.statement, table);
     }
+    compile::execute(statement_type, &statement.statement, table, run_program)
 }
 
-fn execute_insert(statement: Statement, table: &mut table::Table) -> ExecuteResult {
-    if table.num_rows >= table::TABLE_MAX_ROWS {
-        return ExecuteResult::TableFull;
+/// Runs a compiled statement, printing the rows it returns under a header of
+/// the column names.
+fn run_program(program: Arc<Program>, table: &mut table::Table) -> ExecuteResult {
+    if !program.columns.is_empty() {
+        println!("{}", program.columns.join(" | "));
+    }
+    let mut vm = Vm::new(program);
+    loop {
+        match vm.step(table) {
+            Ok(Some(row)) => {
+                let values = row.values.iter().map(Value::to_string).collect::<Vec<_>>();
+                println!("{}", values.join(" | "));
+            }
+            Ok(None) => return ExecuteResult::Success,
+            Err(error) => return error.into(),
+        }
     }
-    let row_to_insert = statement.row_to_insert;
-    let (page, idx) = table.cursor_value(CursorLocation::End);
-    table.serialize_row(&row_to_insert, (page, idx));
-    table.num_rows += 1;
-    ExecuteResult::Success
 }
 
-fn execute_select(_statement: Statement, table: &mut table::Table) -> ExecuteResult {
-    let mut row;
-    while !table.start_cursor.end_of_table {
-        let (page, idx) = table.cursor_value(CursorLocation::Start);
-        row = table.deserialize_row(page, idx);
-        println!("{}", row);
-        table.start_cursor.advance();
+fn execute_explain(explain: Explain, sql: &str, table: &mut table::Table) -> ExecuteResult {
+    if explain.query_plan {
+        let plan = match query_plan(*explain.statement, table) {
+            Ok(plan) => plan,
+            Err(error) => return error.into(),
+        };
+        println!("QUERY PLAN");
+        for (i, line) in plan.iter().enumerate() {
+            let branch = if i + 1 == plan.len() { "`--" } else { "|--" };
+            println!("{}{}", branch, line);
+        }
+        return ExecuteResult::Success;
+    }
+    let program = match compile_statement(*explain.statement, sql, table) {
+        Ok(program) => program,
+        Err(error) => return error.into(),
+    };
+    println!("addr | opcode | detail");
+    for (addr, insn) in program.insns.iter().enumerate() {
+        let (opcode, detail) = insn.describe();
+        println!("{} | {} | {}", addr, opcode, detail);
     }
     ExecuteResult::Success
 }
@@ -79,50 +89,24 @@ fn execute_meta_command(cmd: &str, table: &mut table::Table) -> MetaCommandResul
     if cmd == ".exit" {
         table.db_close();
         std::process::exit(0);
+    } else if let Some(Ok(ms)) = cmd.strip_prefix(".timeout ").map(|ms| ms.trim().parse()) {
+        // How long to retry a statement blocked by another connection
+        table.set_busy_timeout(Duration::from_millis(ms));
+        MetaCommandResult::Success
     } else {
         MetaCommandResult::UnrecognizedCommand
     }
 }
 
 fn prepare_statement(input: &str, statement: &mut Statement) -> StatementResult {
-    if input.starts_with("insert") {
-        statement.statement_type = StatementType::Insert;
-        statement.statement = input.to_string();
-        let parts = input.split_whitespace().collect::<Vec<&str>>();
-        println!("parts {:?}", parts);
-
-        if parts.len() != 4 {
-            return StatementResult::PrepareSyntaxError(
-                PrepareSyntaxError::InvalidNumberOfArguments,
-            );
-        }
-
-        match parts[1].parse::<u32>() {
-            Ok(id) => statement.row_to_insert.id = id,
-            Err(_) => return StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidId),
+    statement.statement = input.to_string();
+    match parser::parse_statement(input) {
+        Ok(statement_type) => {
+            statement.statement_type = Some(statement_type);
+            StatementResult::Success
         }
-
-        let username_bytes = parts[2].as_bytes();
-        if username_bytes.len() > 32 {
-            return StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidUsername);
-        }
-        statement.row_to_insert.username = [0; 32];
-        statement.row_to_insert.username[..username_bytes.len()].copy_from_slice(username_bytes);
-
-        let email_bytes = parts[3].as_bytes();
-        if email_bytes.len() > 255 {
-            return StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidEmail);
-        }
-        statement.row_to_insert.email = [0; 255];
-        statement.row_to_insert.email[..email_bytes.len()].copy_from_slice(email_bytes);
-
-        StatementResult::Success
-    } else if input == "select" {
-        statement.statement_type = StatementType::Select;
-        statement.statement = input.to_string();
-        StatementResult::Success
-    } else {
-        StatementResult::UnrecognizedStatement
+        Err(PrepareSyntaxError::UnrecognizedStatement) => StatementResult::UnrecognizedStatement,
+        Err(error) => StatementResult::PrepareSyntaxError(error),
     }
 }
 
@@ -162,16 +146,22 @@ fn print_prompt() {
 }
 
 pub fn run(table_name: &str) {
-    let mut table = table::Table::db_open(table_name);
-    println!("Table rows: {}", table.num_rows);
-    println!("Table pages: {}", table.pager.pages.len());
+    let mut table = match table::Table::open(table_name, None) {
+        Ok(table) => table,
+        Err(e) => {
+            println!("Error: Can not open {}: {}.", table_name, e);
+            return;
+        }
+    };
+    println!("Tables: {}", table.catalog.tables.len());
+    println!("Table pages: {}", table.pager.num_pages);
     let mut input_buffer = InputBuffer::new();
     loop {
         print_prompt();
         input_buffer.read_input();
         match input_buffer.buffer.trim() {
             cmd if cmd.starts_with(".") => match execute_meta_command(cmd, &mut table) {
-                MetaCommandResult::_Success => continue,
+                MetaCommandResult::Success => continue,
                 MetaCommandResult::UnrecognizedCommand => {
                     println!("Unrecognized command '{}'.", cmd);
                     continue;
@@ -186,8 +176,69 @@ pub fn run(table_name: &str) {
                             ExecuteResult::Success => {
                                 println!("Executed.");
                             }
-                            ExecuteResult::TableFull => {
-                                println!("Error: Table full.");
+                            ExecuteResult::RowNotFound => {
+                                println!("Error: Row not found.");
+                            }
+                            ExecuteResult::TableNotFound(name) => {
+                                println!("Error: No such table: {}.", name);
+                            }
+                            ExecuteResult::TableExists(name) => {
+                                println!("Error: Table {} already exists.", name);
+                            }
+                            ExecuteResult::IndexExists(name) => {
+                                println!("Error: Index {} already exists.", name);
+                            }
+                            ExecuteResult::ColumnNotFound(name) => {
+                                println!("Error: No such column: {}.", name);
+                            }
+                            ExecuteResult::AmbiguousColumn(name) => {
+                                println!("Error: Ambiguous column name: {}.", name);
+                            }
+                            ExecuteResult::NoSuchFunction(name) => {
+                                println!("Error: No such function: {}.", name);
+                            }
+                            ExecuteResult::WrongNumberOfArguments(name) => {
+                                println!("Error: Wrong number of arguments to {}().", name);
+                            }
+                            ExecuteResult::MisuseOfAggregate(name) => {
+                                println!("Error: Misuse of aggregate function {}().", name);
+                            }
+                            ExecuteResult::ColumnCountMismatch { expected, actual } => {
+                                println!("Error: Expected {} values, got {}.", expected, actual);
+                            }
+                            ExecuteResult::UniqueViolation(violation) => {
+                                let values = violation
+                                    .values
+                                    .iter()
+                                    .map(Value::to_string)
+                                    .collect::<Vec<_>>();
+                                println!(
+                                    "Error: UNIQUE constraint failed: {} ({}).",
+                                    violation.index,
+                                    values.join(", ")
+                                );
+                            }
+                            ExecuteResult::OrderByOutOfRange(position) => {
+                                println!("Error: ORDER BY term out of range: {}.", position);
+                            }
+                            ExecuteResult::TransactionActive => {
+                                println!("Error: Cannot start a transaction within a transaction.");
+                            }
+                            ExecuteResult::NoTransaction => {
+                                println!("Error: No transaction is active.");
+                            }
+                            ExecuteResult::NoSuchSavepoint(name) => {
+                                println!("Error: No such savepoint: {}.", name);
+                            }
+                            ExecuteResult::Busy => {
+                                println!("Error: database is locked.");
+                            }
+                            ExecuteResult::IoError(error) => {
+                                println!("Error: {}.", error);
+                            }
+                            ExecuteResult::SyntaxError(error) => {
+                                println!("Syntax error. Could not parse statement.");
+                                println!("Error: {:?}", error);
                             }
                         }
                     }
@@ -210,6 +261,16 @@ pub fn run(table_name: &str) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::expr::Expr;
+
+    fn run_statement(input: &str, table: &mut table::Table) -> ExecuteResult {
+        let mut statement = Statement::new();
+        assert!(matches!(
+            prepare_statement(input, &mut statement),
+            StatementResult::Success
+        ));
+        execute_statement(statement, table)
+    }
 
     #[test]
     fn test_prepare_statement_insert() {
@@ -218,21 +279,21 @@ mod tests {
 
         let result = prepare_statement(input, &mut statement);
         assert!(matches!(result, StatementResult::Success));
-        assert!(matches!(statement.statement_type, StatementType::Insert));
-        assert_eq!(statement.row_to_insert.id, 1);
+        let Some(StatementType::Insert(insert)) = statement.statement_type else {
+            panic!("expected insert");
+        };
+        assert_eq!(insert.rows[0][0], Expr::Literal(Value::Integer(1)));
 
         // Check username was properly copied
-        let expected_username = "user1".as_bytes();
         assert_eq!(
-            &statement.row_to_insert.username[..expected_username.len()],
-            expected_username
+            insert.rows[0][1],
+            Expr::Literal(Value::Text("user1".to_string()))
         );
 
         // Check email was properly copied
-        let expected_email = "user1@example.com".as_bytes();
         assert_eq!(
-            &statement.row_to_insert.email[..expected_email.len()],
-            expected_email
+            insert.rows[0][2],
+            Expr::Literal(Value::Text("user1@example.com".to_string()))
         );
     }
 
@@ -254,27 +315,29 @@ mod tests {
             StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidId)
         ));
 
-        // Test username too long (> 32 bytes)
-        let long_username = "a".repeat(33);
+        // Long values are no longer rejected, they go to overflow pages
+        let long_email = "a".repeat(2 * table::PAGE_SIZE);
+        let result = prepare_statement(&format!("insert 1 user1 {}", long_email), &mut statement);
+        assert!(matches!(result, StatementResult::Success));
+        let Some(StatementType::Insert(insert)) = &statement.statement_type else {
+            panic!("expected insert");
+        };
+        assert_eq!(insert.rows[0][2], Expr::Literal(Value::Text(long_email)));
+
+        // Negative and 64-bit IDs are valid
+        let result = prepare_statement("insert -1 user1 email@test.com", &mut statement);
+        assert!(matches!(result, StatementResult::Success));
         let result = prepare_statement(
-            &format!("insert 1 {} email@test.com", long_username),
+            "insert 1700000000000000 user1 email@test.com",
             &mut statement,
         );
-        assert!(matches!(
-            result,
-            StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidUsername)
-        ));
-
-        // Test email too long (> 255 bytes)
-        let long_email = "a".repeat(256);
-        let result = prepare_statement(&format!("insert 1 user1 {}", long_email), &mut statement);
-        assert!(matches!(
-            result,
-            StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidEmail)
-        ));
+        assert!(matches!(result, StatementResult::Success));
 
-        // Test negative ID
-        let result = prepare_statement("insert -1 user1 email@test.com", &mut statement);
+        // Test ID out of 64-bit range
+        let result = prepare_statement(
+            "insert 9223372036854775808 user1 email@test.com",
+            &mut statement,
+        );
         assert!(matches!(
             result,
             StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidId)
@@ -287,7 +350,10 @@ mod tests {
         let result = prepare_statement("select", &mut statement);
 
         assert!(matches!(result, StatementResult::Success));
-        assert!(matches!(statement.statement_type, StatementType::Select));
+        assert!(matches!(
+            statement.statement_type,
+            Some(StatementType::Select(_))
+        ));
     }
 
     #[test]
@@ -298,11 +364,62 @@ mod tests {
         assert!(matches!(result, StatementResult::UnrecognizedStatement));
     }
 
+    #[test]
+    fn test_prepare_statement_update_and_delete() {
+        let mut statement = Statement::new();
+        let result = prepare_statement("update 3 carol carol@test.com", &mut statement);
+        assert!(matches!(result, StatementResult::Success));
+        let Some(StatementType::LegacyUpdate(update)) = statement.statement_type else {
+            panic!("expected update");
+        };
+        assert_eq!(update.values[1], Value::Text("carol".to_string()));
+
+        let mut statement = Statement::new();
+        let result = prepare_statement("delete 3", &mut statement);
+        assert!(matches!(result, StatementResult::Success));
+        let Some(StatementType::LegacyDelete(delete)) = statement.statement_type else {
+            panic!("expected delete");
+        };
+        assert_eq!(delete.key, Value::Integer(3));
+
+        let mut statement = Statement::new();
+        let result = prepare_statement("delete 3 carol", &mut statement);
+        assert!(matches!(
+            result,
+            StatementResult::PrepareSyntaxError(PrepareSyntaxError::InvalidNumberOfArguments)
+        ));
+    }
+
+    #[test]
+    fn test_execute_statement() {
+        let mut table = table::Table::db_open(&table::tests::temp_db_path("repl_execute"));
+        for input in [
+            "insert 1 alice alice@test.com",
+            "select",
+            "explain select * from users",
+            "explain query plan delete 1",
+        ] {
+            assert!(matches!(
+                run_statement(input, &mut table),
+                ExecuteResult::Success
+            ));
+        }
+        assert!(matches!(
+            run_statement("select * from missing", &mut table),
+            ExecuteResult::TableNotFound(_)
+        ));
+    }
+
     #[test]
     fn test_execute_meta_command() {
-        let mut table = table::Table::db_open("test.rdb");
+        let mut table = table::Table::db_open(&table::tests::temp_db_path("meta_command"));
         let result = execute_meta_command(".unknown", &mut table);
         assert!(matches!(result, MetaCommandResult::UnrecognizedCommand));
+        let result = execute_meta_command(".timeout 250", &mut table);
+        assert!(matches!(result, MetaCommandResult::Success));
+        assert_eq!(table.pager.busy_timeout, Duration::from_millis(250));
+        let result = execute_meta_command(".timeout soon", &mut table);
+        assert!(matches!(result, MetaCommandResult::UnrecognizedCommand));
 
         // Note: We can't easily test ".exit" as it calls std::process::exit()
     }
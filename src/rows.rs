@@ -0,0 +1,369 @@
+//! The rows a statement returns, as an iterator for programs to read them
+//! with. Each value is converted to the Rust type asked for by `FromValue`.
+
+use crate::error::Error;
+use crate::table::Table;
+use crate::value::Value;
+use crate::vm::Vm;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use crate::error::Result;
+
+/// A Rust type a column value can be read as.
+pub trait FromValue: Sized {
+    /// The value as `Self`, `None` if it has a type that does not convert.
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Integer(i) => Some(*i),
+            Value::Boolean(b) => Some(i64::from(*b)),
+            _ => None,
+        }
+    }
+}
+
+/// Integers that do not fit read as `None`.
+macro_rules! from_integer {
+    ($($t:ty),*) => {
+        $(
+            impl FromValue for $t {
+                fn from_value(value: &Value) -> Option<Self> {
+                    i64::from_value(value)?.try_into().ok()
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i8, i16, i32, u8, u16, u32, u64);
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Real(r) => Some(*r),
+            Value::Integer(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        f64::from_value(value).map(|r| r as f32)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Boolean(b) => Some(*b),
+            Value::Integer(i) => Some(*i != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Text(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Blob(b) => Some(b.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// NULL reads as `None`, anything else as the inner type.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+/// Timestamps are stored as SQLite's date functions do: seconds since the
+/// Unix epoch, or text `YYYY-MM-DD[ HH:MM:SS[.SSS]]` in UTC.
+impl FromValue for SystemTime {
+    fn from_value(value: &Value) -> Option<Self> {
+        let seconds = match value {
+            Value::Integer(i) => *i as f64,
+            Value::Real(r) => *r,
+            Value::Text(s) => parse_timestamp(s)?,
+            _ => return None,
+        };
+        let offset = Duration::try_from_secs_f64(seconds.abs()).ok()?;
+        match seconds < 0.0 {
+            true => UNIX_EPOCH.checked_sub(offset),
+            false => UNIX_EPOCH.checked_add(offset),
+        }
+    }
+}
+
+/// Days from 1970-01-01 to the date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Seconds since the epoch of `YYYY-MM-DD[ HH:MM:SS[.SSS]]`, with a space
+/// or `T` between date and time.
+fn parse_timestamp(text: &str) -> Option<f64> {
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (text, None),
+    };
+    let mut parts = date.splitn(3, '-');
+    let mut field = |range: std::ops::RangeInclusive<i64>| {
+        let value = parts.next()?.parse::<i64>().ok()?;
+        range.contains(&value).then_some(value)
+    };
+    let (year, month, day) = (field(0..=9999)?, field(1..=12)?, field(1..=31)?);
+    let mut seconds = days_from_civil(year, month, day) as f64 * 86_400.0;
+    if let Some(time) = time {
+        let mut parts = time.splitn(3, ':');
+        let hours = parts.next()?.parse::<u32>().ok().filter(|&h| h < 24)?;
+        let minutes = parts.next()?.parse::<u32>().ok().filter(|&m| m < 60)?;
+        let secs = match parts.next() {
+            Some(secs) => secs
+                .parse::<f64>()
+                .ok()
+                .filter(|&s| (0.0..60.0).contains(&s))?,
+            None => 0.0,
+        };
+        seconds += f64::from(hours * 3600 + minutes * 60) + secs;
+    }
+    Some(seconds)
+}
+
+/// A column of a result row, by position or by name.
+pub trait RowIndex {
+    fn index(&self, columns: &[String]) -> Result<usize>;
+}
+
+impl RowIndex for usize {
+    fn index(&self, columns: &[String]) -> Result<usize> {
+        match *self < columns.len() {
+            true => Ok(*self),
+            false => Err(Error::ColumnIndexOutOfRange(*self)),
+        }
+    }
+}
+
+impl RowIndex for &str {
+    fn index(&self, columns: &[String]) -> Result<usize> {
+        columns
+            .iter()
+            .position(|column| column.eq_ignore_ascii_case(self))
+            .ok_or_else(|| Error::ColumnNotFound(self.to_string()))
+    }
+}
+
+/// A row a statement returned, along with the names of its columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultRow {
+    columns: Arc<[String]>,
+    values: Vec<Value>,
+}
+
+impl ResultRow {
+    pub fn new(columns: Arc<[String]>, values: Vec<Value>) -> Self {
+        ResultRow { columns, values }
+    }
+
+    /// The value of the column, as a `T`.
+    pub fn get<T: FromValue>(&self, index: impl RowIndex) -> Result<T> {
+        let index = index.index(&self.columns)?;
+        let value = &self.values[index];
+        T::from_value(value).ok_or_else(|| Error::InvalidColumnType {
+            column: self.columns[index].clone(),
+            value: value.clone(),
+        })
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+}
+
+/// A run of a statement's program. If the statement started the
+/// transaction it runs in, that ends with the last row, or is rolled back
+/// if the run is stopped before then.
+pub(crate) struct Execution {
+    vm: Vm,
+    columns: Arc<[String]>,
+    autocommit: bool,
+    done: bool,
+}
+
+impl Execution {
+    pub(crate) fn new(vm: Vm, columns: Arc<[String]>, autocommit: bool) -> Self {
+        Execution {
+            vm,
+            columns,
+            autocommit,
+            done: false,
+        }
+    }
+
+    /// The next row, `None` once the program halted.
+    pub(crate) fn step(&mut self, table: &mut Table) -> Option<Result<ResultRow>> {
+        if self.done {
+            return None;
+        }
+        match self.vm.step(table) {
+            Ok(Some(row)) => Some(Ok(ResultRow::new(self.columns.clone(), row.values))),
+            Ok(None) => self.finish(table, Ok(())).err().map(Err),
+            Err(error) => self.finish(table, Err(error.into())).err().map(Err),
+        }
+    }
+
+    /// Ends the transaction the statement started, committing if it ran to
+    /// the end.
+    fn finish(&mut self, table: &mut Table, result: Result<()>) -> Result<()> {
+        self.done = true;
+        if !self.autocommit || !table.in_transaction() {
+            return result;
+        }
+        if result.is_err() {
+            table.rollback();
+            return result;
+        }
+        if let Err(error) = table.commit() {
+            // A busy commit keeps the transaction
+            if table.in_transaction() {
+                table.rollback();
+            }
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    /// Stops the run, rolling back the transaction it started if it did
+    /// not get to the end.
+    pub(crate) fn abort(&mut self, table: &mut Table) {
+        if !self.done && self.autocommit && table.in_transaction() {
+            table.rollback();
+        }
+        self.done = true;
+    }
+}
+
+/// The rows of a running statement.
+pub struct Rows<'a> {
+    execution: Execution,
+    table: &'a mut Table,
+}
+
+impl<'a> Rows<'a> {
+    pub(crate) fn new(execution: Execution, table: &'a mut Table) -> Self {
+        Rows { execution, table }
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<ResultRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.execution.step(self.table)
+    }
+}
+
+impl Drop for Rows<'_> {
+    fn drop(&mut self) {
+        self.execution.abort(self.table);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_value() {
+        let row = ResultRow::new(
+            Arc::from(["id".to_string(), "name".to_string(), "score".to_string()]),
+            vec![
+                Value::Integer(7),
+                Value::Text("alice".to_string()),
+                Value::Null,
+            ],
+        );
+        assert_eq!(row.get::<i64>(0).unwrap(), 7);
+        assert_eq!(row.get::<f64>("ID").unwrap(), 7.0);
+        assert_eq!(row.get::<String>("name").unwrap(), "alice");
+        assert_eq!(row.get::<Option<f64>>("score").unwrap(), None);
+        assert_eq!(row.get::<Option<i64>>(0).unwrap(), Some(7));
+        assert!(matches!(
+            row.get::<i64>("name"),
+            Err(Error::InvalidColumnType { column, .. }) if column == "name"
+        ));
+        assert!(matches!(
+            row.get::<i64>("missing"),
+            Err(Error::ColumnNotFound(_))
+        ));
+        assert!(matches!(
+            row.get::<i64>(3),
+            Err(Error::ColumnIndexOutOfRange(3))
+        ));
+        assert_eq!(
+            Vec::<u8>::from_value(&Value::Blob(vec![1, 2])),
+            Some(vec![1, 2])
+        );
+        assert_eq!(bool::from_value(&Value::Integer(2)), Some(true));
+        assert_eq!(u8::from_value(&Value::Integer(255)), Some(255));
+        assert_eq!(u8::from_value(&Value::Integer(256)), None);
+        assert_eq!(i32::from_value(&Value::Boolean(true)), Some(1));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let at = |seconds: u64| UNIX_EPOCH + Duration::from_secs(seconds);
+        let timestamp = |text: &str| SystemTime::from_value(&Value::Text(text.to_string()));
+        assert_eq!(timestamp("1970-01-01"), Some(at(0)));
+        assert_eq!(timestamp("2000-03-01 12:30:15"), Some(at(951_913_815)));
+        assert_eq!(timestamp("2024-02-29T00:00:00"), Some(at(1_709_164_800)));
+        assert_eq!(
+            timestamp("2024-02-29 00:00:00.5"),
+            Some(at(1_709_164_800) + Duration::from_millis(500))
+        );
+        assert_eq!(
+            timestamp("1969-12-31 23:59:59"),
+            UNIX_EPOCH.checked_sub(Duration::from_secs(1))
+        );
+        assert_eq!(timestamp("2024-13-01"), None);
+        assert_eq!(timestamp("yesterday"), None);
+        assert_eq!(
+            SystemTime::from_value(&Value::Integer(86_400)),
+            Some(at(86_400))
+        );
+        assert_eq!(SystemTime::from_value(&Value::Blob(Vec::new())), None);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::btree::BTree;
+use crate::expr::Scope;
+use crate::parser::{ColumnDef, CreateIndex, CreateTable};
+use crate::stats::TableStats;
+use crate::table::Row;
+use crate::value::Affinity;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The next catalog version, unique across connections.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub type_name: String,
+    pub affinity: Affinity,
+    pub primary_key: bool,
+}
+
+impl From<&ColumnDef> for Column {
+    fn from(def: &ColumnDef) -> Self {
+        Column {
+            name: def.name.clone(),
+            type_name: def.type_name.clone(),
+            affinity: Affinity::from_type_name(&def.type_name),
+            primary_key: def.primary_key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSchema {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub root_page: u32,
+    pub sql: String,
+}
+
+impl TableSchema {
+    pub fn new(create: &CreateTable, root_page: u32, sql: &str) -> Self {
+        TableSchema {
+            name: create.name.clone(),
+            columns: create.columns.iter().map(Column::from).collect(),
+            root_page,
+            sql: sql.to_string(),
+        }
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The B-tree holding the table's rows by rowid.
+    pub fn btree(&self) -> BTree {
+        BTree {
+            root_page: self.root_page,
+        }
+    }
+
+    /// The column rows are looked up by, the first column if none is declared.
+    pub fn primary_key_index(&self) -> usize {
+        self.columns
+            .iter()
+            .position(|column| column.primary_key)
+            .unwrap_or(0)
+    }
+
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|column| column.name.clone())
+            .collect()
+    }
+
+    /// The table's columns for binding expressions against its rows.
+    pub fn scope(&self) -> Scope {
+        self.scope_as(&self.name)
+    }
+
+    /// Like `scope`, with the columns qualified by an alias.
+    pub fn scope_as(&self, name: &str) -> Scope {
+        let mut scope = Scope::new(name, self.columns.iter().map(|column| column.name.clone()));
+        scope.affinities = self.columns.iter().map(|column| column.affinity).collect();
+        scope
+    }
+}
+
+/// A secondary index: a B-tree keyed by the indexed columns followed by the
+/// primary key, whose entries hold the rowid of the row they index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSchema {
+    pub name: String,
+    pub table: String,
+    /// Whether two rows may not have equal values in the indexed columns.
+    pub unique: bool,
+    /// Positions of the indexed columns in the table.
+    pub columns: Vec<usize>,
+    /// Position of the table's primary key column.
+    pub primary_key: usize,
+    pub root_page: u32,
+    pub sql: String,
+}
+
+impl IndexSchema {
+    /// Fails with the name of the first column the table does not have.
+    pub fn new(
+        create: &CreateIndex,
+        table: &TableSchema,
+        root_page: u32,
+        sql: &str,
+    ) -> Result<Self, String> {
+        let columns = create
+            .columns
+            .iter()
+            .map(|name| table.column_index(name).ok_or_else(|| name.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(IndexSchema {
+            name: create.name.clone(),
+            table: table.name.clone(),
+            unique: create.unique,
+            columns,
+            primary_key: table.primary_key_index(),
+            root_page,
+            sql: sql.to_string(),
+        })
+    }
+
+    pub fn btree(&self) -> BTree {
+        BTree {
+            root_page: self.root_page,
+        }
+    }
+
+    /// The indexed values of a row that has to be unique, `None` if the
+    /// index is not unique or one of them is NULL, since NULLs never equal
+    /// each other.
+    pub fn unique_values(&self, row: &Row) -> Option<Vec<Value>> {
+        let values = self
+            .columns
+            .iter()
+            .map(|&column| row.values[column].clone())
+            .collect::<Vec<_>>();
+        (self.unique && !values.iter().any(Value::is_null)).then_some(values)
+    }
+
+    /// The index key of a row of the table.
+    pub fn key(&self, row: &Row) -> Vec<Value> {
+        self.columns
+            .iter()
+            .chain([&self.primary_key])
+            .map(|&column| row.values[column].clone())
+            .collect()
+    }
+}
+
+/// The unique indexes a table gets for its primary key and its unique
+/// column and table constraints, named like SQLite's automatic indexes.
+pub fn unique_constraints(create: &CreateTable) -> Vec<CreateIndex> {
+    let primary_key = create
+        .columns
+        .iter()
+        .filter(|column| column.primary_key)
+        .map(|column| column.name.clone())
+        .collect::<Vec<_>>();
+    let unique_columns = create
+        .columns
+        .iter()
+        .filter(|column| column.unique)
+        .map(|column| vec![column.name.clone()]);
+    let mut column_lists: Vec<Vec<String>> = Vec::new();
+    for columns in [primary_key]
+        .into_iter()
+        .chain(unique_columns)
+        .chain(create.unique.iter().cloned())
+    {
+        let duplicate = column_lists.iter().any(|existing| {
+            existing.len() == columns.len()
+                && existing
+                    .iter()
+                    .zip(&columns)
+                    .all(|(a, b)| a.eq_ignore_ascii_case(b))
+        });
+        if !columns.is_empty() && !duplicate {
+            column_lists.push(columns);
+        }
+    }
+    column_lists
+        .into_iter()
+        .enumerate()
+        .map(|(i, columns)| CreateIndex {
+            unique: true,
+            name: format!("sqlite_autoindex_{}_{}", create.name, i + 1),
+            table: create.name.clone(),
+            columns,
+        })
+        .collect()
+}
+
+/// In-memory copy of the schema table, which lists every table and index in
+/// the file.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    pub tables: Vec<TableSchema>,
+    pub indexes: Vec<IndexSchema>,
+    /// The statistics of every analyzed table, by lowercase table name.
+    pub stats: HashMap<String, TableStats>,
+    /// The schema cookie of the file the catalog was read from.
+    pub schema_cookie: u32,
+    /// Changes along with the schema cookie, but unlike the cookie never
+    /// comes back to an earlier value once a schema change is rolled back.
+    pub version: u64,
+}
+
+impl Catalog {
+    pub fn next_version() -> u64 {
+        NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn find_table(&self, name: &str) -> Option<&TableSchema> {
+        self.tables
+            .iter()
+            .find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn find_index(&self, name: &str) -> Option<&IndexSchema> {
+        self.indexes
+            .iter()
+            .find(|index| index.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The indexes on the table rooted at `root_page`.
+    pub fn table_indexes(&self, root_page: u32) -> Vec<IndexSchema> {
+        let Some(table) = self.tables.iter().find(|t| t.root_page == root_page) else {
+            return Vec::new();
+        };
+        self.indexes
+            .iter()
+            .filter(|index| index.table.eq_ignore_ascii_case(&table.name))
+            .cloned()
+            .collect()
+    }
+
+    /// The statistics of the last ANALYZE of a table, if any.
+    pub fn table_stats(&self, name: &str) -> Option<&TableStats> {
+        self.stats.get(&name.to_lowercase())
+    }
+}
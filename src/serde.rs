@@ -0,0 +1,748 @@
+//! Rows to and from Rust structs with serde, fields matched with columns by
+//! name. `insert` stores a `Serialize` struct as a new row,
+//! `PreparedStatement::bind_struct` binds its fields to `:name` parameters
+//! and `ResultRow::deserialize` reads a row back as a `Deserialize` struct.
+//!
+//! Fields convert the way `FromValue` reads values: integers, floats,
+//! booleans and strings as they are, byte vectors as blobs, `None` as NULL
+//! and unit enum variants as their names.
+
+use crate::rows::{FromValue, Result, ResultRow};
+use crate::statement::PreparedStatement;
+use crate::table::Table;
+use crate::value::Value;
+use ::serde::de::value::{SeqDeserializer, StrDeserializer};
+use ::serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use ::serde::ser::{self, Impossible, Serialize, Serializer};
+use ::serde::{forward_to_deserialize_any, Deserializer};
+use std::fmt;
+
+/// Why a value did not convert, reported as
+/// `crate::Error::Serialization`.
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error(message.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error(message.to_string())
+    }
+}
+
+impl From<Error> for crate::Error {
+    fn from(error: Error) -> Self {
+        crate::Error::Serialization(error.0)
+    }
+}
+
+/// The fields of a struct, or the entries of a map, as column names and
+/// values.
+pub fn to_values<T: Serialize + ?Sized>(value: &T) -> Result<Vec<(String, Value)>> {
+    let mut fields = Vec::new();
+    value.serialize(RowSerializer {
+        fields: &mut fields,
+    })?;
+    Ok(fields)
+}
+
+/// Inserts the struct as a new row of `table_name`, each field into the
+/// column of the same name. Columns it has no field for are NULL. The
+/// connection keeps the insert prepared for the next struct with the same
+/// fields.
+pub fn insert<T: Serialize + ?Sized>(table: &mut Table, table_name: &str, value: &T) -> Result<()> {
+    let fields = to_values(value)?;
+    let quote = |name: &str| format!("\"{}\"", name.replace('"', "\"\""));
+    let columns = fields
+        .iter()
+        .map(|(name, _)| quote(name))
+        .collect::<Vec<_>>();
+    let parameters = (1..=fields.len())
+        .map(|n| format!("?{}", n))
+        .collect::<Vec<_>>();
+    let sql = format!(
+        "insert into {} ({}) values ({})",
+        quote(table_name),
+        columns.join(", "),
+        parameters.join(", ")
+    );
+    PreparedStatement::cached(&sql, table, |statement, table| {
+        for (n, (_, value)) in fields.into_iter().enumerate() {
+            statement.bind(n + 1, value)?;
+        }
+        statement.execute(table)
+    })
+}
+
+impl PreparedStatement {
+    /// Binds each field of the struct to the `:name` parameter of the same
+    /// name. Fields the statement has no parameter for are left out, so one
+    /// struct serves statements that only use some of them.
+    pub fn bind_struct<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        for (name, value) in to_values(value)? {
+            match self.bind(format!(":{}", name).as_str(), value) {
+                Ok(()) | Err(crate::Error::ParameterNotFound(_)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ResultRow {
+    /// The row as a `T`, each field read from the column of the same name.
+    /// Columns without a field are skipped.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(T::deserialize(RowDeserializer { row: self })?)
+    }
+}
+
+/// Fails for everything but the methods a serializer implements itself.
+macro_rules! unsupported {
+    ($message:expr; $($method:ident($($arg:ty),*) -> $ok:ty;)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> std::result::Result<$ok, Error> {
+                Err(Error($message.to_string()))
+            }
+        )*
+    };
+}
+
+/// Collects the fields of a struct or map.
+struct RowSerializer<'a> {
+    fields: &'a mut Vec<(String, Value)>,
+}
+
+const NOT_A_ROW: &str = "only structs and maps can be stored as rows";
+
+impl<'a> Serializer for RowSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Impossible<(), Error>;
+    type SerializeTuple = Impossible<(), Error>;
+    type SerializeTupleStruct = Impossible<(), Error>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = FieldSerializer<'a>;
+    type SerializeStruct = FieldSerializer<'a>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    unsupported! {
+        NOT_A_ROW;
+        serialize_bool(bool) -> ();
+        serialize_i8(i8) -> ();
+        serialize_i16(i16) -> ();
+        serialize_i32(i32) -> ();
+        serialize_i64(i64) -> ();
+        serialize_u8(u8) -> ();
+        serialize_u16(u16) -> ();
+        serialize_u32(u32) -> ();
+        serialize_u64(u64) -> ();
+        serialize_f32(f32) -> ();
+        serialize_f64(f64) -> ();
+        serialize_char(char) -> ();
+        serialize_str(&str) -> ();
+        serialize_bytes(&[u8]) -> ();
+        serialize_none() -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(&'static str) -> ();
+        serialize_unit_variant(&'static str, u32, &'static str) -> ();
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize)
+            -> Self::SerializeTupleVariant;
+        serialize_struct_variant(&'static str, u32, &'static str, usize)
+            -> Self::SerializeStructVariant;
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> std::result::Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> std::result::Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> std::result::Result<(), Error> {
+        Err(Error(NOT_A_ROW.to_string()))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> std::result::Result<FieldSerializer<'a>, Error> {
+        Ok(FieldSerializer {
+            fields: self.fields,
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<FieldSerializer<'a>, Error> {
+        self.serialize_map(None)
+    }
+}
+
+struct FieldSerializer<'a> {
+    fields: &'a mut Vec<(String, Value)>,
+    /// The key of the map entry whose value comes next.
+    key: Option<String>,
+}
+
+impl ser::SerializeStruct for FieldSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> std::result::Result<(), Error> {
+        let value = value
+            .serialize(ValueSerializer)
+            .map_err(|error| Error(format!("field {}: {}", name, error)))?;
+        self.fields.push((name.to_string(), value));
+        Ok(())
+    }
+
+    fn end(self) -> std::result::Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for FieldSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> std::result::Result<(), Error> {
+        match key.serialize(ValueSerializer)? {
+            Value::Text(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            key => Err(Error(format!("column name {} is not text", key))),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> std::result::Result<(), Error> {
+        let name = self.key.take().expect("map value without a key");
+        let value = value
+            .serialize(ValueSerializer)
+            .map_err(|error| Error(format!("field {}: {}", name, error)))?;
+        self.fields.push((name, value));
+        Ok(())
+    }
+
+    fn end(self) -> std::result::Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Turns a field into a value.
+struct ValueSerializer;
+
+const NOT_A_VALUE: &str = "only scalars, byte vectors and unit variants can be stored in a column";
+
+impl Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = BlobSerializer;
+    type SerializeTuple = Impossible<Value, Error>;
+    type SerializeTupleStruct = Impossible<Value, Error>;
+    type SerializeTupleVariant = Impossible<Value, Error>;
+    type SerializeMap = Impossible<Value, Error>;
+    type SerializeStruct = Impossible<Value, Error>;
+    type SerializeStructVariant = Impossible<Value, Error>;
+
+    fn serialize_bool(self, b: bool) -> std::result::Result<Value, Error> {
+        Ok(Value::Boolean(b))
+    }
+
+    fn serialize_i8(self, i: i8) -> std::result::Result<Value, Error> {
+        self.serialize_i64(i64::from(i))
+    }
+
+    fn serialize_i16(self, i: i16) -> std::result::Result<Value, Error> {
+        self.serialize_i64(i64::from(i))
+    }
+
+    fn serialize_i32(self, i: i32) -> std::result::Result<Value, Error> {
+        self.serialize_i64(i64::from(i))
+    }
+
+    fn serialize_i64(self, i: i64) -> std::result::Result<Value, Error> {
+        Ok(Value::Integer(i))
+    }
+
+    fn serialize_u8(self, i: u8) -> std::result::Result<Value, Error> {
+        self.serialize_i64(i64::from(i))
+    }
+
+    fn serialize_u16(self, i: u16) -> std::result::Result<Value, Error> {
+        self.serialize_i64(i64::from(i))
+    }
+
+    fn serialize_u32(self, i: u32) -> std::result::Result<Value, Error> {
+        self.serialize_i64(i64::from(i))
+    }
+
+    fn serialize_u64(self, i: u64) -> std::result::Result<Value, Error> {
+        i64::try_from(i)
+            .map(Value::Integer)
+            .map_err(|_| Error(format!("integer {} is too large", i)))
+    }
+
+    fn serialize_f32(self, r: f32) -> std::result::Result<Value, Error> {
+        self.serialize_f64(f64::from(r))
+    }
+
+    fn serialize_f64(self, r: f64) -> std::result::Result<Value, Error> {
+        Ok(Value::Real(r))
+    }
+
+    fn serialize_char(self, c: char) -> std::result::Result<Value, Error> {
+        Ok(Value::Text(c.to_string()))
+    }
+
+    fn serialize_str(self, s: &str) -> std::result::Result<Value, Error> {
+        Ok(Value::Text(s.to_string()))
+    }
+
+    fn serialize_bytes(self, b: &[u8]) -> std::result::Result<Value, Error> {
+        Ok(Value::Blob(b.to_vec()))
+    }
+
+    fn serialize_none(self) -> std::result::Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> std::result::Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> std::result::Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> std::result::Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> std::result::Result<Value, Error> {
+        Ok(Value::Text(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> std::result::Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> std::result::Result<Value, Error> {
+        Err(Error(NOT_A_VALUE.to_string()))
+    }
+
+    // A `Vec<u8>` serializes as a sequence of bytes
+    fn serialize_seq(self, len: Option<usize>) -> std::result::Result<BlobSerializer, Error> {
+        Ok(BlobSerializer {
+            bytes: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    unsupported! {
+        NOT_A_VALUE;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize)
+            -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize)
+            -> Self::SerializeStructVariant;
+    }
+}
+
+struct BlobSerializer {
+    bytes: Vec<u8>,
+}
+
+impl ser::SerializeSeq for BlobSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> std::result::Result<(), Error> {
+        match value.serialize(ValueSerializer)? {
+            Value::Integer(i) if (0..=255).contains(&i) => {
+                self.bytes.push(i as u8);
+                Ok(())
+            }
+            _ => Err(Error(NOT_A_VALUE.to_string())),
+        }
+    }
+
+    fn end(self) -> std::result::Result<Value, Error> {
+        Ok(Value::Blob(self.bytes))
+    }
+}
+
+/// Reads a row as a map from column names to values.
+struct RowDeserializer<'a> {
+    row: &'a ResultRow,
+}
+
+impl<'de> Deserializer<'de> for RowDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        visitor.visit_map(ColumnAccess {
+            row: self.row,
+            column: 0,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct ColumnAccess<'a> {
+    row: &'a ResultRow,
+    /// The column whose value comes next.
+    column: usize,
+}
+
+impl<'de> MapAccess<'de> for ColumnAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> std::result::Result<Option<K::Value>, Error> {
+        let Some(name) = self.row.columns().get(self.column) else {
+            return Ok(None);
+        };
+        let name: StrDeserializer<'_, Error> = name.as_str().into_deserializer();
+        seed.deserialize(name).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> std::result::Result<V::Value, Error> {
+        let column = self.column;
+        self.column += 1;
+        seed.deserialize(ValueDeserializer(&self.row.values()[column]))
+            .map_err(|error| Error(format!("column {}: {}", self.row.columns()[column], error)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.row.columns().len() - self.column)
+    }
+}
+
+/// Reads a field from a value, converting it as `FromValue` does.
+struct ValueDeserializer<'a>(&'a Value);
+
+impl<'de> Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Integer(i) => visitor.visit_i64(*i),
+            Value::Real(r) => visitor.visit_f64(*r),
+            Value::Boolean(b) => visitor.visit_bool(*b),
+            Value::Text(s) => visitor.visit_str(s),
+            Value::Blob(b) => visitor.visit_bytes(b),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match bool::from_value(self.0) {
+            Some(b) => visitor.visit_bool(b),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    // The visitors of the narrower types check the range
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match i64::from_value(self.0) {
+            Some(i) => visitor.visit_i64(i),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match f64::from_value(self.0) {
+            Some(r) => visitor.visit_f64(r),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> std::result::Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> std::result::Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        match self.0 {
+            Value::Blob(bytes) => {
+                let mut bytes = SeqDeserializer::new(bytes.iter().copied());
+                let value = visitor.visit_seq(&mut bytes)?;
+                bytes.end()?;
+                Ok(value)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, Error> {
+        match self.0 {
+            Value::Text(variant) => {
+                let variant: StrDeserializer<'_, Error> = variant.as_str().into_deserializer();
+                visitor.visit_enum(variant)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+    use ::serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Status {
+        Active,
+        Retired,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        id: i64,
+        name: String,
+        price: Option<f64>,
+        in_stock: bool,
+        status: Status,
+        data: Vec<u8>,
+        quantity: u16,
+    }
+
+    fn item(id: i64, price: Option<f64>) -> Item {
+        Item {
+            id,
+            name: format!("item{}", id),
+            price,
+            in_stock: id % 2 == 0,
+            status: Status::Active,
+            data: vec![id as u8, 0xff],
+            quantity: 7,
+        }
+    }
+
+    #[test]
+    fn test_structs_round_trip() {
+        let path = temp_db_path("serde_round_trip");
+        let mut table = Table::db_open(&path);
+        PreparedStatement::prepare(
+            "create table items (id integer primary key, name text, price real, \
+             in_stock boolean, status text, data blob, quantity integer, note text)",
+            &mut table,
+        )
+        .and_then(|mut statement| statement.execute(&mut table))
+        .unwrap();
+        insert(&mut table, "items", &item(1, Some(2.5))).unwrap();
+        insert(&mut table, "items", &item(2, None)).unwrap();
+        // Structs with the same fields share one prepared insert
+        assert_eq!(table.statements.len(), 1);
+
+        // Fields bound to parameters by name, the ones not used left out
+        let mut insert_named = PreparedStatement::prepare(
+            "insert into items (id, name, status, in_stock, data, quantity) \
+             values (:id, :name, :status, :in_stock, :data, :quantity)",
+            &mut table,
+        )
+        .unwrap();
+        let mut retired = item(3, Some(9.0));
+        retired.status = Status::Retired;
+        insert_named.bind_struct(&retired).unwrap();
+        insert_named.execute(&mut table).unwrap();
+        retired.price = None;
+
+        let mut select =
+            PreparedStatement::prepare("select * from items order by id", &mut table).unwrap();
+        let items = select
+            .query(&mut table)
+            .unwrap()
+            .map(|row| row?.deserialize::<Item>())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(items, [item(1, Some(2.5)), item(2, None), retired]);
+
+        // Maps work as well as structs
+        let mut values = BTreeMap::new();
+        values.insert("id", 4);
+        values.insert("quantity", 1);
+        insert(&mut table, "items", &values).unwrap();
+        assert_eq!(table.statements.len(), 2);
+        let mut select =
+            PreparedStatement::prepare("select id, quantity from items where id = 4", &mut table)
+                .unwrap();
+        let row = select.step(&mut table).unwrap().unwrap();
+        assert_eq!(row.deserialize::<BTreeMap<String, i64>>().unwrap(), {
+            let mut expected = BTreeMap::new();
+            expected.insert("id".to_string(), 4);
+            expected.insert("quantity".to_string(), 1);
+            expected
+        });
+        select.reset(&mut table);
+    }
+
+    #[test]
+    fn test_conversion_errors() {
+        assert!(matches!(to_values(&5), Err(crate::Error::Serialization(_))));
+        #[derive(Serialize)]
+        struct Nested {
+            inner: (i64, i64),
+        }
+        assert!(matches!(
+            to_values(&Nested { inner: (1, 2) }),
+            Err(crate::Error::Serialization(message)) if message.starts_with("field inner")
+        ));
+        assert!(matches!(
+            to_values(&BTreeMap::from([("big", u64::MAX)])),
+            Err(crate::Error::Serialization(_))
+        ));
+
+        let row = |values: Vec<Value>| {
+            let columns = [
+                "id", "name", "price", "in_stock", "status", "data", "quantity",
+            ];
+            ResultRow::new(columns.map(String::from).into(), values)
+        };
+        let mut values = vec![
+            Value::Integer(1),
+            Value::Text("a".to_string()),
+            Value::Integer(3),
+            Value::Integer(1),
+            Value::Text("Retired".to_string()),
+            Value::Blob(vec![1]),
+            Value::Integer(70_000),
+        ];
+        assert!(matches!(
+            row(values.clone()).deserialize::<Item>(),
+            Err(crate::Error::Serialization(message)) if message.starts_with("column quantity")
+        ));
+        values[6] = Value::Integer(2);
+        let item = row(values.clone()).deserialize::<Item>().unwrap();
+        assert_eq!(item.price, Some(3.0));
+        assert!(item.in_stock);
+        assert_eq!(item.status, Status::Retired);
+        values[1] = Value::Integer(5);
+        assert!(matches!(
+            row(values).deserialize::<Item>(),
+            Err(crate::Error::Serialization(message)) if message.starts_with("column name")
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::pager::{read_u32, write_u32, Pager};
+use crate::table::{Row, PAGE_SIZE};
+use crate::value::Value;
+use log::info;
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Bytes of records the sorter keeps in memory before spilling a run.
+pub const DEFAULT_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
+
+// Run pages start with the number of the next page of the run (0 ends it)
+const RUN_NEXT_OFFSET: usize = 0;
+const RUN_DATA_OFFSET: usize = RUN_NEXT_OFFSET + 4;
+
+// How many names a spill file tries before giving up
+const SPILL_FILE_ATTEMPTS: usize = 16;
+
+/// Sorts records whose first `descending.len()` values are the sort key.
+///
+/// Records are buffered in memory until they exceed the memory budget, then
+/// sorted and written out as a run to a temporary file through a `Pager`.
+/// Once every record is in, the runs are merged back together.
+pub struct Sorter {
+    descending: Arc<[bool]>,
+    memory_budget: usize,
+    memory_used: usize,
+    records: Vec<Row>,
+    spill: Option<SpillFile>,
+    runs: Vec<u32>,
+}
+
+impl Sorter {
+    pub fn new(descending: Vec<bool>, memory_budget: usize) -> Self {
+        Sorter {
+            descending: descending.into(),
+            memory_budget,
+            memory_used: 0,
+            records: Vec::new(),
+            spill: None,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: Row) -> io::Result<()> {
+        self.memory_used += record_size(&record.values);
+        self.records.push(record);
+        if self.memory_used > self.memory_budget {
+            self.spill_run()?;
+        }
+        Ok(())
+    }
+
+    /// Number of runs written to disk so far.
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn sort_records(&mut self) {
+        let descending = self.descending.clone();
+        self.records
+            .sort_by(|a, b| compare_keys(&descending, &a.values, &b.values));
+    }
+
+    fn spill_run(&mut self) -> io::Result<()> {
+        self.sort_records();
+        if self.spill.is_none() {
+            self.spill = Some(SpillFile::create()?);
+        }
+        let spill = self.spill.as_mut().unwrap();
+        let records = std::mem::take(&mut self.records);
+        info!("Spilling sorted run of {} records", records.len());
+        let first_page = spill.write_run(records)?;
+        self.runs.push(first_page);
+        self.memory_used = 0;
+        Ok(())
+    }
+
+    /// Returns every record in sorted order.
+    pub fn finish(mut self) -> io::Result<SortedRecords> {
+        self.sort_records();
+        let Some(spill) = self.spill.take() else {
+            return Ok(SortedRecords::Memory(self.records.into_iter()));
+        };
+
+        // The records still in memory are the newest and act as the last run
+        let mut sources = self
+            .runs
+            .iter()
+            .map(|&page| Source::Run(RunReader::new(page)))
+            .collect::<Vec<_>>();
+        sources.push(Source::Memory(self.records.into_iter()));
+        let mut merge = Merge {
+            spill,
+            sources,
+            heap: BinaryHeap::new(),
+        };
+        for index in 0..merge.sources.len() {
+            merge.refill(index, &self.descending)?;
+        }
+        Ok(SortedRecords::Merge(Box::new(merge)))
+    }
+}
+
+/// Rough number of bytes a record occupies in memory.
+pub fn record_size(values: &[Value]) -> usize {
+    values
+        .iter()
+        .map(|value| {
+            std::mem::size_of::<Value>()
+                + match value {
+                    Value::Text(s) => s.len(),
+                    Value::Blob(b) => b.len(),
+                    _ => 0,
+                }
+        })
+        .sum()
+}
+
+/// Compares the sort keys at the start of two records. NULLs sort first in
+/// ascending order, as in `Value::collate_cmp`.
+pub fn compare_keys(descending: &[bool], a: &[Value], b: &[Value]) -> Ordering {
+    for (i, &descending) in descending.iter().enumerate() {
+        let ordering = a[i].collate_cmp(&b[i]);
+        if ordering != Ordering::Equal {
+            return if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+        }
+    }
+    Ordering::Equal
+}
+
+/// Temporary file holding the spilled runs, removed when dropped.
+struct SpillFile {
+    path: PathBuf,
+    pager: Pager,
+}
+
+impl SpillFile {
+    /// Creates the file under a random name in the temporary directory.
+    /// A file that is already there is never opened, so another user of the
+    /// directory can not have the runs written into a file of theirs.
+    fn create() -> io::Result<Self> {
+        for _ in 0..SPILL_FILE_ATTEMPTS {
+            let path = std::env::temp_dir().join(format!(
+                "rsqlite3-sort-{}-{:016x}.tmp",
+                std::process::id(),
+                RandomState::new().build_hasher().finish()
+            ));
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+            let Some(filename) = path.to_str() else {
+                let _ = std::fs::remove_file(&path);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Temporary directory is not valid UTF-8: {}", path.display()),
+                ));
+            };
+            return match Pager::open(filename, None) {
+                Ok(pager) => Ok(SpillFile { path, pager }),
+                Err(e) => {
+                    let _ = std::fs::remove_file(&path);
+                    Err(e)
+                }
+            };
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Could not find an unused name for a sort spill file",
+        ))
+    }
+
+    /// Writes the records as a chain of pages, each record stored as its
+    /// length followed by its payload. Pages are written out and dropped from
+    /// the cache as soon as they are full. Returns the first page of the run.
+    fn write_run(&mut self, records: Vec<Row>) -> io::Result<u32> {
+        let first_page = self.pager.allocate_page();
+        let mut page_num = first_page;
+        let mut offset = RUN_DATA_OFFSET;
+        for record in records {
+            let payload = record.to_payload();
+            let mut data = (payload.len() as u32).to_le_bytes().to_vec();
+            data.extend_from_slice(&payload);
+            let mut data = &data[..];
+            while !data.is_empty() {
+                if offset == PAGE_SIZE {
+                    let next = self.pager.allocate_page();
+                    write_u32(self.pager.fetch_page_mut(page_num), RUN_NEXT_OFFSET, next);
+                    self.pager.evict(page_num)?;
+                    page_num = next;
+                    offset = RUN_DATA_OFFSET;
+                }
+                let len = data.len().min(PAGE_SIZE - offset);
+                self.pager.fetch_page_mut(page_num)[offset..offset + len]
+                    .copy_from_slice(&data[..len]);
+                offset += len;
+                data = &data[len..];
+            }
+        }
+        self.pager.evict(page_num)?;
+        Ok(first_page)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reads one run back a page at a time.
+struct RunReader {
+    page_num: u32,
+    page: Vec<u8>,
+    offset: usize,
+}
+
+impl RunReader {
+    fn new(first_page: u32) -> Self {
+        RunReader {
+            page_num: first_page,
+            page: Vec::new(),
+            offset: PAGE_SIZE,
+        }
+    }
+
+    fn read_bytes(&mut self, pager: &mut Pager, len: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            if self.offset == PAGE_SIZE {
+                if self.page_num == 0 {
+                    return Ok(None);
+                }
+                self.page = pager.fetch_page(self.page_num).clone();
+                pager.evict(self.page_num)?;
+                self.page_num = read_u32(&self.page, RUN_NEXT_OFFSET);
+                self.offset = RUN_DATA_OFFSET;
+            }
+            let chunk = (len - data.len()).min(PAGE_SIZE - self.offset);
+            data.extend_from_slice(&self.page[self.offset..self.offset + chunk]);
+            self.offset += chunk;
+        }
+        Ok(Some(data))
+    }
+
+    fn next(&mut self, pager: &mut Pager) -> io::Result<Option<Row>> {
+        let Some(len) = self.read_bytes(pager, 4)? else {
+            return Ok(None);
+        };
+        // A zero length can only be the unused tail of the last page
+        let len = read_u32(&len, 0) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let payload = self
+            .read_bytes(pager, len)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "sort run truncated"))?;
+        Row::from_payload(&payload).map(Some)
+    }
+}
+
+enum Source {
+    Memory(std::vec::IntoIter<Row>),
+    Run(RunReader),
+}
+
+/// The next record of one source, ordered so that `BinaryHeap` pops the
+/// smallest key first.
+struct HeapEntry {
+    record: Row,
+    source: usize,
+    descending: Arc<[bool]>,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.descending, &other.record.values, &self.record.values)
+            // Equal keys come out in source order so the sort is stable
+            .then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+/// k-way merge of the spilled runs and the records left in memory.
+pub struct Merge {
+    spill: SpillFile,
+    sources: Vec<Source>,
+    heap: BinaryHeap<HeapEntry>,
+}
+
+impl Merge {
+    fn refill(&mut self, source: usize, descending: &Arc<[bool]>) -> io::Result<()> {
+        let record = match &mut self.sources[source] {
+            Source::Memory(records) => records.next(),
+            Source::Run(reader) => reader.next(&mut self.spill.pager)?,
+        };
+        if let Some(record) = record {
+            self.heap.push(HeapEntry {
+                record,
+                source,
+                descending: descending.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+pub enum SortedRecords {
+    Memory(std::vec::IntoIter<Row>),
+    Merge(Box<Merge>),
+}
+
+impl Iterator for SortedRecords {
+    type Item = io::Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedRecords::Memory(records) => records.next().map(Ok),
+            SortedRecords::Merge(merge) => {
+                let entry = merge.heap.pop()?;
+                if let Err(e) = merge.refill(entry.source, &entry.descending) {
+                    return Some(Err(e));
+                }
+                Some(Ok(entry.record))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: Value, tag: i64) -> Row {
+        Row {
+            values: vec![key, Value::Integer(tag)],
+        }
+    }
+
+    fn sort(records: Vec<Row>, descending: bool, budget: usize) -> (Vec<Row>, usize) {
+        let mut sorter = Sorter::new(vec![descending], budget);
+        for record in records {
+            sorter.push(record).unwrap();
+        }
+        let runs = sorter.num_runs();
+        let sorted = sorter.finish().unwrap().collect::<io::Result<Vec<_>>>();
+        (sorted.unwrap(), runs)
+    }
+
+    #[test]
+    fn test_sort_in_memory() {
+        let records = vec![
+            record(Value::Integer(3), 0),
+            record(Value::Null, 1),
+            record(Value::Text("a".to_string()), 2),
+            record(Value::Real(1.5), 3),
+        ];
+        let (sorted, runs) = sort(records, false, DEFAULT_MEMORY_BUDGET);
+        assert_eq!(runs, 0);
+        let tags = sorted
+            .iter()
+            .map(|r| r.values[1].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            [1, 3, 0, 2].map(Value::Integer),
+            "NULL, then numbers, then text"
+        );
+    }
+
+    #[test]
+    fn test_sort_spills_and_merges_runs() {
+        // Keys repeat so the merge has to keep equal keys in insertion order
+        let records = (0..5000)
+            .map(|i| {
+                let key = Value::Text(format!("key{:03}{}", (i * 7919) % 500, "x".repeat(i % 50)));
+                record(key, i as i64)
+            })
+            .collect::<Vec<_>>();
+        let mut expected = records.clone();
+        expected.sort_by(|a, b| b.values[0].collate_cmp(&a.values[0]));
+
+        let (sorted, runs) = sort(records, true, 64 * 1024);
+        assert!(runs > 5, "expected the sort to spill, got {} runs", runs);
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_spill_files_get_new_names() {
+        let first = SpillFile::create().unwrap();
+        let second = SpillFile::create().unwrap();
+        assert_ne!(first.path, second.path);
+        assert!(first.path.exists());
+        let path = first.path.clone();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,532 @@
+//! Statements compiled once for a program to run, reading their rows as an
+//! iterator instead of having them printed. A statement can hold `?`,
+//! `?NNN` and `:name` parameters, so it is prepared once and run again and
+//! again with different values bound to them.
+
+use crate::compile::compile_statement;
+use crate::error::Error;
+use crate::parser::{self, StatementType};
+use crate::rows::{Execution, Result, ResultRow, Rows};
+use crate::table::Table;
+use crate::value::Value;
+use crate::vm::{Program, Vm};
+use std::sync::Arc;
+
+/// A parameter of a statement, by number from 1 or by name. Names include
+/// their `:` or `?`.
+pub trait ParameterIndex {
+    /// The position of the parameter among `names`.
+    fn index(&self, names: &[Option<String>]) -> Result<usize>;
+}
+
+impl ParameterIndex for usize {
+    fn index(&self, names: &[Option<String>]) -> Result<usize> {
+        match (1..=names.len()).contains(self) {
+            true => Ok(*self - 1),
+            false => Err(Error::ParameterIndexOutOfRange(*self)),
+        }
+    }
+}
+
+impl ParameterIndex for &str {
+    fn index(&self, names: &[Option<String>]) -> Result<usize> {
+        names
+            .iter()
+            .position(|name| name.as_deref() == Some(*self))
+            .ok_or_else(|| Error::ParameterNotFound(self.to_string()))
+    }
+}
+
+/// How many statements a connection keeps prepared for `cached`.
+const STATEMENT_CACHE_SIZE: usize = 16;
+
+/// The statements a connection keeps prepared, by SQL, most recently used
+/// first.
+#[derive(Default)]
+pub(crate) struct StatementCache {
+    statements: Vec<(String, PreparedStatement)>,
+}
+
+impl StatementCache {
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.statements.len()
+    }
+}
+
+pub struct PreparedStatement {
+    sql: String,
+    program: Arc<Program>,
+    /// The version of the catalog the program was compiled against. A run
+    /// after the schema changed compiles the SQL again first.
+    schema_version: u64,
+    parameters: Vec<Value>,
+    parameter_names: Vec<Option<String>>,
+    columns: Arc<[String]>,
+    /// Whether the statement may write, so that a transaction it runs in
+    /// by itself takes the write lock up front.
+    write: bool,
+    /// Whether the statement starts or ends transactions itself.
+    transaction_control: bool,
+    /// The run `step` is part way through.
+    execution: Option<Execution>,
+}
+
+impl PreparedStatement {
+    /// Parses and compiles the SQL against the tables the connection knows.
+    pub fn prepare(sql: &str, table: &mut Table) -> Result<Self> {
+        let (statement, parameter_names) =
+            parser::parse_with_parameters(sql).map_err(Error::Syntax)?;
+        let write = !matches!(
+            statement,
+            StatementType::Select(_) | StatementType::Pragma(_) | StatementType::Explain(_)
+        );
+        let transaction_control = matches!(
+            statement,
+            StatementType::Begin
+                | StatementType::Commit
+                | StatementType::Rollback
+                | StatementType::Savepoint(_)
+                | StatementType::Release(_)
+                | StatementType::RollbackTo(_)
+        );
+        // The catalog is only sure to be current under a lock, so outside
+        // of a transaction the statement compiles in a read one of its own
+        let locked = !table.in_transaction();
+        if locked {
+            table.begin(false)?;
+        }
+        let program = compile_statement(statement, sql, table);
+        let schema_version = table.catalog.version;
+        if locked {
+            table.rollback();
+        }
+        let program = program?;
+        Ok(PreparedStatement {
+            sql: sql.to_string(),
+            columns: program.columns.clone().into(),
+            program: Arc::new(program),
+            schema_version,
+            parameters: vec![Value::Null; parameter_names.len()],
+            parameter_names,
+            write,
+            transaction_control,
+            execution: None,
+        })
+    }
+
+    /// The names of the columns of the rows the statement returns.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// The number of the statement's highest parameter.
+    pub fn parameter_count(&self) -> usize {
+        self.parameter_names.len()
+    }
+
+    /// The name of the parameter numbered `n` from 1, `None` for a `?`.
+    pub fn parameter_name(&self, n: usize) -> Option<&str> {
+        self.parameter_names.get(n.checked_sub(1)?)?.as_deref()
+    }
+
+    /// Binds a value to the parameter, for the runs the statement starts
+    /// from now on. Parameters nothing was bound to are NULL.
+    pub fn bind(&mut self, parameter: impl ParameterIndex, value: impl Into<Value>) -> Result<()> {
+        let index = parameter.index(&self.parameter_names)?;
+        self.parameters[index] = value.into();
+        Ok(())
+    }
+
+    /// Sets every parameter back to NULL.
+    pub fn clear_bindings(&mut self) {
+        self.parameters.fill(Value::Null);
+    }
+
+    /// Starts a run of the program with the values bound now. Outside of a
+    /// transaction it runs in one of its own.
+    fn start(&mut self, table: &mut Table) -> Result<Execution> {
+        let autocommit = !table.in_transaction() && !self.transaction_control;
+        if autocommit {
+            table.begin(self.write)?;
+        }
+        if self.schema_version != table.catalog.version {
+            if let Err(error) = self.recompile(table) {
+                if autocommit {
+                    table.rollback();
+                }
+                return Err(error);
+            }
+        }
+        let vm = Vm::with_parameters(Arc::clone(&self.program), self.parameters.clone());
+        Ok(Execution::new(vm, self.columns.clone(), autocommit))
+    }
+
+    /// Compiles the SQL again against the schema as it is now. Fails if it
+    /// no longer compiles, as when the transaction that created its table
+    /// rolled back.
+    fn recompile(&mut self, table: &mut Table) -> Result<()> {
+        let prepared = PreparedStatement::prepare(&self.sql, table)?;
+        self.program = prepared.program;
+        self.columns = prepared.columns;
+        self.schema_version = prepared.schema_version;
+        Ok(())
+    }
+
+    /// Runs the statement from the start, returning its rows as they are
+    /// produced.
+    pub fn query<'a>(&mut self, table: &'a mut Table) -> Result<Rows<'a>> {
+        self.reset(table);
+        let execution = self.start(table)?;
+        Ok(Rows::new(execution, table))
+    }
+
+    /// Runs the statement from the start to the end, dropping any rows it
+    /// returns.
+    pub fn execute(&mut self, table: &mut Table) -> Result<()> {
+        self.query(table)?.try_for_each(|row| row.map(drop))
+    }
+
+    /// Runs the statement until its next row, starting a new run if none is
+    /// under way. `None` once the run is over, and the next call starts
+    /// over.
+    pub fn step(&mut self, table: &mut Table) -> Result<Option<ResultRow>> {
+        if self.execution.is_none() {
+            self.execution = Some(self.start(table)?);
+        }
+        let execution = self.execution.as_mut().expect("a run was just started");
+        match execution.step(table) {
+            Some(Ok(row)) => Ok(Some(row)),
+            Some(Err(error)) => {
+                self.execution = None;
+                Err(error)
+            }
+            None => {
+                self.execution = None;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs `run` with the statement the connection keeps prepared for
+    /// `sql`, preparing it the first time. The connection keeps the
+    /// statements used last this way, each with no values bound when it is
+    /// handed out again.
+    pub fn cached<T>(
+        sql: &str,
+        table: &mut Table,
+        run: impl FnOnce(&mut PreparedStatement, &mut Table) -> Result<T>,
+    ) -> Result<T> {
+        let cache = &mut table.statements.statements;
+        let mut statement = match cache.iter().position(|(cached, _)| cached == sql) {
+            Some(position) => cache.remove(position).1,
+            None => PreparedStatement::prepare(sql, table)?,
+        };
+        let result = run(&mut statement, table);
+        statement.reset(table);
+        statement.clear_bindings();
+        let cache = &mut table.statements.statements;
+        cache.insert(0, (sql.to_string(), statement));
+        cache.truncate(STATEMENT_CACHE_SIZE);
+        result
+    }
+
+    /// Stops a run `step` is part way through, so the next one starts over.
+    /// The transaction the run started is rolled back. Bound values are
+    /// kept.
+    pub fn reset(&mut self, table: &mut Table) {
+        if let Some(mut execution) = self.execution.take() {
+            execution.abort(table);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    #[test]
+    fn test_query_rows() {
+        let path = temp_db_path("statement_query");
+        let mut table = Table::db_open(&path);
+        let execute = |sql: &str, table: &mut Table| {
+            PreparedStatement::prepare(sql, table)
+                .and_then(|mut statement| statement.execute(table))
+                .unwrap()
+        };
+        execute(
+            "create table people (id integer primary key, name text, age integer)",
+            &mut table,
+        );
+        execute(
+            "insert into people values (1, 'alice', 30), (2, 'bob', NULL)",
+            &mut table,
+        );
+
+        let mut statement =
+            PreparedStatement::prepare("select name, age from people order by id", &mut table)
+                .unwrap();
+        assert_eq!(statement.columns(), ["name", "age"]);
+        let people = statement
+            .query(&mut table)
+            .unwrap()
+            .map(|row| {
+                let row = row?;
+                Ok((row.get::<String>("name")?, row.get::<Option<i64>>(1)?))
+            })
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            people,
+            [("alice".to_string(), Some(30)), ("bob".to_string(), None)]
+        );
+        assert!(!table.in_transaction());
+
+        // Rows left unread end the transaction too
+        let mut rows = statement.query(&mut table).unwrap();
+        assert!(rows.next().is_some());
+        drop(rows);
+        assert!(!table.in_transaction());
+
+        // A failing statement is undone
+        let mut insert = PreparedStatement::prepare(
+            "insert into people values (3, 'carol', 25), (1, 'dup', 1)",
+            &mut table,
+        )
+        .unwrap();
+        assert!(matches!(
+            insert.execute(&mut table),
+            Err(Error::UniqueViolation(_))
+        ));
+        let mut count =
+            PreparedStatement::prepare("select count(*) from people", &mut table).unwrap();
+        let mut rows = count.query(&mut table).unwrap();
+        assert_eq!(rows.next().unwrap().unwrap().get::<i64>(0).unwrap(), 2);
+        assert!(rows.next().is_none());
+        drop(rows);
+
+        assert!(matches!(
+            PreparedStatement::prepare("select * from missing", &mut table),
+            Err(Error::TableNotFound(_))
+        ));
+        assert!(matches!(
+            PreparedStatement::prepare("select from", &mut table),
+            Err(Error::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_bind_and_step() {
+        let path = temp_db_path("statement_bind");
+        let mut table = Table::db_open(&path);
+        PreparedStatement::prepare(
+            "create table items (id integer primary key, name text, price real)",
+            &mut table,
+        )
+        .and_then(|mut statement| statement.execute(&mut table))
+        .unwrap();
+
+        // One insert, run again and again with new values
+        let mut insert =
+            PreparedStatement::prepare("insert into items values (?, :name, ?)", &mut table)
+                .unwrap();
+        assert_eq!(insert.parameter_count(), 3);
+        assert_eq!(insert.parameter_name(2), Some(":name"));
+        assert_eq!(insert.parameter_name(1), None);
+        let program = Arc::clone(&insert.program);
+        for id in 1..=5 {
+            insert.bind(1, id).unwrap();
+            insert.bind(":name", format!("item{}", id)).unwrap();
+            // Text is stored as a number in a REAL column
+            insert.bind(3, (id * 10).to_string()).unwrap();
+            assert!(insert.step(&mut table).unwrap().is_none());
+        }
+        insert.clear_bindings();
+        insert.bind(1, 6).unwrap();
+        insert.execute(&mut table).unwrap();
+        // Every run reads its values from the one program
+        assert!(Arc::ptr_eq(&program, &insert.program));
+        assert!(matches!(
+            insert.bind(4, 1),
+            Err(Error::ParameterIndexOutOfRange(4))
+        ));
+        assert!(matches!(
+            insert.bind(":missing", 1),
+            Err(Error::ParameterNotFound(_))
+        ));
+
+        // Parameters in expressions, index seeks and aggregates
+        let mut select = PreparedStatement::prepare(
+            "select name, price * ? from items where id >= :low and id < :low + 2 order by id",
+            &mut table,
+        )
+        .unwrap();
+        select.bind(1, 2).unwrap();
+        select.bind(":low", 2).unwrap();
+        let first = select.step(&mut table).unwrap().unwrap();
+        assert_eq!(first.get::<String>(0).unwrap(), "item2");
+        assert_eq!(first.get::<f64>(1).unwrap(), 40.0);
+        assert!(table.in_transaction());
+        select.reset(&mut table);
+        assert!(!table.in_transaction());
+        select.bind(":low", 4).unwrap();
+        let names = std::iter::from_fn(|| select.step(&mut table).unwrap())
+            .map(|row| row.get::<String>("name").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["item4", "item5"]);
+        assert!(!table.in_transaction());
+
+        let mut count = PreparedStatement::prepare(
+            "select count(*), sum(price + ?) from items where price is not null",
+            &mut table,
+        )
+        .unwrap();
+        count.bind(1, 1.0).unwrap();
+        let row = count.step(&mut table).unwrap().unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), 5);
+        assert_eq!(row.get::<f64>(1).unwrap(), 155.0);
+        assert!(count.step(&mut table).unwrap().is_none());
+
+        // Unbound parameters are NULL
+        let mut lookup =
+            PreparedStatement::prepare("select id from items where name is ?", &mut table).unwrap();
+        let row = lookup.step(&mut table).unwrap().unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_recompile_after_schema_change() {
+        let path = temp_db_path("statement_schema_change");
+        let mut table = Table::db_open(&path);
+        let execute = |sql: &str, table: &mut Table| {
+            PreparedStatement::prepare(sql, table)
+                .and_then(|mut statement| statement.execute(table))
+                .unwrap()
+        };
+        let opcodes = |statement: &PreparedStatement| {
+            let opcodes = statement.program.insns.iter();
+            opcodes.map(|insn| insn.describe().0).collect::<Vec<_>>()
+        };
+        execute(
+            "create table items (id integer primary key, name text)",
+            &mut table,
+        );
+        execute("insert into items values (1, 'a'), (2, 'b')", &mut table);
+
+        let mut lookup =
+            PreparedStatement::prepare("select id from items where name = ?", &mut table).unwrap();
+        lookup.bind(1, "b").unwrap();
+        assert!(!opcodes(&lookup).contains(&"OpenIndex"));
+
+        // An index made by another connection is used on the next run
+        let mut other = Table::db_open(&path);
+        execute("create index items_name on items (name)", &mut other);
+        let row = lookup.step(&mut table).unwrap().unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), 2);
+        assert!(opcodes(&lookup).contains(&"OpenIndex"));
+        lookup.reset(&mut table);
+
+        // A table rolled back with its transaction is gone for the statement
+        execute("begin", &mut table);
+        execute("create table scratch (a)", &mut table);
+        let mut select = PreparedStatement::prepare("select * from scratch", &mut table).unwrap();
+        execute("rollback", &mut table);
+        assert!(matches!(
+            select.execute(&mut table),
+            Err(Error::TableNotFound(_))
+        ));
+        assert!(!table.in_transaction());
+        execute("create table scratch (a, b)", &mut table);
+        select.execute(&mut table).unwrap();
+        assert_eq!(select.columns(), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_prepare_sees_other_connections_and_explains() {
+        let path = temp_db_path("statement_prepare_fresh");
+        let mut table = Table::db_open(&path);
+        let mut other = Table::db_open(&path);
+        let execute = |sql: &str, table: &mut Table| {
+            PreparedStatement::prepare(sql, table)
+                .and_then(|mut statement| statement.execute(table))
+                .unwrap()
+        };
+
+        // A table another connection created is there to compile against
+        execute(
+            "create table items (id integer primary key, name text)",
+            &mut other,
+        );
+        let mut plan = PreparedStatement::prepare(
+            "explain query plan select name from items where id = ?",
+            &mut table,
+        )
+        .unwrap();
+        assert!(!table.in_transaction());
+        assert_eq!(plan.columns(), ["detail"]);
+        let rows = plan.query(&mut table).unwrap();
+        let lines = rows
+            .map(|row| row?.get::<String>(0))
+            .collect::<Result<Vec<_>>>();
+        assert_eq!(lines.unwrap(), ["SEARCH items USING PRIMARY KEY (id=?)"]);
+
+        // EXPLAIN lists the program without running it
+        let mut explain =
+            PreparedStatement::prepare("explain insert into items values (1, 'a')", &mut table)
+                .unwrap();
+        assert_eq!(explain.columns(), ["addr", "opcode", "detail"]);
+        let rows = explain.query(&mut table).unwrap();
+        let opcodes = rows
+            .map(|row| row?.get::<String>(1))
+            .collect::<Result<Vec<_>>>();
+        assert!(opcodes.unwrap().iter().any(|opcode| opcode == "Insert"));
+        let mut count =
+            PreparedStatement::prepare("select count(*) from items", &mut table).unwrap();
+        let row = count.step(&mut table).unwrap().unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cached_statements() {
+        let path = temp_db_path("statement_cached");
+        let mut table = Table::db_open(&path);
+        let execute = |sql: &str, table: &mut Table| {
+            PreparedStatement::cached(sql, table, |statement, table| statement.execute(table))
+        };
+        execute("create table t (id integer primary key)", &mut table).unwrap();
+        let insert = "insert into t values (?)";
+        let program = PreparedStatement::cached(insert, &mut table, |statement, table| {
+            statement.bind(1, 1)?;
+            statement.execute(table)?;
+            Ok(Arc::clone(&statement.program))
+        })
+        .unwrap();
+        // The same statement comes back, with nothing bound
+        PreparedStatement::cached(insert, &mut table, |statement, table| {
+            assert!(Arc::ptr_eq(&program, &statement.program));
+            statement.execute(table)
+        })
+        .unwrap();
+        let ids: Result<Vec<_>> =
+            PreparedStatement::cached("select id from t", &mut table, |statement, table| {
+                statement
+                    .query(table)?
+                    .map(|row| row?.get::<Option<i64>>(0))
+                    .collect()
+            });
+        assert_eq!(ids.unwrap(), [Some(1), None]);
+        assert_eq!(table.statements.len(), 3);
+
+        // The statements used longest ago make way
+        for n in 0..STATEMENT_CACHE_SIZE {
+            execute(&format!("select {} from t", n), &mut table).unwrap();
+        }
+        assert_eq!(table.statements.len(), STATEMENT_CACHE_SIZE);
+        assert!(table
+            .statements
+            .statements
+            .iter()
+            .all(|(sql, _)| sql != insert));
+    }
+}
@@ -0,0 +1,388 @@
+use crate::aggregate::group_key;
+use crate::btree::compare_prefix;
+use crate::parser::{self, StatementType};
+use crate::schema::TableSchema;
+use crate::table::{self, Row, Table};
+use crate::value::Value;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+/// The table ANALYZE keeps its statistics in. Every analyzed table has a
+/// row with only `nrow` set, a row per column with `col` set and a row per
+/// index with `idx` set. A column's `nrow` counts its non-NULL values and
+/// its `histogram` holds bucket bounds over a sample of them; an index's
+/// `ndistinct` counts its distinct keys.
+pub const STAT_TABLE: &str = "sqlite_stat";
+const STAT_TABLE_SQL: &str = "create table sqlite_stat (tbl text, idx text, col text, \
+                              nrow integer, ndistinct integer, histogram blob)";
+
+/// At most this many rows of a table are sampled for the histograms.
+const SAMPLE_SIZE: usize = 1000;
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// What the last ANALYZE found in a table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStats {
+    pub rows: usize,
+    /// By position of the column in the table.
+    pub columns: Vec<ColumnStats>,
+    /// The number of distinct keys of each index, by index name.
+    pub indexes: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnStats {
+    pub non_null: usize,
+    pub distinct: usize,
+    /// Ascending bounds of buckets holding about as many sampled values
+    /// each, from the smallest value to the largest. Empty if the column
+    /// only holds NULLs.
+    pub histogram: Vec<Value>,
+}
+
+impl ColumnStats {
+    /// The fraction of a table of `rows` rows whose value equals a given
+    /// non-NULL value.
+    pub fn equal_selectivity(&self, rows: usize) -> f64 {
+        if self.distinct == 0 || rows == 0 {
+            return 0.0;
+        }
+        self.non_null as f64 / rows as f64 / self.distinct as f64
+    }
+
+    /// The fraction of a table of `rows` rows whose value lies between the
+    /// bounds, `None` without a histogram to tell.
+    pub fn range_selectivity(
+        &self,
+        rows: usize,
+        lower: Option<&Value>,
+        upper: Option<&Value>,
+    ) -> Option<f64> {
+        if self.histogram.is_empty() || rows == 0 {
+            return None;
+        }
+        let from = lower.map_or(0.0, |value| self.fraction_below(value));
+        let to = upper.map_or(1.0, |value| self.fraction_below(value));
+        Some((to - from).max(0.0) * self.non_null as f64 / rows as f64)
+    }
+
+    /// The estimated fraction of the column's values below `value`, taking
+    /// the values of a bucket as spread evenly over it.
+    fn fraction_below(&self, value: &Value) -> f64 {
+        let buckets = (self.histogram.len() - 1).max(1) as f64;
+        let below = self
+            .histogram
+            .iter()
+            .take_while(|bound| bound.collate_cmp(value) == Ordering::Less)
+            .count();
+        match below {
+            0 => 0.0,
+            below if below == self.histogram.len() => 1.0,
+            below => (below as f64 - 0.5) / buckets,
+        }
+    }
+}
+
+/// Reads every row of the table and every entry of its indexes.
+pub fn analyze_table(table: &mut Table, schema: &TableSchema) -> io::Result<TableStats> {
+    let rows = table.num_rows(schema.root_page);
+    let step = rows.div_ceil(SAMPLE_SIZE).max(1);
+    let width = schema.columns.len();
+    let mut columns = vec![ColumnStats::default(); width];
+    let mut distinct = vec![HashSet::new(); width];
+    let mut samples = vec![Vec::new(); width];
+    let mut cursor = schema.btree().cursor();
+    let mut found = cursor.first(&mut table.pager);
+    let mut position = 0;
+    while found {
+        let row = Row::from_payload(cursor.value())?;
+        for (column, value) in row.values.into_iter().enumerate().take(width) {
+            if value.is_null() {
+                continue;
+            }
+            columns[column].non_null += 1;
+            distinct[column].insert(group_key(std::slice::from_ref(&value)));
+            if position % step == 0 {
+                samples[column].push(value);
+            }
+        }
+        position += 1;
+        found = cursor.next(&mut table.pager);
+    }
+    for ((stats, distinct), sample) in columns.iter_mut().zip(distinct).zip(samples) {
+        stats.distinct = distinct.len();
+        stats.histogram = histogram(sample);
+    }
+
+    let mut indexes = HashMap::new();
+    for index in table.catalog.table_indexes(schema.root_page) {
+        // Entries come in key order, so equal keys are next to each other
+        let num_columns = index.columns.len();
+        let mut cursor = index.btree().cursor();
+        let mut previous: Option<Vec<Value>> = None;
+        let mut keys = 0;
+        let mut found = cursor.first(&mut table.pager);
+        while found {
+            let key = &cursor.key()[..num_columns];
+            if previous
+                .as_ref()
+                .is_none_or(|previous| compare_prefix(previous, key) != Ordering::Equal)
+            {
+                keys += 1;
+            }
+            previous = Some(key.to_vec());
+            found = cursor.next(&mut table.pager);
+        }
+        indexes.insert(index.name, keys);
+    }
+    Ok(TableStats {
+        rows,
+        columns,
+        indexes,
+    })
+}
+
+fn histogram(mut sample: Vec<Value>) -> Vec<Value> {
+    if sample.is_empty() {
+        return Vec::new();
+    }
+    sample.sort_by(Value::collate_cmp);
+    let last = sample.len() - 1;
+    (0..=HISTOGRAM_BUCKETS)
+        .map(|bucket| sample[bucket * last / HISTOGRAM_BUCKETS].clone())
+        .collect()
+}
+
+/// Analyzes the table and replaces its rows in the stats table, which is
+/// created the first time.
+pub fn analyze(table: &mut Table, schema: &TableSchema) -> io::Result<()> {
+    let stats = analyze_table(table, schema)?;
+    let stat_table = match table.catalog.find_table(STAT_TABLE) {
+        Some(stat_table) => stat_table.clone(),
+        None => {
+            let Ok(StatementType::CreateTable(create)) = parser::parse_statement(STAT_TABLE_SQL)
+            else {
+                unreachable!("the stats table definition parses")
+            };
+            table
+                .create_table(&create, STAT_TABLE_SQL)
+                .expect("the stats table has no constraints")
+        }
+    };
+    let root_page = stat_table.root_page;
+    let mut stale = Vec::new();
+    let mut cursor = stat_table.btree().cursor();
+    let mut found = cursor.first(&mut table.pager);
+    while found {
+        let row = Row::from_payload(cursor.value())?;
+        if matches!(&row.values[0], Value::Text(name) if name.eq_ignore_ascii_case(&schema.name)) {
+            stale.push(table::cursor_rowid(&cursor));
+        }
+        found = cursor.next(&mut table.pager);
+    }
+    for rowid in stale {
+        table.delete_row(root_page, rowid)?;
+    }
+
+    let text = |text: &str| Value::Text(text.to_string());
+    let count = |count: usize| Value::Integer(count as i64);
+    let mut rows = vec![[
+        text(&schema.name),
+        Value::Null,
+        Value::Null,
+        count(stats.rows),
+        Value::Null,
+        Value::Null,
+    ]];
+    for (column, column_stats) in schema.columns.iter().zip(&stats.columns) {
+        let histogram = Row {
+            values: column_stats.histogram.clone(),
+        };
+        rows.push([
+            text(&schema.name),
+            Value::Null,
+            text(&column.name),
+            count(column_stats.non_null),
+            count(column_stats.distinct),
+            Value::Blob(histogram.to_payload()),
+        ]);
+    }
+    let mut indexes = stats.indexes.iter().collect::<Vec<_>>();
+    indexes.sort();
+    for (index, keys) in indexes {
+        rows.push([
+            text(&schema.name),
+            text(index),
+            Value::Null,
+            count(stats.rows),
+            count(*keys),
+            Value::Null,
+        ]);
+    }
+    for values in rows {
+        let row = Row {
+            values: values.to_vec(),
+        };
+        table.insert_row(root_page, &row);
+    }
+    table
+        .catalog
+        .stats
+        .insert(schema.name.to_lowercase(), stats);
+    // Plans chosen without the statistics may no longer be the best
+    table.schema_changed();
+    Ok(())
+}
+
+/// Reads the statistics of every table from the stats table, keyed by
+/// lowercase table name. Rows of tables or columns that no longer exist are
+/// ignored.
+pub fn load(table: &mut Table) -> HashMap<String, TableStats> {
+    let mut stats = HashMap::new();
+    let Some(stat_table) = table.catalog.find_table(STAT_TABLE).cloned() else {
+        return stats;
+    };
+    let mut cursor = stat_table.btree().cursor();
+    let mut found = cursor.first(&mut table.pager);
+    while found {
+        let row = Row::from_payload(cursor.value());
+        found = cursor.next(&mut table.pager);
+        // Rows that do not decode are skipped like any others of the wrong
+        // shape, leaving the planner without them
+        let Ok(row) = row else {
+            continue;
+        };
+        let [Value::Text(name), idx, col, nrow, ndistinct, histogram] = &row.values[..] else {
+            continue;
+        };
+        let Some(schema) = table.catalog.find_table(name) else {
+            continue;
+        };
+        let count = |value: &Value| match value {
+            Value::Integer(count) => *count as usize,
+            _ => 0,
+        };
+        let entry = stats
+            .entry(name.to_lowercase())
+            .or_insert_with(|| TableStats {
+                columns: vec![ColumnStats::default(); schema.columns.len()],
+                ..TableStats::default()
+            });
+        match (idx, col) {
+            (Value::Text(index), _) => {
+                entry.indexes.insert(index.clone(), count(ndistinct));
+            }
+            (_, Value::Text(column)) => {
+                let Some(column) = schema.column_index(column) else {
+                    continue;
+                };
+                let histogram = match histogram {
+                    Value::Blob(payload) => Row::from_payload(payload)
+                        .map(|row| row.values)
+                        .unwrap_or_default(),
+                    _ => Vec::new(),
+                };
+                entry.columns[column] = ColumnStats {
+                    non_null: count(nrow),
+                    distinct: count(ndistinct),
+                    histogram,
+                };
+            }
+            _ => entry.rows = count(nrow),
+        }
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    fn create(table: &mut Table, sql: &str) -> TableSchema {
+        let Ok(StatementType::CreateTable(create)) = parser::parse_statement(sql) else {
+            unreachable!()
+        };
+        table.create_table(&create, sql).unwrap()
+    }
+
+    #[test]
+    fn test_analyze_table() {
+        let mut table = Table::db_open(&temp_db_path("stats_analyze"));
+        let schema = create(
+            &mut table,
+            "create table t (id integer primary key, tag text, size integer)",
+        );
+        for i in 0..100 {
+            let tag = match i % 4 {
+                0 => Value::Null,
+                tag => Value::Text(format!("tag{}", tag)),
+            };
+            let row = Row {
+                values: vec![Value::Integer(i), tag, Value::Integer(i * 10)],
+            };
+            table.insert_row(schema.root_page, &row);
+        }
+        let stats = analyze_table(&mut table, &schema).unwrap();
+        assert_eq!(stats.rows, 100);
+        assert_eq!(
+            (stats.columns[0].non_null, stats.columns[0].distinct),
+            (100, 100)
+        );
+        assert_eq!(
+            (stats.columns[1].non_null, stats.columns[1].distinct),
+            (75, 3)
+        );
+        assert_eq!(stats.indexes["sqlite_autoindex_t_1"], 100);
+        let histogram = &stats.columns[2].histogram;
+        assert_eq!(histogram.len(), HISTOGRAM_BUCKETS + 1);
+        assert_eq!(
+            (histogram.first(), histogram.last()),
+            (Some(&Value::Integer(0)), Some(&Value::Integer(990)))
+        );
+
+        assert_eq!(stats.columns[1].equal_selectivity(100), 0.25);
+        let range = |lower: i64, upper: i64| {
+            stats.columns[2]
+                .range_selectivity(
+                    100,
+                    Some(&Value::Integer(lower)),
+                    Some(&Value::Integer(upper)),
+                )
+                .unwrap()
+        };
+        assert!((range(0, 500) - 0.5).abs() < 0.1);
+        assert!(range(0, 100) < 0.2);
+        assert_eq!(range(2000, 3000), 0.0);
+        assert_eq!(
+            ColumnStats::default().range_selectivity(100, None, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_analyze_saves_and_loads_stats() {
+        let path = temp_db_path("stats_save");
+        let mut table = Table::db_open(&path);
+        let schema = create(&mut table, "create table t (id integer unique, name text)");
+        for i in 0..10 {
+            let row = Row {
+                values: vec![Value::Integer(i), Value::Text(format!("n{}", i % 2))],
+            };
+            table.insert_row(schema.root_page, &row);
+        }
+        analyze(&mut table, &schema).unwrap();
+        // Analyzing again replaces the old rows
+        analyze(&mut table, &schema).unwrap();
+        let stat_table = table.catalog.find_table(STAT_TABLE).unwrap().clone();
+        assert_eq!(table.num_rows(stat_table.root_page), 4);
+
+        let saved = table.catalog.stats["t"].clone();
+        assert_eq!(saved.columns[1].distinct, 2);
+        assert_eq!(load(&mut table)["t"], saved);
+        table.db_close();
+        let table = Table::db_open(&path);
+        assert_eq!(table.catalog.stats["t"], saved);
+    }
+}
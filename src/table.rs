@@ -1,287 +1,930 @@
-use crate::cursor::{Cursor, CursorLocation};
-use crate::pager::Pager;
-use log::{error, info};
+use crate::aggregate::group_key;
+use crate::btree::{compare_prefix, BTree, BTreeCursor};
+use crate::cache::PageCache;
+use crate::pager::{read_u32, JournalMode, Pager};
+use crate::parser::{self, StatementType};
+use crate::schema::{self, Catalog, IndexSchema, TableSchema};
+use crate::sorter;
+use crate::statement::StatementCache;
+use crate::stats;
+use crate::value::Value;
+use log::error;
+use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::fmt;
-use std::mem;
-// Define the Row struct first (if not already defined)
-#[repr(C)]
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Row {
-    pub id: u32,
-    pub username: [u8; 32], // Assuming these sizes based on common SQLite implementations
-    pub email: [u8; 255],
+    pub values: Vec<Value>,
+}
+
+// Type tags of the values in a row payload. Integers use the smallest of
+// the 1, 2, 4 or 8 byte encodings that fits, booleans need no data at all.
+const NULL_TAG: u8 = 0;
+const INT64_TAG: u8 = 1;
+const REAL_TAG: u8 = 2;
+const TEXT_TAG: u8 = 3;
+const BLOB_TAG: u8 = 4;
+const INT8_TAG: u8 = 5;
+const INT16_TAG: u8 = 6;
+const INT32_TAG: u8 = 7;
+const FALSE_TAG: u8 = 8;
+const TRUE_TAG: u8 = 9;
+
+fn integer_tag(i: i64) -> (u8, usize) {
+    if i8::try_from(i).is_ok() {
+        (INT8_TAG, 1)
+    } else if i16::try_from(i).is_ok() {
+        (INT16_TAG, 2)
+    } else if i32::try_from(i).is_ok() {
+        (INT32_TAG, 4)
+    } else {
+        (INT64_TAG, 8)
+    }
 }
 
 impl Row {
     pub fn new() -> Self {
-        Row {
-            id: 0,
-            username: [0; 32],
-            email: [0; 255],
+        Row { values: Vec::new() }
+    }
+
+    /// Encodes the row as a variable-length payload: the number of values
+    /// followed by each value as a type tag and its data. Integers are
+    /// little-endian two's complement, reals IEEE 754 doubles, and text and
+    /// blobs are length-prefixed.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(self.values.len() as u32).to_le_bytes());
+        for value in &self.values {
+            match value {
+                Value::Null => payload.push(NULL_TAG),
+                Value::Integer(i) => {
+                    let (tag, len) = integer_tag(*i);
+                    payload.push(tag);
+                    payload.extend_from_slice(&i.to_le_bytes()[..len]);
+                }
+                Value::Boolean(b) => payload.push(if *b { TRUE_TAG } else { FALSE_TAG }),
+                Value::Real(r) => {
+                    payload.push(REAL_TAG);
+                    payload.extend_from_slice(&r.to_le_bytes());
+                }
+                Value::Text(s) => {
+                    payload.push(TEXT_TAG);
+                    payload.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                    payload.extend_from_slice(s.as_bytes());
+                }
+                Value::Blob(b) => {
+                    payload.push(BLOB_TAG);
+                    payload.extend_from_slice(&(b.len() as u32).to_le_bytes());
+                    payload.extend_from_slice(b);
+                }
+            }
         }
+        payload
+    }
+
+    /// Decodes a payload written by `to_payload`. Fails if it is cut short
+    /// or holds a value tag that does not exist.
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        let corrupt = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let bytes = |offset: usize, len: usize| {
+            payload
+                .get(offset..offset + len)
+                .ok_or_else(|| corrupt("Corrupt row payload, cut short".to_string()))
+        };
+        let num_values = read_u32(bytes(0, 4)?, 0) as usize;
+        let mut offset = 4;
+        let mut values = Vec::with_capacity(num_values.min(payload.len()));
+        for _ in 0..num_values {
+            let tag = bytes(offset, 1)?[0];
+            offset += 1;
+            let value = match tag {
+                NULL_TAG => Value::Null,
+                FALSE_TAG => Value::Boolean(false),
+                TRUE_TAG => Value::Boolean(true),
+                INT8_TAG | INT16_TAG | INT32_TAG | INT64_TAG => {
+                    let len = match tag {
+                        INT8_TAG => 1,
+                        INT16_TAG => 2,
+                        INT32_TAG => 4,
+                        _ => 8,
+                    };
+                    let stored = bytes(offset, len)?;
+                    // Sign-extend from the top byte of the stored value
+                    let fill = if stored[len - 1] & 0x80 != 0 { 0xff } else { 0 };
+                    let mut extended = [fill; 8];
+                    extended[..len].copy_from_slice(stored);
+                    offset += len;
+                    Value::Integer(i64::from_le_bytes(extended))
+                }
+                REAL_TAG => {
+                    let stored = bytes(offset, 8)?;
+                    offset += 8;
+                    Value::Real(f64::from_le_bytes(stored.try_into().unwrap()))
+                }
+                TEXT_TAG | BLOB_TAG => {
+                    let len = read_u32(bytes(offset, 4)?, 0) as usize;
+                    let stored = bytes(offset + 4, len)?.to_vec();
+                    offset += 4 + len;
+                    if tag == TEXT_TAG {
+                        Value::Text(String::from_utf8_lossy(&stored).to_string())
+                    } else {
+                        Value::Blob(stored)
+                    }
+                }
+                _ => {
+                    let message = format!("Corrupt row payload, unknown value tag {}", tag);
+                    return Err(corrupt(message));
+                }
+            };
+            values.push(value);
+        }
+        Ok(Row { values })
     }
 }
 
 impl fmt::Display for Row {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let username = String::from_utf8_lossy(&self.username).to_string();
-        let email = String::from_utf8_lossy(&self.email).to_string();
-        write!(
-            f,
-            "Row {{ id: {}, username: {}, email: {} }}",
-            self.id, username, email
-        )
+        let values = self
+            .values
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>();
+        write!(f, "({})", values.join(", "))
     }
 }
 
-// Constants for field sizes
-const ID_SIZE: usize = mem::size_of::<u32>();
-const USERNAME_SIZE: usize = 32;
-const EMAIL_SIZE: usize = 255;
+pub const PAGE_SIZE: usize = 4096;
 
-// Field offsets
-pub const ID_OFFSET: usize = 0;
-pub const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
-pub const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
-pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
+// The schema table lists every other table as
+// (type, name, tbl_name, rootpage, sql) rows
+pub const SCHEMA_ROOT_PAGE: u32 = 1;
 
-pub const PAGE_SIZE: usize = 4096;
-pub const TABLE_MAX_PAGES: usize = 100;
-pub const ROWS_PER_PAGE: usize = PAGE_SIZE / ROW_SIZE;
-pub const TABLE_MAX_ROWS: usize = ROWS_PER_PAGE * TABLE_MAX_PAGES;
+/// A connection to a database file. Each table is a B-tree keyed by rowid,
+/// holding the rows as payloads, and each index a B-tree whose entries
+/// point at rowids.
 pub struct Table {
-    pub num_rows: usize,
     pub pager: Pager,
-    pub start_cursor: Cursor,
-    pub end_cursor: Cursor,
+    pub catalog: Catalog,
+    /// Bytes ORDER BY may hold in memory before spilling to a temporary file.
+    pub sort_memory_budget: usize,
+    /// Statements kept prepared by `PreparedStatement::cached`.
+    pub(crate) statements: StatementCache,
 }
 
-pub type TableOffset = (usize, usize);
+/// A row that would repeat the `values` another row has in a unique index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UniqueViolation {
+    pub index: String,
+    pub values: Vec<Value>,
+}
 
-impl Default for Table {
-    fn default() -> Self {
-        Table::db_open("test.rdb")
+#[derive(Debug)]
+pub enum IndexError {
+    /// The table has no column of this name.
+    ColumnNotFound(String),
+    /// A unique index can not be built over rows that already repeat a key.
+    Unique(UniqueViolation),
+    Io(io::Error),
+}
+
+/// The key of a row in its table's B-tree.
+pub fn rowid_key(rowid: i64) -> Vec<Value> {
+    vec![Value::Integer(rowid)]
+}
+
+/// The rowid of the row under a cursor on a table's B-tree.
+pub fn cursor_rowid(cursor: &BTreeCursor) -> i64 {
+    match cursor.key() {
+        [Value::Integer(rowid)] => *rowid,
+        key => panic!("Corrupt table key {:?}", key),
     }
 }
 
-impl Default for Row {
+/// The value of an index entry: the rowid of the row it points at.
+pub fn row_locator(rowid: i64) -> Vec<u8> {
+    rowid.to_le_bytes().to_vec()
+}
+
+/// Reads back a rowid stored by `row_locator`.
+pub fn locator_rowid(locator: &[u8]) -> i64 {
+    i64::from_le_bytes(locator.try_into().expect("index entry is a rowid"))
+}
+
+impl Default for Table {
     fn default() -> Self {
-        Row::new()
+        Table::db_open("test.rdb")
     }
 }
 
 impl Table {
     pub fn db_open(filename: &str) -> Self {
-        let pager = Pager::pager_open(filename);
-        let file_length = pager.file_length as usize;
-        Table {
-            pager: pager,
-            num_rows: file_length / ROW_SIZE,
-            start_cursor: Cursor::table_start(file_length / ROW_SIZE),
-            end_cursor: Cursor::table_end(
-                file_length / ROW_SIZE,
-                (TABLE_MAX_PAGES * PAGE_SIZE) / ROW_SIZE,
-            ),
+        Table::open(filename, None).unwrap_or_else(|e| panic!("Error opening {}: {}", filename, e))
+    }
+
+    /// Opens a connection, sharing committed pages through `cache` if
+    /// there is one. Fails if the file is not a database this version can
+    /// read.
+    pub fn open(filename: &str, cache: Option<Arc<PageCache>>) -> io::Result<Self> {
+        let mut table = Table {
+            pager: Pager::open(filename, cache)?,
+            catalog: Catalog::default(),
+            sort_memory_budget: sorter::DEFAULT_MEMORY_BUDGET,
+            statements: StatementCache::default(),
+        };
+        table.load_catalog();
+        Ok(table)
+    }
+
+    fn load_catalog(&mut self) {
+        if self.pager.num_pages == SCHEMA_ROOT_PAGE {
+            // New database, set up an empty schema table
+            BTree::create(&mut self.pager);
+        }
+        // Indexes are resolved once every table is known
+        let mut indexes = Vec::new();
+        let mut cursor = BTree {
+            root_page: SCHEMA_ROOT_PAGE,
+        }
+        .cursor();
+        let mut found = cursor.first(&mut self.pager);
+        while found {
+            let row = Row::from_payload(cursor.value())
+                .unwrap_or_else(|e| panic!("Corrupt schema table: {}", e));
+            let (Value::Integer(root_page), Value::Text(sql)) = (&row.values[3], &row.values[4])
+            else {
+                panic!("Corrupt schema table row {}", row);
+            };
+            match parser::parse_statement(sql) {
+                Ok(StatementType::CreateTable(create)) => {
+                    let schema = TableSchema::new(&create, *root_page as u32, sql);
+                    self.catalog.tables.push(schema);
+                }
+                Ok(StatementType::CreateIndex(create)) => {
+                    indexes.push((create, *root_page as u32, sql.clone()));
+                }
+                _ => panic!("Corrupt schema table entry: {}", sql),
+            }
+            found = cursor.next(&mut self.pager);
         }
+        for (create, root_page, sql) in indexes {
+            let index = self
+                .catalog
+                .find_table(&create.table)
+                .and_then(|table| IndexSchema::new(&create, table, root_page, &sql).ok())
+                .unwrap_or_else(|| panic!("Corrupt schema table entry: {}", sql));
+            self.catalog.indexes.push(index);
+        }
+        self.catalog.stats = stats::load(self);
+        self.catalog.schema_cookie = self.pager.schema_cookie();
+        self.catalog.version = Catalog::next_version();
     }
 
+    /// Writes everything out and syncs the file. A transaction still open
+    /// is rolled back.
     pub fn db_close(&mut self) {
-        let num_full_pages = self.num_rows / ROWS_PER_PAGE;
-        for i in 0..num_full_pages {
-            if self.pager.pages[i].iter().all(|&x| x == 0) {
-                continue;
+        if self.pager.in_transaction() {
+            self.rollback();
+        }
+        if let Err(e) = self.pager.close() {
+            error!("Error closing database file: {}", e);
+        }
+    }
+
+    /// How long to wait for other connections to let go of the file
+    /// before failing with a busy error.
+    pub fn set_busy_timeout(&mut self, timeout: Duration) {
+        self.pager.busy_timeout = timeout;
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.pager.in_transaction()
+    }
+
+    pub fn journal_mode(&self) -> JournalMode {
+        self.pager.journal_mode()
+    }
+
+    /// Switches the journal mode when the transaction commits. Fails with a
+    /// busy error if other connections are reading.
+    pub fn set_journal_mode(&mut self, mode: JournalMode) -> io::Result<()> {
+        self.pager.set_journal_mode(mode)
+    }
+
+    /// Starts a transaction. Its changes reach the file together on
+    /// `commit`, or are all undone by `rollback`. A transaction that will
+    /// `write` waits for other writers up front.
+    pub fn begin(&mut self, write: bool) -> io::Result<()> {
+        if self.pager.begin(write)? {
+            self.reload_catalog();
+        }
+        Ok(())
+    }
+
+    /// Locks the file for the transaction's writes.
+    pub fn reserve(&mut self) -> io::Result<()> {
+        self.pager.reserve()
+    }
+
+    /// Fails with a busy error, keeping the transaction, if other
+    /// connections keep reading.
+    pub fn commit(&mut self) -> io::Result<()> {
+        let result = self.pager.commit();
+        if result.is_err() && !self.in_transaction() {
+            // The pager rolled back, maybe past a schema change
+            self.reload_catalog();
+        }
+        result
+    }
+
+    pub fn rollback(&mut self) {
+        self.pager.rollback();
+        self.reload_catalog();
+    }
+
+    /// Opens a savepoint, starting a transaction if there is none.
+    pub fn savepoint(&mut self, name: &str) -> io::Result<()> {
+        if self.pager.savepoint(name)? {
+            self.reload_catalog();
+        }
+        Ok(())
+    }
+
+    /// Closes the savepoint, committing if it started the transaction.
+    /// Returns false if there is no such savepoint.
+    pub fn release(&mut self, name: &str) -> io::Result<bool> {
+        let result = self.pager.release(name);
+        if result.is_err() && !self.in_transaction() {
+            self.reload_catalog();
+        }
+        result
+    }
+
+    /// Undoes the changes since the savepoint. Returns false if there is no
+    /// such savepoint.
+    pub fn rollback_to(&mut self, name: &str) -> bool {
+        let found = self.pager.rollback_to(name);
+        if found {
+            self.reload_catalog();
+        }
+        found
+    }
+
+    /// Locks the file for a statement's writes and starts keeping what the
+    /// statement changes, so that `rollback_statement` can undo it alone.
+    pub fn begin_statement(&mut self) -> io::Result<()> {
+        self.pager.reserve()?;
+        self.pager.begin_statement();
+        Ok(())
+    }
+
+    /// Keeps the running statement's changes.
+    pub fn end_statement(&mut self) {
+        self.pager.end_statement();
+    }
+
+    /// Undoes the running statement's changes, and its schema changes too.
+    pub fn rollback_statement(&mut self) {
+        if self.pager.rollback_statement() {
+            self.reload_catalog();
+        }
+    }
+
+    fn reload_catalog(&mut self) {
+        let previous = std::mem::take(&mut self.catalog);
+        self.load_catalog();
+        if self.catalog.schema_cookie == previous.schema_cookie {
+            self.catalog.version = previous.version;
+        }
+    }
+
+    /// Marks the schema as changed, so that statements compiled against it
+    /// are compiled again.
+    pub fn schema_changed(&mut self) {
+        self.pager.bump_schema_cookie();
+        self.catalog.schema_cookie = self.pager.schema_cookie();
+        self.catalog.version = Catalog::next_version();
+    }
+
+    /// Allocates a root page for the table and records it in the schema
+    /// table, along with a unique index for the primary key and for each
+    /// unique constraint. Fails with the name of a constrained column the
+    /// table does not have.
+    pub fn create_table(
+        &mut self,
+        create: &parser::CreateTable,
+        sql: &str,
+    ) -> Result<TableSchema, String> {
+        let mut schema = TableSchema::new(create, 0, sql);
+        let constraints = schema::unique_constraints(create);
+        for constraint in &constraints {
+            IndexSchema::new(constraint, &schema, 0, "")?;
+        }
+
+        schema.root_page = BTree::create(&mut self.pager).root_page;
+        let entry = Row {
+            values: vec![
+                Value::Text("table".to_string()),
+                Value::Text(schema.name.clone()),
+                Value::Text(schema.name.clone()),
+                Value::Integer(schema.root_page as i64),
+                Value::Text(sql.to_string()),
+            ],
+        };
+        self.insert_row(SCHEMA_ROOT_PAGE, &entry);
+        self.catalog.tables.push(schema.clone());
+        self.schema_changed();
+        for constraint in constraints {
+            let sql = format!(
+                "create unique index {} on {} ({})",
+                constraint.name,
+                constraint.table,
+                constraint.columns.join(", ")
+            );
+            self.create_index(&constraint, &schema, &sql)
+                .expect("constraint columns were checked and the table is empty");
+        }
+        Ok(schema)
+    }
+
+    /// Builds an index over the rows already in the table and records it in
+    /// the schema table.
+    pub fn create_index(
+        &mut self,
+        create: &parser::CreateIndex,
+        table: &TableSchema,
+        sql: &str,
+    ) -> Result<IndexSchema, IndexError> {
+        let mut index =
+            IndexSchema::new(create, table, 0, sql).map_err(IndexError::ColumnNotFound)?;
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cursor = BTree {
+            root_page: table.root_page,
+        }
+        .cursor();
+        let mut found = cursor.first(&mut self.pager);
+        while found {
+            let row = Row::from_payload(cursor.value()).map_err(IndexError::Io)?;
+            if let Some(values) = index.unique_values(&row) {
+                if !seen.insert(group_key(&values)) {
+                    return Err(IndexError::Unique(UniqueViolation {
+                        index: index.name,
+                        values,
+                    }));
+                }
             }
-            self.pager.flush(i as u64, None).unwrap();
-            self.pager.pages[i] = vec![0; PAGE_SIZE];
-        }
-
-        // Handle partial page
-        let num_remaining_rows = self.num_rows % ROWS_PER_PAGE;
-        if num_remaining_rows > 0 {
-            let page_num = num_full_pages;
-            if !self.pager.pages[page_num].iter().all(|&x| x == 0) {
-                self.pager
-                    .flush(page_num as u64, Some(num_remaining_rows))
-                    .unwrap();
-                self.pager.pages[page_num] = vec![0; PAGE_SIZE];
+            entries.push((index.key(&row), cursor_rowid(&cursor)));
+            found = cursor.next(&mut self.pager);
+        }
+
+        let btree = BTree::create(&mut self.pager);
+        index.root_page = btree.root_page;
+        for (key, rowid) in entries {
+            btree.insert(&mut self.pager, key, row_locator(rowid));
+        }
+        let entry = Row {
+            values: vec![
+                Value::Text("index".to_string()),
+                Value::Text(index.name.clone()),
+                Value::Text(table.name.clone()),
+                Value::Integer(index.root_page as i64),
+                Value::Text(sql.to_string()),
+            ],
+        };
+        self.insert_row(SCHEMA_ROOT_PAGE, &entry);
+        self.catalog.indexes.push(index.clone());
+        self.schema_changed();
+        Ok(index)
+    }
+
+    /// Checks that the row would not repeat the values of another row in a
+    /// unique index of the table. `rowid` is the row being replaced, if
+    /// any, whose own entries do not count.
+    pub fn check_unique(
+        &mut self,
+        root_page: u32,
+        row: &Row,
+        rowid: Option<i64>,
+    ) -> Result<(), UniqueViolation> {
+        for index in self.catalog.table_indexes(root_page) {
+            let Some(values) = index.unique_values(row) else {
+                continue;
+            };
+            let mut cursor = index.btree().cursor();
+            let mut found = cursor.seek(&mut self.pager, &values);
+            while found && compare_prefix(cursor.key(), &values) == Ordering::Equal {
+                if rowid != Some(locator_rowid(cursor.value())) {
+                    return Err(UniqueViolation {
+                        index: index.name,
+                        values,
+                    });
+                }
+                found = cursor.next(&mut self.pager);
             }
         }
+        Ok(())
+    }
 
-        if let Err(e) = self.pager.file_descriptor.sync_data() {
-            error!("Error closing database file: {}", e);
+    /// Counts the rows of the table, reading all of its leaves.
+    pub fn num_rows(&mut self, root_page: u32) -> usize {
+        let mut cursor = BTree { root_page }.cursor();
+        let mut found = cursor.first(&mut self.pager);
+        let mut num_rows = 0;
+        while found {
+            num_rows += 1;
+            found = cursor.next(&mut self.pager);
         }
+        num_rows
     }
 
-    pub fn cursor_value(&mut self, cursor: CursorLocation) -> TableOffset {
-        let row_num: usize;
-        if cursor == CursorLocation::Start {
-            row_num = self.start_cursor.row_num;
-        } else {
-            row_num = self.end_cursor.row_num;
+    /// Reads the row with the given rowid, `None` if there is none.
+    pub fn read_row(&mut self, root_page: u32, rowid: i64) -> io::Result<Option<Row>> {
+        let mut cursor = BTree { root_page }.cursor();
+        if !cursor.seek(&mut self.pager, &rowid_key(rowid)) {
+            return Ok(None);
         }
-        let page_num = row_num / ROWS_PER_PAGE;
-        // let page = self.pages[page_num];
+        Row::from_payload(cursor.value()).map(Some)
+    }
 
-        let row_offset = row_num % ROWS_PER_PAGE;
-        let byte_offset = row_offset * ROW_SIZE;
+    /// Adds the row under a rowid one past the largest in the table, and
+    /// returns the rowid.
+    pub fn insert_row(&mut self, root_page: u32, row: &Row) -> i64 {
+        let btree = BTree { root_page };
+        let mut cursor = btree.cursor();
+        let rowid = match cursor.last(&mut self.pager) {
+            true => cursor_rowid(&cursor) + 1,
+            false => 1,
+        };
+        btree.insert(&mut self.pager, rowid_key(rowid), row.to_payload());
+        for index in self.catalog.table_indexes(root_page) {
+            index
+                .btree()
+                .insert(&mut self.pager, index.key(row), row_locator(rowid));
+        }
+        rowid
+    }
 
-        (page_num, byte_offset)
+    /// Replaces the row with the given rowid, releasing the overflow chain
+    /// of the old value.
+    pub fn update_row(&mut self, root_page: u32, rowid: i64, row: &Row) -> io::Result<()> {
+        let old = self.remove_row(root_page, rowid)?;
+        let key = rowid_key(rowid);
+        BTree { root_page }.insert(&mut self.pager, key, row.to_payload());
+        for index in self.catalog.table_indexes(root_page) {
+            let btree = index.btree();
+            btree.delete(&mut self.pager, &index.key(&old), &row_locator(rowid));
+            btree.insert(&mut self.pager, index.key(row), row_locator(rowid));
+        }
+        Ok(())
     }
 
-    pub fn serialize_row(&mut self, source: &Row, destination: (usize, usize)) {
-        let id = source.id;
-        info!(
-            "Serialize Row \nDestination {:?}\nLength of pages: {}",
-            destination,
-            self.pager.pages.len()
-        );
+    /// Deletes the row with the given rowid and its index entries.
+    pub fn delete_row(&mut self, root_page: u32, rowid: i64) -> io::Result<()> {
+        let old = self.remove_row(root_page, rowid)?;
+        for index in self.catalog.table_indexes(root_page) {
+            let btree = index.btree();
+            btree.delete(&mut self.pager, &index.key(&old), &row_locator(rowid));
+        }
+        Ok(())
+    }
 
-        self.pager.pages[destination.0]
-            [destination.1 + ID_OFFSET..destination.1 + ID_OFFSET + ID_SIZE]
-            .copy_from_slice(&id.to_le_bytes());
-        self.pager.pages[destination.0]
-            [destination.1 + USERNAME_OFFSET..destination.1 + USERNAME_OFFSET + USERNAME_SIZE]
-            .copy_from_slice(&source.username);
-        self.pager.pages[destination.0]
-            [destination.1 + EMAIL_OFFSET..destination.1 + EMAIL_OFFSET + EMAIL_SIZE]
-            .copy_from_slice(&source.email);
+    /// Takes the row out of the table's B-tree, leaving its index entries,
+    /// and returns it.
+    fn remove_row(&mut self, root_page: u32, rowid: i64) -> io::Result<Row> {
+        let btree = BTree { root_page };
+        let key = rowid_key(rowid);
+        let mut cursor = btree.cursor();
+        if !cursor.seek(&mut self.pager, &key) {
+            panic!("No row {} in the table rooted at page {}", rowid, root_page);
+        }
+        let payload = cursor.value().to_vec();
+        let row = Row::from_payload(&payload)?;
+        btree.delete(&mut self.pager, &key, &payload);
+        Ok(row)
     }
 
-    pub fn deserialize_row(&mut self, page: usize, offset: usize) -> Row {
-        info!("Deserialize Row \nPage: {}, Offset: {}", page, offset);
-        Row {
-            id: u32::from_le_bytes(
-                self.pager.pages[page][offset + ID_OFFSET..offset + ID_OFFSET + ID_SIZE]
-                    .try_into()
-                    .unwrap(),
-            ),
-            username: self.pager.pages[page]
-                [offset + USERNAME_OFFSET..offset + USERNAME_OFFSET + USERNAME_SIZE]
-                .try_into()
-                .unwrap(),
-            email: self.pager.pages[page]
-                [offset + EMAIL_OFFSET..offset + EMAIL_OFFSET + EMAIL_SIZE]
-                .try_into()
-                .unwrap(),
+    /// Finds the first row whose value in `column` equals `key`, through an
+    /// index that starts with the column if the table has one, and returns
+    /// its rowid.
+    pub fn find_row(
+        &mut self,
+        root_page: u32,
+        column: usize,
+        key: &Value,
+    ) -> io::Result<Option<i64>> {
+        let indexes = self.catalog.table_indexes(root_page);
+        if let Some(index) = indexes.iter().find(|index| index.columns[0] == column) {
+            if key.is_null() {
+                return Ok(None);
+            }
+            let key = std::slice::from_ref(key);
+            let mut cursor = index.btree().cursor();
+            return Ok(cursor
+                .seek(&mut self.pager, key)
+                .then(|| locator_rowid(cursor.value())));
         }
+        let mut cursor = BTree { root_page }.cursor();
+        let mut found = cursor.first(&mut self.pager);
+        while found {
+            let row = Row::from_payload(cursor.value())?;
+            if row.values.get(column).and_then(|value| value.sql_eq(key)) == Some(true) {
+                return Ok(Some(cursor_rowid(&cursor)));
+            }
+            found = cursor.next(&mut self.pager);
+        }
+        Ok(None)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    /// Returns a path in the temp dir that no other test uses.
+    pub(crate) fn temp_db_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("rsqlite3-{}-{}.rdb", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn user_row(id: i64, username: &str, email: &str) -> Row {
+        Row {
+            values: vec![
+                Value::Integer(id),
+                Value::Text(username.to_string()),
+                Value::Text(email.to_string()),
+            ],
+        }
+    }
+
+    fn users_table(table: &mut Table) -> u32 {
+        let StatementType::CreateTable(create) =
+            parser::parse_statement(parser::LEGACY_TABLE_SQL).unwrap()
+        else {
+            unreachable!()
+        };
+        table
+            .create_table(&create, parser::LEGACY_TABLE_SQL)
+            .unwrap()
+            .root_page
+    }
+
     #[test]
     fn test_row_creation() {
         let row = Row::new();
-        assert_eq!(row.id, 0);
-        assert_eq!(row.username, [0; 32]);
-        assert_eq!(row.email, [0; 255]);
+        assert!(row.values.is_empty());
     }
 
     #[test]
     fn test_row_display() {
-        let mut row = Row::new();
-        row.id = 1;
-        row.username[..5].copy_from_slice(b"alice");
-        row.email[..14].copy_from_slice(b"alice@test.com");
+        let mut row = user_row(1, "alice", "alice@test.com");
+        row.values.push(Value::Null);
 
         let display_string = format!("{}", row);
-        assert!(display_string.contains("id: 1"));
-        assert!(display_string.contains("username: alice"));
-        assert!(display_string.contains("email: alice@test.com"));
+        assert_eq!(display_string, "(1, alice, alice@test.com, NULL)");
     }
 
     #[test]
-    fn test_table_serialization_deserialization() {
-        let mut table = Table::db_open("test.rdb");
-        let mut row = Row::new();
-        row.id = 42;
-        row.username[..5].copy_from_slice(b"alice");
-        row.email[..14].copy_from_slice(b"alice@test.com");
+    fn test_row_payload_roundtrip() {
+        let row = Row {
+            values: vec![
+                Value::Null,
+                Value::Integer(-3),
+                Value::Integer(-300),
+                Value::Integer(70_000),
+                Value::Integer(i64::MIN),
+                Value::Real(2.5),
+                Value::Real(-1e300),
+                Value::Boolean(true),
+                Value::Boolean(false),
+                Value::Text("héllo".to_string()),
+                Value::Blob(vec![0, 1, 255]),
+            ],
+        };
+        assert_eq!(Row::from_payload(&row.to_payload()).unwrap(), row);
+    }
 
-        // Test cursor_value calculation
-        let slot = table.cursor_value(0);
-        assert_eq!(slot.0, 0); // First page
-        assert_eq!(slot.1, 0); // First position
+    #[test]
+    fn test_row_payload_corrupt() {
+        let payload = user_row(1, "alice", "alice@test.com").to_payload();
+        for corrupt in [
+            &payload[..payload.len() - 1],
+            &payload[..2],
+            &[1, 0, 0, 0, 99],
+        ] {
+            let error = Row::from_payload(corrupt).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
 
-        // Test serialization
-        table.serialize_row(&row, slot);
+    #[test]
+    fn test_row_payload_uses_compact_encodings() {
+        let small = Row {
+            values: vec![Value::Integer(1), Value::Boolean(true)],
+        };
+        // Count, tag and one byte for the integer, tag only for the boolean
+        assert_eq!(small.to_payload().len(), 4 + 2 + 1);
+        let large = Row {
+            values: vec![Value::Integer(1 << 40)],
+        };
+        assert_eq!(large.to_payload().len(), 4 + 1 + 8);
+    }
 
-        // Test deserialization
-        let deserialized_row = table.deserialize_row(slot.0, slot.1);
-        assert_eq!(deserialized_row.id, 42);
-        assert_eq!(&deserialized_row.username[..5], b"alice");
-        assert_eq!(&deserialized_row.email[..14], b"alice@test.com");
+    #[test]
+    fn test_rows_are_read_back_by_rowid() {
+        let mut table = Table::db_open(&temp_db_path("rowids"));
+        let root_page = users_table(&mut table);
+        let row1 = user_row(1, "bob", "bob@test.com");
+        let row2 = user_row(2, "alice", "alice@test.com");
+
+        assert_eq!(table.insert_row(root_page, &row1), 1);
+        assert_eq!(table.insert_row(root_page, &row2), 2);
+        assert_eq!(table.read_row(root_page, 1).unwrap(), Some(row1));
+        assert_eq!(table.read_row(root_page, 2).unwrap(), Some(row2.clone()));
+        assert_eq!(table.read_row(root_page, 3).unwrap(), None);
+
+        // Rowids are not reused while a larger one is in the table
+        table.delete_row(root_page, 1).unwrap();
+        assert_eq!(table.insert_row(root_page, &user_row(3, "c", "c")), 3);
+        table.delete_row(root_page, 3).unwrap();
+        assert_eq!(table.insert_row(root_page, &user_row(3, "c", "c")), 3);
+        assert_eq!(table.num_rows(root_page), 2);
+        assert_eq!(table.read_row(root_page, 2).unwrap(), Some(row2));
     }
 
     #[test]
-    fn test_table_multiple_rows() {
-        let mut table = Table::db_open("test.rdb");
+    fn test_table_spans_many_pages() {
+        let path = temp_db_path("many_pages");
+        let mut table = Table::db_open(&path);
+        let sql = "create table numbers (n integer)";
+        let StatementType::CreateTable(create) = parser::parse_statement(sql).unwrap() else {
+            unreachable!()
+        };
+        let root_page = table.create_table(&create, sql).unwrap().root_page;
+
+        // More rows than a table could hold when its pages were listed in
+        // the root page
+        let num_rows = 17_000;
+        let row = |i: i64| Row {
+            values: vec![Value::Integer(i)],
+        };
+        for i in 0..num_rows {
+            table.insert_row(root_page, &row(i));
+        }
+        table.db_close();
 
-        // Create and insert first row
-        let mut row1 = Row::new();
-        row1.id = 1;
-        row1.username[..3].copy_from_slice(b"bob");
-        row1.email[..12].copy_from_slice(b"bob@test.com");
+        let mut table = Table::db_open(&path);
+        let num_pages = table.pager.num_pages;
+        assert_eq!(table.num_rows(root_page), num_rows as usize);
+        for i in [0, 9_999, num_rows - 1] {
+            assert_eq!(table.read_row(root_page, i + 1).unwrap(), Some(row(i)));
+        }
+        // Reading past the last row allocates nothing
+        assert_eq!(table.read_row(root_page, num_rows + 1).unwrap(), None);
+        assert_eq!(table.pager.num_pages, num_pages);
+    }
 
-        // Create and insert second row
-        let mut row2 = Row::new();
-        row2.id = 2;
-        row2.username[..5].copy_from_slice(b"alice");
-        row2.email[..14].copy_from_slice(b"alice@test.com");
+    #[test]
+    fn test_old_layout_is_an_error() {
+        let path = temp_db_path("old_layout");
+        let mut table = Table::db_open(&path);
+        users_table(&mut table);
+        table.db_close();
+
+        // Files from before tables were B-trees have no format version
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &[0; 4], 36).unwrap();
+        let error = Table::open(&path, None).err().expect("opening should fail");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("older table layout"));
+    }
 
-        // Insert both rows
-        let slot1 = table.cursor_value(0);
-        let slot2 = table.cursor_value(1);
-        table.serialize_row(&row1, slot1);
-        table.serialize_row(&row2, slot2);
+    #[test]
+    fn test_large_row_uses_overflow_pages() {
+        let path = temp_db_path("overflow");
+        let mut table = Table::db_open(&path);
+        let root_page = users_table(&mut table);
+        let num_pages = table.pager.num_pages;
+        let document = "x".repeat(3 * PAGE_SIZE);
+        let row = user_row(7, "alice", &document);
+
+        let rowid = table.insert_row(root_page, &row);
+        // A chain of overflow pages for the row, and another for the
+        // email's entry in the unique index
+        assert_eq!(table.pager.num_pages, num_pages + 3 + 3);
+        table.db_close();
+
+        let mut table = Table::db_open(&path);
+        assert_eq!(table.catalog.tables.len(), 1);
+        assert_eq!(table.num_rows(root_page), 1);
+        assert_eq!(table.read_row(root_page, rowid).unwrap(), Some(row));
+    }
 
-        // Verify both rows
-        let retrieved_row1 = table.deserialize_row(slot1.0, slot1.1);
-        let retrieved_row2 = table.deserialize_row(slot2.0, slot2.1);
+    #[test]
+    fn test_overflow_pages_are_reused_after_delete_and_update() {
+        let mut table = Table::db_open(&temp_db_path("overflow_free"));
+        let root_page = users_table(&mut table);
+        let big = user_row(1, "alice", &"a".repeat(2 * PAGE_SIZE));
+        let small = user_row(2, "bob", "bob@test.com");
+        table.insert_row(root_page, &big);
+        table.insert_row(root_page, &small);
+        let num_pages = table.pager.num_pages;
+
+        // The row and its email index entry each free two overflow pages
+        table
+            .update_row(root_page, 1, &user_row(1, "alice", "alice@test.com"))
+            .unwrap();
+        assert_eq!(table.pager.freelist_count, 4);
+
+        table
+            .update_row(
+                root_page,
+                2,
+                &user_row(2, "bob", &"b".repeat(2 * PAGE_SIZE)),
+            )
+            .unwrap();
+        assert_eq!(table.pager.freelist_count, 0);
+        assert_eq!(table.pager.num_pages, num_pages);
+
+        table.delete_row(root_page, 1).unwrap();
+        assert_eq!(table.num_rows(root_page), 1);
+        assert_eq!(table.pager.freelist_count, 0);
+        assert_eq!(
+            table.find_row(root_page, 0, &Value::Integer(2)).unwrap(),
+            Some(2)
+        );
 
-        assert_eq!(retrieved_row1.id, 1);
-        assert_eq!(&retrieved_row1.username[..3], b"bob");
-        assert_eq!(&retrieved_row1.email[..12], b"bob@test.com");
+        table.delete_row(root_page, 2).unwrap();
+        assert_eq!(table.num_rows(root_page), 0);
+        assert_eq!(table.pager.freelist_count, 4);
+    }
 
-        assert_eq!(retrieved_row2.id, 2);
-        assert_eq!(&retrieved_row2.username[..5], b"alice");
-        assert_eq!(&retrieved_row2.email[..14], b"alice@test.com");
+    /// Every entry of the index as its key and rowid.
+    fn index_entries(table: &mut Table, index: &IndexSchema) -> Vec<(Vec<Value>, i64)> {
+        let mut cursor = index.btree().cursor();
+        let mut found = cursor.first(&mut table.pager);
+        std::iter::from_fn(|| {
+            let entry = found.then(|| (cursor.key().to_vec(), locator_rowid(cursor.value())));
+            found = found && cursor.next(&mut table.pager);
+            entry
+        })
+        .collect()
     }
 
     #[test]
-    fn test_table_page_boundary() {
-        let mut table = Table::db_open("test.rdb");
-
-        // Calculate how many rows we need to fill a page and spill over
-        let rows_for_test = ROWS_PER_PAGE + 2;
-
-        // Create and insert rows
-        for i in 0..rows_for_test {
-            let mut row = Row::new();
-            row.id = i as u32;
-            row.username[..4].copy_from_slice(b"user");
-            row.email[..13].copy_from_slice(b"user@test.com");
-
-            let slot = table.cursor_value(table.num_rows);
-            table.serialize_row(&row, slot);
-            table.num_rows += 1;
-            // Verify the page number calculation
-            println!("slot: {:?}, i: {}", slot, i);
-            assert_eq!(slot.0, i / ROWS_PER_PAGE);
-            assert_eq!(slot.1, (i % ROWS_PER_PAGE) * ROW_SIZE);
-        }
-
-        // Verify rows from both pages
-        for i in 0..rows_for_test {
-            let slot = table.cursor_value(i);
-            let retrieved_row = table.deserialize_row(slot.0, slot.1);
-            assert_eq!(retrieved_row.id, i as u32);
-            assert_eq!(&retrieved_row.username[..4], b"user");
-            assert_eq!(&retrieved_row.email[..13], b"user@test.com");
-        }
-
-        // Verify we actually used multiple pages
-        assert!(table.pager.pages.len() > 1);
+    fn test_index_is_maintained_by_row_changes() {
+        let path = temp_db_path("index_maintenance");
+        let mut table = Table::db_open(&path);
+        let root_page = users_table(&mut table);
+        table.insert_row(root_page, &user_row(1, "carol", "c@test.com"));
+        table.insert_row(root_page, &user_row(2, "alice", "a@test.com"));
+
+        let sql = "create index users_username on users (username)";
+        let StatementType::CreateIndex(create) = parser::parse_statement(sql).unwrap() else {
+            unreachable!()
+        };
+        let schema = table.catalog.tables[0].clone();
+        let index = table.create_index(&create, &schema, sql).unwrap();
+        table.insert_row(root_page, &user_row(3, "bob", "b@test.com"));
+        let key = |name: &str, id: i64| vec![Value::Text(name.to_string()), Value::Integer(id)];
+        assert_eq!(
+            index_entries(&mut table, &index),
+            [
+                (key("alice", 2), 2),
+                (key("bob", 3), 3),
+                (key("carol", 1), 1)
+            ]
+        );
+
+        table.delete_row(root_page, 1).unwrap();
+        table
+            .update_row(root_page, 2, &user_row(2, "zed", "z@test.com"))
+            .unwrap();
+        assert_eq!(
+            index_entries(&mut table, &index),
+            [(key("bob", 3), 3), (key("zed", 2), 2)]
+        );
+        table.db_close();
+
+        let mut table = Table::db_open(&path);
+        assert_eq!(table.catalog.find_index("users_username"), Some(&index));
+        assert_eq!(index_entries(&mut table, &index).len(), 2);
+        assert_eq!(
+            IndexSchema::new(&create, &schema, 0, sql).map(|index| index.columns),
+            Ok(vec![1])
+        );
+        let create = parser::CreateIndex {
+            columns: vec!["missing".to_string()],
+            ..create
+        };
+        assert!(matches!(
+            table.create_index(&create, &schema, sql),
+            Err(IndexError::ColumnNotFound(column)) if column == "missing"
+        ));
     }
 }
@@ -0,0 +1,197 @@
+use crate::parser::PrepareSyntaxError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Identifier(String),
+    Number(String),
+    String(String),
+    Blob(Vec<u8>),
+    /// A placeholder for a bound value: `?`, `?NNN` or `:name`.
+    Parameter(String),
+    Symbol(&'static str),
+}
+
+impl Token {
+    /// True if the token is the given keyword, ignoring case.
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Identifier(name) if name.eq_ignore_ascii_case(keyword))
+    }
+}
+
+// Longest symbols first so that `<=` is not read as `<` followed by `=`
+const SYMBOLS: [&str; 19] = [
+    "<=", ">=", "<>", "!=", "==", "||", "(", ")", ",", ";", "*", "=", "<", ">", "+", "-", "/", "%",
+    ".",
+];
+
+pub fn tokenize(input: &str) -> Result<Vec<Token>, PrepareSyntaxError> {
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if (c == 'x' || c == 'X') && chars.get(i + 1) == Some(&'\'') {
+            let (hex, next) = read_quoted(&chars, i + 1, '\'')?;
+            tokens.push(Token::Blob(decode_hex(&hex)?));
+            i = next;
+        } else if c == '\'' {
+            let (text, next) = read_quoted(&chars, i, '\'')?;
+            tokens.push(Token::String(text));
+            i = next;
+        } else if c == '"' {
+            let (name, next) = read_quoted(&chars, i, '"')?;
+            tokens.push(Token::Identifier(name));
+            i = next;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                // Allow a sign directly after an exponent marker
+                if (chars[i] == 'e' || chars[i] == 'E')
+                    && matches!(chars.get(i + 1), Some('+') | Some('-'))
+                {
+                    i += 1;
+                }
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Identifier(chars[start..i].iter().collect()));
+        } else if c == '?' || (c == ':' && chars.get(i + 1).is_some_and(|c| is_name_char(*c))) {
+            // `?` is followed by an optional number, `:` by a name
+            let start = i;
+            let part: fn(char) -> bool = match c {
+                '?' => |c| c.is_ascii_digit(),
+                _ => is_name_char,
+            };
+            i += 1;
+            while i < chars.len() && part(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Parameter(chars[start..i].iter().collect()));
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| {
+            symbol
+                .chars()
+                .enumerate()
+                .all(|(j, s)| chars.get(i + j) == Some(&s))
+        }) {
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        } else {
+            return Err(PrepareSyntaxError::UnexpectedCharacter(c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Reads a quoted string starting at the opening quote, where a doubled quote
+/// stands for a literal one. Returns the contents and the index after it.
+fn read_quoted(
+    chars: &[char],
+    start: usize,
+    quote: char,
+) -> Result<(String, usize), PrepareSyntaxError> {
+    let mut text = String::new();
+    let mut i = start + 1;
+    loop {
+        match chars.get(i) {
+            None => return Err(PrepareSyntaxError::UnterminatedString),
+            Some(&c) if c == quote => {
+                if chars.get(i + 1) == Some(&quote) {
+                    text.push(quote);
+                    i += 2;
+                } else {
+                    return Ok((text, i + 1));
+                }
+            }
+            Some(&c) => {
+                text.push(c);
+                i += 1;
+            }
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, PrepareSyntaxError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(PrepareSyntaxError::InvalidBlob);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| PrepareSyntaxError::InvalidBlob)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_insert() {
+        let tokens = tokenize("insert into users values (1, 'o''brien', NULL, x'00ff');").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Identifier("insert".to_string()),
+                Token::Identifier("into".to_string()),
+                Token::Identifier("users".to_string()),
+                Token::Identifier("values".to_string()),
+                Token::Symbol("("),
+                Token::Number("1".to_string()),
+                Token::Symbol(","),
+                Token::String("o'brien".to_string()),
+                Token::Symbol(","),
+                Token::Identifier("NULL".to_string()),
+                Token::Symbol(","),
+                Token::Blob(vec![0x00, 0xff]),
+                Token::Symbol(")"),
+                Token::Symbol(";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_parameters() {
+        assert_eq!(
+            tokenize("? ?12 :name :a1,?").unwrap(),
+            vec![
+                Token::Parameter("?".to_string()),
+                Token::Parameter("?12".to_string()),
+                Token::Parameter(":name".to_string()),
+                Token::Parameter(":a1".to_string()),
+                Token::Symbol(","),
+                Token::Parameter("?".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize("select :"),
+            Err(PrepareSyntaxError::UnexpectedCharacter(':'))
+        );
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert_eq!(
+            tokenize("select 'abc"),
+            Err(PrepareSyntaxError::UnterminatedString)
+        );
+        assert_eq!(tokenize("x'0'"), Err(PrepareSyntaxError::InvalidBlob));
+        assert_eq!(
+            tokenize("select @"),
+            Err(PrepareSyntaxError::UnexpectedCharacter('@'))
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A single SQL value as stored in a row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Ordering used for sorting: NULL < numbers < text < blobs, with
+    /// integers, reals and booleans (as 0 and 1) compared numerically.
+    pub fn collate_cmp(&self, other: &Value) -> Ordering {
+        fn class(value: &Value) -> u8 {
+            match value {
+                Value::Null => 0,
+                Value::Integer(_) | Value::Real(_) | Value::Boolean(_) => 1,
+                Value::Text(_) => 2,
+                Value::Blob(_) => 3,
+            }
+        }
+        match (self.as_numeric(), other.as_numeric()) {
+            (Some(Value::Integer(a)), Some(Value::Integer(b))) => return a.cmp(&b),
+            (Some(a), Some(b)) => return a.as_f64().total_cmp(&b.as_f64()),
+            _ => {}
+        }
+        match (self, other) {
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+            _ => class(self).cmp(&class(other)),
+        }
+    }
+
+    /// SQL comparison: unknown (`None`) when either side is NULL.
+    pub fn sql_cmp(&self, other: &Value) -> Option<Ordering> {
+        if self.is_null() || other.is_null() {
+            return None;
+        }
+        Some(self.collate_cmp(other))
+    }
+
+    /// Integers and reals as they are and booleans as 0 or 1.
+    fn as_numeric(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Real(_) => Some(self.clone()),
+            Value::Boolean(b) => Some(Value::Integer(*b as i64)),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Value::Integer(i) => *i as f64,
+            Value::Real(r) => *r,
+            Value::Boolean(b) => *b as i64 as f64,
+            _ => 0.0,
+        }
+    }
+
+    pub fn sql_eq(&self, other: &Value) -> Option<bool> {
+        self.sql_cmp(other)
+            .map(|ordering| ordering == Ordering::Equal)
+    }
+
+    /// Interprets the value as a truth value: NULL is unknown, numbers are
+    /// true when non-zero and text is converted like a numeric literal.
+    pub fn truth(&self) -> Option<bool> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => Some(*i != 0),
+            Value::Real(r) => Some(*r != 0.0),
+            Value::Boolean(b) => Some(*b),
+            Value::Text(s) => Some(s.trim().parse::<f64>().is_ok_and(|r| r != 0.0)),
+            Value::Blob(_) => Some(false),
+        }
+    }
+
+    pub fn from_truth(truth: Option<bool>) -> Value {
+        match truth {
+            None => Value::Null,
+            Some(b) => Value::Integer(b as i64),
+        }
+    }
+
+    /// `value IS NULL`, which is never unknown.
+    pub fn is_null_value(&self) -> Value {
+        Value::from_truth(Some(self.is_null()))
+    }
+
+    /// `value IS NOT NULL`, which is never unknown.
+    pub fn is_not_null_value(&self) -> Value {
+        Value::from_truth(Some(!self.is_null()))
+    }
+}
+
+/// Three-valued AND: false wins over unknown.
+pub fn and3(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+/// Three-valued OR: true wins over unknown.
+pub fn or3(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+pub fn not3(a: Option<bool>) -> Option<bool> {
+    a.map(|b| !b)
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Real(r) => write!(f, "{:?}", r),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Blob(b) => {
+                write!(f, "x'")?;
+                for byte in b {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+macro_rules! from_integer {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Value {
+                fn from(i: $t) -> Self {
+                    Value::Integer(i64::from(i))
+                }
+            }
+        )*
+    };
+}
+
+from_integer!(i8, i16, i32, u8, u16, u32);
+
+impl From<f64> for Value {
+    fn from(r: f64) -> Self {
+        Value::Real(r)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(r: f32) -> Self {
+        Value::Real(f64::from(r))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Blob(b)
+    }
+}
+
+/// `None` is NULL.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+/// How a column converts values stored into it, derived from its declared
+/// type name using the same rules as SQLite.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Integer,
+    Real,
+    Numeric,
+    Boolean,
+    Text,
+    Blob,
+}
+
+impl Affinity {
+    /// Whether the column prefers numbers, booleans counting as 0 and 1.
+    pub fn is_numeric(self) -> bool {
+        matches!(
+            self,
+            Affinity::Integer | Affinity::Real | Affinity::Numeric | Affinity::Boolean
+        )
+    }
+
+    pub fn from_type_name(type_name: &str) -> Affinity {
+        let type_name = type_name.to_ascii_uppercase();
+        if type_name.contains("INT") {
+            Affinity::Integer
+        } else if type_name.contains("BOOL") {
+            Affinity::Boolean
+        } else if ["CHAR", "CLOB", "TEXT"]
+            .iter()
+            .any(|t| type_name.contains(t))
+        {
+            Affinity::Text
+        } else if type_name.contains("BLOB") || type_name.is_empty() {
+            Affinity::Blob
+        } else if ["REAL", "FLOA", "DOUB"]
+            .iter()
+            .any(|t| type_name.contains(t))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// Converts `value` to the column's preferred storage class when that can
+    /// be done without losing information. NULL is never converted.
+    pub fn apply(self, value: Value) -> Value {
+        match (self, value) {
+            (Affinity::Text, Value::Integer(i)) => Value::Text(i.to_string()),
+            (Affinity::Text, Value::Real(r)) => Value::Text(format!("{:?}", r)),
+            (Affinity::Integer | Affinity::Numeric, Value::Text(s)) => {
+                numeric_from_text(&s).unwrap_or(Value::Text(s))
+            }
+            (Affinity::Integer | Affinity::Numeric, Value::Real(r)) => real_to_integer(r),
+            (Affinity::Real, Value::Text(s)) => match numeric_from_text(&s) {
+                Some(Value::Integer(i)) => Value::Real(i as f64),
+                Some(value) => value,
+                None => Value::Text(s),
+            },
+            (Affinity::Real, Value::Integer(i)) => Value::Real(i as f64),
+            (Affinity::Integer | Affinity::Numeric, Value::Boolean(b)) => Value::Integer(b as i64),
+            (Affinity::Real, Value::Boolean(b)) => Value::Real(b as i64 as f64),
+            (Affinity::Text, Value::Boolean(b)) => Value::Text(b.to_string()),
+            (Affinity::Boolean, value) => boolean_from(value),
+            (_, value) => value,
+        }
+    }
+}
+
+fn real_to_integer(r: f64) -> Value {
+    if r.fract() == 0.0 && r >= i64::MIN as f64 && r < i64::MAX as f64 {
+        Value::Integer(r as i64)
+    } else {
+        Value::Real(r)
+    }
+}
+
+/// Converts 0, 1, `true` and `false` (in any case) to booleans and leaves
+/// every other value alone.
+fn boolean_from(value: Value) -> Value {
+    match value {
+        Value::Integer(i @ (0 | 1)) => Value::Boolean(i == 1),
+        Value::Real(r) if r == 0.0 || r == 1.0 => Value::Boolean(r == 1.0),
+        Value::Text(s) => match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" => Value::Boolean(true),
+            "false" | "0" => Value::Boolean(false),
+            _ => Value::Text(s),
+        },
+        value => value,
+    }
+}
+
+fn numeric_from_text(s: &str) -> Option<Value> {
+    let s = s.trim();
+    if let Ok(i) = s.parse::<i64>() {
+        return Some(Value::Integer(i));
+    }
+    match s.parse::<f64>() {
+        Ok(r) if r.is_finite() => Some(real_to_integer(r)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_affinity_from_type_name() {
+        assert_eq!(Affinity::from_type_name("INTEGER"), Affinity::Integer);
+        assert_eq!(Affinity::from_type_name("bigint"), Affinity::Integer);
+        assert_eq!(Affinity::from_type_name("VARCHAR(255)"), Affinity::Text);
+        assert_eq!(Affinity::from_type_name(""), Affinity::Blob);
+        assert_eq!(Affinity::from_type_name("DOUBLE"), Affinity::Real);
+        assert_eq!(Affinity::from_type_name("BOOLEAN"), Affinity::Boolean);
+        assert_eq!(Affinity::from_type_name("DECIMAL(10,2)"), Affinity::Numeric);
+    }
+
+    #[test]
+    fn test_affinity_conversions() {
+        assert_eq!(
+            Affinity::Integer.apply(Value::Text("42".to_string())),
+            Value::Integer(42)
+        );
+        assert_eq!(
+            Affinity::Integer.apply(Value::Text("abc".to_string())),
+            Value::Text("abc".to_string())
+        );
+        assert_eq!(
+            Affinity::Text.apply(Value::Integer(7)),
+            Value::Text("7".to_string())
+        );
+        assert_eq!(Affinity::Real.apply(Value::Integer(2)), Value::Real(2.0));
+        assert_eq!(
+            Affinity::Numeric.apply(Value::Text("2.0".to_string())),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            Affinity::Blob.apply(Value::Text("1".to_string())),
+            Value::Text("1".to_string())
+        );
+        assert_eq!(Affinity::Integer.apply(Value::Null), Value::Null);
+        assert_eq!(
+            Affinity::Boolean.apply(Value::Integer(1)),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            Affinity::Boolean.apply(Value::Text("FALSE".to_string())),
+            Value::Boolean(false)
+        );
+        assert_eq!(
+            Affinity::Boolean.apply(Value::Integer(2)),
+            Value::Integer(2)
+        );
+        assert_eq!(
+            Affinity::Integer.apply(Value::Boolean(true)),
+            Value::Integer(1)
+        );
+    }
+
+    #[test]
+    fn test_null_comparisons_are_unknown() {
+        assert_eq!(Value::Null.sql_eq(&Value::Null), None);
+        assert_eq!(Value::Integer(1).sql_eq(&Value::Null), None);
+        assert_eq!(Value::Integer(1).sql_eq(&Value::Real(1.0)), Some(true));
+        assert_eq!(
+            Value::Integer(1).sql_cmp(&Value::Text("1".to_string())),
+            Some(Ordering::Less)
+        );
+        assert_eq!(Value::Null.is_null_value(), Value::Integer(1));
+        assert_eq!(Value::Integer(0).is_not_null_value(), Value::Integer(1));
+    }
+
+    #[test]
+    fn test_numeric_comparisons() {
+        // Large integers must not lose precision by going through f64
+        assert_eq!(
+            Value::Integer(i64::MAX).sql_cmp(&Value::Integer(i64::MAX - 1)),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            Value::Integer(-5).sql_cmp(&Value::Real(-4.5)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(Value::Boolean(true).sql_eq(&Value::Integer(1)), Some(true));
+        assert_eq!(
+            Value::Boolean(false).sql_cmp(&Value::Real(0.5)),
+            Some(Ordering::Less)
+        );
+    }
+
+    #[test]
+    fn test_three_valued_logic() {
+        assert_eq!(and3(None, Some(false)), Some(false));
+        assert_eq!(and3(None, Some(true)), None);
+        assert_eq!(or3(None, Some(true)), Some(true));
+        assert_eq!(or3(None, Some(false)), None);
+        assert_eq!(not3(None), None);
+        assert_eq!(not3(Some(true)), Some(false));
+    }
+}
@@ -0,0 +1,1805 @@
+use crate::aggregate::{group_key, Aggregation, HashAggregator, SortedGroups};
+use crate::btree::{compare_prefix, BTree, BTreeCursor};
+use crate::expr::{self, BinaryOp, Expr, ScalarFunction, UnaryOp};
+use crate::pager::JournalMode;
+use crate::parser::{CreateIndex, CreateTable};
+use crate::sorter::{SortedRecords, Sorter};
+use crate::stats;
+use crate::table::{self, IndexError, Row, Table, UniqueViolation};
+use crate::value::{not3, Affinity, Value};
+use log::info;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// One instruction of a program. Registers are numbered from 0 and cursors
+/// are opened by number. Jump targets are instruction addresses.
+#[derive(Debug, Clone)]
+pub enum Insn {
+    /// Jumps to `target`.
+    Goto {
+        target: usize,
+    },
+    /// Ends the program.
+    Halt,
+    /// Ends the program with a row-not-found error.
+    HaltNotFound,
+    Integer {
+        value: i64,
+        dest: usize,
+    },
+    Value {
+        value: Value,
+        dest: usize,
+    },
+    /// Sets every register of `dest` to NULL.
+    Null {
+        dest: Range<usize>,
+    },
+    /// Copies the registers of `src` to the ones starting at `dest`.
+    Copy {
+        src: Range<usize>,
+        dest: usize,
+    },
+    /// Stores the value bound to parameter `parameter`, converted by the
+    /// affinity of the column it is stored into. NULL until one is bound.
+    Variable {
+        parameter: usize,
+        affinity: Affinity,
+        dest: usize,
+    },
+    /// Converts the register's value by the affinity of the column it is
+    /// stored into.
+    Affinity {
+        reg: usize,
+        affinity: Affinity,
+    },
+    /// Negates the register's value, as a number or as a truth value.
+    Unary {
+        op: UnaryOp,
+        reg: usize,
+        dest: usize,
+    },
+    /// Arithmetic, concatenation, a comparison or a three-valued AND/OR of
+    /// two registers.
+    Binary {
+        op: BinaryOp,
+        left: usize,
+        right: usize,
+        dest: usize,
+    },
+    /// `left IS [NOT] right`, where NULL equals NULL.
+    Is {
+        left: usize,
+        right: usize,
+        negated: bool,
+        dest: usize,
+    },
+    Like {
+        value: usize,
+        pattern: usize,
+        dest: usize,
+    },
+    /// Calls a scalar function on the registers of `args`.
+    Function {
+        function: ScalarFunction,
+        args: Range<usize>,
+        dest: usize,
+    },
+    /// Jumps if the register holds true.
+    If {
+        reg: usize,
+        target: usize,
+    },
+    /// Jumps if the register holds false or NULL.
+    IfNot {
+        reg: usize,
+        target: usize,
+    },
+    /// Jumps if the register holds false, but not if it is NULL.
+    IfFalse {
+        reg: usize,
+        target: usize,
+    },
+    IsNull {
+        reg: usize,
+        target: usize,
+    },
+    /// Jumps, taking one off the register, if it holds a positive number.
+    IfPos {
+        reg: usize,
+        target: usize,
+    },
+    /// Takes one off the register and jumps if it reaches zero.
+    DecrJumpZero {
+        reg: usize,
+        target: usize,
+    },
+
+    /// Opens a cursor over the rows of a table.
+    OpenRead {
+        cursor: usize,
+        root_page: u32,
+    },
+    /// Opens a cursor over the rows of a table that will be changed.
+    OpenWrite {
+        cursor: usize,
+        root_page: u32,
+    },
+    /// Moves a table, aggregate or sorter cursor to its first row, jumping
+    /// if there is none.
+    Rewind {
+        cursor: usize,
+        if_empty: usize,
+    },
+    /// Moves to the next row, jumping if there is one.
+    Next {
+        cursor: usize,
+        if_more: usize,
+    },
+    /// Reads a value of the row under the cursor.
+    Column {
+        cursor: usize,
+        column: usize,
+        dest: usize,
+    },
+    /// Reads the rowid of the row under a table cursor.
+    Rowid {
+        cursor: usize,
+        dest: usize,
+    },
+    /// Moves a table cursor to the row the index cursor's entry points at.
+    SeekRow {
+        cursor: usize,
+        index_cursor: usize,
+    },
+    /// Moves a table cursor to the row whose rowid is in the register,
+    /// jumping if there is none.
+    SeekRowid {
+        cursor: usize,
+        rowid: usize,
+        if_none: usize,
+    },
+    /// Fails if the record would repeat the values of another row in a
+    /// unique index. With `replaces_current`, the row under the cursor is
+    /// the one being replaced and its own entries do not count.
+    CheckUnique {
+        cursor: usize,
+        record: Range<usize>,
+        replaces_current: bool,
+    },
+    /// Appends the record to the cursor's table.
+    Insert {
+        cursor: usize,
+        record: Range<usize>,
+    },
+    /// Replaces the row under the cursor with the record.
+    Update {
+        cursor: usize,
+        record: Range<usize>,
+    },
+    /// Deletes the row under the cursor.
+    Delete {
+        cursor: usize,
+    },
+
+    /// Opens a cursor over the entries of an index.
+    OpenIndex {
+        cursor: usize,
+        root_page: u32,
+    },
+    /// Moves to the first entry whose key is at least `key`, jumping if
+    /// there is none.
+    SeekGe {
+        cursor: usize,
+        key: Range<usize>,
+        if_none: usize,
+    },
+    /// Jumps if the start of the entry's key is greater than `key`.
+    IdxGt {
+        cursor: usize,
+        key: Range<usize>,
+        target: usize,
+    },
+    /// Jumps if the start of the entry's key is at least `key`.
+    IdxGe {
+        cursor: usize,
+        key: Range<usize>,
+        target: usize,
+    },
+    /// Jumps if the start of the entry's key is at most `key`.
+    IdxLe {
+        cursor: usize,
+        key: Range<usize>,
+        target: usize,
+    },
+
+    /// Opens an empty hash table.
+    OpenHash {
+        cursor: usize,
+    },
+    /// Adds the record to the hash table under `key`, which is not NULL.
+    HashInsert {
+        cursor: usize,
+        key: usize,
+        record: Range<usize>,
+    },
+    /// Moves to the first record added under `key`, jumping if there is none.
+    HashSeek {
+        cursor: usize,
+        key: usize,
+        if_none: usize,
+    },
+
+    /// Opens a sorter on records whose first `descending.len()` values are
+    /// the sort key.
+    SorterOpen {
+        cursor: usize,
+        descending: Vec<bool>,
+    },
+    SorterInsert {
+        cursor: usize,
+        record: Range<usize>,
+    },
+    /// Sorts the records and moves to the first, jumping if there is none.
+    SorterSort {
+        cursor: usize,
+        if_empty: usize,
+    },
+
+    /// Opens a cursor that groups rows in a hash table, following the
+    /// program's aggregation at index `aggregation`.
+    AggOpen {
+        cursor: usize,
+        aggregation: usize,
+    },
+    /// Adds a record of the aggregation's layout to its group, jumping if
+    /// the groups outgrew the memory budget.
+    AggStep {
+        cursor: usize,
+        record: Range<usize>,
+        if_full: usize,
+    },
+    /// Opens a cursor that groups the records of a sorter, sorted by their
+    /// GROUP BY keys.
+    AggSorted {
+        cursor: usize,
+        aggregation: usize,
+        sorter: usize,
+    },
+    Close {
+        cursor: usize,
+    },
+
+    /// Hands the registers back to the caller as a result row.
+    ResultRow {
+        registers: Range<usize>,
+    },
+
+    CreateTable {
+        create: CreateTable,
+        sql: String,
+    },
+    CreateIndex {
+        create: CreateIndex,
+        sql: String,
+    },
+    /// Gathers the statistics of the table and stores them in the stats
+    /// table.
+    Analyze {
+        table: String,
+    },
+    /// Starts a transaction when `enable` is false, otherwise ends it by
+    /// committing or, with `rollback`, rolling it back.
+    AutoCommit {
+        enable: bool,
+        rollback: bool,
+    },
+    Savepoint {
+        op: SavepointOp,
+        name: String,
+    },
+    /// Switches the journal mode if `mode` is set, then stores the mode's
+    /// name in `dest`.
+    JournalMode {
+        mode: Option<JournalMode>,
+        dest: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SavepointOp {
+    /// Opens the savepoint, starting a transaction if there is none.
+    Begin,
+    /// Closes it, keeping its changes.
+    Release,
+    /// Undoes its changes and leaves it open.
+    Rollback,
+}
+
+impl Insn {
+    /// The instruction's jump target, if it has one.
+    fn target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Insn::Goto { target }
+            | Insn::If { target, .. }
+            | Insn::IfNot { target, .. }
+            | Insn::IfFalse { target, .. }
+            | Insn::IsNull { target, .. }
+            | Insn::IfPos { target, .. }
+            | Insn::DecrJumpZero { target, .. }
+            | Insn::IdxGt { target, .. }
+            | Insn::IdxGe { target, .. }
+            | Insn::IdxLe { target, .. } => Some(target),
+            Insn::Rewind { if_empty, .. } | Insn::SorterSort { if_empty, .. } => Some(if_empty),
+            Insn::SeekGe { if_none, .. }
+            | Insn::SeekRowid { if_none, .. }
+            | Insn::HashSeek { if_none, .. } => Some(if_none),
+            Insn::Next { if_more, .. } => Some(if_more),
+            Insn::AggStep { if_full, .. } => Some(if_full),
+            _ => None,
+        }
+    }
+
+    /// The opcode's name and its operands, the way EXPLAIN lists them.
+    pub fn describe(&self) -> (&'static str, String) {
+        let regs = |range: &Range<usize>| format!("r[{}..{}]", range.start, range.end);
+        match self {
+            Insn::Goto { target } => ("Goto", format!("goto {}", target)),
+            Insn::Halt => ("Halt", String::new()),
+            Insn::HaltNotFound => ("HaltNotFound", String::new()),
+            Insn::Integer { value, dest } => ("Integer", format!("r[{}]={}", dest, value)),
+            Insn::Value { value, dest } => ("Value", format!("r[{}]={}", dest, value)),
+            Insn::Null { dest } => ("Null", format!("{}=NULL", regs(dest))),
+            Insn::Copy { src, dest } => ("Copy", format!("r[{}..]={}", dest, regs(src))),
+            Insn::Variable {
+                parameter, dest, ..
+            } => ("Variable", format!("r[{}]=?{}", dest, parameter)),
+            Insn::Affinity { reg, affinity } => ("Affinity", format!("r[{}] {:?}", reg, affinity)),
+            Insn::Unary { op, reg, dest } => match op {
+                UnaryOp::Not => ("Not", format!("r[{}]=!r[{}]", dest, reg)),
+                UnaryOp::Negate => ("Negate", format!("r[{}]=-r[{}]", dest, reg)),
+                UnaryOp::Plus => ("Plus", format!("r[{}]=+r[{}]", dest, reg)),
+            },
+            Insn::Binary {
+                op,
+                left,
+                right,
+                dest,
+            } => (
+                binary_opcode(*op),
+                format!("r[{}]=r[{}] {} r[{}]", dest, left, op.symbol(), right),
+            ),
+            Insn::Is {
+                left,
+                right,
+                negated,
+                dest,
+            } => (
+                "Is",
+                format!(
+                    "r[{}]=r[{}] IS {}r[{}]",
+                    dest,
+                    left,
+                    if *negated { "NOT " } else { "" },
+                    right
+                ),
+            ),
+            Insn::Like {
+                value,
+                pattern,
+                dest,
+            } => (
+                "Like",
+                format!("r[{}]=r[{}] LIKE r[{}]", dest, value, pattern),
+            ),
+            Insn::Function {
+                function,
+                args,
+                dest,
+            } => (
+                "Function",
+                format!("r[{}]={}({})", dest, function.name(), regs(args)),
+            ),
+            Insn::If { reg, target } => ("If", format!("if r[{}] goto {}", reg, target)),
+            Insn::IfNot { reg, target } => ("IfNot", format!("if not r[{}] goto {}", reg, target)),
+            Insn::IfFalse { reg, target } => {
+                ("IfFalse", format!("if r[{}] is false goto {}", reg, target))
+            }
+            Insn::IsNull { reg, target } => {
+                ("IsNull", format!("if r[{}] is null goto {}", reg, target))
+            }
+            Insn::IfPos { reg, target } => (
+                "IfPos",
+                format!("if r[{}]>0 then r[{}]-=1, goto {}", reg, reg, target),
+            ),
+            Insn::DecrJumpZero { reg, target } => (
+                "DecrJumpZero",
+                format!("if (--r[{}])==0 goto {}", reg, target),
+            ),
+            Insn::OpenRead { cursor, root_page } => {
+                ("OpenRead", format!("cursor {} root {}", cursor, root_page))
+            }
+            Insn::OpenWrite { cursor, root_page } => {
+                ("OpenWrite", format!("cursor {} root {}", cursor, root_page))
+            }
+            Insn::Rewind { cursor, if_empty } => (
+                "Rewind",
+                format!("cursor {}, if empty goto {}", cursor, if_empty),
+            ),
+            Insn::Next { cursor, if_more } => (
+                "Next",
+                format!("cursor {}, if more goto {}", cursor, if_more),
+            ),
+            Insn::Column {
+                cursor,
+                column,
+                dest,
+            } => (
+                "Column",
+                format!("r[{}]=cursor {} column {}", dest, cursor, column),
+            ),
+            Insn::Rowid { cursor, dest } => ("Rowid", format!("r[{}]=cursor {}", dest, cursor)),
+            Insn::SeekRow {
+                cursor,
+                index_cursor,
+            } => (
+                "SeekRow",
+                format!("cursor {} to the row of cursor {}", cursor, index_cursor),
+            ),
+            Insn::SeekRowid {
+                cursor,
+                rowid,
+                if_none,
+            } => (
+                "SeekRowid",
+                format!(
+                    "cursor {} to rowid r[{}], if none goto {}",
+                    cursor, rowid, if_none
+                ),
+            ),
+            Insn::CheckUnique {
+                cursor,
+                record,
+                replaces_current,
+            } => (
+                "CheckUnique",
+                match replaces_current {
+                    true => format!("{} replacing the row of cursor {}", regs(record), cursor),
+                    false => format!("{} into cursor {}", regs(record), cursor),
+                },
+            ),
+            Insn::Insert { cursor, record } => {
+                ("Insert", format!("cursor {} {}", cursor, regs(record)))
+            }
+            Insn::Update { cursor, record } => {
+                ("Update", format!("cursor {} {}", cursor, regs(record)))
+            }
+            Insn::Delete { cursor } => ("Delete", format!("cursor {}", cursor)),
+            Insn::OpenIndex { cursor, root_page } => {
+                ("OpenIndex", format!("cursor {} root {}", cursor, root_page))
+            }
+            Insn::SeekGe {
+                cursor,
+                key,
+                if_none,
+            } => (
+                "SeekGe",
+                format!(
+                    "cursor {} key {}, if none goto {}",
+                    cursor,
+                    regs(key),
+                    if_none
+                ),
+            ),
+            Insn::IdxGt {
+                cursor,
+                key,
+                target,
+            } => (
+                "IdxGt",
+                format!("cursor {} key {}, goto {}", cursor, regs(key), target),
+            ),
+            Insn::IdxGe {
+                cursor,
+                key,
+                target,
+            } => (
+                "IdxGe",
+                format!("cursor {} key {}, goto {}", cursor, regs(key), target),
+            ),
+            Insn::IdxLe {
+                cursor,
+                key,
+                target,
+            } => (
+                "IdxLe",
+                format!("cursor {} key {}, goto {}", cursor, regs(key), target),
+            ),
+            Insn::OpenHash { cursor } => ("OpenHash", format!("cursor {}", cursor)),
+            Insn::HashInsert {
+                cursor,
+                key,
+                record,
+            } => (
+                "HashInsert",
+                format!("cursor {} key r[{}] {}", cursor, key, regs(record)),
+            ),
+            Insn::HashSeek {
+                cursor,
+                key,
+                if_none,
+            } => (
+                "HashSeek",
+                format!("cursor {} key r[{}], if none goto {}", cursor, key, if_none),
+            ),
+            Insn::SorterOpen { cursor, descending } => (
+                "SorterOpen",
+                format!("cursor {} with {} keys", cursor, descending.len()),
+            ),
+            Insn::SorterInsert { cursor, record } => (
+                "SorterInsert",
+                format!("cursor {} {}", cursor, regs(record)),
+            ),
+            Insn::SorterSort { cursor, if_empty } => (
+                "SorterSort",
+                format!("cursor {}, if empty goto {}", cursor, if_empty),
+            ),
+            Insn::AggOpen {
+                cursor,
+                aggregation,
+            } => (
+                "AggOpen",
+                format!("cursor {} aggregation {}", cursor, aggregation),
+            ),
+            Insn::AggStep {
+                cursor,
+                record,
+                if_full,
+            } => (
+                "AggStep",
+                format!(
+                    "cursor {} {}, if full goto {}",
+                    cursor,
+                    regs(record),
+                    if_full
+                ),
+            ),
+            Insn::AggSorted {
+                cursor,
+                aggregation,
+                sorter,
+            } => (
+                "AggSorted",
+                format!(
+                    "cursor {} aggregation {} from sorter {}",
+                    cursor, aggregation, sorter
+                ),
+            ),
+            Insn::Close { cursor } => ("Close", format!("cursor {}", cursor)),
+            Insn::ResultRow { registers } => ("ResultRow", regs(registers)),
+            Insn::CreateTable { create, .. } => ("CreateTable", create.name.clone()),
+            Insn::CreateIndex { create, .. } => (
+                "CreateIndex",
+                format!("{} on {}", create.name, create.table),
+            ),
+            Insn::Analyze { table } => ("Analyze", table.clone()),
+            Insn::AutoCommit { enable, rollback } => (
+                "AutoCommit",
+                format!("{} {}", u8::from(*enable), u8::from(*rollback)),
+            ),
+            Insn::Savepoint { op, name } => {
+                let op = match op {
+                    SavepointOp::Begin => "begin",
+                    SavepointOp::Release => "release",
+                    SavepointOp::Rollback => "rollback",
+                };
+                ("Savepoint", format!("{} {}", op, name))
+            }
+            Insn::JournalMode { mode, dest } => (
+                "JournalMode",
+                match mode {
+                    Some(mode) => format!("r[{}] {}", dest, mode.name()),
+                    None => format!("r[{}]", dest),
+                },
+            ),
+        }
+    }
+}
+
+/// The name EXPLAIN gives a `Binary` instruction.
+fn binary_opcode(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "Add",
+        BinaryOp::Subtract => "Subtract",
+        BinaryOp::Multiply => "Multiply",
+        BinaryOp::Divide => "Divide",
+        BinaryOp::Remainder => "Remainder",
+        BinaryOp::Concat => "Concat",
+        BinaryOp::Equal => "Eq",
+        BinaryOp::NotEqual => "Ne",
+        BinaryOp::Less => "Lt",
+        BinaryOp::LessEqual => "Le",
+        BinaryOp::Greater => "Gt",
+        BinaryOp::GreaterEqual => "Ge",
+        BinaryOp::And => "And",
+        BinaryOp::Or => "Or",
+    }
+}
+
+/// A compiled statement.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub insns: Vec<Insn>,
+    /// Names of the columns of the result rows.
+    pub columns: Vec<String>,
+    aggregations: Vec<Arc<Aggregation>>,
+    num_registers: usize,
+    num_cursors: usize,
+}
+
+/// Builds a program. Jumps are emitted to labels, which are resolved to
+/// addresses once the program is finished.
+pub struct ProgramBuilder {
+    insns: Vec<Insn>,
+    labels: Vec<Option<usize>>,
+    aggregations: Vec<Arc<Aggregation>>,
+    num_registers: usize,
+    num_cursors: usize,
+}
+
+impl ProgramBuilder {
+    /// Registers below `reserved` are left for the caller to lay out.
+    pub fn new(reserved: usize) -> Self {
+        ProgramBuilder {
+            insns: Vec::new(),
+            labels: Vec::new(),
+            aggregations: Vec::new(),
+            num_registers: reserved,
+            num_cursors: 0,
+        }
+    }
+
+    /// Reserves `count` consecutive registers and returns the first.
+    pub fn alloc_registers(&mut self, count: usize) -> usize {
+        self.num_registers += count;
+        self.num_registers - count
+    }
+
+    pub fn alloc_cursor(&mut self) -> usize {
+        self.num_cursors += 1;
+        self.num_cursors - 1
+    }
+
+    pub fn add_aggregation(&mut self, aggregation: Aggregation) -> usize {
+        self.aggregations.push(Arc::new(aggregation));
+        self.aggregations.len() - 1
+    }
+
+    /// A new label to jump to, placed later with `resolve`.
+    pub fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    /// Places the label at the next instruction.
+    pub fn resolve(&mut self, label: usize) {
+        self.labels[label] = Some(self.insns.len());
+    }
+
+    pub fn emit(&mut self, insn: Insn) {
+        self.insns.push(insn);
+    }
+
+    /// Emits code that evaluates a bound expression into `dest`, with its
+    /// column `i` read from register `base + i`.
+    pub fn expr(&mut self, expr: &Expr, base: usize, dest: usize) {
+        match expr {
+            Expr::Literal(value) => self.emit(Insn::Value {
+                value: value.clone(),
+                dest,
+            }),
+            Expr::Column(column) => unreachable!("column {} was not bound", column),
+            Expr::ColumnIndex(index) => self.emit(Insn::Copy {
+                src: base + index..base + index + 1,
+                dest,
+            }),
+            Expr::Parameter(parameter) => self.emit(Insn::Variable {
+                parameter: *parameter,
+                affinity: Affinity::Blob,
+                dest,
+            }),
+            Expr::Affinity { expr, affinity } => {
+                self.expr(expr, base, dest);
+                self.emit(Insn::Affinity {
+                    reg: dest,
+                    affinity: *affinity,
+                });
+            }
+            Expr::Unary {
+                op: UnaryOp::Plus,
+                expr,
+            } => self.expr(expr, base, dest),
+            Expr::Unary { op, expr } => {
+                self.expr(expr, base, dest);
+                self.emit(Insn::Unary {
+                    op: *op,
+                    reg: dest,
+                    dest,
+                });
+            }
+            // The right side is skipped when the left one decides
+            Expr::Binary {
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                left,
+                right,
+            } => {
+                let (decided, end) = (self.label(), self.label());
+                self.expr(left, base, dest);
+                self.emit(match op {
+                    BinaryOp::And => Insn::IfFalse {
+                        reg: dest,
+                        target: decided,
+                    },
+                    _ => Insn::If {
+                        reg: dest,
+                        target: decided,
+                    },
+                });
+                let reg = self.alloc_registers(1);
+                self.expr(right, base, reg);
+                self.emit(Insn::Binary {
+                    op: *op,
+                    left: dest,
+                    right: reg,
+                    dest,
+                });
+                self.emit(Insn::Goto { target: end });
+                self.resolve(decided);
+                self.emit(Insn::Integer {
+                    value: i64::from(*op == BinaryOp::Or),
+                    dest,
+                });
+                self.resolve(end);
+            }
+            Expr::Binary { op, left, right } => {
+                let regs = self.alloc_registers(2);
+                self.expr(left, base, regs);
+                self.expr(right, base, regs + 1);
+                self.emit(Insn::Binary {
+                    op: *op,
+                    left: regs,
+                    right: regs + 1,
+                    dest,
+                });
+            }
+            Expr::Is {
+                left,
+                right,
+                negated,
+            } => {
+                let regs = self.alloc_registers(2);
+                self.expr(left, base, regs);
+                self.expr(right, base, regs + 1);
+                self.emit(Insn::Is {
+                    left: regs,
+                    right: regs + 1,
+                    negated: *negated,
+                    dest,
+                });
+            }
+            Expr::Like {
+                expr,
+                pattern,
+                negated,
+            } => {
+                let regs = self.alloc_registers(2);
+                self.expr(expr, base, regs);
+                self.expr(pattern, base, regs + 1);
+                self.emit(Insn::Like {
+                    value: regs,
+                    pattern: regs + 1,
+                    dest,
+                });
+                self.negate_if(*negated, dest);
+            }
+            // A chain of ORs that stops at the first match
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let end = self.label();
+                let (value, item) = (self.alloc_registers(1), self.alloc_registers(1));
+                self.expr(expr, base, value);
+                self.emit(Insn::Integer { value: 0, dest });
+                for entry in list {
+                    self.expr(entry, base, item);
+                    self.emit(Insn::Binary {
+                        op: BinaryOp::Equal,
+                        left: value,
+                        right: item,
+                        dest: item,
+                    });
+                    self.emit(Insn::Binary {
+                        op: BinaryOp::Or,
+                        left: dest,
+                        right: item,
+                        dest,
+                    });
+                    self.emit(Insn::If {
+                        reg: dest,
+                        target: end,
+                    });
+                }
+                self.resolve(end);
+                self.negate_if(*negated, dest);
+            }
+            Expr::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => {
+                let (value, bound, above) = (
+                    self.alloc_registers(1),
+                    self.alloc_registers(1),
+                    self.alloc_registers(1),
+                );
+                self.expr(expr, base, value);
+                for (op, limit, result) in [
+                    (BinaryOp::GreaterEqual, low, above),
+                    (BinaryOp::LessEqual, high, bound),
+                ] {
+                    self.expr(limit, base, bound);
+                    self.emit(Insn::Binary {
+                        op,
+                        left: value,
+                        right: bound,
+                        dest: result,
+                    });
+                }
+                self.emit(Insn::Binary {
+                    op: BinaryOp::And,
+                    left: above,
+                    right: bound,
+                    dest,
+                });
+                self.negate_if(*negated, dest);
+            }
+            // Each WHEN jumps past its THEN unless it holds, or equals the
+            // operand if there is one
+            Expr::Case {
+                operand,
+                branches,
+                default,
+            } => {
+                let end = self.label();
+                let operand = operand.as_ref().map(|operand| {
+                    let reg = self.alloc_registers(1);
+                    self.expr(operand, base, reg);
+                    reg
+                });
+                let when = self.alloc_registers(1);
+                for (condition, result) in branches {
+                    let next = self.label();
+                    self.expr(condition, base, when);
+                    if let Some(operand) = operand {
+                        self.emit(Insn::Binary {
+                            op: BinaryOp::Equal,
+                            left: operand,
+                            right: when,
+                            dest: when,
+                        });
+                    }
+                    self.emit(Insn::IfNot {
+                        reg: when,
+                        target: next,
+                    });
+                    self.expr(result, base, dest);
+                    self.emit(Insn::Goto { target: end });
+                    self.resolve(next);
+                }
+                match default {
+                    Some(default) => self.expr(default, base, dest),
+                    None => self.emit(Insn::Null {
+                        dest: dest..dest + 1,
+                    }),
+                }
+                self.resolve(end);
+            }
+            Expr::Function { name, args, .. } => {
+                let Some(function) = ScalarFunction::from_name(name) else {
+                    unreachable!("aggregate {} was not computed", expr);
+                };
+                let start = self.alloc_registers(args.len());
+                for (i, arg) in args.iter().enumerate() {
+                    self.expr(arg, base, start + i);
+                }
+                self.emit(Insn::Function {
+                    function,
+                    args: start..start + args.len(),
+                    dest,
+                });
+            }
+        }
+    }
+
+    fn negate_if(&mut self, negated: bool, reg: usize) {
+        if negated {
+            self.emit(Insn::Unary {
+                op: UnaryOp::Not,
+                reg,
+                dest: reg,
+            });
+        }
+    }
+
+    pub fn finish(mut self, columns: Vec<String>) -> Program {
+        for insn in &mut self.insns {
+            if let Some(target) = insn.target_mut() {
+                *target = self.labels[*target].expect("jump to a label that was never placed");
+            }
+        }
+        Program {
+            insns: self.insns,
+            columns,
+            aggregations: self.aggregations,
+            num_registers: self.num_registers,
+            num_cursors: self.num_cursors,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    Io(io::Error),
+    UniqueViolation(UniqueViolation),
+    RowNotFound,
+    ColumnNotFound(String),
+    /// BEGIN inside a transaction.
+    TransactionActive,
+    /// COMMIT or ROLLBACK outside of one.
+    NoTransaction,
+    /// RELEASE or ROLLBACK TO a savepoint that is not open.
+    NoSuchSavepoint(String),
+}
+
+impl From<io::Error> for VmError {
+    fn from(error: io::Error) -> Self {
+        VmError::Io(error)
+    }
+}
+
+enum Groups {
+    Hashing(HashAggregator),
+    Hashed(std::vec::IntoIter<Vec<Value>>),
+    Sorted(SortedGroups),
+}
+
+enum VmCursor {
+    Table {
+        root_page: u32,
+        cursor: BTreeCursor,
+        /// The row under the cursor, once a column of it was read.
+        row: Option<Row>,
+    },
+    Index {
+        cursor: BTreeCursor,
+    },
+    Hash {
+        rows: HashMap<Vec<u8>, Vec<Row>>,
+        /// The key looked up and the position among its rows.
+        current: Option<(Vec<u8>, usize)>,
+    },
+    Sorter {
+        sorter: Option<Box<Sorter>>,
+        records: Option<SortedRecords>,
+        record: Option<Row>,
+    },
+    Aggregate {
+        groups: Groups,
+        group: Option<Vec<Value>>,
+    },
+}
+
+impl VmCursor {
+    /// Moves to the next row or group, false if there is none.
+    fn advance(&mut self, table: &mut Table) -> Result<bool, VmError> {
+        match self {
+            VmCursor::Table { cursor, row, .. } => {
+                *row = None;
+                Ok(cursor.next(&mut table.pager))
+            }
+            VmCursor::Index { cursor } => Ok(cursor.next(&mut table.pager)),
+            VmCursor::Hash { rows, current } => {
+                let Some((key, position)) = current else {
+                    return Ok(false);
+                };
+                *position += 1;
+                if *position < rows[key].len() {
+                    return Ok(true);
+                }
+                *current = None;
+                Ok(false)
+            }
+            VmCursor::Sorter {
+                records, record, ..
+            } => {
+                *record = records.as_mut().and_then(Iterator::next).transpose()?;
+                Ok(record.is_some())
+            }
+            VmCursor::Aggregate { groups, group } => {
+                *group = match groups {
+                    Groups::Hashing(_) => None,
+                    Groups::Hashed(rows) => rows.next(),
+                    Groups::Sorted(sorted) => sorted.next().transpose()?,
+                };
+                Ok(group.is_some())
+            }
+        }
+    }
+
+    fn column(&mut self, column: usize) -> io::Result<Value> {
+        Ok(match self {
+            VmCursor::Table { cursor, row, .. } if cursor.is_valid() => {
+                if row.is_none() {
+                    *row = Some(Row::from_payload(cursor.value())?);
+                }
+                row.as_ref().expect("the row was just read").values[column].clone()
+            }
+            VmCursor::Table { .. } => panic!("table cursor has no row"),
+            VmCursor::Index { cursor } if cursor.is_valid() => cursor.key()[column].clone(),
+            VmCursor::Index { .. } => panic!("index cursor has no entry"),
+            VmCursor::Hash { rows, current } => {
+                let (key, position) = current.as_ref().expect("hash cursor has no row");
+                rows[key][*position].values[column].clone()
+            }
+            VmCursor::Sorter { record, .. } => {
+                record.as_ref().expect("sorter has no record").values[column].clone()
+            }
+            VmCursor::Aggregate { group, .. } => {
+                group.as_ref().expect("aggregate cursor has no group")[column].clone()
+            }
+        })
+    }
+}
+
+/// Runs a program one result row at a time.
+pub struct Vm {
+    program: Arc<Program>,
+    pc: usize,
+    registers: Vec<Value>,
+    cursors: Vec<Option<VmCursor>>,
+    /// The values bound to the program's parameters, by number from 1.
+    parameters: Vec<Value>,
+    /// Whether the statement has started writing, so that its changes are
+    /// kept until it ends.
+    writing: bool,
+}
+
+impl Vm {
+    pub fn new(program: Arc<Program>) -> Self {
+        Vm::with_parameters(program, Vec::new())
+    }
+
+    /// A run of the program with `parameters` bound by number from 1.
+    /// Parameters past the end of them are NULL.
+    pub fn with_parameters(program: Arc<Program>, parameters: Vec<Value>) -> Self {
+        Vm {
+            pc: 0,
+            registers: vec![Value::Null; program.num_registers],
+            cursors: (0..program.num_cursors).map(|_| None).collect(),
+            program,
+            parameters,
+            writing: false,
+        }
+    }
+
+    /// Runs until the next result row, `None` once the program halts. If
+    /// the statement fails, every change it made is undone.
+    pub fn step(&mut self, table: &mut Table) -> Result<Option<Row>, VmError> {
+        let result = self.execute(table);
+        if self.writing && !matches!(result, Ok(Some(_))) {
+            self.writing = false;
+            match result {
+                Ok(_) => table.end_statement(),
+                Err(_) => table.rollback_statement(),
+            }
+        }
+        result
+    }
+
+    /// Gets the table ready for the statement's first write.
+    fn begin_write(&mut self, table: &mut Table) -> io::Result<()> {
+        if !self.writing {
+            table.begin_statement()?;
+            self.writing = true;
+        }
+        Ok(())
+    }
+
+    fn cursor(&mut self, cursor: usize) -> &mut VmCursor {
+        self.cursors[cursor].as_mut().expect("cursor is not open")
+    }
+
+    fn record(&self, registers: &Range<usize>) -> Row {
+        Row {
+            values: self.registers[registers.clone()].to_vec(),
+        }
+    }
+
+    /// The table and rowid under a table cursor, `None` for the rowid if
+    /// the cursor is on no row.
+    fn table_row(&mut self, cursor: usize) -> (u32, Option<i64>) {
+        match self.cursor(cursor) {
+            VmCursor::Table {
+                root_page, cursor, ..
+            } => (
+                *root_page,
+                cursor.is_valid().then(|| table::cursor_rowid(cursor)),
+            ),
+            _ => panic!("cursor {} is not a table cursor", cursor),
+        }
+    }
+
+    /// Compares the start of the index cursor's entry with the key.
+    fn compare_entry(&mut self, cursor: usize, key: &Range<usize>) -> Ordering {
+        let key = self.registers[key.clone()].to_vec();
+        match self.cursor(cursor) {
+            VmCursor::Index { cursor } if cursor.is_valid() => compare_prefix(cursor.key(), &key),
+            _ => panic!("cursor {} has no index entry", cursor),
+        }
+    }
+
+    fn execute(&mut self, table: &mut Table) -> Result<Option<Row>, VmError> {
+        let program = Arc::clone(&self.program);
+        loop {
+            let insn = &program.insns[self.pc];
+            self.pc += 1;
+            match insn {
+                Insn::Goto { target } => self.pc = *target,
+                Insn::Halt => {
+                    self.pc -= 1;
+                    return Ok(None);
+                }
+                Insn::HaltNotFound => {
+                    self.pc -= 1;
+                    return Err(VmError::RowNotFound);
+                }
+                Insn::Integer { value, dest } => self.registers[*dest] = Value::Integer(*value),
+                Insn::Value { value, dest } => self.registers[*dest] = value.clone(),
+                Insn::Null { dest } => self.registers[dest.clone()].fill(Value::Null),
+                Insn::Copy { src, dest } => {
+                    for (i, reg) in src.clone().enumerate() {
+                        self.registers[dest + i] = self.registers[reg].clone();
+                    }
+                }
+                Insn::Variable {
+                    parameter,
+                    affinity,
+                    dest,
+                } => {
+                    let value = expr::parameter(&self.parameters, *parameter);
+                    self.registers[*dest] = affinity.apply(value);
+                }
+                Insn::Affinity { reg, affinity } => {
+                    let value = std::mem::replace(&mut self.registers[*reg], Value::Null);
+                    self.registers[*reg] = affinity.apply(value);
+                }
+                Insn::Unary { op, reg, dest } => {
+                    let value = &self.registers[*reg];
+                    self.registers[*dest] = match op {
+                        UnaryOp::Not => Value::from_truth(not3(value.truth())),
+                        UnaryOp::Negate => expr::negate(value),
+                        UnaryOp::Plus => value.clone(),
+                    };
+                }
+                Insn::Binary {
+                    op,
+                    left,
+                    right,
+                    dest,
+                } => {
+                    let value =
+                        expr::binary_op(*op, &self.registers[*left], &self.registers[*right]);
+                    self.registers[*dest] = value;
+                }
+                Insn::Is {
+                    left,
+                    right,
+                    negated,
+                    dest,
+                } => {
+                    let equal = expr::is_equal(&self.registers[*left], &self.registers[*right]);
+                    self.registers[*dest] = Value::from_truth(Some(equal != *negated));
+                }
+                Insn::Like {
+                    value,
+                    pattern,
+                    dest,
+                } => {
+                    let (value, pattern) = (&self.registers[*value], &self.registers[*pattern]);
+                    self.registers[*dest] = match value.is_null() || pattern.is_null() {
+                        true => Value::Null,
+                        false => Value::from_truth(Some(expr::like(
+                            &pattern.to_string(),
+                            &value.to_string(),
+                        ))),
+                    };
+                }
+                Insn::Function {
+                    function,
+                    args,
+                    dest,
+                } => {
+                    self.registers[*dest] = function.call(&self.registers[args.clone()]);
+                }
+                Insn::If { reg, target } => {
+                    if self.registers[*reg].truth() == Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Insn::IfNot { reg, target } => {
+                    if self.registers[*reg].truth() != Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Insn::IfFalse { reg, target } => {
+                    if self.registers[*reg].truth() == Some(false) {
+                        self.pc = *target;
+                    }
+                }
+                Insn::IsNull { reg, target } => {
+                    if self.registers[*reg].is_null() {
+                        self.pc = *target;
+                    }
+                }
+                Insn::IfPos { reg, target } => {
+                    if let Value::Integer(n) = &mut self.registers[*reg] {
+                        if *n > 0 {
+                            *n -= 1;
+                            self.pc = *target;
+                        }
+                    }
+                }
+                Insn::DecrJumpZero { reg, target } => {
+                    if let Value::Integer(n) = &mut self.registers[*reg] {
+                        *n -= 1;
+                        if *n == 0 {
+                            self.pc = *target;
+                        }
+                    }
+                }
+
+                Insn::OpenRead { cursor, root_page } | Insn::OpenWrite { cursor, root_page } => {
+                    if matches!(insn, Insn::OpenWrite { .. }) {
+                        self.begin_write(table)?;
+                    }
+                    self.cursors[*cursor] = Some(VmCursor::Table {
+                        root_page: *root_page,
+                        cursor: BTree {
+                            root_page: *root_page,
+                        }
+                        .cursor(),
+                        row: None,
+                    });
+                }
+                Insn::Rewind { cursor, if_empty } => {
+                    let found = match self.cursor(*cursor) {
+                        VmCursor::Table { cursor, row, .. } => {
+                            *row = None;
+                            cursor.first(&mut table.pager)
+                        }
+                        VmCursor::Aggregate { groups, group } => {
+                            if let Groups::Hashing(_) = groups {
+                                let Groups::Hashing(aggregator) = std::mem::replace(
+                                    groups,
+                                    Groups::Hashed(Vec::new().into_iter()),
+                                ) else {
+                                    unreachable!()
+                                };
+                                *groups = Groups::Hashed(aggregator.finish().into_iter());
+                            }
+                            *group = None;
+                            self.cursor(*cursor).advance(table)?
+                        }
+                        _ => panic!("cursor {} can not be rewound", cursor),
+                    };
+                    if !found {
+                        self.pc = *if_empty;
+                    }
+                }
+                Insn::Next { cursor, if_more } => {
+                    if self.cursor(*cursor).advance(table)? {
+                        self.pc = *if_more;
+                    }
+                }
+                Insn::Column {
+                    cursor,
+                    column,
+                    dest,
+                } => {
+                    self.registers[*dest] = self.cursor(*cursor).column(*column)?;
+                }
+                Insn::Rowid { cursor, dest } => {
+                    let (_, rowid) = self.table_row(*cursor);
+                    let rowid = rowid.expect("rowid cursor is on a row");
+                    self.registers[*dest] = Value::Integer(rowid);
+                }
+                Insn::SeekRow {
+                    cursor,
+                    index_cursor,
+                } => {
+                    let rowid = match self.cursor(*index_cursor) {
+                        VmCursor::Index { cursor } if cursor.is_valid() => {
+                            table::locator_rowid(cursor.value())
+                        }
+                        _ => panic!("cursor {} has no index entry", index_cursor),
+                    };
+                    if let VmCursor::Table { cursor, row, .. } = self.cursor(*cursor) {
+                        if !cursor.seek(&mut table.pager, &table::rowid_key(rowid)) {
+                            panic!("Corrupt index entry for missing row {}", rowid);
+                        }
+                        *row = None;
+                    }
+                }
+                Insn::SeekRowid {
+                    cursor,
+                    rowid,
+                    if_none,
+                } => {
+                    let Value::Integer(rowid) = self.registers[*rowid] else {
+                        panic!("r[{}] holds no rowid", rowid);
+                    };
+                    let VmCursor::Table { cursor, row, .. } = self.cursor(*cursor) else {
+                        panic!("cursor {} is not a table cursor", cursor);
+                    };
+                    *row = None;
+                    if !cursor.seek(&mut table.pager, &table::rowid_key(rowid)) {
+                        self.pc = *if_none;
+                    }
+                }
+                Insn::CheckUnique {
+                    cursor,
+                    record,
+                    replaces_current,
+                } => {
+                    let row = self.record(record);
+                    let (root_page, rowid) = self.table_row(*cursor);
+                    let rowid = rowid.filter(|_| *replaces_current);
+                    if let Err(violation) = table.check_unique(root_page, &row, rowid) {
+                        return Err(VmError::UniqueViolation(violation));
+                    }
+                }
+                Insn::Insert { cursor, record } => {
+                    let row = self.record(record);
+                    let (root_page, _) = self.table_row(*cursor);
+                    table.insert_row(root_page, &row);
+                }
+                Insn::Update { cursor, record } => {
+                    let row = self.record(record);
+                    let (root_page, rowid) = self.table_row(*cursor);
+                    let rowid = rowid.expect("update cursor is on a row");
+                    table.update_row(root_page, rowid, &row)?;
+                }
+                Insn::Delete { cursor } => {
+                    let (root_page, rowid) = self.table_row(*cursor);
+                    let rowid = rowid.expect("delete cursor is on a row");
+                    table.delete_row(root_page, rowid)?;
+                }
+
+                Insn::OpenIndex { cursor, root_page } => {
+                    self.cursors[*cursor] = Some(VmCursor::Index {
+                        cursor: BTree {
+                            root_page: *root_page,
+                        }
+                        .cursor(),
+                    });
+                }
+                Insn::SeekGe {
+                    cursor,
+                    key,
+                    if_none,
+                } => {
+                    let key = self.registers[key.clone()].to_vec();
+                    let VmCursor::Index { cursor } = self.cursor(*cursor) else {
+                        panic!("cursor {} is not an index cursor", cursor);
+                    };
+                    if !cursor.seek_ge(&mut table.pager, &key) {
+                        self.pc = *if_none;
+                    }
+                }
+                Insn::IdxGt {
+                    cursor,
+                    key,
+                    target,
+                } => {
+                    if self.compare_entry(*cursor, key) == Ordering::Greater {
+                        self.pc = *target;
+                    }
+                }
+                Insn::IdxGe {
+                    cursor,
+                    key,
+                    target,
+                } => {
+                    if self.compare_entry(*cursor, key) != Ordering::Less {
+                        self.pc = *target;
+                    }
+                }
+                Insn::IdxLe {
+                    cursor,
+                    key,
+                    target,
+                } => {
+                    if self.compare_entry(*cursor, key) != Ordering::Greater {
+                        self.pc = *target;
+                    }
+                }
+
+                Insn::OpenHash { cursor } => {
+                    self.cursors[*cursor] = Some(VmCursor::Hash {
+                        rows: HashMap::new(),
+                        current: None,
+                    });
+                }
+                Insn::HashInsert {
+                    cursor,
+                    key,
+                    record,
+                } => {
+                    let key = group_key(&self.registers[*key..*key + 1]);
+                    let row = self.record(record);
+                    if let VmCursor::Hash { rows, .. } = self.cursor(*cursor) {
+                        rows.entry(key).or_default().push(row);
+                    }
+                }
+                Insn::HashSeek {
+                    cursor,
+                    key,
+                    if_none,
+                } => {
+                    let key = group_key(&self.registers[*key..*key + 1]);
+                    let VmCursor::Hash { rows, current } = self.cursor(*cursor) else {
+                        panic!("cursor {} is not a hash table", cursor);
+                    };
+                    *current = rows.contains_key(&key).then_some((key, 0));
+                    if current.is_none() {
+                        self.pc = *if_none;
+                    }
+                }
+
+                Insn::SorterOpen { cursor, descending } => {
+                    self.cursors[*cursor] = Some(VmCursor::Sorter {
+                        sorter: Some(Box::new(Sorter::new(
+                            descending.clone(),
+                            table.sort_memory_budget,
+                        ))),
+                        records: None,
+                        record: None,
+                    });
+                }
+                Insn::SorterInsert { cursor, record } => {
+                    let row = self.record(record);
+                    if let VmCursor::Sorter {
+                        sorter: Some(sorter),
+                        ..
+                    } = self.cursor(*cursor)
+                    {
+                        sorter.push(row)?;
+                    }
+                }
+                Insn::SorterSort { cursor, if_empty } => {
+                    let sorter_cursor = self.cursor(*cursor);
+                    if let VmCursor::Sorter {
+                        sorter, records, ..
+                    } = sorter_cursor
+                    {
+                        let sorter = sorter.take().expect("sorter was already sorted");
+                        *records = Some(sorter.finish()?);
+                    }
+                    if !sorter_cursor.advance(table)? {
+                        self.pc = *if_empty;
+                    }
+                }
+
+                Insn::AggOpen {
+                    cursor,
+                    aggregation,
+                } => {
+                    let aggregation = Arc::clone(&program.aggregations[*aggregation]);
+                    let aggregator = HashAggregator::new(aggregation, table.sort_memory_budget);
+                    self.cursors[*cursor] = Some(VmCursor::Aggregate {
+                        groups: Groups::Hashing(aggregator),
+                        group: None,
+                    });
+                }
+                Insn::AggStep {
+                    cursor,
+                    record,
+                    if_full,
+                } => {
+                    let values = &self.registers[record.clone()];
+                    let Some(VmCursor::Aggregate {
+                        groups: Groups::Hashing(aggregator),
+                        ..
+                    }) = self.cursors[*cursor].as_mut()
+                    else {
+                        panic!("cursor {} is not grouping rows", cursor);
+                    };
+                    if !aggregator.push(values) {
+                        info!("Too many groups for hash aggregation, sorting instead");
+                        self.pc = *if_full;
+                    }
+                }
+                Insn::AggSorted {
+                    cursor,
+                    aggregation,
+                    sorter,
+                } => {
+                    let Some(VmCursor::Sorter {
+                        sorter: Some(sorter),
+                        ..
+                    }) = self.cursors[*sorter].take()
+                    else {
+                        panic!("cursor {} is not an unsorted sorter", sorter);
+                    };
+                    let aggregation = Arc::clone(&program.aggregations[*aggregation]);
+                    let groups = SortedGroups::new(aggregation, sorter.finish()?);
+                    self.cursors[*cursor] = Some(VmCursor::Aggregate {
+                        groups: Groups::Sorted(groups),
+                        group: None,
+                    });
+                }
+                Insn::Close { cursor } => self.cursors[*cursor] = None,
+
+                Insn::ResultRow { registers } => return Ok(Some(self.record(registers))),
+
+                Insn::CreateTable { create, sql } => {
+                    self.begin_write(table)?;
+                    table
+                        .create_table(create, sql)
+                        .map_err(VmError::ColumnNotFound)?;
+                }
+                Insn::CreateIndex { create, sql } => {
+                    self.begin_write(table)?;
+                    let schema = table
+                        .catalog
+                        .find_table(&create.table)
+                        .cloned()
+                        .expect("index table was looked up when compiling");
+                    match table.create_index(create, &schema, sql) {
+                        Ok(_) => {}
+                        Err(IndexError::ColumnNotFound(column)) => {
+                            return Err(VmError::ColumnNotFound(column))
+                        }
+                        Err(IndexError::Unique(violation)) => {
+                            return Err(VmError::UniqueViolation(violation))
+                        }
+                        Err(IndexError::Io(error)) => return Err(VmError::Io(error)),
+                    }
+                }
+                Insn::Analyze { table: name } => {
+                    self.begin_write(table)?;
+                    let schema = table
+                        .catalog
+                        .find_table(name)
+                        .cloned()
+                        .expect("analyzed table was looked up when compiling");
+                    stats::analyze(table, &schema)?;
+                }
+                Insn::AutoCommit { enable, rollback } => match (table.in_transaction(), enable) {
+                    (true, false) => return Err(VmError::TransactionActive),
+                    (false, true) => return Err(VmError::NoTransaction),
+                    (false, false) => table.begin(false)?,
+                    (true, true) if *rollback => table.rollback(),
+                    (true, true) => table.commit()?,
+                },
+                Insn::Savepoint { op, name } => {
+                    let found = match op {
+                        SavepointOp::Begin => {
+                            table.savepoint(name)?;
+                            true
+                        }
+                        SavepointOp::Release => table.release(name)?,
+                        SavepointOp::Rollback => table.rollback_to(name),
+                    };
+                    if !found {
+                        return Err(VmError::NoSuchSavepoint(name.clone()));
+                    }
+                }
+                Insn::JournalMode { mode, dest } => {
+                    if let Some(mode) = mode {
+                        table.set_journal_mode(*mode)?;
+                    }
+                    let name = table.journal_mode().name();
+                    self.registers[*dest] = Value::Text(name.to_string());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Scope;
+    use crate::parser::{self, StatementType};
+    use crate::table::tests::temp_db_path;
+
+    fn run(program: Program, table: &mut Table) -> Vec<Vec<Value>> {
+        let mut vm = Vm::new(Arc::new(program));
+        let mut rows = Vec::new();
+        while let Some(row) = vm.step(table).unwrap() {
+            rows.push(row.values);
+        }
+        rows
+    }
+
+    #[test]
+    fn test_counting_loop() {
+        // for (r0 = 3; r0 > 0; r0--) yield r0
+        let mut builder = ProgramBuilder::new(0);
+        let counter = builder.alloc_registers(1);
+        let (top, done) = (builder.label(), builder.label());
+        builder.emit(Insn::Integer {
+            value: 3,
+            dest: counter,
+        });
+        builder.resolve(top);
+        builder.emit(Insn::ResultRow {
+            registers: counter..counter + 1,
+        });
+        builder.emit(Insn::DecrJumpZero {
+            reg: counter,
+            target: done,
+        });
+        builder.emit(Insn::Goto { target: top });
+        builder.resolve(done);
+        builder.emit(Insn::Halt);
+        let program = builder.finish(vec!["n".to_string()]);
+        assert_eq!(program.insns[3].describe().1, "goto 1");
+
+        let mut table = Table::db_open(&temp_db_path("vm_counting_loop"));
+        let rows = run(program, &mut table);
+        assert_eq!(
+            rows,
+            [
+                [Value::Integer(3)],
+                [Value::Integer(2)],
+                [Value::Integer(1)]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_insert_and_scan_table() {
+        let mut table = Table::db_open(&temp_db_path("vm_insert_and_scan"));
+        let root_page = BTree::create(&mut table.pager).root_page;
+
+        let mut builder = ProgramBuilder::new(2);
+        let cursor = builder.alloc_cursor();
+        builder.emit(Insn::OpenWrite { cursor, root_page });
+        for i in 1..=3 {
+            builder.emit(Insn::Integer { value: i, dest: 0 });
+            builder.emit(Insn::Value {
+                value: Value::Text(format!("row{}", i)),
+                dest: 1,
+            });
+            builder.emit(Insn::Insert {
+                cursor,
+                record: 0..2,
+            });
+        }
+        builder.emit(Insn::Halt);
+        assert!(run(builder.finish(Vec::new()), &mut table).is_empty());
+        assert_eq!(table.num_rows(root_page), 3);
+
+        // select b from t where a > 1
+        let mut builder = ProgramBuilder::new(2);
+        let cursor = builder.alloc_cursor();
+        let filter = builder.alloc_registers(1);
+        let (top, next, done) = (builder.label(), builder.label(), builder.label());
+        builder.emit(Insn::OpenRead { cursor, root_page });
+        builder.emit(Insn::Rewind {
+            cursor,
+            if_empty: done,
+        });
+        builder.resolve(top);
+        for column in 0..2 {
+            builder.emit(Insn::Column {
+                cursor,
+                column,
+                dest: column,
+            });
+        }
+        let StatementType::Select(select) =
+            parser::parse_statement("select a from t where a > 1").unwrap()
+        else {
+            unreachable!()
+        };
+        let scope = Scope::new("t", ["a".to_string(), "b".to_string()]);
+        builder.expr(&select.filter.unwrap().bind(&scope).unwrap(), 0, filter);
+        builder.emit(Insn::IfNot {
+            reg: filter,
+            target: next,
+        });
+        builder.emit(Insn::ResultRow { registers: 1..2 });
+        builder.resolve(next);
+        builder.emit(Insn::Next {
+            cursor,
+            if_more: top,
+        });
+        builder.resolve(done);
+        builder.emit(Insn::Halt);
+        let rows = run(builder.finish(vec!["b".to_string()]), &mut table);
+        assert_eq!(
+            rows,
+            [
+                [Value::Text("row2".to_string())],
+                [Value::Text("row3".to_string())]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compiled_expressions() {
+        let scope = Scope::new("t", ["a".to_string(), "b".to_string(), "c".to_string()]);
+        let row = [
+            Value::Integer(2),
+            Value::Null,
+            Value::Text("Bob".to_string()),
+        ];
+        let mut table = Table::db_open(&temp_db_path("vm_expressions"));
+        let cases = [
+            ("a * 3 + 1", "7"),
+            ("-a || c", "-2Bob"),
+            ("b = 1 or a = 2", "1"),
+            ("b = 1 and a = 3", "0"),
+            ("b = 1 and a = 2", "NULL"),
+            ("not (a < 1)", "1"),
+            ("b is null and c is not null", "1"),
+            ("c like 'b%' and c not like '%x'", "1"),
+            ("a in (1, b, 2)", "1"),
+            ("a not in (1, b)", "NULL"),
+            ("a between 1 and 3", "1"),
+            ("a not between 3 and 4", "1"),
+            ("case a when 1 then 'one' when 2 then 'two' end", "two"),
+            (
+                "case when b then 'b' when a > 1 then 'a' else 'none' end",
+                "a",
+            ),
+            ("case b when 1 then 'one' end", "NULL"),
+            ("coalesce(b, abs(-a), 3)", "2"),
+            ("upper(c) || length(c) || typeof(b)", "BOB3null"),
+            ("nullif(a, 2)", "NULL"),
+        ];
+        for (sql, expected) in cases {
+            let statement = parser::parse_statement(&format!("select * from t where {}", sql));
+            let Ok(StatementType::Select(select)) = statement else {
+                panic!("{} does not parse", sql);
+            };
+            let expr = select.filter.unwrap().bind(&scope).unwrap();
+            let mut builder = ProgramBuilder::new(3);
+            for (dest, value) in row.iter().enumerate() {
+                builder.emit(Insn::Value {
+                    value: value.clone(),
+                    dest,
+                });
+            }
+            let dest = builder.alloc_registers(1);
+            builder.expr(&expr, 0, dest);
+            builder.emit(Insn::ResultRow {
+                registers: dest..dest + 1,
+            });
+            builder.emit(Insn::Halt);
+            let rows = run(builder.finish(Vec::new()), &mut table);
+            assert_eq!(rows[0][0].to_string(), expected, "{}", sql);
+            // The program agrees with the planner's constant folding
+            assert_eq!(rows[0][0], expr.evaluate(&row), "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,516 @@
+//! The write-ahead log of a database in WAL mode. A commit appends the
+//! pages it changed to the log as frames, the last one marked with the size
+//! of the database, and leaves the database file alone. A reader takes each
+//! page from its newest frame among the commits that were in the log when
+//! its transaction started, so a writer never changes what it reads.
+//!
+//! Like SQLite, each reader records in the header how far into the log it
+//! reads, its read mark, under a read lock on the mark's byte of the
+//! database file. A checkpoint copies the newest version of each page into
+//! the file, but only from the frames up to the oldest mark in use, so
+//! that the file only changes in ways no reader can see. Once the file has
+//! every frame, the next commit starts the log over, unless a reader still
+//! takes pages from it. A reader that starts when the file has every frame
+//! reads only the file, so readers that come and go never keep the log
+//! from starting over. The last connection to close checkpoints and
+//! removes the log.
+//!
+//! That gives each transaction a snapshot of the whole database, not
+//! versions of single rows as MVCC keeps: there is still one writer at a
+//! time, and a write transaction fails with a busy error if its snapshot
+//! is older than the last commit.
+
+use crate::lock::{self, READ_MARKS};
+use crate::pager::{read_u32, write_u32};
+use crate::table::PAGE_SIZE;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The log starts with the magic and a salt that changes every time it
+// starts over, so frames of an earlier round are never taken for new ones.
+// Then the number of frames checkpointed into the database file, and the
+// read marks: the number of frames a reader reads, 0 for only the file.
+const WAL_MAGIC: &[u8; 16] = b"rsqlite3 wal\0\0\0\0";
+const SALT_OFFSET: usize = WAL_MAGIC.len();
+const BACKFILL_OFFSET: usize = SALT_OFFSET + 4;
+const READ_MARKS_OFFSET: usize = BACKFILL_OFFSET + 4;
+const WAL_HEADER_SIZE: usize = READ_MARKS_OFFSET + READ_MARKS * 4;
+
+/// How many times a reader looks for a read mark before giving up, while
+/// commits or the log starting over get in the way.
+const READ_MARK_ATTEMPTS: usize = 100;
+
+// Each frame is the page number, the size of the database in pages if the
+// frame ends a commit (0 otherwise), the salt and a checksum of the rest,
+// followed by the page. Checksums carry on from the frame before, so frames
+// left over from a commit cut short never pass for part of a later one.
+const FRAME_PAGE_OFFSET: usize = 0;
+const FRAME_COMMIT_OFFSET: usize = FRAME_PAGE_OFFSET + 4;
+const FRAME_SALT_OFFSET: usize = FRAME_COMMIT_OFFSET + 4;
+const FRAME_CHECKSUM_OFFSET: usize = FRAME_SALT_OFFSET + 4;
+const FRAME_HEADER_SIZE: usize = FRAME_CHECKSUM_OFFSET + 4;
+const FRAME_SIZE: usize = FRAME_HEADER_SIZE + PAGE_SIZE;
+
+pub struct Wal {
+    file: File,
+    /// `None` while the log has no header.
+    salt: Option<u32>,
+    /// The checksum of the last committed frame, the salt before the first.
+    checksum: u32,
+    /// The frames of committed pages, oldest first.
+    frames: HashMap<u32, Vec<u32>>,
+    /// The number of frames that belong to complete commits.
+    pub max_frame: u32,
+    /// The number of frames a checkpoint copied into the database file.
+    pub backfill: u32,
+}
+
+/// A reader's hold on one of the read marks, until its transaction ends.
+pub struct ReadMark {
+    pub slot: usize,
+    /// Whether the reader takes pages from the log. If not, the database
+    /// file has every page of its snapshot.
+    pub read_log: bool,
+}
+
+fn frame_offset(frame: u32) -> u64 {
+    WAL_HEADER_SIZE as u64 + frame as u64 * FRAME_SIZE as u64
+}
+
+/// FNV-1a over a frame, leaving out the checksum itself, starting from the
+/// checksum of the frame before.
+fn checksum(previous: u32, frame: &[u8]) -> u32 {
+    frame[..FRAME_CHECKSUM_OFFSET]
+        .iter()
+        .chain(&frame[FRAME_HEADER_SIZE..])
+        .fold(previous ^ 0x811c_9dc5, |hash, &byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        })
+}
+
+impl Wal {
+    /// Opens the log, creating an empty one, and reads its commits.
+    pub fn open(path: &str) -> io::Result<Wal> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut wal = Wal {
+            file,
+            salt: None,
+            checksum: 0,
+            frames: HashMap::new(),
+            max_frame: 0,
+            backfill: 0,
+        };
+        wal.refresh()?;
+        Ok(wal)
+    }
+
+    /// Whether the file at `path` is no longer the log this opened, as
+    /// after the last connection to close removed it.
+    pub fn replaced(&self, path: &str) -> io::Result<bool> {
+        let opened = self.file.metadata()?;
+        match std::fs::metadata(path) {
+            Ok(found) => Ok((found.dev(), found.ino()) != (opened.dev(), opened.ino())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the commits other connections appended since the last call.
+    /// Returns whether the log started over, so that earlier frames are
+    /// gone.
+    pub fn refresh(&mut self) -> io::Result<bool> {
+        let mut header = [0; WAL_HEADER_SIZE];
+        let salt = match self.file.read_exact_at(&mut header, 0) {
+            Ok(()) if &header[..WAL_MAGIC.len()] == WAL_MAGIC => {
+                Some(read_u32(&header, SALT_OFFSET))
+            }
+            Ok(()) => None,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e),
+        };
+        let restarted = salt != self.salt;
+        if restarted {
+            self.salt = salt;
+            self.checksum = salt.unwrap_or(0);
+            self.frames.clear();
+            self.max_frame = 0;
+        }
+        let Some(salt) = salt else {
+            self.backfill = 0;
+            return Ok(restarted);
+        };
+        self.backfill = read_u32(&header, BACKFILL_OFFSET);
+
+        // Frames past the last one that ends a commit were cut short
+        let mut pending = Vec::new();
+        let mut frame = vec![0; FRAME_SIZE];
+        let mut frame_num = self.max_frame;
+        let mut previous = self.checksum;
+        loop {
+            match self.file.read_exact_at(&mut frame, frame_offset(frame_num)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            previous = checksum(previous, &frame);
+            if read_u32(&frame, FRAME_SALT_OFFSET) != salt
+                || read_u32(&frame, FRAME_CHECKSUM_OFFSET) != previous
+            {
+                break;
+            }
+            pending.push((read_u32(&frame, FRAME_PAGE_OFFSET), frame_num));
+            frame_num += 1;
+            let num_pages = read_u32(&frame, FRAME_COMMIT_OFFSET);
+            if num_pages != 0 {
+                for (page_num, frame_num) in pending.drain(..) {
+                    self.frames.entry(page_num).or_default().push(frame_num);
+                }
+                self.max_frame = frame_num;
+                self.checksum = previous;
+            }
+        }
+        Ok(restarted)
+    }
+
+    /// Reads the new commits like `refresh`, then registers the connection
+    /// as a reader of them under a read lock on one of the read marks of
+    /// `db`, which it holds until its transaction ends. Also returns
+    /// whether the log started over.
+    pub fn begin_read(&mut self, db: &File) -> io::Result<(bool, ReadMark)> {
+        let mut restarted = false;
+        for _ in 0..READ_MARK_ATTEMPTS {
+            restarted |= self.refresh()?;
+            let read_log = self.max_frame > self.backfill;
+            let mark = if read_log { self.max_frame } else { 0 };
+            let Some(slot) = self.take_read_mark(db, mark)? else {
+                std::thread::yield_now();
+                continue;
+            };
+            // A commit that came in meanwhile may be checkpointed past the
+            // mark, and the log may have started over
+            let seen = (self.salt, self.max_frame);
+            restarted |= self.refresh()?;
+            if (self.salt, self.max_frame) == seen {
+                return Ok((restarted, ReadMark { slot, read_log }));
+            }
+            lock::unlock_read_mark(db, slot)?;
+        }
+        Err(lock::busy_error())
+    }
+
+    /// Takes a read lock on a read mark set to `mark`, setting a free one
+    /// to it if there is none. Returns the slot, or `None` if every read
+    /// mark is in use with another value.
+    fn take_read_mark(&self, db: &File, mark: u32) -> io::Result<Option<usize>> {
+        for slot in 0..READ_MARKS {
+            if self.read_mark(slot)? == mark && lock::lock_read_mark(db, slot, false)? {
+                if self.read_mark(slot)? == mark {
+                    return Ok(Some(slot));
+                }
+                lock::unlock_read_mark(db, slot)?;
+            }
+        }
+        for slot in 0..READ_MARKS {
+            if lock::lock_read_mark(db, slot, true)? {
+                self.set_read_mark(slot, mark)?;
+                lock::lock_read_mark(db, slot, false)?;
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The read mark in `slot`, 0 while the log has no header.
+    fn read_mark(&self, slot: usize) -> io::Result<u32> {
+        if self.salt.is_none() {
+            return Ok(0);
+        }
+        let mut mark = [0; 4];
+        self.file
+            .read_exact_at(&mut mark, (READ_MARKS_OFFSET + slot * 4) as u64)?;
+        Ok(u32::from_le_bytes(mark))
+    }
+
+    fn set_read_mark(&self, slot: usize, mark: u32) -> io::Result<()> {
+        if self.salt.is_none() {
+            return Ok(());
+        }
+        let offset = (READ_MARKS_OFFSET + slot * 4) as u64;
+        self.file.write_all_at(&mark.to_le_bytes(), offset)
+    }
+
+    /// The pages committed in frames from `max_frame` on.
+    pub fn changed_since(&self, max_frame: u32) -> Vec<u32> {
+        let mut pages = self
+            .frames
+            .iter()
+            .filter(|(_, frames)| frames.last().is_some_and(|&frame| frame >= max_frame))
+            .map(|(&page_num, _)| page_num)
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+        pages
+    }
+
+    /// The newest frame of the page among the first `max_frame` frames.
+    pub fn find(&self, page_num: u32, max_frame: u32) -> Option<u32> {
+        let frames = self.frames.get(&page_num)?;
+        let visible = frames.partition_point(|&frame| frame < max_frame);
+        visible.checked_sub(1).map(|i| frames[i])
+    }
+
+    pub fn read_frame(&self, frame: u32, page: &mut [u8]) -> io::Result<()> {
+        let offset = frame_offset(frame) + FRAME_HEADER_SIZE as u64;
+        self.file.read_exact_at(page, offset)
+    }
+
+    /// Appends a commit of the pages that leaves the database `num_pages`
+    /// pages long, and syncs the log. Starts the log over first if the
+    /// readers of `db` let it. Needs the writer's lock.
+    pub fn append(&mut self, db: &File, pages: &[(u32, &[u8])], num_pages: u32) -> io::Result<()> {
+        self.restart_if_unused(db)?;
+        let salt = match self.salt {
+            Some(salt) => salt,
+            None => self.restart()?,
+        };
+        let mut frames = vec![0; pages.len() * FRAME_SIZE];
+        for ((page_num, page), frame) in pages.iter().zip(frames.chunks_mut(FRAME_SIZE)) {
+            write_u32(frame, FRAME_PAGE_OFFSET, *page_num);
+            write_u32(frame, FRAME_SALT_OFFSET, salt);
+            frame[FRAME_HEADER_SIZE..].copy_from_slice(page);
+        }
+        if let Some(last) = frames.chunks_mut(FRAME_SIZE).last() {
+            write_u32(last, FRAME_COMMIT_OFFSET, num_pages);
+        }
+        let mut previous = self.checksum;
+        for frame in frames.chunks_mut(FRAME_SIZE) {
+            previous = checksum(previous, frame);
+            write_u32(frame, FRAME_CHECKSUM_OFFSET, previous);
+        }
+        // Frames left over from a commit cut short are written over
+        self.file
+            .write_all_at(&frames, frame_offset(self.max_frame))?;
+        self.file.sync_data()?;
+        for (i, (page_num, _)) in pages.iter().enumerate() {
+            let frame_num = self.max_frame + i as u32;
+            self.frames.entry(*page_num).or_default().push(frame_num);
+        }
+        self.max_frame += pages.len() as u32;
+        self.checksum = previous;
+        Ok(())
+    }
+
+    /// Copies the newest version of each page into the database file, from
+    /// the frames up to the oldest read mark in use of `db`. Returns whether
+    /// the file has every frame. Needs the writer's lock.
+    pub fn checkpoint(&mut self, db: &File) -> io::Result<bool> {
+        let mut safe = self.max_frame;
+        for slot in 0..READ_MARKS {
+            if lock::lock_read_mark(db, slot, true)? {
+                lock::unlock_read_mark(db, slot)?;
+            } else {
+                // A reader of the file alone must not see it change
+                let mark = self.read_mark(slot)?;
+                safe = safe.min(if mark == 0 { self.backfill } else { mark });
+            }
+        }
+        if safe > self.backfill {
+            self.copy_frames(db, safe)?;
+        }
+        Ok(self.backfill == self.max_frame)
+    }
+
+    /// Copies the pages of the first `max_frame` frames that are not in the
+    /// file yet.
+    fn copy_frames(&mut self, db: &File, max_frame: u32) -> io::Result<()> {
+        let mut pages = self
+            .frames
+            .keys()
+            .filter_map(|&page_num| {
+                let frame = self.find(page_num, max_frame)?;
+                (frame >= self.backfill).then_some((page_num, frame))
+            })
+            .collect::<Vec<_>>();
+        // The header goes last: until it is written the file is still in
+        // WAL mode, and a checkpoint cut short is done again from the log
+        pages.sort_by_key(|&(page_num, _)| (page_num == 0, page_num));
+        let mut frame_header = [0; FRAME_HEADER_SIZE];
+        self.file
+            .read_exact_at(&mut frame_header, frame_offset(max_frame - 1))?;
+        let num_pages = read_u32(&frame_header, FRAME_COMMIT_OFFSET);
+        let mut page = vec![0; PAGE_SIZE];
+        for (page_num, frame) in pages {
+            if page_num == 0 {
+                db.set_len(num_pages as u64 * PAGE_SIZE as u64)?;
+                db.sync_data()?;
+            }
+            self.read_frame(frame, &mut page)?;
+            db.write_all_at(&page, page_num as u64 * PAGE_SIZE as u64)?;
+        }
+        db.sync_data()?;
+        self.file
+            .write_all_at(&max_frame.to_le_bytes(), BACKFILL_OFFSET as u64)?;
+        self.backfill = max_frame;
+        Ok(())
+    }
+
+    /// Starts the log over if the file has every frame and no reader takes
+    /// pages from the log. The free read marks are held meanwhile, so that
+    /// no reader starts on the frames about to go.
+    fn restart_if_unused(&mut self, db: &File) -> io::Result<()> {
+        if self.max_frame == 0 || self.backfill < self.max_frame {
+            return Ok(());
+        }
+        let mut held = Vec::new();
+        let mut in_use = false;
+        for slot in 0..READ_MARKS {
+            if lock::lock_read_mark(db, slot, true)? {
+                held.push(slot);
+            } else if self.read_mark(slot)? != 0 {
+                in_use = true;
+                break;
+            }
+        }
+        let result = match in_use {
+            true => Ok(()),
+            false => self.restart().map(|_| ()),
+        };
+        for slot in held {
+            lock::unlock_read_mark(db, slot)?;
+        }
+        result
+    }
+
+    /// Empties the log under a new salt, returning the salt.
+    pub fn restart(&mut self) -> io::Result<u32> {
+        let salt = match self.salt {
+            Some(salt) => salt.wrapping_add(1),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.subsec_nanos()),
+        };
+        let mut header = [0; WAL_HEADER_SIZE];
+        header[..WAL_MAGIC.len()].copy_from_slice(WAL_MAGIC);
+        write_u32(&mut header, SALT_OFFSET, salt);
+        self.file.set_len(0)?;
+        self.file.write_all_at(&header, 0)?;
+        self.file.sync_data()?;
+        self.salt = Some(salt);
+        self.checksum = salt;
+        self.frames.clear();
+        self.max_frame = 0;
+        self.backfill = 0;
+        Ok(salt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::tests::temp_db_path;
+
+    fn open_db(path: &str) -> File {
+        File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .unwrap()
+    }
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    #[test]
+    fn test_commits_and_checkpoint() {
+        let path = temp_db_path("wal_commits");
+        let wal_path = format!("{}-wal", path);
+        let db = open_db(&path);
+        let mut writer = Wal::open(&wal_path).unwrap();
+        let mut reader = Wal::open(&wal_path).unwrap();
+        writer
+            .append(&db, &[(0, &page(1)), (1, &page(2))], 2)
+            .unwrap();
+        writer.append(&db, &[(1, &page(3))], 2).unwrap();
+        assert_eq!(writer.max_frame, 3);
+
+        // The reader picks up both commits, and a commit cut short is left
+        // out
+        writer
+            .file
+            .write_all_at(&page(9), frame_offset(writer.max_frame))
+            .unwrap();
+        assert!(reader.refresh().unwrap());
+        assert_eq!(reader.max_frame, 3);
+        assert_eq!(reader.find(1, 3), Some(2));
+        assert_eq!(reader.find(1, 2), Some(1));
+        assert_eq!(reader.find(1, 1), None);
+        assert_eq!(reader.find(2, 3), None);
+        let mut read = page(0);
+        reader.read_frame(2, &mut read).unwrap();
+        assert_eq!(read, page(3));
+        writer.append(&db, &[(0, &page(4))], 2).unwrap();
+        assert!(!reader.refresh().unwrap());
+        assert_eq!(reader.changed_since(3), [0]);
+        assert_eq!(reader.changed_since(2), [0, 1]);
+
+        // The log starts over with the commit after the checkpoint
+        assert!(writer.checkpoint(&db).unwrap());
+        assert_eq!(writer.backfill, 4);
+        assert_eq!(db.metadata().unwrap().len(), 2 * PAGE_SIZE as u64);
+        db.read_exact_at(&mut read, PAGE_SIZE as u64).unwrap();
+        assert_eq!(read, page(3));
+        assert!(!reader.refresh().unwrap());
+        assert_eq!(reader.backfill, 4);
+        writer.append(&db, &[(1, &page(5))], 2).unwrap();
+        assert_eq!(writer.max_frame, 1);
+        assert!(reader.refresh().unwrap());
+        assert_eq!(reader.find(1, 3), Some(0));
+    }
+
+    // Elsewhere locks of one process never conflict
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read_marks_hold_back_checkpoints() {
+        let path = temp_db_path("wal_read_marks");
+        let wal_path = format!("{}-wal", path);
+        let (db, reader_db) = (open_db(&path), open_db(&path));
+        let mut writer = Wal::open(&wal_path).unwrap();
+        let mut reader = Wal::open(&wal_path).unwrap();
+        writer.append(&db, &[(0, &page(1))], 1).unwrap();
+
+        // The file only gets the frames the reader reads
+        let (_, mark) = reader.begin_read(&reader_db).unwrap();
+        assert!(mark.read_log);
+        writer.append(&db, &[(0, &page(2))], 1).unwrap();
+        assert!(!writer.checkpoint(&db).unwrap());
+        assert_eq!(writer.backfill, 1);
+        let mut read = page(0);
+        db.read_exact_at(&mut read, 0).unwrap();
+        assert_eq!(read, page(1));
+        lock::unlock_read_mark(&reader_db, mark.slot).unwrap();
+        assert!(writer.checkpoint(&db).unwrap());
+        assert_eq!(writer.backfill, 2);
+
+        // A reader of the file alone lets the log start over, but keeps
+        // the file as it is
+        let (_, mark) = reader.begin_read(&reader_db).unwrap();
+        assert!(!mark.read_log);
+        writer.append(&db, &[(0, &page(3))], 1).unwrap();
+        assert_eq!(writer.max_frame, 1);
+        assert!(!writer.checkpoint(&db).unwrap());
+        db.read_exact_at(&mut read, 0).unwrap();
+        assert_eq!(read, page(2));
+        lock::unlock_read_mark(&reader_db, mark.slot).unwrap();
+        assert!(writer.checkpoint(&db).unwrap());
+    }
+}